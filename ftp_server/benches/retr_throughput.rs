@@ -0,0 +1,120 @@
+//! Measures how fast `RETR` sends a big file in binary mode, with `sendfile(2)` and with a
+//! buffered copy.
+//!
+//! Every run starts its own server on an ephemeral port, with its users and homes in a temporary
+//! directory: `cargo bench --bench retr_throughput`
+//!
+//! * `FTP_BENCH_SIZE_MB` - size of the file that is downloaded, 1024 by default
+use ftp_server::ftp::config::Config;
+use ftp_server::ServerBuilder;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::Path;
+use std::thread;
+use std::time::Instant;
+
+const FILE_NAME: &str = "retr_bench.bin";
+
+const USER: &str = "bench";
+
+fn expect_code(reader: &mut BufReader<TcpStream>, code: &str) {
+    let mut line = String::new();
+    reader.read_line(&mut line).expect("reading response");
+    assert!(line.starts_with(code), "expected {} got {:?}", code, line);
+}
+
+fn send(reader: &mut BufReader<TcpStream>, command: &str, code: &str) {
+    reader
+        .get_mut()
+        .write_all(format!("{}\r\n", command).as_bytes())
+        .expect("writing command");
+    expect_code(reader, code);
+}
+
+/// Writes the users file of `dir` with `USER` and its home, where the file of `size_mb` is
+fn write_user(dir: &Path, size_mb: u64) -> Config {
+    let mut config = Config::default();
+    config.users.file = dir.join("users.json");
+    config.users.root = dir.join("root");
+    config.users.log = dir.join("users.log");
+    let home = config.users.root.join(USER);
+    fs::create_dir_all(&home).expect("user directory");
+    let users = format!(
+        r#"{{"{}": {{"passwd": "123456", "chroot": {:?}, "uid": 0}}}}"#,
+        USER,
+        home.to_str().expect("UTF-8 temporary directory")
+    );
+    fs::write(&config.users.file, users).expect("users file");
+    File::create(home.join(FILE_NAME))
+        .and_then(|file| file.set_len(size_mb << 20))
+        .expect("creating the benchmark file");
+    config
+}
+
+/// Downloads the file from the server at `addr`, returns the bytes and the seconds it took
+fn retr(addr: SocketAddr) -> (u64, f64) {
+    let stream = TcpStream::connect(addr).expect("connecting to the server");
+    let mut reader = BufReader::new(stream);
+    expect_code(&mut reader, "220");
+    send(&mut reader, &format!("USER {}", USER), "331");
+    send(&mut reader, "PASS 123456", "230");
+    send(&mut reader, "TYPE I", "200");
+    let listener = TcpListener::bind("127.0.0.1:0").expect("data listener");
+    let port = listener.local_addr().unwrap().port();
+    send(
+        &mut reader,
+        &format!("PORT 127,0,0,1,{},{}", port >> 8, port & 0xff),
+        "200",
+    );
+    let start = Instant::now();
+    let receiver = thread::spawn(move || {
+        let (mut conn, _) = listener.accept().expect("data connection");
+        let mut buf = vec![0; 1 << 16];
+        let mut total = 0u64;
+        loop {
+            match conn.read(&mut buf) {
+                Ok(0) => return total,
+                Ok(read) => total += read as u64,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => panic!("{}", err),
+            }
+        }
+    });
+    send(&mut reader, &format!("RETR {}", FILE_NAME), "150");
+    let received = receiver.join().unwrap();
+    let elapsed = start.elapsed().as_secs_f64();
+    expect_code(&mut reader, "226");
+    let _ = reader.get_mut().write_all(b"QUIT\r\n");
+    (received, elapsed)
+}
+
+fn main() {
+    let size_mb: u64 = env::var("FTP_BENCH_SIZE_MB")
+        .ok()
+        .and_then(|size| size.parse().ok())
+        .unwrap_or(1024);
+    let dir = tempfile::tempdir().expect("temporary directory");
+    let config = write_user(dir.path(), size_mb);
+
+    for (sendfile, name) in [(true, "sendfile"), (false, "buffered")].iter() {
+        let mut config = config.clone();
+        config.transfer.sendfile = *sendfile;
+        let server = ServerBuilder::from_config(config)
+            .bind("127.0.0.1:0")
+            .spawn()
+            .expect("starting the server");
+        let (received, elapsed) = retr(server.local_addr());
+        assert_eq!(received, size_mb << 20);
+        println!(
+            "RETR {} MiB {:>8}: {:>8.1} MiB/s ({:.2}s)",
+            size_mb,
+            name,
+            received as f64 / (1 << 20) as f64 / elapsed,
+            elapsed
+        );
+        server.shutdown();
+        server.join().expect("stopping the server");
+    }
+}
@@ -0,0 +1,14 @@
+#![no_main]
+use ftp_server::ftp::command::Command;
+use libfuzzer_sys::fuzz_target;
+use std::convert::TryFrom;
+
+fuzz_target!(|data: &[u8]| {
+    // It should never panic, whatever the client sends
+    let _ = Command::parse(data, true);
+    if let Ok(command) = Command::try_from(data) {
+        // And every command that it accepts should be written back the same way
+        let line = [command.to_bytes(), b"\r\n".to_vec()].concat();
+        assert_eq!(Command::try_from(&line[..]), Ok(command));
+    }
+});
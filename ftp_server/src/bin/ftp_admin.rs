@@ -0,0 +1,134 @@
+//! Client of the admin socket of the server (`[admin] socket` or `--admin_socket`)
+#[cfg(unix)]
+use clap::{App, AppSettings, Arg, SubCommand};
+#[cfg(unix)]
+use ftp_server::ftp::admin::{self, Session};
+#[cfg(unix)]
+use serde_json::Value;
+
+/// Socket of the sample config
+#[cfg(unix)]
+const DEFAULT_SOCKET: &str = "./var/ftp_server.sock";
+
+#[cfg(unix)]
+fn main() {
+    let matches = App::new("FTP Server admin")
+        .version("1.0")
+        .about("Lists and kills the sessions of a running FTP server, disables users and reloads its config.")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(
+            Arg::with_name("socket")
+                .help("Admin socket of the server")
+                .short("s")
+                .long("socket")
+                .value_name("SOCKET")
+                .default_value(DEFAULT_SOCKET),
+        )
+        .subcommand(SubCommand::with_name("list").about("Lists the sessions and their transfers"))
+        .subcommand(
+            SubCommand::with_name("kill")
+                .about("Closes a session and its transfers")
+                .arg(Arg::with_name("id").help("Id of the session, see list").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("disable")
+                .about("Stops a user from logging in and kills its sessions")
+                .arg(Arg::with_name("user").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("enable")
+                .about("Lets a disabled user log in again")
+                .arg(Arg::with_name("user").required(true)),
+        )
+        .subcommand(SubCommand::with_name("reload").about("Loads the config and the users again"))
+        .get_matches();
+    let command = match matches.subcommand() {
+        ("kill", Some(args)) => format!("KILL {}", args.value_of("id").unwrap()),
+        ("disable", Some(args)) => format!("DISABLE {}", args.value_of("user").unwrap()),
+        ("enable", Some(args)) => format!("ENABLE {}", args.value_of("user").unwrap()),
+        (name, _) => name.to_ascii_uppercase(),
+    };
+    let socket = matches.value_of("socket").unwrap();
+    let answer = admin::request(socket, &command).unwrap_or_else(|err| {
+        eprintln!("{}: {}", socket, err);
+        std::process::exit(2)
+    });
+    if let Some(err) = answer["error"].as_str() {
+        eprintln!("{}", err);
+        std::process::exit(1)
+    }
+    match matches.subcommand_name() {
+        Some("list") => {
+            let sessions: Vec<Session> =
+                serde_json::from_value(answer["sessions"].clone()).unwrap_or_default();
+            print_sessions(&sessions);
+        }
+        Some("kill") => println!("Killed session {}", answer["killed"][0]),
+        Some("disable") => println!(
+            "Disabled {}, killed sessions: {}",
+            text(&answer["disabled"]),
+            answer["killed"]
+        ),
+        Some("enable") => println!("Enabled {}", text(&answer["enabled"])),
+        _ => println!("Reload requested"),
+    }
+}
+
+#[cfg(not(unix))]
+fn main() {
+    eprintln!("The admin socket needs Unix sockets");
+    std::process::exit(2)
+}
+
+#[cfg(unix)]
+fn text(value: &Value) -> &str {
+    value.as_str().unwrap_or_default()
+}
+
+#[cfg(unix)]
+fn print_sessions(sessions: &[Session]) {
+    println!(
+        "{:<6} {:<16} {:<24} {:<24} TRANSFER",
+        "ID", "USER", "REMOTE", "CWD"
+    );
+    for session in sessions {
+        let user = match (&session.user, session.logged_in) {
+            (Some(user), true) => user.clone(),
+            (Some(user), false) => format!("({})", user),
+            (None, _) => "-".to_string(),
+        };
+        let remote = session
+            .remote
+            .map(|remote| remote.to_string())
+            .unwrap_or_else(|| "-".to_string());
+        let transfer = match &session.transfer {
+            Some(transfer) => {
+                let progress = match transfer.size {
+                    Some(size) if size > 0 => format!(
+                        "{}/{} bytes ({}%)",
+                        transfer.bytes,
+                        size,
+                        transfer.bytes * 100 / size
+                    ),
+                    _ => format!("{} bytes", transfer.bytes),
+                };
+                format!(
+                    "{} {} {} in {:.1}s",
+                    transfer.direction,
+                    transfer.path.display(),
+                    progress,
+                    transfer.seconds
+                )
+            }
+            None => "-".to_string(),
+        };
+        println!(
+            "{:<6} {:<16} {:<24} {:<24} {}",
+            session.id,
+            user,
+            remote,
+            session.cwd.as_deref().unwrap_or("-"),
+            transfer
+        );
+    }
+}
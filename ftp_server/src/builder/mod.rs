@@ -0,0 +1,644 @@
+#[cfg(unix)]
+use crate::ftp::admin::AdminServer;
+use crate::ftp::config::{Config, ConfigError, ConfigLoader, FilenameEncoding, PartialUploads};
+use crate::ftp::hooks::{NoHooks, ServerHooks};
+use crate::ftp::metrics::MetricsServer;
+use crate::ftp::FTPServer;
+use crate::storage::{self, StorageBackend};
+use crate::tcp::{ReloadHandle, Server, ShutdownHandle};
+use std::error::Error;
+use std::io::{self, ErrorKind};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use user_manage::SystemUsers;
+
+/// Configures and starts an FTP server, so it can be embedded in other programs.
+/// ## Behaviour
+/// * It starts from a `Config` (the defaults with `new`), every setter overrides a value of it.
+/// * Without `users`, the users are loaded from `users_file` and the homes of new users are created inside `root`.
+/// * `build` binds every address, `run` blocks until the server stops and `spawn` runs it in a new thread.
+/// * With `metrics`, `build` binds that address too and serves the Prometheus metrics there from another thread.
+/// * With `admin_socket`, `build` also listens on that Unix socket for the commands of `ftp_admin`.
+/// * The files of the users are kept by the backend of `[storage]` (the local disk by default) unless there is a `storage`.
+///   `build` removes what the uploads that didn't finish left in the homes.
+/// * TLS isn't implemented by the connection layer yet, so `build` returns an error if the config has `[tls]`.
+/// * A reload (see `ReloadHandle`) loads the users again, and the config too if there is a `config_loader`.
+pub struct ServerBuilder {
+    config: Config,
+
+    config_loader: Option<ConfigLoader>,
+
+    /// Addresses passed to `bind`, they replace the ones of the config
+    addrs: Vec<String>,
+
+    users: Option<SystemUsers>,
+
+    hooks: Arc<dyn ServerHooks>,
+
+    /// Replaces the backend of the config
+    storage: Option<Arc<dyn StorageBackend>>,
+}
+
+impl Default for ServerBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ServerBuilder {
+    pub fn new() -> Self {
+        Self::from_config(Config::default())
+    }
+
+    /// Starts from a config that was loaded from a file
+    pub fn from_config(config: Config) -> Self {
+        Self {
+            config,
+            config_loader: None,
+            addrs: Vec::new(),
+            users: None,
+            hooks: Arc::new(NoHooks),
+            storage: None,
+        }
+    }
+
+    /// Adds an address to listen to, it can be called more than once
+    pub fn bind<T: Into<String>>(mut self, addr: T) -> Self {
+        self.addrs.push(addr.into());
+        self
+    }
+
+    /// Directory that contains the homes of the users
+    pub fn root<P: Into<PathBuf>>(mut self, root: P) -> Self {
+        self.config.users.root = root.into();
+        self
+    }
+
+    /// JSON file with the users, new users are also saved there
+    pub fn users_file<P: Into<PathBuf>>(mut self, users_file: P) -> Self {
+        self.config.users.file = users_file.into();
+        self
+    }
+
+    /// File where the user store writes its log
+    pub fn users_log<P: Into<PathBuf>>(mut self, users_log: P) -> Self {
+        self.config.users.log = users_log.into();
+        self
+    }
+
+    /// Uses a user store that is already loaded instead of `users_file`, `root` and `users_log`
+    pub fn users(mut self, users: SystemUsers) -> Self {
+        self.users = Some(users);
+        self
+    }
+
+    pub fn max_connections(mut self, max_connections: usize) -> Self {
+        self.config.limits.max_connections = max_connections;
+        self
+    }
+
+    pub fn workers(mut self, workers: usize) -> Self {
+        self.config.limits.workers = workers;
+        self
+    }
+
+    /// How many events can wait for a free worker before the event loop stops taking new ones
+    pub fn queue_size(mut self, queue_size: usize) -> Self {
+        self.config.limits.queue_size = queue_size;
+        self
+    }
+
+    /// Levels below the listed directory that `LIST -R` goes down
+    pub fn list_depth(mut self, list_depth: usize) -> Self {
+        self.config.limits.list_depth = list_depth;
+        self
+    }
+
+    /// Accepts commands that end with a bare LF instead of CRLF
+    pub fn allow_bare_lf(mut self, allow_bare_lf: bool) -> Self {
+        self.config.server.allow_bare_lf = allow_bare_lf;
+        self
+    }
+
+    /// Encoding of the file names of the clients, see `FilenameEncoding`
+    pub fn encoding(mut self, encoding: FilenameEncoding) -> Self {
+        self.config.server.encoding = encoding;
+        self
+    }
+
+    /// Message of the 220 reply that greets new clients
+    pub fn banner<T: Into<String>>(mut self, banner: T) -> Self {
+        self.config.server.banner = banner.into();
+        self
+    }
+
+    /// Inclusive range of ports of the passive data connections
+    pub fn passive_ports(mut self, min_port: u16, max_port: u16) -> Self {
+        self.config.passive.min_port = Some(min_port);
+        self.config.passive.max_port = Some(max_port);
+        self
+    }
+
+    /// How long the transfers can run after a graceful shutdown starts, it's rounded down to seconds
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.config.shutdown.timeout = timeout.as_secs();
+        self
+    }
+
+    /// What a graceful shutdown does with the uploads that didn't finish before the timeout
+    pub fn partial_uploads(mut self, policy: PartialUploads) -> Self {
+        self.config.shutdown.partial_uploads = policy;
+        self
+    }
+
+    /// If `DELE` and `RMD` move what they remove to the trash of the home, see `[trash]`
+    pub fn trash(mut self, enabled: bool) -> Self {
+        self.config.trash.enabled = enabled;
+        self
+    }
+
+    /// Address where the Prometheus metrics are served on `GET /metrics`
+    pub fn metrics<T: Into<String>>(mut self, addr: T) -> Self {
+        self.config.metrics.bind = Some(addr.into());
+        self
+    }
+
+    /// Unix socket where the admin commands are accepted, see `ftp::admin`
+    pub fn admin_socket<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.config.admin.socket = Some(path.into());
+        self
+    }
+
+    /// Gives the config on every reload, the setters of the builder don't apply to it
+    pub fn config_loader<F>(mut self, loader: F) -> Self
+    where
+        F: Fn() -> Result<Config, ConfigError> + Send + 'static,
+    {
+        self.config_loader = Some(Box::new(loader));
+        self
+    }
+
+    pub fn hooks<H: ServerHooks + 'static>(mut self, hooks: H) -> Self {
+        self.hooks = Arc::new(hooks);
+        self
+    }
+
+    /// Where the files of the users are kept, the homes are passed to it as they are in the users
+    pub fn storage<S: StorageBackend + 'static>(mut self, storage: S) -> Self {
+        self.storage = Some(Arc::new(storage));
+        self
+    }
+
+    /// Loads the users and binds every address, the server doesn't accept connections until `run`
+    pub fn build(self) -> Result<BuiltServer, Box<dyn Error>> {
+        let mut config = self.config;
+        if !self.addrs.is_empty() {
+            config.server.bind = self.addrs;
+        }
+        config.validate()?;
+        if config.tls.is_some() {
+            return Err(Box::new(io::Error::new(
+                ErrorKind::Unsupported,
+                "TLS is not supported by the server yet",
+            )));
+        }
+        #[cfg(not(unix))]
+        if config.admin.socket.is_some() {
+            return Err(Box::new(io::Error::new(
+                ErrorKind::Unsupported,
+                "the admin socket needs Unix sockets",
+            )));
+        }
+        let users = match self.users {
+            Some(users) => users,
+            None => SystemUsers::load(&config.users)?,
+        };
+        // A reload reads the users from the same files
+        config.users = users.config();
+        let server = Server::bind_all(&config.server.bind)?;
+        let metrics_listener = match &config.metrics.bind {
+            Some(addr) => Some(std::net::TcpListener::bind(addr)?),
+            None => None,
+        };
+        #[cfg(unix)]
+        let admin_socket = config.admin.socket.clone();
+        let storage = match self.storage {
+            Some(storage) => storage,
+            None => storage::from_config(&config.storage)?,
+        };
+        match storage.clean_up(users.root()) {
+            Ok(0) => {}
+            Ok(removed) => log_info!("[STORAGE] Removed {} unfinished uploads", removed),
+            Err(err) => log_warn!("[STORAGE] Couldn't remove the unfinished uploads: {}", err),
+        }
+        let mut ftp_server = FTPServer::with_users(config, users);
+        ftp_server.set_hooks(self.hooks);
+        ftp_server.set_storage(storage);
+        ftp_server.start_trash_purger();
+        if let Some(loader) = self.config_loader {
+            ftp_server.set_config_loader(loader);
+        }
+        let metrics = match metrics_listener {
+            Some(listener) => Some(MetricsServer::start(listener, ftp_server.metrics())?),
+            None => None,
+        };
+        #[cfg(unix)]
+        let admin = match admin_socket {
+            Some(path) => {
+                let admin = ftp_server.admin(server.kill_handle(), server.reload_handle());
+                Some(AdminServer::start(path, admin)?)
+            }
+            None => None,
+        };
+        Ok(BuiltServer {
+            server,
+            ftp_server,
+            metrics,
+            #[cfg(unix)]
+            admin,
+        })
+    }
+
+    /// Builds the server and handles connections on this thread until it's shut down
+    pub fn run(self) -> Result<(), Box<dyn Error>> {
+        self.build()?.run()
+    }
+
+    /// Builds the server and handles connections on a new thread
+    pub fn spawn(self) -> Result<ServerHandle, Box<dyn Error>> {
+        self.build()?.spawn()
+    }
+}
+
+/// Server that is bound but doesn't accept connections yet
+pub struct BuiltServer {
+    server: Server,
+
+    ftp_server: FTPServer,
+
+    /// It stops serving the metrics when it's dropped, so after the server stops
+    metrics: Option<MetricsServer>,
+
+    /// Like `metrics`, it removes the socket when the server stops
+    #[cfg(unix)]
+    admin: Option<AdminServer>,
+}
+
+impl BuiltServer {
+    pub fn local_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        self.server.local_addrs()
+    }
+
+    /// Address of the metrics endpoint, if they are served
+    pub fn metrics_addr(&self) -> Option<SocketAddr> {
+        self.metrics.as_ref().map(MetricsServer::local_addr)
+    }
+
+    /// Path of the admin socket, if there is one
+    #[cfg(unix)]
+    pub fn admin_socket(&self) -> Option<&Path> {
+        self.admin.as_ref().map(AdminServer::path)
+    }
+
+    /// Handle to stop `run` from other thread
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.server.shutdown_handle()
+    }
+
+    /// Handle to reload the config and the users from other thread
+    pub fn reload_handle(&self) -> ReloadHandle {
+        self.server.reload_handle()
+    }
+
+    /// Handles connections until an error happens or the server is shut down
+    pub fn run(mut self) -> Result<(), Box<dyn Error>> {
+        self.server.run(&mut self.ftp_server)
+    }
+
+    pub fn spawn(self) -> Result<ServerHandle, Box<dyn Error>> {
+        let addrs = self.local_addrs()?;
+        let metrics_addr = self.metrics_addr();
+        let shutdown = self.shutdown_handle();
+        let reload = self.reload_handle();
+        let thread = std::thread::Builder::new()
+            .name("ftp-server".to_string())
+            .spawn(move || self.run().map_err(|err| err.to_string()))?;
+        Ok(ServerHandle {
+            addrs,
+            metrics_addr,
+            shutdown,
+            reload,
+            thread,
+        })
+    }
+}
+
+/// Server that runs in its own thread
+pub struct ServerHandle {
+    addrs: Vec<SocketAddr>,
+
+    metrics_addr: Option<SocketAddr>,
+
+    shutdown: ShutdownHandle,
+
+    reload: ReloadHandle,
+
+    thread: JoinHandle<Result<(), String>>,
+}
+
+impl ServerHandle {
+    /// Address of the first listener, useful when it was bound to port 0
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addrs[0]
+    }
+
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.addrs
+    }
+
+    /// Address of the metrics endpoint, if they are served
+    pub fn metrics_addr(&self) -> Option<SocketAddr> {
+        self.metrics_addr
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    pub fn reload_handle(&self) -> ReloadHandle {
+        self.reload.clone()
+    }
+
+    /// Loads the config and the users again, see `FTPServer::reload`
+    pub fn reload(&self) {
+        self.reload.reload();
+    }
+
+    /// Closes the listeners, lets the transfers finish and stops, `join` waits for the thread.
+    /// See `ShutdownHandle::graceful_shutdown`
+    pub fn graceful_shutdown(&self) {
+        self.shutdown.graceful_shutdown();
+    }
+
+    /// Stops accepting connections and events, `join` waits for the thread
+    pub fn shutdown(&self) {
+        self.shutdown.shutdown();
+    }
+
+    /// Waits until the server stops, returning its error if it failed
+    pub fn join(self) -> Result<(), String> {
+        self.thread
+            .join()
+            .map_err(|_| "the server thread panicked".to_string())?
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ServerBuilder;
+    use crate::ftp::config::Config;
+    use crate::ftp::hooks::ServerHooks;
+    use crate::ftp::testing::write_users;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{SocketAddr, TcpStream};
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Default)]
+    struct Recorder(Arc<Mutex<Vec<String>>>);
+
+    impl ServerHooks for Recorder {
+        fn on_connect(&self, _peer: Option<SocketAddr>) {
+            self.0.lock().unwrap().push("connect".to_string());
+        }
+
+        fn on_login(&self, user: &str) {
+            self.0.lock().unwrap().push(format!("login {}", user));
+        }
+
+        fn on_disconnect(&self, user: Option<&str>) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("disconnect {:?}", user));
+        }
+    }
+
+    fn session(addr: SocketAddr, user: &str) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "220 Service ready for new user.\r\n");
+        write!(stream, "USER {}\r\nPASS 123456\r\nQUIT\r\n", user).unwrap();
+        let mut lines = String::new();
+        while reader.read_line(&mut lines).unwrap() > 0 {}
+        assert_eq!(
+            lines,
+            "331 User name okay, need password.\r\n\
+             230 User logged in, proceed.\r\n\
+             221 Service closing control connection.\r\n"
+        );
+    }
+
+    #[test]
+    fn builder_spawns_and_shuts_down() {
+        let dir = tempfile::tempdir().unwrap();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let handle = ServerBuilder::new()
+            .bind("127.0.0.1:0")
+            .bind("127.0.0.1:0")
+            .users(write_users(dir.path(), &["user_builder"]))
+            .workers(2)
+            .hooks(Recorder(events.clone()))
+            .spawn()
+            .unwrap();
+        assert_eq!(handle.local_addrs().len(), 2);
+        for addr in handle.local_addrs() {
+            session(*addr, "user_builder");
+        }
+        handle.shutdown();
+        handle.join().unwrap();
+        let events = events.lock().unwrap();
+        let logins = events.iter().filter(|e| *e == "login user_builder").count();
+        assert_eq!(logins, 2);
+        assert_eq!(events.iter().filter(|e| *e == "connect").count(), 2);
+    }
+
+    #[test]
+    fn builder_loads_users_file() {
+        let dir = tempfile::tempdir().unwrap();
+        write_users(dir.path(), &["user_builder_file"]);
+        let server = ServerBuilder::new()
+            .bind("127.0.0.1:0")
+            .root(dir.path().join("root"))
+            .users_file(dir.path().join("etc/users.json"))
+            .users_log(dir.path().join("var/ftpserver.log"))
+            .build()
+            .unwrap();
+        let addr = server.local_addrs().unwrap()[0];
+        let handle = server.spawn().unwrap();
+        session(addr, "user_builder_file");
+        // Users that don't exist are created in the root of the builder
+        session(addr, "user_builder_new");
+        assert!(dir.path().join("root/user_builder_new").is_dir());
+        handle.shutdown();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn builder_serves_the_metrics() {
+        let dir = tempfile::tempdir().unwrap();
+        let handle = ServerBuilder::new()
+            .bind("127.0.0.1:0")
+            .users(write_users(dir.path(), &["user_builder_metrics"]))
+            .metrics("127.0.0.1:0")
+            .spawn()
+            .unwrap();
+        session(handle.local_addr(), "user_builder_metrics");
+        let mut stream = TcpStream::connect(handle.metrics_addr().unwrap()).unwrap();
+        write!(stream, "GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut body = String::new();
+        stream.read_to_string(&mut body).unwrap();
+        assert!(body.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(body.contains("ftp_logins_total{result=\"success\"} 1\n"));
+        assert!(body.contains("ftp_commands_total{verb=\"PASS\",code=\"230\"} 1\n"));
+        handle.shutdown();
+        handle.join().unwrap();
+        // Nothing is served without `metrics`
+        let dir = tempfile::tempdir().unwrap();
+        let server = ServerBuilder::new()
+            .bind("127.0.0.1:0")
+            .users(write_users(dir.path(), &["user_builder_no_metrics"]))
+            .build()
+            .unwrap();
+        assert!(server.metrics_addr().is_none());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn builder_serves_the_admin_socket() {
+        use crate::ftp::admin;
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("admin.sock");
+        let handle = ServerBuilder::new()
+            .bind("127.0.0.1:0")
+            .users(write_users(dir.path(), &["user_admin_socket"]))
+            .admin_socket(&socket)
+            .spawn()
+            .unwrap();
+        let mut stream = TcpStream::connect(handle.local_addr()).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        write!(stream, "USER user_admin_socket\r\nPASS 123456\r\n").unwrap();
+        let mut lines = String::new();
+        while !lines.contains("230") {
+            reader.read_line(&mut lines).unwrap();
+        }
+        let sessions = admin::request(&socket, "LIST").unwrap()["sessions"].clone();
+        assert_eq!(sessions[0]["user"], "user_admin_socket");
+        assert_eq!(sessions[0]["logged_in"], true);
+        assert_eq!(sessions[0]["cwd"], "/");
+        let answer = admin::request(&socket, "DISABLE user_admin_socket").unwrap();
+        assert_eq!(answer["killed"][0], sessions[0]["id"]);
+        let mut rest = String::new();
+        while reader.read_line(&mut rest).unwrap() > 0 {}
+        assert_eq!(
+            rest,
+            "421 Service not available, closing control connection.\r\n"
+        );
+        let mut stream = TcpStream::connect(handle.local_addr()).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        write!(stream, "USER user_admin_socket\r\nPASS 123456\r\nQUIT\r\n").unwrap();
+        let mut lines = String::new();
+        while reader.read_line(&mut lines).unwrap() > 0 {}
+        assert!(lines.contains("\r\n530 Not logged in.\r\n"), "{}", lines);
+        assert!(admin::request(&socket, "ENABLE user_admin_socket").unwrap()["error"].is_null());
+        handle.shutdown();
+        handle.join().unwrap();
+        // The socket is removed with the server
+        assert!(!socket.exists());
+    }
+
+    #[test]
+    fn builder_uses_the_config() {
+        let dir = tempfile::tempdir().unwrap();
+        write_users(dir.path(), &["user_builder_config"]);
+        let config = Config::parse(&format!(
+            "[server]\nbind = [\"127.0.0.1:0\"]\nbanner = \"Hi\"\n\
+             [users]\nroot = {:?}\nfile = {:?}\nlog = {:?}\n",
+            dir.path().join("root"),
+            dir.path().join("etc/users.json"),
+            dir.path().join("var/ftpserver.log"),
+        ))
+        .unwrap();
+        let handle = ServerBuilder::from_config(config).spawn().unwrap();
+        let mut stream = TcpStream::connect(handle.local_addr()).unwrap();
+        let mut line = String::new();
+        BufReader::new(stream.try_clone().unwrap())
+            .read_line(&mut line)
+            .unwrap();
+        assert_eq!(line, "220 Hi\r\n");
+        write!(stream, "QUIT\r\n").unwrap();
+        handle.shutdown();
+        handle.join().unwrap();
+        assert!(ServerBuilder::new()
+            .bind("127.0.0.1:0")
+            .workers(0)
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn builder_reloads_the_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let users = write_users(dir.path(), &["user_builder_reload"]);
+        let config_file = dir.path().join("etc/ftp_server.toml");
+        // The reloads load the users of the temporary directory again
+        let config = |banner: &str| {
+            format!(
+                "[server]\nbanner = {:?}\n[users]\nfile = {:?}\nroot = {:?}\nlog = {:?}\n",
+                banner,
+                dir.path().join("etc/users.json"),
+                dir.path().join("root"),
+                dir.path().join("var/ftpserver.log"),
+            )
+        };
+        std::fs::write(&config_file, config("Before")).unwrap();
+        let loader_file = config_file.clone();
+        let handle = ServerBuilder::from_config(Config::load(&config_file).unwrap())
+            .bind("127.0.0.1:0")
+            .users(users)
+            .config_loader(move || Config::load(&loader_file))
+            .spawn()
+            .unwrap();
+        let banner = || {
+            let stream = TcpStream::connect(handle.local_addr()).unwrap();
+            let mut line = String::new();
+            BufReader::new(stream).read_line(&mut line).unwrap();
+            line
+        };
+        assert_eq!(banner(), "220 Before\r\n");
+        std::fs::write(&config_file, config("After")).unwrap();
+        handle.reload();
+        assert_eq!(banner(), "220 After\r\n");
+        // A config that can't be loaded keeps the current one
+        std::fs::write(&config_file, "[server]\nbanner = ").unwrap();
+        handle.reload();
+        assert_eq!(banner(), "220 After\r\n");
+        handle.shutdown();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn builder_rejects_tls() {
+        let config =
+            Config::parse("[tls]\ncertificate = \"cert.pem\"\nprivate_key = \"key.pem\"\n")
+                .unwrap();
+        let result = ServerBuilder::from_config(config)
+            .bind("127.0.0.1:0")
+            .build();
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,441 @@
+//! Admin interface of the server on a local Unix socket (`[admin] socket`), `ftp_admin` is its
+//! command line client. Every connection sends one command in a line and gets one JSON object
+//! back before the socket is closed:
+//! ```text
+//! LIST             {"sessions":[{"id":3,"user":"bob","logged_in":true,"remote":"127.0.0.1:50122","cwd":"/docs","transfer":null}]}
+//! KILL <id>        {"killed":[3]}
+//! DISABLE <user>   {"disabled":"bob","killed":[3,5]}
+//! ENABLE <user>    {"enabled":"bob"}
+//! RELOAD           {"reload":"requested"}
+//! ```
+//! A command that fails gets `{"error":"..."}`. Only the owner of the socket file can use it.
+use super::xferlog::{Direction, Transfer};
+use super::{FileTransferType, HashMutex, RequestContextMutex, RequestType};
+use crate::storage;
+use crate::tcp::{KillHandle, ReloadHandle};
+use mio::Token;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs::{self, DirBuilder, Permissions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::SocketAddr;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use user_manage::{SystemUsers, User};
+
+/// Longest command that the socket reads
+const MAX_COMMAND_LENGTH: u64 = 1024;
+
+/// Control connection of the server
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Session {
+    /// Id of the control connection, the one of `KILL`
+    pub id: usize,
+
+    /// User of `USER`, even if it didn't log in yet
+    pub user: Option<String>,
+
+    pub logged_in: bool,
+
+    pub remote: Option<SocketAddr>,
+
+    /// Current directory, relative to the home of the user
+    pub cwd: Option<String>,
+
+    pub transfer: Option<TransferProgress>,
+}
+
+/// `RETR` or `STOR` that a session is running
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TransferProgress {
+    /// `upload` or `download`
+    pub direction: String,
+
+    /// Where the file is on the disk
+    pub path: PathBuf,
+
+    /// Bytes that went through the data connection
+    pub bytes: u64,
+
+    /// Size of the file, only known for downloads
+    pub size: Option<u64>,
+
+    pub seconds: f64,
+}
+
+impl TransferProgress {
+    fn new(transfer: &Transfer, size: Option<u64>) -> Self {
+        Self {
+            direction: match transfer.direction() {
+                Direction::Incoming => "upload",
+                Direction::Outgoing => "download",
+            }
+            .to_string(),
+            path: transfer.path.clone(),
+            bytes: transfer.bytes,
+            size,
+            seconds: transfer.elapsed().as_secs_f64(),
+        }
+    }
+}
+
+/// What the admin commands act on, made by `FTPServer::admin`
+#[derive(Clone)]
+pub struct Admin {
+    connections: HashMutex<Token, RequestContextMutex>,
+
+    users: Arc<Mutex<SystemUsers>>,
+
+    kill: KillHandle,
+
+    reload: ReloadHandle,
+}
+
+impl Admin {
+    pub(super) fn new(
+        connections: HashMutex<Token, RequestContextMutex>,
+        users: Arc<Mutex<SystemUsers>>,
+        kill: KillHandle,
+        reload: ReloadHandle,
+    ) -> Self {
+        Self {
+            connections,
+            users,
+            kill,
+            reload,
+        }
+    }
+
+    /// Control connections that are open, by id
+    pub fn sessions(&self) -> Vec<Session> {
+        let connections: Vec<(Token, RequestContextMutex)> = self
+            .connections
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(token, ctx)| (*token, ctx.clone()))
+            .collect();
+        let mut sessions = Vec::new();
+        // Transfers by the control connection they belong to
+        let mut transfers = HashMap::new();
+        for (token, ctx) in connections {
+            let ctx = ctx.lock().unwrap();
+            match &ctx.request_type {
+                RequestType::CommandTransfer(_, _, _, _) => sessions.push(Session {
+                    id: token.0,
+                    user: ctx.user_id.clone(),
+                    logged_in: ctx.loged,
+                    remote: ctx.remote,
+                    cwd: None,
+                    transfer: None,
+                }),
+                RequestType::FileTransferActive(_, transfer_type, command)
+                | RequestType::FileTransferPassive(_, transfer_type, command) => {
+                    let progress = match transfer_type {
+                        FileTransferType::FileUpload(_, transfer, _) => {
+                            TransferProgress::new(transfer, None)
+                        }
+                        FileTransferType::FileDownload(to_send) => TransferProgress::new(
+                            &to_send.transfer,
+                            to_send.file.size().ok(),
+                        ),
+                        // A `LIST`
+                        FileTransferType::Buffer(_) => continue,
+                    };
+                    transfers.insert(*command, progress);
+                }
+                _ => {}
+            }
+        }
+        let users = self.users.lock().unwrap();
+        for session in sessions.iter_mut() {
+            session.transfer = transfers.remove(&Token(session.id));
+            if session.logged_in {
+                session.cwd = session
+                    .user
+                    .as_deref()
+                    .and_then(|user| users.get_user(user))
+                    .map(cwd);
+            }
+        }
+        sessions.sort_by_key(|session| session.id);
+        sessions
+    }
+
+    /// Closes the session `id` and its transfers, returns false if there isn't such a session
+    pub fn kill(&self, id: usize) -> bool {
+        let ctx = self.connections.lock().unwrap().get(&Token(id)).cloned();
+        let is_session = match ctx {
+            Some(ctx) => matches!(
+                ctx.lock().unwrap().request_type,
+                RequestType::CommandTransfer(_, _, _, _)
+            ),
+            None => false,
+        };
+        if is_session {
+            self.kill.kill(id);
+        }
+        is_session
+    }
+
+    /// Stops `user` from logging in and kills its sessions, returns their ids or `None` if there
+    /// isn't such a user. It lasts until `enable_user` or a restart
+    pub fn disable_user(&self, user: &str) -> Option<Vec<usize>> {
+        if !self.users.lock().unwrap().disable_user(user) {
+            return None;
+        }
+        let killed: Vec<usize> = self
+            .sessions()
+            .into_iter()
+            .filter(|session| session.user.as_deref() == Some(user))
+            .map(|session| session.id)
+            .filter(|id| self.kill(*id))
+            .collect();
+        Some(killed)
+    }
+
+    /// Lets a disabled user log in again, returns false if it wasn't disabled
+    pub fn enable_user(&self, user: &str) -> bool {
+        self.users.lock().unwrap().enable_user(user)
+    }
+
+    /// Loads the config and the users again, like `kill -HUP`
+    pub fn reload(&self) {
+        self.reload.reload();
+    }
+
+    /// Runs a command line of the socket
+    pub fn answer(&self, line: &str) -> Value {
+        let mut words = line.split_whitespace();
+        let verb = words.next().unwrap_or_default().to_ascii_uppercase();
+        match (verb.as_str(), words.next(), words.next()) {
+            ("LIST", None, None) => json!({ "sessions": self.sessions() }),
+            ("KILL", Some(id), None) => match id.parse() {
+                Ok(id) if self.kill(id) => json!({ "killed": [id] }),
+                _ => error(format!("there isn't a session {}", id)),
+            },
+            ("DISABLE", Some(user), None) => match self.disable_user(user) {
+                Some(killed) => json!({ "disabled": user, "killed": killed }),
+                None => error(format!("there isn't a user {}", user)),
+            },
+            ("ENABLE", Some(user), None) => {
+                if self.enable_user(user) {
+                    json!({ "enabled": user })
+                } else {
+                    error(format!("{} isn't disabled", user))
+                }
+            }
+            ("RELOAD", None, None) => {
+                self.reload();
+                json!({ "reload": "requested" })
+            }
+            _ => error(
+                "unknown command, the commands are LIST, KILL <id>, DISABLE <user>, \
+                 ENABLE <user> and RELOAD"
+                    .to_string(),
+            ),
+        }
+    }
+}
+
+fn error(message: String) -> Value {
+    json!({ "error": message })
+}
+
+/// Directory of `user` as `PWD` shows it
+fn cwd(user: &User) -> String {
+    match storage::resolve(Path::new("/"), user.get_actual_dir()) {
+        Some(cwd) => cwd.to_string_lossy().into_owned(),
+        None => user.get_actual_dir().to_string_lossy().into_owned(),
+    }
+}
+
+/// Thread that answers the admin commands, it stops and removes the socket when it's dropped
+pub struct AdminServer {
+    path: PathBuf,
+
+    stop: Arc<AtomicBool>,
+
+    thread: Option<JoinHandle<()>>,
+}
+
+impl AdminServer {
+    /// Binds the socket `path` and answers its commands from a new thread. A socket file that
+    /// nothing listens on is left by a server that didn't stop cleanly, it's replaced. Anything
+    /// else at `path` makes it fail
+    pub fn start<P: Into<PathBuf>>(path: P, admin: Admin) -> io::Result<Self> {
+        let path = path.into();
+        if let Ok(metadata) = fs::symlink_metadata(&path) {
+            if metadata.file_type().is_socket() && UnixStream::connect(&path).is_err() {
+                fs::remove_file(&path)?;
+            }
+        }
+        let listener = bind_private(&path)?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_thread = stop.clone();
+        let thread = std::thread::Builder::new()
+            .name("admin".to_string())
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    if stop_thread.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    if let Ok(stream) = stream {
+                        if let Err(err) = respond(stream, &admin) {
+                            log_debug!("[ADMIN] Error answering a command -> {}", err);
+                        }
+                    }
+                }
+            })?;
+        Ok(Self {
+            path,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for AdminServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        // Wake up the accept
+        let _ = UnixStream::connect(&self.path);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Binds a socket at `path` that only the owner of the process can connect to. It's bound inside
+/// a new 0700 directory next to `path` and linked there once it's 0600, so there is no moment when
+/// other users can connect. Fails if `path` exists
+fn bind_private(path: &Path) -> io::Result<UnixListener> {
+    let name = path.file_name().ok_or(io::ErrorKind::InvalidInput)?;
+    let dir = path.with_file_name(format!(
+        ".{}.{}",
+        name.to_string_lossy(),
+        std::process::id()
+    ));
+    DirBuilder::new().mode(0o700).create(&dir)?;
+    let socket = dir.join("socket");
+    let listener = UnixListener::bind(&socket).and_then(|listener| {
+        fs::set_permissions(&socket, Permissions::from_mode(0o600))?;
+        // Unlike a rename, the link doesn't replace what is at `path`
+        fs::hard_link(&socket, path)?;
+        Ok(listener)
+    });
+    let _ = fs::remove_file(&socket);
+    let _ = fs::remove_dir(&dir);
+    listener
+}
+
+/// Reads one command and answers it
+fn respond(stream: UnixStream, admin: &Admin) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut line = String::new();
+    BufReader::new((&stream).take(MAX_COMMAND_LENGTH)).read_line(&mut line)?;
+    let line = line.trim();
+    if line.is_empty() {
+        return Ok(());
+    }
+    log_info!("[ADMIN] {}", line);
+    let mut answer = admin.answer(line).to_string();
+    answer.push('\n');
+    (&stream).write_all(answer.as_bytes())
+}
+
+/// Sends `command` to the admin socket `path` and returns the answer
+pub fn request<P: AsRef<Path>>(path: P, command: &str) -> io::Result<Value> {
+    let mut stream = UnixStream::connect(path)?;
+    writeln!(stream, "{}", command)?;
+    let mut answer = String::new();
+    stream.read_to_string(&mut answer)?;
+    serde_json::from_str(&answer).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{request, AdminServer};
+    use crate::ftp::config::Config;
+    use crate::ftp::testing::write_users;
+    use crate::ftp::FTPServer;
+    use crate::tcp::Server;
+    use serde_json::json;
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::net::UnixListener;
+
+    #[test]
+    fn admin_answers_the_commands() {
+        let dir = tempfile::tempdir().unwrap();
+        let users = write_users(dir.path(), &["user_admin"]);
+        let server = Server::bind("127.0.0.1:0").unwrap();
+        let ftp_server = FTPServer::with_users(Config::default(), users);
+        let admin = ftp_server.admin(server.kill_handle(), server.reload_handle());
+        assert_eq!(admin.answer("LIST"), json!({ "sessions": [] }));
+        assert_eq!(
+            admin.answer("kill 7"),
+            json!({ "error": "there isn't a session 7" })
+        );
+        assert_eq!(
+            admin.answer("DISABLE nobody"),
+            json!({ "error": "there isn't a user nobody" })
+        );
+        assert_eq!(
+            admin.answer("DISABLE user_admin"),
+            json!({ "disabled": "user_admin", "killed": [] })
+        );
+        assert_eq!(
+            admin.answer("ENABLE user_admin"),
+            json!({ "enabled": "user_admin" })
+        );
+        assert_eq!(
+            admin.answer("ENABLE user_admin"),
+            json!({ "error": "user_admin isn't disabled" })
+        );
+        assert_eq!(admin.answer("RELOAD"), json!({ "reload": "requested" }));
+        assert!(admin.answer("LIST extra")["error"].is_string());
+        assert!(admin.answer("").get("error").is_some());
+    }
+
+    #[test]
+    fn admin_socket_is_private() {
+        let dir = tempfile::tempdir().unwrap();
+        let users = write_users(dir.path(), &["user_admin_socket"]);
+        let server = Server::bind("127.0.0.1:0").unwrap();
+        let ftp_server = FTPServer::with_users(Config::default(), users);
+        let admin = || ftp_server.admin(server.kill_handle(), server.reload_handle());
+        let path = dir.path().join("admin.sock");
+        // A file that isn't a socket is never removed
+        std::fs::write(&path, "not a socket").unwrap();
+        assert!(AdminServer::start(&path, admin()).is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "not a socket");
+        std::fs::remove_file(&path).unwrap();
+        // What a server that didn't stop cleanly left is replaced
+        drop(UnixListener::bind(&path).unwrap());
+        let admin_server = AdminServer::start(&path, admin()).unwrap();
+        let mode = std::fs::symlink_metadata(&path)
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(request(&path, "LIST").unwrap(), json!({ "sessions": [] }));
+        // One that is listening is kept
+        assert!(AdminServer::start(&path, admin()).is_err());
+        assert_eq!(request(&path, "LIST").unwrap(), json!({ "sessions": [] }));
+        drop(admin_server);
+        assert!(!path.exists());
+        let private_dir = format!(".admin.sock.{}", std::process::id());
+        assert!(!dir.path().join(private_dir).exists());
+    }
+}
@@ -1,4 +1,48 @@
-use std::{convert::TryFrom, net::Ipv4Addr, path::Path};
+use crate::system;
+use std::{convert::TryFrom, fmt, net::Ipv4Addr, path::Path};
+
+/// Representation type set by `TYPE`, it decides how files are sent on the data connection
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TransferMode {
+    /// `TYPE A`, line endings are sent as CRLF
+    Ascii,
+
+    /// `TYPE I`, the file is sent as it is
+    Binary,
+}
+
+impl Default for TransferMode {
+    /// The server always sent files byte by byte before supporting `TYPE`, so keep that by default
+    fn default() -> Self {
+        TransferMode::Binary
+    }
+}
+
+/// Commands of `SITE`, the extensions of the server
+#[derive(Clone, Debug, PartialEq)]
+pub enum SiteCommand<'a> {
+    /// SITE TRASH, lists the trash of the user
+    Trash,
+
+    /// SITE RESTORE <id>, moves an entry of the trash back to where it was
+    Restore(&'a str),
+
+    /// SITE RMDIR -r <path>, removes the directory and everything inside of it
+    RemoveTree(&'a Path),
+}
+
+/// Arguments of `LIST` and `NLST`, like `-la *.csv`
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub struct ListArgs<'a> {
+    /// `-a`, the names that start with a dot are listed too
+    pub all: bool,
+
+    /// `-R`, the directories are listed with what is inside of them
+    pub recursive: bool,
+
+    /// Directory, file or glob pattern like `*.csv`, None is the current directory
+    pub path: Option<&'a Path>,
+}
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum Command<'a> {
@@ -7,11 +51,11 @@ pub enum Command<'a> {
     ///  PORT h1,h2,h3,h4,p1,p2
     Port(Ipv4Addr, u16),
 
-    /// Pointer to string, which indicates the desired folder path
-    /// ## Cases
-    /// * './' | None -> ./
-    /// LIST <path>
-    List(&'a Path),
+    /// LIST [-aR] [<path>], the lines have the path of every entry
+    List(ListArgs<'a>),
+
+    /// NLST [-aR] [<path>], the lines only have the names, from the current directory
+    NameList(ListArgs<'a>),
 
     /// Pointer to string, which indicates the desired folder path
     /// RETR <path>
@@ -40,6 +84,9 @@ pub enum Command<'a> {
     /// Similar to CD in Unix, the FTP command is CWD
     ChangeDirectory(&'a Path),
 
+    /// CDUP, changes to the parent of the current directory
+    ChangeToParentDirectory,
+
     /// RNFR, indicates which filename or folder to rename
     RenameFrom(&'a Path),
 
@@ -48,6 +95,12 @@ pub enum Command<'a> {
 
     /// Quit the connection
     Quit,
+
+    /// TYPE A | TYPE I, sets the representation type for the next transfers
+    Type(TransferMode),
+
+    /// SITE <command> [<argument>]
+    Site(SiteCommand<'a>),
 }
 
 impl<'a> Command<'a> {
@@ -56,6 +109,7 @@ impl<'a> Command<'a> {
         match self {
             &Command::Port(_, _)
             | &Command::List(_)
+            | &Command::NameList(_)
             | &Command::Retr(_)
             | &Command::Mkdir(_)
             | &Command::Store(_)
@@ -63,22 +117,207 @@ impl<'a> Command<'a> {
             | &Command::RemoveDirectory(_)
             | &Command::CurrentDirectory
             | &Command::ChangeDirectory(_)
+            | &Command::ChangeToParentDirectory
             | &Command::RenameTo(_)
-            | &Command::RenameFrom(_) => true,
+            | &Command::RenameFrom(_)
+            | &Command::Site(_) => true,
             _ => false,
         }
     }
+
+    /// The command as it's written to the log, without the password of `PASS`
+    pub fn redacted(&self) -> String {
+        match self {
+            Command::Password(_) => "PASS ****".to_string(),
+            command => command.to_string(),
+        }
+    }
+
+    /// The command as it's sent on the wire, without the CRLF. Unlike `Display` the paths are
+    /// written byte by byte, so parsing the output (plus CRLF) returns the same command even for
+    /// names that aren't UTF-8
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let (start, path) = match self {
+            Command::List(args) => return list_bytes("LIST", args),
+            Command::NameList(args) => return list_bytes("NLST", args),
+            Command::Retr(path) => ("RETR ".to_string(), path),
+            Command::Store(path) => ("STOR ".to_string(), path),
+            Command::Mkdir(path) => ("MKD ".to_string(), path),
+            Command::Delete(path) => ("DELE ".to_string(), path),
+            Command::RemoveDirectory(path) => ("RMD ".to_string(), path),
+            Command::ChangeDirectory(path) => ("CWD ".to_string(), path),
+            Command::RenameFrom(path) => ("RNFR ".to_string(), path),
+            Command::RenameTo(path) => ("RNTO ".to_string(), path),
+            Command::Site(SiteCommand::RemoveTree(path)) => ("SITE RMDIR -r ".to_string(), path),
+            command => return command.to_string().into_bytes(),
+        };
+        let mut bytes = start.into_bytes();
+        bytes.extend_from_slice(&system::os_bytes(path.as_os_str()));
+        bytes
+    }
+}
+
+/// `LIST` or `NLST` with `args`, see `Command::to_bytes`
+fn list_bytes(verb: &str, args: &ListArgs) -> Vec<u8> {
+    // The flags are written by `Display`, the path byte by byte
+    let flags = ListArgs {
+        path: None,
+        ..*args
+    };
+    let mut bytes = format!("{}{}", verb, flags).into_bytes();
+    if let Some(path) = args.path {
+        bytes.push(b' ');
+        bytes.extend_from_slice(&system::os_bytes(path.as_os_str()));
+    }
+    bytes
+}
+
+impl fmt::Display for TransferMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransferMode::Ascii => write!(f, "A"),
+            TransferMode::Binary => write!(f, "I"),
+        }
+    }
+}
+
+impl fmt::Display for ListArgs<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.all || self.recursive {
+            write!(f, " -")?;
+            if self.all {
+                write!(f, "a")?;
+            }
+            if self.recursive {
+                write!(f, "R")?;
+            }
+        }
+        match self.path {
+            Some(path) => write!(f, " {}", path.display()),
+            None => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for SiteCommand<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SiteCommand::Trash => write!(f, "TRASH"),
+            SiteCommand::Restore(id) => write!(f, "RESTORE {}", id),
+            SiteCommand::RemoveTree(path) => write!(f, "RMDIR -r {}", path.display()),
+        }
+    }
 }
 
-fn expects_byte(byte: u8, expected_byte: u8, msg: &'static str) -> Result<(), &'static str> {
-    if byte != expected_byte {
-        return Err(msg);
+/// Writes the command as it's sent on the wire, without the CRLF, for the logs. Paths that aren't
+/// UTF-8 are written lossily, `Command::to_bytes` keeps their bytes
+impl fmt::Display for Command<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Command::Port(ip, port) => {
+                let [h1, h2, h3, h4] = ip.octets();
+                write!(
+                    f,
+                    "PORT {},{},{},{},{},{}",
+                    h1,
+                    h2,
+                    h3,
+                    h4,
+                    port / 256,
+                    port % 256
+                )
+            }
+            Command::List(args) => write!(f, "LIST{}", args),
+            Command::NameList(args) => write!(f, "NLST{}", args),
+            Command::Retr(path) => write!(f, "RETR {}", path.display()),
+            Command::User(user) => write!(f, "USER {}", user),
+            Command::Password(password) => write!(f, "PASS {}", password),
+            Command::CurrentDirectory => write!(f, "PWD"),
+            Command::Store(path) => write!(f, "STOR {}", path.display()),
+            Command::Passive => write!(f, "PASV"),
+            Command::Mkdir(path) => write!(f, "MKD {}", path.display()),
+            Command::Delete(path) => write!(f, "DELE {}", path.display()),
+            Command::RemoveDirectory(path) => write!(f, "RMD {}", path.display()),
+            Command::ChangeDirectory(path) => write!(f, "CWD {}", path.display()),
+            Command::ChangeToParentDirectory => write!(f, "CDUP"),
+            Command::RenameFrom(path) => write!(f, "RNFR {}", path.display()),
+            Command::RenameTo(path) => write!(f, "RNTO {}", path.display()),
+            Command::Quit => write!(f, "QUIT"),
+            Command::Type(mode) => write!(f, "TYPE {}", mode),
+            Command::Site(command) => write!(f, "SITE {}", command),
+        }
     }
-    Ok(())
 }
 
+/// Why a command line couldn't be parsed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ParseError {
+    /// The line doesn't end with CRLF (or LF when bare LF is allowed)
+    MissingLineEnding,
+
+    /// There is no command in the line
+    Empty,
+
+    /// The verb is not a command that the server implements
+    UnknownCommand,
+
+    /// The command needs an argument, e.g `RETR` without a path
+    MissingArgument(&'static str),
+
+    /// The command doesn't take arguments, e.g `PWD foo`
+    UnexpectedArgument(&'static str),
+
+    /// The argument is not valid UTF-8
+    InvalidUtf8,
+
+    /// The path has a NUL, CR or LF in it
+    InvalidPath,
+
+    /// The host part of `PORT h1,h2,h3,h4,p1,p2` is not valid
+    InvalidAddress,
+
+    /// The port part of `PORT h1,h2,h3,h4,p1,p2` is not valid
+    InvalidPort,
+
+    /// `TYPE` with a type that is not `A`, `I` or `L`
+    UnsupportedType,
+
+    /// `SITE` with a command that the server doesn't have
+    UnknownSiteCommand,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::MissingLineEnding => write!(f, "All commands should finish with CRLF"),
+            ParseError::Empty => write!(f, "Command is empty"),
+            ParseError::UnknownCommand => write!(f, "Unknown command"),
+            ParseError::MissingArgument(verb) => write!(f, "`{}` needs an argument", verb),
+            ParseError::UnexpectedArgument(verb) => {
+                write!(f, "`{}` doesn't take arguments", verb)
+            }
+            ParseError::InvalidUtf8 => write!(f, "Expected an UTF-8 argument"),
+            ParseError::InvalidPath => write!(f, "Paths can't have NUL, CR or LF"),
+            ParseError::InvalidAddress => write!(f, "Invalid IPv4 address"),
+            ParseError::InvalidPort => write!(f, "Invalid port number"),
+            ParseError::UnsupportedType => write!(f, "Unsupported type, expected `A` or `I`"),
+            ParseError::UnknownSiteCommand => {
+                write!(
+                    f,
+                    "Unknown SITE command, expected `TRASH`, `RESTORE` or `RMDIR`"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Longest verb that the server knows
+const MAX_VERB_LENGTH: usize = 4;
+
 fn ascii_to_u8(buff: &[u8]) -> Option<u8> {
-    if buff.len() > 3 {
+    if buff.is_empty() || buff.len() > 3 {
         return None;
     }
     let mut n: u16 = 0;
@@ -89,215 +328,247 @@ fn ascii_to_u8(buff: &[u8]) -> Option<u8> {
         let digit = (*byte as char).to_digit(10)?;
         n += digit as u16;
     }
-    if n > std::u8::MAX as u16 {
+    if n > u8::MAX as u16 {
         return None;
     }
     Some(n as u8)
 }
 
-/// Parses path out of the command
-fn parse_path<'a>(
-    // The entire command
-    command: &'a [u8],
-    // Expected command
-    expected_command: &[u8],
-    // Range of the command that should be used to compare
-    range_command: (usize, usize),
-) -> Result<&'a Path, &'static str> {
-    if command.len() <= 6 {
-        return Err("invalid command length");
-    }
-    if &command[range_command.0..range_command.1] != expected_command {
-        return Err("Invalid command");
-    }
-    expects_byte(
-        command[range_command.1],
-        b' ',
-        "Expected space in between command and the rest.",
-    )?;
-    // -2 because we wanna skip \r\n
-    let path_str = std::str::from_utf8(&command[range_command.1 + 1..command.len() - 2])
-        .map_err(|_| "expected utf8 string")?;
-    let path = Path::new(path_str);
-    Ok(path)
+/// Path argument as it was sent, spaces included. Names don't have to be UTF-8, but NUL, CR and
+/// LF are refused so they can't end up in the replies
+fn parse_path<'a>(verb: &'static str, argument: Option<&'a [u8]>) -> Result<&'a Path, ParseError> {
+    match argument {
+        Some(argument) if !argument.is_empty() => {
+            if argument
+                .iter()
+                .any(|byte| matches!(byte, b'\0' | b'\r' | b'\n'))
+            {
+                return Err(ParseError::InvalidPath);
+            }
+            path_from_bytes(argument)
+        }
+        _ => Err(ParseError::MissingArgument(verb)),
+    }
 }
 
-impl<'a> TryFrom<&'a [u8]> for Command<'a> {
-    type Error = &'static str;
+#[cfg(unix)]
+fn path_from_bytes(bytes: &[u8]) -> Result<&Path, ParseError> {
+    use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+    Ok(Path::new(OsStr::from_bytes(bytes)))
+}
+
+/// Other systems can't have names that aren't Unicode
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: &[u8]) -> Result<&Path, ParseError> {
+    std::str::from_utf8(bytes)
+        .map(Path::new)
+        .map_err(|_| ParseError::InvalidUtf8)
+}
 
-    fn try_from(command: &'a [u8]) -> Result<Self, &'static str> {
-        if command.len() <= 2 {
-            return Err("Command is too short");
+/// Single word argument, the surrounding whitespace is ignored
+fn parse_word<'a>(verb: &'static str, argument: Option<&'a [u8]>) -> Result<&'a str, ParseError> {
+    let word = argument.unwrap_or_default().trim_ascii();
+    if word.is_empty() {
+        return Err(ParseError::MissingArgument(verb));
+    }
+    std::str::from_utf8(word).map_err(|_| ParseError::InvalidUtf8)
+}
+
+/// Commands without arguments, trailing whitespace is allowed
+fn expect_no_argument(verb: &'static str, argument: Option<&[u8]>) -> Result<(), ParseError> {
+    if argument.unwrap_or_default().trim_ascii().is_empty() {
+        Ok(())
+    } else {
+        Err(ParseError::UnexpectedArgument(verb))
+    }
+}
+
+/// Parses `h1,h2,h3,h4,p1,p2`
+fn parse_host_port(argument: Option<&[u8]>) -> Result<(Ipv4Addr, u16), ParseError> {
+    let argument = argument
+        .ok_or(ParseError::MissingArgument("PORT"))?
+        .trim_ascii();
+    let mut numbers = argument.split(|byte| *byte == b',').map(<[u8]>::trim_ascii);
+    let mut ip_addr = [0_u8; 4];
+    for part in ip_addr.iter_mut() {
+        *part = numbers
+            .next()
+            .and_then(ascii_to_u8)
+            .ok_or(ParseError::InvalidAddress)?;
+    }
+    let mut port = [0_u8; 2];
+    for part in port.iter_mut() {
+        *part = numbers
+            .next()
+            .and_then(ascii_to_u8)
+            .ok_or(ParseError::InvalidPort)?;
+    }
+    if numbers.next().is_some() {
+        return Err(ParseError::InvalidPort);
+    }
+    // This is the formula for getting the port number
+    Ok((
+        Ipv4Addr::from(ip_addr),
+        port[0] as u16 * 256 + port[1] as u16,
+    ))
+}
+
+fn parse_type(argument: Option<&[u8]>) -> Result<TransferMode, ParseError> {
+    let argument = argument
+        .ok_or(ParseError::MissingArgument("TYPE"))?
+        .trim_ascii();
+    // We only care about the type code, the format/byte size that may follow is ignored
+    match argument.first().map(u8::to_ascii_uppercase) {
+        Some(b'A') => Ok(TransferMode::Ascii),
+        Some(b'I') | Some(b'L') => Ok(TransferMode::Binary),
+        Some(_) => Err(ParseError::UnsupportedType),
+        None => Err(ParseError::MissingArgument("TYPE")),
+    }
+}
+
+/// First word of `argument` and what is after the space that follows it
+fn split_word(argument: &[u8]) -> (&[u8], Option<&[u8]>) {
+    let argument = argument.trim_ascii_start();
+    match argument.iter().position(|byte| *byte == b' ') {
+        Some(space) => (&argument[..space], Some(&argument[space + 1..])),
+        None => (argument, None),
+    }
+}
+
+/// `LIST` and `NLST` arguments: the words that start with `-` are ls-style flags, the rest is the
+/// path. Only `-a` and `-R` change the listing, the other flags that clients send (`-l`...) are
+/// ignored
+fn parse_list(argument: Option<&[u8]>) -> Result<ListArgs<'_>, ParseError> {
+    let mut args = ListArgs::default();
+    let mut rest = argument.unwrap_or_default();
+    loop {
+        let (word, after) = split_word(rest);
+        let is_flags =
+            word.len() > 1 && word[0] == b'-' && word[1..].iter().all(u8::is_ascii_alphabetic);
+        if !is_flags {
+            break;
         }
-        expects_byte(
-            command[command.len() - 1],
-            b'\n',
-            "All commands should finish with slash r slash n",
-        )?;
-        expects_byte(
-            command[command.len() - 2],
-            b'\r',
-            "All commands should finish with slash r slash n",
-        )?;
-
-        // For maximum performance, we are gonna use a trie of matches
-        // This is also done in compilers with switch statements, where they create
-        // a trie of switches where they check if the word is a keyword.
-        match command[0] {
-            b'C' => Ok(Command::ChangeDirectory(parse_path(
-                &command,
-                b"WD",
-                (1, 3),
-            )?)),
-
-            b'D' => Ok(Command::Delete(parse_path(&command, b"ELE", (1, 4))?)),
-
-            b'M' => Ok(Command::Mkdir(parse_path(&command, b"KD", (1, 3))?)),
-
-            b'Q' => {
-                if command.len() <= 4 || &command[1..4] != b"UIT" {
-                    return Err("Invalid command, did you mean `QUIT`?");
-                }
-                Ok(Command::Quit)
+        for flag in &word[1..] {
+            match flag {
+                b'a' | b'A' => args.all = true,
+                b'R' => args.recursive = true,
+                _ => {}
             }
+        }
+        rest = after.unwrap_or_default();
+    }
+    if !rest.trim_ascii().is_empty() {
+        args.path = Some(parse_path("LIST", Some(rest))?);
+    }
+    Ok(args)
+}
 
-            // Possible commands = LIST
-            b'L' => {
-                if command.len() <= 5 {
-                    return Err("invalid command length");
-                }
-                if &command[1..4] != b"IST" {
-                    return Err("Invalid command, maybe you meant: `LIST`?");
-                }
-                if command.len() == 6 {
-                    return Ok(Command::List(Path::new("./")));
-                }
-                expects_byte(
-                    command[4],
-                    b' ',
-                    "Expected space in between command and the rest.",
-                )?;
-                // -2 because we wanna skip \r\n
-                let path_str = std::str::from_utf8(&command[5..command.len() - 2])
-                    .map_err(|_| "expected utf8 string")?;
-                let path = Path::new(path_str);
-                Ok(Command::List(path))
+/// `SITE <command> [<argument>]`, the command and the flags are case insensitive
+fn parse_site(argument: Option<&[u8]>) -> Result<SiteCommand<'_>, ParseError> {
+    let (command, rest) = split_word(argument.unwrap_or_default());
+    if command.is_empty() {
+        return Err(ParseError::MissingArgument("SITE"));
+    }
+    if command.eq_ignore_ascii_case(b"TRASH") {
+        expect_no_argument("SITE TRASH", rest).map(|_| SiteCommand::Trash)
+    } else if command.eq_ignore_ascii_case(b"RESTORE") {
+        Ok(SiteCommand::Restore(parse_word("SITE RESTORE", rest)?))
+    } else if command.eq_ignore_ascii_case(b"RMDIR") {
+        // Only the recursive one, `RMD` removes the empty directories
+        match split_word(rest.unwrap_or_default()) {
+            (flag, path) if flag.eq_ignore_ascii_case(b"-r") => {
+                Ok(SiteCommand::RemoveTree(parse_path("SITE RMDIR -r", path)?))
             }
+            _ => Err(ParseError::MissingArgument("SITE RMDIR -r")),
+        }
+    } else {
+        Err(ParseError::UnknownSiteCommand)
+    }
+}
 
-            b'R' => match command[1] {
-                b'E' => Ok(Command::Retr(parse_path(&command, b"TR", (2, 4))?)),
-                b'M' => Ok(Command::RemoveDirectory(parse_path(
-                    &command,
-                    b"D",
-                    (2, 3),
-                )?)),
-                b'N' => match command[2] {
-                    b'F' => Ok(Command::RenameFrom(parse_path(&command, b"R", (3, 4))?)),
-                    b'T' => Ok(Command::RenameTo(parse_path(&command, b"O", (3, 4))?)),
-                    _ => return Err("Unknown command"),
-                },
-                _ => return Err("Unknown command, maybe you meant 'RETR' or 'RMD'?"),
-            },
-
-            b'S' => Ok(Command::Store(parse_path(&command, b"TOR", (1, 4))?)),
-
-            b'P' => {
-                match command[1] {
-                    b'W' => {
-                        if command[2] != b'D' || command.len() != 5 {
-                            return Err("Unknown command");
-                        }
-                        return Ok(Command::CurrentDirectory);
-                    }
-                    b'A' => match command[2] {
-                        b'S' => match command[3] {
-                            b'V' => {
-                                if command.len() != 6 {
-                                    return Err("Bad command length");
-                                }
-                                return Ok(Command::Passive);
-                            }
-                            b'S' => {
-                                expects_byte(command[4], b' ', "Expected a space in between")?;
-                                let password = std::str::from_utf8(&command[5..command.len() - 2])
-                                    .map_err(|_| "Expected ASCII compliant username")?;
-                                return Ok(Command::Password(password));
-                            }
-                            _ => return Err("Unknown command, maybe you meant 'PASS' or 'PASV'"),
-                        },
-                        _ => return Err("Unknown command, maybe you meant 'PASS' or 'PASV'"),
-                    },
-
-                    b'O' => {
-                        if command.len() <= 6 {
-                            return Err("invalid command length");
-                        }
-                        if &command[2..4] != b"RT" {
-                            return Err("Invalid command, maybe you meant: `PORT`?");
-                        }
-                        expects_byte(
-                            command[4],
-                            b' ',
-                            "Expected space in between command and the rest.",
-                        )?;
-
-                        let mut ip_addr = [0_u8; 4];
-                        let mut port = [0_u8; 2];
-                        let mut byte_idx = 5;
-
-                        // Parse IP + port
-                        for i in 0..6 {
-                            let prev = byte_idx;
-                            while byte_idx < command.len() - 2 && command[byte_idx] != b',' {
-                                byte_idx += 1;
-                            }
-                            if i >= 4 {
-                                port[i - 4] = ascii_to_u8(&command[prev..byte_idx])
-                                    .ok_or("Invalid port number")?;
-                            } else {
-                                ip_addr[i] = ascii_to_u8(&command[prev..byte_idx])
-                                    .ok_or("Invalid IPv4 address")?;
-                            }
-                            byte_idx += 1;
-                        }
-
-                        // Check we reached the end of the command
-                        if byte_idx != command.len() - 1 {
-                            return Err("Bad format of the `PORT` command");
-                        }
-
-                        // Try to get the IPv4
-                        let ip = Ipv4Addr::from(ip_addr);
-
-                        // This is the formula for getting the port number
-                        let port: u16 = port[0] as u16 * 256 + port[1] as u16;
-                        Ok(Command::Port(ip, port))
-                    }
-                    _ => return Err("Unknown command"),
-                }
+impl<'a> Command<'a> {
+    /// Parses a command line, ending included.
+    /// The verb is case insensitive and may be preceded by whitespace. Lines ending with a bare LF
+    /// are only accepted if `allow_bare_lf` is true, some clients don't send the CR.
+    /// The returned command borrows its arguments from `line`.
+    pub fn parse(line: &'a [u8], allow_bare_lf: bool) -> Result<Self, ParseError> {
+        let line = if let Some(line) = line.strip_suffix(b"\r\n") {
+            line
+        } else {
+            match line.strip_suffix(b"\n") {
+                Some(line) if allow_bare_lf => line,
+                _ => return Err(ParseError::MissingLineEnding),
             }
+        };
 
-            b'U' => {
-                if command.len() <= 6 {
-                    return Err("Invalid command length");
-                }
-                if &command[1..4] != b"SER" {
-                    return Err("Invalid command, maybe you meant: `USER`?");
-                }
-                expects_byte(command[4], b' ', "Expected a space in between")?;
-                let username = std::str::from_utf8(&command[5..command.len() - 2])
-                    .map_err(|_| "Expected ASCII compliant username")?;
-                return Ok(Command::User(username));
+        let line = line.trim_ascii_start();
+        if line.is_empty() {
+            return Err(ParseError::Empty);
+        }
+        let (verb, argument) = match line.iter().position(|byte| *byte == b' ') {
+            Some(space) => (&line[..space], Some(&line[space + 1..])),
+            None => (line, None),
+        };
+        if verb.len() > MAX_VERB_LENGTH {
+            return Err(ParseError::UnknownCommand);
+        }
+        // Uppercase copy of the verb on the stack, so we can match it without allocating
+        let mut verb_upper = [0_u8; MAX_VERB_LENGTH];
+        verb_upper[..verb.len()].copy_from_slice(verb);
+        verb_upper.make_ascii_uppercase();
+
+        match &verb_upper[..verb.len()] {
+            b"USER" => Ok(Command::User(parse_word("USER", argument)?)),
+            b"PASS" => {
+                // Passwords can have spaces, so they are taken as they are
+                let password = argument.ok_or(ParseError::MissingArgument("PASS"))?;
+                let password =
+                    std::str::from_utf8(password).map_err(|_| ParseError::InvalidUtf8)?;
+                Ok(Command::Password(password))
             }
-
-            _ => Err("invalid command"),
+            // The X versions are the ones of RFC 775, some old clients still send them
+            b"PWD" | b"XPWD" => {
+                expect_no_argument("PWD", argument).map(|_| Command::CurrentDirectory)
+            }
+            b"CWD" | b"XCWD" => Ok(Command::ChangeDirectory(parse_path("CWD", argument)?)),
+            b"CDUP" => {
+                expect_no_argument("CDUP", argument).map(|_| Command::ChangeToParentDirectory)
+            }
+            b"MKD" | b"XMKD" => Ok(Command::Mkdir(parse_path("MKD", argument)?)),
+            b"RMD" | b"XRMD" => Ok(Command::RemoveDirectory(parse_path("RMD", argument)?)),
+            b"DELE" => Ok(Command::Delete(parse_path("DELE", argument)?)),
+            b"RNFR" => Ok(Command::RenameFrom(parse_path("RNFR", argument)?)),
+            b"RNTO" => Ok(Command::RenameTo(parse_path("RNTO", argument)?)),
+            b"RETR" => Ok(Command::Retr(parse_path("RETR", argument)?)),
+            b"STOR" => Ok(Command::Store(parse_path("STOR", argument)?)),
+            b"LIST" => Ok(Command::List(parse_list(argument)?)),
+            b"NLST" => Ok(Command::NameList(parse_list(argument)?)),
+            b"PASV" => expect_no_argument("PASV", argument).map(|_| Command::Passive),
+            b"QUIT" => expect_no_argument("QUIT", argument).map(|_| Command::Quit),
+            b"PORT" => {
+                let (ip, port) = parse_host_port(argument)?;
+                Ok(Command::Port(ip, port))
+            }
+            b"TYPE" => Ok(Command::Type(parse_type(argument)?)),
+            b"SITE" => Ok(Command::Site(parse_site(argument)?)),
+            _ => Err(ParseError::UnknownCommand),
         }
     }
 }
 
+impl<'a> TryFrom<&'a [u8]> for Command<'a> {
+    type Error = ParseError;
+
+    /// Parses a command line that ends with CRLF, see `Command::parse`
+    fn try_from(command: &'a [u8]) -> Result<Self, ParseError> {
+        Command::parse(command, false)
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use super::Command;
+    use super::{Command, ListArgs, ParseError, SiteCommand, TransferMode};
+    use proptest::prelude::*;
     use std::{convert::TryFrom, net::Ipv4Addr, path::Path};
 
     #[test]
@@ -305,7 +576,10 @@ mod test {
         let tests = [
             (
                 "LIST ./test/test/test1.txt\r\n".as_bytes(),
-                Command::List(Path::new("./test/test/test1.txt")),
+                Command::List(ListArgs {
+                    path: Some(Path::new("./test/test/test1.txt")),
+                    ..ListArgs::default()
+                }),
                 true,
             ),
             (
@@ -356,13 +630,72 @@ mod test {
             ),
             ("PASV\r\n".as_bytes(), Command::Passive, true),
             ("PWD\r\n".as_bytes(), Command::CurrentDirectory, true),
+            ("XPWD\r\n".as_bytes(), Command::CurrentDirectory, true),
+            (
+                "CDUP\r\n".as_bytes(),
+                Command::ChangeToParentDirectory,
+                true,
+            ),
+            (
+                "XCWD docs\r\n".as_bytes(),
+                Command::ChangeDirectory(Path::new("docs")),
+                true,
+            ),
+            (
+                "XMKD docs\r\n".as_bytes(),
+                Command::Mkdir(Path::new("docs")),
+                true,
+            ),
+            (
+                "XRMD docs\r\n".as_bytes(),
+                Command::RemoveDirectory(Path::new("docs")),
+                true,
+            ),
             ("PASS GABI\r\n".as_bytes(), Command::Password("GABI"), true),
             (
                 "PASS GABI_is_COOL\r\n".as_bytes(),
                 Command::Password("GABI_is_COOL"),
                 true,
             ),
-            ("LIST\r\n".as_bytes(), Command::List(Path::new("./")), true),
+            (
+                "LIST\r\n".as_bytes(),
+                Command::List(ListArgs::default()),
+                true,
+            ),
+            (
+                "LIST -la *.csv\r\n".as_bytes(),
+                Command::List(ListArgs {
+                    all: true,
+                    recursive: false,
+                    path: Some(Path::new("*.csv")),
+                }),
+                true,
+            ),
+            (
+                "LIST -l -a\r\n".as_bytes(),
+                Command::List(ListArgs {
+                    all: true,
+                    ..ListArgs::default()
+                }),
+                true,
+            ),
+            (
+                "NLST -R dir\r\n".as_bytes(),
+                Command::NameList(ListArgs {
+                    recursive: true,
+                    path: Some(Path::new("dir")),
+                    ..ListArgs::default()
+                }),
+                true,
+            ),
+            (
+                "NLST -\r\n".as_bytes(),
+                Command::NameList(ListArgs {
+                    path: Some(Path::new("-")),
+                    ..ListArgs::default()
+                }),
+                true,
+            ),
             (
                 "PORT 0,0,0,0,0,20\r\n".as_bytes(),
                 Command::Port(Ipv4Addr::new(0, 0, 0, 0), 20),
@@ -378,12 +711,42 @@ mod test {
                 Command::Port(Ipv4Addr::new(1, 253, 0, 20), 40 * 256 + 200),
                 true,
             ),
+            (
+                "TYPE A\r\n".as_bytes(),
+                Command::Type(TransferMode::Ascii),
+                true,
+            ),
+            (
+                "TYPE I\r\n".as_bytes(),
+                Command::Type(TransferMode::Binary),
+                true,
+            ),
+            (
+                "TYPE L 8\r\n".as_bytes(),
+                Command::Type(TransferMode::Binary),
+                true,
+            ),
+            (
+                "SITE TRASH\r\n".as_bytes(),
+                Command::Site(SiteCommand::Trash),
+                true,
+            ),
+            (
+                "SITE RMDIR -r docs/old\r\n".as_bytes(),
+                Command::Site(SiteCommand::RemoveTree(Path::new("docs/old"))),
+                true,
+            ),
+            (
+                "SITE RESTORE 20240131T120000Z-a.txt\r\n".as_bytes(),
+                Command::Site(SiteCommand::Restore("20240131T120000Z-a.txt")),
+                true,
+            ),
         ];
         for test in tests.iter() {
             let (command_buff, expected_path, should_be_equal) = test;
             let command_try = Command::try_from(&command_buff[..]);
             if let Err(msg) = command_try {
-                panic!(msg);
+                panic!("{}", msg);
             }
             let command = command_try.unwrap();
             assert_eq!(
@@ -395,4 +758,233 @@ mod test {
             );
         }
     }
+
+    #[test]
+    fn check_command_parsing_is_tolerant() {
+        let tests = [
+            ("user bob\r\n".as_bytes(), Command::User("bob")),
+            (
+                "Retr file.txt\r\n".as_bytes(),
+                Command::Retr(Path::new("file.txt")),
+            ),
+            ("  pwd  \r\n".as_bytes(), Command::CurrentDirectory),
+            ("USER   bob  \r\n".as_bytes(), Command::User("bob")),
+            (
+                "PASS  secret \r\n".as_bytes(),
+                Command::Password(" secret "),
+            ),
+            ("list \r\n".as_bytes(), Command::List(ListArgs::default())),
+            (
+                "nlst  spaced name\r\n".as_bytes(),
+                Command::NameList(ListArgs {
+                    path: Some(Path::new(" spaced name")),
+                    ..ListArgs::default()
+                }),
+            ),
+            ("type  a\r\n".as_bytes(), Command::Type(TransferMode::Ascii)),
+            (
+                "STOR  spaced name \r\n".as_bytes(),
+                Command::Store(Path::new(" spaced name ")),
+            ),
+            (
+                "port 127, 0, 0, 1, 8, 186\r\n".as_bytes(),
+                Command::Port(Ipv4Addr::new(127, 0, 0, 1), 8 * 256 + 186),
+            ),
+            (
+                "site  restore  a.txt \r\n".as_bytes(),
+                Command::Site(SiteCommand::Restore("a.txt")),
+            ),
+            (
+                "SITE rmdir  -R  old \r\n".as_bytes(),
+                Command::Site(SiteCommand::RemoveTree(Path::new(" old "))),
+            ),
+        ];
+        for (line, expected) in tests.iter() {
+            assert_eq!(Command::try_from(*line).as_ref(), Ok(expected));
+        }
+        assert_eq!(
+            Command::parse(b"quit\n", true),
+            Ok(Command::Quit),
+            "bare LF is allowed"
+        );
+    }
+
+    #[test]
+    fn check_command_parsing_errors() {
+        let tests = [
+            ("QUIT\n".as_bytes(), ParseError::MissingLineEnding),
+            ("QUIT".as_bytes(), ParseError::MissingLineEnding),
+            ("   \r\n".as_bytes(), ParseError::Empty),
+            ("NOPE\r\n".as_bytes(), ParseError::UnknownCommand),
+            ("PA\r\n".as_bytes(), ParseError::UnknownCommand),
+            ("R\r\n".as_bytes(), ParseError::UnknownCommand),
+            ("\r\n".as_bytes(), ParseError::Empty),
+            ("USERNAME bob\r\n".as_bytes(), ParseError::UnknownCommand),
+            ("RETR\r\n".as_bytes(), ParseError::MissingArgument("RETR")),
+            ("USER  \r\n".as_bytes(), ParseError::MissingArgument("USER")),
+            (
+                "PWD now\r\n".as_bytes(),
+                ParseError::UnexpectedArgument("PWD"),
+            ),
+            (
+                "CDUP ..\r\n".as_bytes(),
+                ParseError::UnexpectedArgument("CDUP"),
+            ),
+            (&b"USER \xff\r\n"[..], ParseError::InvalidUtf8),
+            (&b"RETR a\rb\r\n"[..], ParseError::InvalidPath),
+            (&b"STOR a\r\nDELE b\r\n"[..], ParseError::InvalidPath),
+            (&b"DELE a\0b\r\n"[..], ParseError::InvalidPath),
+            ("PORT 1,2,3\r\n".as_bytes(), ParseError::InvalidAddress),
+            (
+                "PORT 1,2,3,256,0,1\r\n".as_bytes(),
+                ParseError::InvalidAddress,
+            ),
+            ("PORT 1,2,3,4,5\r\n".as_bytes(), ParseError::InvalidPort),
+            ("PORT 1,2,3,4,5,6,7\r\n".as_bytes(), ParseError::InvalidPort),
+            ("TYPE E\r\n".as_bytes(), ParseError::UnsupportedType),
+            ("SITE\r\n".as_bytes(), ParseError::MissingArgument("SITE")),
+            (
+                "SITE CHMOD 777 a\r\n".as_bytes(),
+                ParseError::UnknownSiteCommand,
+            ),
+            (
+                "SITE RESTORE\r\n".as_bytes(),
+                ParseError::MissingArgument("SITE RESTORE"),
+            ),
+            (
+                "SITE RMDIR old\r\n".as_bytes(),
+                ParseError::MissingArgument("SITE RMDIR -r"),
+            ),
+            (
+                "SITE RMDIR -r\r\n".as_bytes(),
+                ParseError::MissingArgument("SITE RMDIR -r"),
+            ),
+            (
+                "SITE TRASH all\r\n".as_bytes(),
+                ParseError::UnexpectedArgument("SITE TRASH"),
+            ),
+        ];
+        for (line, expected) in tests.iter() {
+            assert_eq!(Command::try_from(*line), Err(*expected), "{:?}", line);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn paths_are_taken_as_bytes() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+        let latin1 = Path::new(OsStr::from_bytes(b"caf\xe9.txt"));
+        assert_eq!(
+            Command::try_from(&b"RETR caf\xe9.txt\r\n"[..]),
+            Ok(Command::Retr(latin1))
+        );
+        assert_eq!(Command::Retr(latin1).to_bytes(), b"RETR caf\xe9.txt");
+        assert_eq!(
+            Command::try_from(&b"MKD  \"quoted\" dir\r\n"[..]),
+            Ok(Command::Mkdir(Path::new(" \"quoted\" dir")))
+        );
+    }
+
+    #[test]
+    fn redacted_hides_the_password() {
+        assert_eq!(Command::Password("secret").redacted(), "PASS ****");
+        assert_eq!(Command::User("bob").redacted(), "USER bob");
+        assert_eq!(Command::Retr(Path::new("a.txt")).redacted(), "RETR a.txt");
+    }
+
+    /// Path with the bytes of `name`, which don't have to be UTF-8
+    #[cfg(unix)]
+    fn path_from(name: &[u8]) -> &Path {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+        Path::new(OsStr::from_bytes(name))
+    }
+
+    /// Other systems can't have names that aren't Unicode
+    #[cfg(not(unix))]
+    fn path_from(name: &[u8]) -> &Path {
+        Path::new(std::str::from_utf8(name).unwrap_or("name"))
+    }
+
+    /// Builds a command that borrows `text` and `path`, `variant` selects which one
+    fn command_from<'a>(
+        variant: u8,
+        text: &'a str,
+        path: &'a Path,
+        ip: Ipv4Addr,
+        port: u16,
+        ascii: bool,
+    ) -> Command<'a> {
+        match variant % 22 {
+            0 => Command::Port(ip, port),
+            1 => Command::List(ListArgs {
+                all: ascii,
+                recursive: !ascii,
+                path: Some(path),
+            }),
+            2 => Command::Retr(path),
+            3 => Command::User(text),
+            4 => Command::Password(text),
+            5 => Command::CurrentDirectory,
+            6 => Command::Store(path),
+            7 => Command::Passive,
+            8 => Command::Mkdir(path),
+            9 => Command::Delete(path),
+            10 => Command::RemoveDirectory(path),
+            11 => Command::ChangeDirectory(path),
+            12 => Command::RenameFrom(path),
+            13 => Command::RenameTo(path),
+            14 => Command::Quit,
+            15 => Command::Site(SiteCommand::Trash),
+            16 => Command::Site(SiteCommand::Restore(text)),
+            17 => Command::Site(SiteCommand::RemoveTree(path)),
+            18 => Command::ChangeToParentDirectory,
+            19 if ascii => Command::Type(TransferMode::Ascii),
+            20 => Command::NameList(ListArgs {
+                all: ascii,
+                recursive: !ascii,
+                path: Some(path),
+            }),
+            _ => Command::Type(TransferMode::Binary),
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn command_round_trips(
+            variant in any::<u8>(),
+            // No line endings, and no surrounding whitespace because user names are trimmed
+            text in "[^\\x00-\\x20\\x7f]([^\\x00-\\x1f\\x7f]*[^\\x00-\\x20\\x7f])?",
+            // Any bytes but NUL, CR and LF, like `RETR \xff`. The first one isn't whitespace or a
+            // `-`, which `LIST` would take as flags
+            first in any::<u8>().prop_filter("whitespace or -", |byte| *byte > b' ' && *byte != b'-'),
+            rest in proptest::collection::vec(
+                any::<u8>().prop_filter("NUL, CR or LF", |byte| !matches!(byte, b'\0' | b'\r' | b'\n')),
+                0..24,
+            ),
+            ip in any::<[u8; 4]>(),
+            port in any::<u16>(),
+            ascii in any::<bool>(),
+        ) {
+            let name = [&[first][..], &rest].concat();
+            let path = path_from(&name);
+            let command = command_from(variant, &text, path, Ipv4Addr::from(ip), port, ascii);
+            let line = [command.to_bytes(), b"\r\n".to_vec()].concat();
+            prop_assert_eq!(Command::try_from(&line[..]), Ok(command.clone()));
+            let lowercase = [line[..4].to_ascii_lowercase(), line[4..].to_vec()].concat();
+            prop_assert_eq!(Command::try_from(&lowercase[..]), Ok(command));
+        }
+
+        #[test]
+        fn parser_never_panics(bytes in proptest::collection::vec(any::<u8>(), 0..64), bare_lf in any::<bool>()) {
+            let _ = Command::parse(&bytes, bare_lf);
+        }
+
+        #[test]
+        fn parser_never_panics_on_verbs(
+            line in "(?i)(USER|PASS|PASV|PWD|P|PA|R|RN|RNFR|RNTO|RETR|RMD|LIST|NLST|PORT|TYPE|QUIT|SITE)[ \t]{0,2}[ -~]{0,12}(\r\n|\n|\r)?",
+            bare_lf in any::<bool>(),
+        ) {
+            let _ = Command::parse(line.as_bytes(), bare_lf);
+        }
+    }
 }
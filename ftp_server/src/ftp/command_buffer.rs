@@ -0,0 +1,126 @@
+/// Maximum length of a single command line, if a client sends more than this without a CRLF
+/// the buffered bytes are discarded
+pub const MAX_COMMAND_LENGTH: usize = 10024;
+
+/// Bytes read from a control connection that weren't handled yet.
+/// ## Behaviour
+/// * A command split in multiple reads is kept until its CRLF arrives.
+/// * Multiple commands in a single read are returned one by one, in order.
+/// * Lines end with CRLF, or also with a bare LF if the buffer was created with `with_bare_lf(true)`.
+#[derive(Debug, Default)]
+pub struct CommandBuffer {
+    buffer: Vec<u8>,
+
+    allow_bare_lf: bool,
+}
+
+impl CommandBuffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_bare_lf(allow_bare_lf: bool) -> Self {
+        Self {
+            buffer: Vec::new(),
+            allow_bare_lf,
+        }
+    }
+
+    /// If lines that end with a bare LF are commands
+    pub fn allows_bare_lf(&self) -> bool {
+        self.allow_bare_lf
+    }
+
+    pub fn extend(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// Returns the first complete command line (CRLF included) and removes it from the buffer
+    pub fn next_line(&mut self) -> Option<Vec<u8>> {
+        let end = self.line_end()?;
+        let rest = self.buffer.split_off(end);
+        Some(std::mem::replace(&mut self.buffer, rest))
+    }
+
+    /// If there is at least one complete command line
+    pub fn has_line(&self) -> bool {
+        self.line_end().is_some()
+    }
+
+    /// If the buffered bytes already reached `MAX_COMMAND_LENGTH` without a complete command line
+    pub fn is_full(&self) -> bool {
+        self.buffer.len() >= MAX_COMMAND_LENGTH && !self.has_line()
+    }
+
+    /// Takes every buffered byte, even if there is no CRLF
+    pub fn take(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.buffer)
+    }
+
+    fn line_end(&self) -> Option<usize> {
+        if self.allow_bare_lf {
+            return self
+                .buffer
+                .iter()
+                .position(|byte| *byte == b'\n')
+                .map(|position| position + 1);
+        }
+        self.buffer
+            .windows(2)
+            .position(|window| window == b"\r\n")
+            .map(|position| position + 2)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CommandBuffer, MAX_COMMAND_LENGTH};
+
+    #[test]
+    fn test_command_byte_by_byte() {
+        let mut buffer = CommandBuffer::new();
+        let command = b"USER user_01\r\n";
+        for (i, byte) in command.iter().enumerate() {
+            assert_eq!(buffer.next_line(), None);
+            buffer.extend(&[*byte]);
+            assert_eq!(buffer.has_line(), i == command.len() - 1);
+        }
+        assert_eq!(buffer.next_line().unwrap(), command.to_vec());
+        assert!(buffer.take().is_empty());
+    }
+
+    #[test]
+    fn test_pipelined_commands() {
+        let mut buffer = CommandBuffer::new();
+        buffer.extend(b"USER user_01\r\nPASS 123456\r\nPW");
+        assert_eq!(buffer.next_line().unwrap(), b"USER user_01\r\n".to_vec());
+        assert_eq!(buffer.next_line().unwrap(), b"PASS 123456\r\n".to_vec());
+        assert_eq!(buffer.next_line(), None);
+        assert!(!buffer.is_full());
+        buffer.extend(b"D\r\n");
+        assert_eq!(buffer.next_line().unwrap(), b"PWD\r\n".to_vec());
+        assert!(!buffer.has_line());
+        buffer.extend(&[b'A'; MAX_COMMAND_LENGTH]);
+        assert!(buffer.is_full());
+    }
+
+    #[test]
+    fn test_split_crlf() {
+        let mut buffer = CommandBuffer::new();
+        buffer.extend(b"QUIT\r");
+        assert!(!buffer.has_line());
+        buffer.extend(b"\n");
+        assert_eq!(buffer.next_line().unwrap(), b"QUIT\r\n".to_vec());
+    }
+
+    #[test]
+    fn test_bare_lf() {
+        let mut buffer = CommandBuffer::new();
+        buffer.extend(b"PWD\nQUIT\r\n");
+        assert_eq!(buffer.next_line().unwrap(), b"PWD\nQUIT\r\n".to_vec());
+        let mut buffer = CommandBuffer::with_bare_lf(true);
+        buffer.extend(b"PWD\nQUIT\r\n");
+        assert_eq!(buffer.next_line().unwrap(), b"PWD\n".to_vec());
+        assert_eq!(buffer.next_line().unwrap(), b"QUIT\r\n".to_vec());
+    }
+}
@@ -1,46 +1,824 @@
-use std::{fs::File, sync::Mutex};
+//! Typed configuration of the server, loaded from a TOML file.
+//!
+//! Every section and every key is optional, missing ones take the defaults of the server.
+//! ```toml
+//! [server]
+//! bind = ["0.0.0.0:8080"]
+//! banner = "Service ready for new user."
+//! allow_bare_lf = false
+//! encoding = "utf-8"
+//!
+//! [users]
+//! file = "./etc/users.json"
+//! root = "./root"
+//! log = "./var/ftpserver.log"
+//!
+//! [log]
+//! stdout = true
+//! file = "./var/debug.log"
+//! level = "info"
+//! xferlog = "./var/xferlog"
+//!
+//! [limits]
+//! max_connections = 500
+//! workers = 8
+//! queue_size = 1024
+//! list_depth = 8
+//!
+//! [transfer]
+//! sendfile = true
+//! chunk_size = 1048576
+//!
+//! [passive]
+//! min_port = 50000
+//! max_port = 50100
+//!
+//! [shutdown]
+//! timeout = 30
+//! partial_uploads = "keep"
+//!
+//! [metrics]
+//! bind = "127.0.0.1:9100"
+//!
+//! [admin]
+//! socket = "./var/ftp_server.sock"
+//!
+//! [trash]
+//! enabled = true
+//! retention_days = 30
+//!
+//! [storage]
+//! backend = "local"
+//!
+//! [storage.s3]
+//! endpoint = "https://s3.eu-west-1.amazonaws.com"
+//! bucket = "partners"
+//! region = "eu-west-1"
+//! prefix = "ftp"
+//! part_size = 8388608
+//!
+//! [tls]
+//! certificate = "./etc/cert.pem"
+//! private_key = "./etc/key.pem"
+//! ```
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::convert::TryFrom;
+use std::ffi::OsStr;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use user_manage::UsersConfig;
 
-pub static mut DEBUG: bool = false;
-pub static mut STDOUT_FILE: Option<Mutex<File>> = None;
+use super::log::Level;
 
-// Don't call this in multithreaded environments!!
-pub(crate) fn use_stdout(file: File) {
-    // This is totally safe because we won't use this function concurrently
-    unsafe {
-        STDOUT_FILE = Some(Mutex::new(file));
+use crate::pool::{DEFAULT_QUEUE_SIZE, DEFAULT_WORKERS};
+use crate::system;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+
+    pub users: UsersConfig,
+
+    pub log: LogConfig,
+
+    pub limits: LimitsConfig,
+
+    pub transfer: TransferConfig,
+
+    pub passive: PassiveConfig,
+
+    pub shutdown: ShutdownConfig,
+
+    pub metrics: MetricsConfig,
+
+    pub admin: AdminConfig,
+
+    pub trash: TrashConfig,
+
+    pub storage: StorageConfig,
+
+    /// Certificate of the server, the connection layer doesn't speak TLS yet so a server with
+    /// this section refuses to start
+    pub tls: Option<TlsConfig>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Addresses where the server listens to control connections
+    pub bind: Vec<String>,
+
+    /// Message of the 220 reply that every new control connection receives
+    pub banner: String,
+
+    /// If commands that end with a bare LF instead of CRLF are accepted
+    pub allow_bare_lf: bool,
+
+    /// Encoding of the file names that the clients send and receive
+    pub encoding: FilenameEncoding,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind: vec!["0.0.0.0:8080".to_string()],
+            banner: "Service ready for new user.".to_string(),
+            allow_bare_lf: false,
+            encoding: FilenameEncoding::Utf8,
+        }
     }
 }
 
-pub(crate) fn set_debug(debug: bool) {
-    // This is totally safe because we won't use this function concurrently
-    unsafe {
-        DEBUG = debug;
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum FilenameEncoding {
+    /// Names are taken and sent as the bytes that they have on the disk, what clients that
+    /// follow RFC 2640 expect
+    #[default]
+    #[serde(rename = "utf-8", alias = "utf8")]
+    Utf8,
+
+    /// ISO-8859-1 of old clients, the names are stored on the disk as UTF-8
+    #[serde(rename = "latin1", alias = "iso-8859-1")]
+    Latin1,
+}
+
+impl FilenameEncoding {
+    /// Path on the disk of `path`, as a client sent it
+    pub fn decode<'a>(&self, path: &'a Path) -> Cow<'a, Path> {
+        let bytes = system::os_bytes(path.as_os_str());
+        match self {
+            FilenameEncoding::Latin1 if !bytes.is_ascii() => {
+                let path: String = bytes.iter().map(|&byte| char::from(byte)).collect();
+                Cow::Owned(PathBuf::from(path))
+            }
+            _ => Cow::Borrowed(path),
+        }
+    }
+
+    /// Bytes of `name` for a client, characters that Latin-1 doesn't have are sent as `?`
+    pub fn encode(&self, name: &OsStr) -> Vec<u8> {
+        match self {
+            FilenameEncoding::Utf8 => system::os_bytes(name).into_owned(),
+            FilenameEncoding::Latin1 => name
+                .to_string_lossy()
+                .chars()
+                .map(|c| u8::try_from(u32::from(c)).unwrap_or(b'?'))
+                .collect(),
+        }
     }
 }
 
-#[macro_export]
-macro_rules! print_stdout {
-    ($($arg:tt)*) => (
-        {
-            use crate::ftp::config::*;
-            use std::io::prelude::*;
-            unsafe {
-                if DEBUG {
-                    if let Err(e) = write!(&mut ::std::io::stdout(), "{}\n", format_args!($($arg)*)) {
-                        panic!("Failed to write to stdout.\
-                            \nOriginal error output: {}\
-                            \nSecondary error writing to stderr: {}", format!($($arg)*), e);
-                    }
-                }
-                if STDOUT_FILE.is_some() {
-                    let mut m = STDOUT_FILE.as_mut().unwrap().lock().unwrap();
-                    if let Err(e) = write!(m, "{}\n", format_args!($($arg)*)) {
-                        panic!("Failed to write to stdout.\
-                            \nOriginal error output: {}\
-                            \nSecondary error writing to stderr: {}", format!($($arg)*), e);
-                    }
-                }
+impl FromStr for FilenameEncoding {
+    type Err = String;
+
+    fn from_str(encoding: &str) -> Result<Self, Self::Err> {
+        match encoding.to_ascii_lowercase().as_str() {
+            "utf-8" | "utf8" => Ok(FilenameEncoding::Utf8),
+            "latin1" | "iso-8859-1" => Ok(FilenameEncoding::Latin1),
+            _ => Err(format!("unknown filename encoding {:?}", encoding)),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// If the log is written to stdout
+    pub stdout: bool,
+
+    /// File where the log is also written
+    pub file: Option<PathBuf>,
+
+    /// Events below this level are dropped
+    pub level: Level,
+
+    /// File where every `RETR` and `STOR` is written in the `xferlog` format
+    pub xferlog: Option<PathBuf>,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            stdout: true,
+            file: None,
+            level: Level::Info,
+            xferlog: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// Maximum of control connections, new ones are closed
+    pub max_connections: usize,
+
+    /// Threads that run the read/write handlers
+    pub workers: usize,
+
+    /// Events that can wait for a free worker before the event loop stops taking new ones
+    pub queue_size: usize,
+
+    /// Levels below the listed directory that `LIST -R` and `NLST -R` go down
+    pub list_depth: usize,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_connections: 500,
+            workers: DEFAULT_WORKERS,
+            queue_size: DEFAULT_QUEUE_SIZE,
+            list_depth: 8,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TransferConfig {
+    /// If binary downloads use `sendfile(2)` when the system has it
+    pub sendfile: bool,
+
+    /// Maximum bytes sent with a single `sendfile(2)`, so a big download doesn't hold a worker
+    pub chunk_size: usize,
+}
+
+impl Default for TransferConfig {
+    fn default() -> Self {
+        Self {
+            sendfile: true,
+            chunk_size: 1 << 20,
+        }
+    }
+}
+
+/// Ports of the listeners opened by `PASV`, without both of them any free port is used
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct PassiveConfig {
+    pub min_port: Option<u16>,
+
+    pub max_port: Option<u16>,
+}
+
+impl PassiveConfig {
+    /// Inclusive range of ports, if it was configured
+    pub fn port_range(&self) -> Option<(u16, u16)> {
+        Some((self.min_port?, self.max_port?))
+    }
+}
+
+/// What a graceful shutdown does with the transfers that are still running
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShutdownConfig {
+    /// Seconds that the running transfers have to finish before they are closed
+    pub timeout: u64,
+
+    /// What happens to the files of the uploads that are closed before they finish
+    pub partial_uploads: PartialUploads,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            timeout: 30,
+            partial_uploads: PartialUploads::Keep,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PartialUploads {
+    /// The bytes that were received are kept as `<name>.partial`, the file stays as it was
+    Keep,
+
+    /// The upload is thrown away and the file stays as it was
+    Delete,
+}
+
+/// Trash of `DELE` and `RMD`, see `crate::storage::trash`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TrashConfig {
+    /// If `DELE` and `RMD` move what they remove to the trash of the home instead of removing it,
+    /// `trash` of a user in the users file overrides it
+    pub enabled: bool,
+
+    /// Days that the entries stay in the trash before they are purged, 0 keeps them forever
+    pub retention_days: u64,
+}
+
+impl Default for TrashConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            retention_days: 30,
+        }
+    }
+}
+
+impl TrashConfig {
+    /// None if the entries are kept forever
+    pub fn retention(&self) -> Option<Duration> {
+        match self.retention_days {
+            0 => None,
+            days => Some(Duration::from_secs(days * 24 * 60 * 60)),
+        }
+    }
+}
+
+/// Prometheus endpoint of the server
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// Address where `GET /metrics` is served, without it the metrics aren't served
+    pub bind: Option<String>,
+}
+
+/// Local admin interface of the server, see `ftp::admin`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    /// Unix socket where the admin commands are accepted, without it there is no admin interface
+    pub socket: Option<PathBuf>,
+}
+
+/// Where the files of the users are kept, see `crate::storage`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub backend: Backend,
+
+    /// Bucket of the `s3` backend
+    pub s3: Option<S3Config>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// The homes of the users on the disk
+    #[default]
+    Local,
+
+    /// Empty homes in memory, everything is lost when the server stops
+    Memory,
+
+    /// Objects of an S3-compatible bucket, see `crate::storage::s3`
+    S3,
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(backend: &str) -> Result<Self, Self::Err> {
+        match backend {
+            "local" => Ok(Backend::Local),
+            "memory" => Ok(Backend::Memory),
+            "s3" => Ok(Backend::S3),
+            _ => Err(format!("unknown storage backend {:?}", backend)),
+        }
+    }
+}
+
+/// Smallest part of a multipart upload that S3 accepts, except for the last one
+pub const MIN_PART_SIZE: usize = 5 * 1024 * 1024;
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct S3Config {
+    /// URL of the service, like `https://s3.eu-west-1.amazonaws.com` or `http://127.0.0.1:9000`
+    pub endpoint: String,
+
+    pub bucket: String,
+
+    /// Region that the requests are signed for
+    #[serde(default = "default_region")]
+    pub region: String,
+
+    /// Without it `AWS_ACCESS_KEY_ID` is used
+    #[serde(default)]
+    pub access_key: Option<String>,
+
+    /// Without it `AWS_SECRET_ACCESS_KEY` is used
+    #[serde(default)]
+    pub secret_key: Option<String>,
+
+    /// Keys of every home start with it
+    #[serde(default)]
+    pub prefix: String,
+
+    /// Bytes of every part of the multipart uploads, smaller files are uploaded with one `PUT`
+    #[serde(default = "default_part_size")]
+    pub part_size: usize,
+}
+
+fn default_region() -> String {
+    "us-east-1".to_string()
+}
+
+fn default_part_size() -> usize {
+    8 * 1024 * 1024
+}
+
+/// Without the secret key, the config ends up in the logs
+impl fmt::Debug for S3Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("S3Config")
+            .field("endpoint", &self.endpoint)
+            .field("bucket", &self.bucket)
+            .field("region", &self.region)
+            .field("access_key", &self.access_key)
+            .field("prefix", &self.prefix)
+            .field("part_size", &self.part_size)
+            .finish()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM file with the certificate chain
+    pub certificate: PathBuf,
+
+    /// PEM file with the private key of the certificate
+    pub private_key: PathBuf,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The file couldn't be read
+    Io(io::Error),
+
+    /// The file isn't valid TOML or has unknown keys
+    Parse(toml::de::Error),
+
+    /// The values can't be used by the server
+    Invalid(&'static str),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(err) => write!(f, "can't read the config file: {}", err),
+            ConfigError::Parse(err) => write!(f, "invalid config file: {}", err),
+            ConfigError::Invalid(message) => write!(f, "invalid config: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Loads the config again when the server is asked to reload, e.g. reading the same file and
+/// applying the same command line options
+pub type ConfigLoader = Box<dyn Fn() -> Result<Config, ConfigError> + Send>;
+
+/// Result of `Config::reloaded`
+#[derive(Debug, PartialEq)]
+pub struct Reload {
+    /// Config that the server uses from now on
+    pub config: Config,
+
+    /// Keys that changed and were applied
+    pub applied: Vec<&'static str>,
+
+    /// Keys that changed but need a restart, they keep their old value
+    pub ignored: Vec<&'static str>,
+}
+
+impl Config {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
+        let content = std::fs::read_to_string(path).map_err(ConfigError::Io)?;
+        Self::parse(&content)
+    }
+
+    pub fn parse(content: &str) -> Result<Self, ConfigError> {
+        let config: Config = toml::from_str(content).map_err(ConfigError::Parse)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Checks the values that serde can't, it's also needed after changing a config by hand
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.server.bind.is_empty() {
+            return Err(ConfigError::Invalid(
+                "server.bind needs at least one address",
+            ));
+        }
+        if self.limits.workers == 0 {
+            return Err(ConfigError::Invalid("limits.workers must be at least 1"));
+        }
+        if self.limits.queue_size == 0 {
+            return Err(ConfigError::Invalid("limits.queue_size must be at least 1"));
+        }
+        if self.transfer.chunk_size == 0 {
+            return Err(ConfigError::Invalid(
+                "transfer.chunk_size must be at least 1",
+            ));
+        }
+        match (self.storage.backend, &self.storage.s3) {
+            (Backend::S3, None) => {
+                return Err(ConfigError::Invalid(
+                    "the s3 storage backend needs a [storage.s3] section",
+                ))
+            }
+            (_, Some(s3))
+                if !s3.endpoint.starts_with("http://") && !s3.endpoint.starts_with("https://") =>
+            {
+                return Err(ConfigError::Invalid(
+                    "storage.s3.endpoint must be an http:// or https:// URL",
+                ))
+            }
+            (_, Some(s3)) if s3.part_size < MIN_PART_SIZE => {
+                return Err(ConfigError::Invalid(
+                    "storage.s3.part_size must be at least 5 MiB",
+                ))
             }
+            _ => {}
+        }
+        match (self.passive.min_port, self.passive.max_port) {
+            (Some(min), Some(max)) if min > max => Err(ConfigError::Invalid(
+                "passive.min_port can't be bigger than passive.max_port",
+            )),
+            (Some(0), _) => Err(ConfigError::Invalid("passive.min_port can't be 0")),
+            (Some(_), None) | (None, Some(_)) => Err(ConfigError::Invalid(
+                "passive.min_port and passive.max_port must be set together",
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Merges `new` into a running server with this config. Only what can change without
+    /// touching the current sessions is applied, the listeners, the pool and TLS stay as they are
+    pub fn reloaded(&self, mut new: Config) -> Reload {
+        let mut applied = Vec::new();
+        let mut ignored = Vec::new();
+        let mut check = |changed: bool, key: &'static str, restart: bool| {
+            if changed && restart {
+                ignored.push(key);
+            } else if changed {
+                applied.push(key);
+            }
+        };
+        check(self.server.bind != new.server.bind, "server.bind", true);
+        check(
+            self.server.banner != new.server.banner,
+            "server.banner",
+            false,
+        );
+        check(
+            self.server.allow_bare_lf != new.server.allow_bare_lf,
+            "server.allow_bare_lf",
+            false,
+        );
+        check(
+            self.server.encoding != new.server.encoding,
+            "server.encoding",
+            false,
+        );
+        check(self.users != new.users, "users", false);
+        check(self.log != new.log, "log", false);
+        check(
+            self.limits.max_connections != new.limits.max_connections,
+            "limits.max_connections",
+            false,
+        );
+        check(
+            self.limits.list_depth != new.limits.list_depth,
+            "limits.list_depth",
+            false,
+        );
+        check(
+            self.limits.workers != new.limits.workers,
+            "limits.workers",
+            true,
+        );
+        check(
+            self.limits.queue_size != new.limits.queue_size,
+            "limits.queue_size",
+            true,
+        );
+        check(self.transfer != new.transfer, "transfer", false);
+        check(self.passive != new.passive, "passive", false);
+        check(self.shutdown != new.shutdown, "shutdown", false);
+        check(self.trash != new.trash, "trash", false);
+        check(self.metrics != new.metrics, "metrics.bind", true);
+        check(self.admin != new.admin, "admin.socket", true);
+        check(self.storage != new.storage, "storage", true);
+        check(self.tls != new.tls, "tls", true);
+        new.server.bind = self.server.bind.clone();
+        new.metrics = self.metrics.clone();
+        new.admin = self.admin.clone();
+        new.storage = self.storage.clone();
+        new.limits.workers = self.limits.workers;
+        new.limits.queue_size = self.limits.queue_size;
+        new.tls = self.tls.clone();
+        Reload {
+            config: new,
+            applied,
+            ignored,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Backend, Config, ConfigError, FilenameEncoding, Level, PartialUploads};
+    use std::ffi::OsStr;
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn empty_config_is_the_default() {
+        assert_eq!(Config::parse("").unwrap(), Config::default());
+        // The sample file documents the defaults
+        assert_eq!(
+            Config::load("./etc/ftp_server.toml").unwrap(),
+            Config::default()
+        );
+    }
+
+    #[test]
+    fn config_parses_every_section() {
+        let config = Config::parse(
+            r#"
+            [server]
+            bind = ["127.0.0.1:2121", "[::1]:2121"]
+            banner = "Welcome"
+            encoding = "latin1"
+
+            [users]
+            root = "/srv/ftp"
+
+            [log]
+            stdout = false
+            level = "warn"
+            xferlog = "/var/log/xferlog"
+
+            [limits]
+            workers = 2
+
+            [passive]
+            min_port = 50000
+            max_port = 50010
+
+            [shutdown]
+            partial_uploads = "delete"
+
+            [trash]
+            enabled = true
+            retention_days = 0
+
+            [storage]
+            backend = "s3"
+
+            [storage.s3]
+            endpoint = "http://127.0.0.1:9000"
+            bucket = "partners"
+            secret_key = "secret"
+
+            [tls]
+            certificate = "cert.pem"
+            private_key = "key.pem"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.server.bind.len(), 2);
+        assert_eq!(config.server.banner, "Welcome");
+        assert_eq!(config.server.encoding, FilenameEncoding::Latin1);
+        assert_eq!(config.users.root, PathBuf::from("/srv/ftp"));
+        assert_eq!(config.users.file, PathBuf::from("./etc/users.json"));
+        assert!(!config.log.stdout);
+        assert_eq!(config.log.level, Level::Warn);
+        assert_eq!(config.log.xferlog, Some(PathBuf::from("/var/log/xferlog")));
+        assert_eq!(config.limits.workers, 2);
+        assert_eq!(config.limits.max_connections, 500);
+        assert_eq!(config.passive.port_range(), Some((50000, 50010)));
+        assert_eq!(config.shutdown.timeout, 30);
+        assert_eq!(config.shutdown.partial_uploads, PartialUploads::Delete);
+        assert!(config.trash.enabled);
+        assert_eq!(config.trash.retention(), None);
+        assert_eq!(config.storage.backend, Backend::S3);
+        let s3 = config.storage.s3.unwrap();
+        assert_eq!(s3.bucket, "partners");
+        assert_eq!(s3.region, "us-east-1");
+        assert_eq!(s3.part_size, 8 * 1024 * 1024);
+        assert!(!format!("{:?}", s3).contains("secret"));
+        assert_eq!(config.tls.unwrap().private_key, PathBuf::from("key.pem"));
+    }
+
+    #[test]
+    fn latin1_names_are_stored_as_utf8() {
+        let latin1 = FilenameEncoding::Latin1;
+        let name = "caf\u{e9}";
+        assert_eq!(latin1.encode(OsStr::new(name)), b"caf\xe9");
+        assert_eq!(latin1.encode(OsStr::new("\u{65e5}.txt")), b"?.txt");
+        assert_eq!(
+            FilenameEncoding::Utf8.encode(OsStr::new(name)),
+            name.as_bytes()
+        );
+        assert_eq!(latin1.decode(Path::new("a b")), Path::new("a b"));
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStrExt;
+            let sent = Path::new(OsStr::from_bytes(b"/caf\xe9"));
+            assert_eq!(latin1.decode(sent), Path::new("/caf\u{e9}"));
+            assert_eq!(FilenameEncoding::Utf8.decode(sent), sent);
         }
-    )
+    }
+
+    #[test]
+    fn reload_keeps_what_needs_a_restart() {
+        let old = Config::default();
+        let new = Config::parse(
+            r#"
+            [server]
+            bind = ["127.0.0.1:2121"]
+            banner = "New banner"
+            encoding = "latin1"
+
+            [limits]
+            max_connections = 10
+            workers = 2
+            list_depth = 2
+
+            [metrics]
+            bind = "127.0.0.1:9100"
+
+            [admin]
+            socket = "./var/ftp_server.sock"
+
+            [storage]
+            backend = "memory"
+            "#,
+        )
+        .unwrap();
+        let reload = old.reloaded(new);
+        assert_eq!(
+            reload.applied,
+            [
+                "server.banner",
+                "server.encoding",
+                "limits.max_connections",
+                "limits.list_depth"
+            ]
+        );
+        assert_eq!(
+            reload.ignored,
+            [
+                "server.bind",
+                "limits.workers",
+                "metrics.bind",
+                "admin.socket",
+                "storage"
+            ]
+        );
+        assert_eq!(reload.config.metrics.bind, None);
+        assert_eq!(reload.config.storage.backend, Backend::Local);
+        assert_eq!(reload.config.admin.socket, None);
+        assert_eq!(reload.config.server.banner, "New banner");
+        assert_eq!(reload.config.server.encoding, FilenameEncoding::Latin1);
+        assert_eq!(reload.config.limits.max_connections, 10);
+        assert_eq!(reload.config.limits.list_depth, 2);
+        assert_eq!(reload.config.server.bind, old.server.bind);
+        assert_eq!(reload.config.limits.workers, old.limits.workers);
+        assert!(old.reloaded(old.clone()).applied.is_empty());
+    }
+
+    #[test]
+    fn config_errors() {
+        let invalid = |content: &str| match Config::parse(content) {
+            Err(ConfigError::Invalid(_)) => {}
+            other => panic!("{:?} for {}", other, content),
+        };
+        invalid("[server]\nbind = []");
+        invalid("[limits]\nworkers = 0");
+        invalid("[passive]\nmin_port = 10\nmax_port = 9");
+        invalid("[passive]\nmin_port = 10");
+        invalid("[storage]\nbackend = \"s3\"");
+        invalid("[storage.s3]\nendpoint = \"s3.amazonaws.com\"\nbucket = \"b\"");
+        invalid("[storage.s3]\nendpoint = \"http://s3\"\nbucket = \"b\"\npart_size = 1024");
+        assert!(matches!(
+            Config::parse("[server]\nport = 21"),
+            Err(ConfigError::Parse(_))
+        ));
+        assert!(matches!(
+            Config::parse("[shutdown]\npartial_uploads = \"rename\""),
+            Err(ConfigError::Parse(_))
+        ));
+        assert!(matches!(
+            Config::parse("[storage]\nbackend = \"ftp\""),
+            Err(ConfigError::Parse(_))
+        ));
+        assert!(matches!(
+            Config::load("./does/not/exist.toml"),
+            Err(ConfigError::Io(_))
+        ));
+    }
 }
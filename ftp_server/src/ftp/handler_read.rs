@@ -1,28 +1,31 @@
-use super::{command::Command, response::Response, FileTransferType};
 use super::{
-    create_response, Action, ActionList, BufferToWrite, HashMutex, RequestContext,
-    RequestContextMutex, RequestType, Token,
+    command::{Command, ListArgs, SiteCommand, TransferMode},
+    command_buffer::{CommandBuffer, MAX_COMMAND_LENGTH},
+    response::Response,
+    metrics::Metrics,
+    xferlog::{Direction, Transfer},
+    FileToSend, FileTransferType,
 };
-use crate::system;
-use crate::port::{get_ftp_port_pair, get_random_port};
+use super::{
+    config::Config, create_path_response, create_response, list_lines, name_lines, restore_response, trash_response, Action, ActionList, BufferToWrite, HashMutex, RequestContext,
+    RequestContextMutex, RequestType, Shared, Token,
+};
+use crate::port::{bind_passive, get_ftp_port_pair};
+use crate::storage::{self, trash::{self, Removal}, ReadFile, StorageBackend, WriteFile};
+use crate::system::list::{self, ListOptions};
 use mio::{net::TcpListener, net::TcpStream, Interest, Waker};
-use std::fs;
 use std::{
-    convert::TryFrom,
-    path::Path,
+    path::{Path, PathBuf},
     sync::{Arc, Mutex},
 };
-use std::{
-    fs::File,
-    io::{ErrorKind, Read},
-};
+use std::io::{ErrorKind, Read};
 use std::{
     io::{Error, Write},
     net::Shutdown,
 };
 // #[macro_use]
 // use super::config::;
-use user_manage::{SystemUsers, User};
+use user_manage::SystemUsers;
 
 pub struct HandlerRead {
     /// The request context token
@@ -45,46 +48,75 @@ pub struct HandlerRead {
     user_id: Option<String>,
 
     loged: bool,
-}
 
-#[derive(Debug, Clone, Copy)]
-pub enum ErrorTypeUser {
-    PathNotFound,
-    UserNotFound
+    transfer_mode: TransferMode,
+
+    config: Arc<Config>,
+
+    /// Command that was handled, as it's written to the log
+    pub command: Option<String>,
+
+    metrics: Arc<Metrics>,
+
+    storage: Arc<dyn StorageBackend>,
 }
 
 impl HandlerRead {
-    pub fn new(
+    /// Creates the handler of `connection`, `ctx` is its request context (already locked)
+    pub(super) fn new(
         connection_token: Token,
-        connection_db: HashMutex<Token, RequestContextMutex>,
         connection: RequestContextMutex,
-        users_db: Arc<Mutex<SystemUsers>>,
-        user_id: Option<String>,
-        loged: bool,
+        shared: &Shared,
+        ctx: &RequestContext,
     ) -> Self {
         Self {
             connection_token,
-            connection_db,
+            connection_db: shared.connections.clone(),
             actions: Vec::new(),
             connection,
-            users_db,
-            user_id,
-            loged,
+            users_db: shared.users.clone(),
+            user_id: ctx.user_id.clone(),
+            loged: ctx.loged,
+            transfer_mode: ctx.transfer_mode,
+            config: shared.config.clone(),
+            command: None,
+            metrics: shared.metrics.clone(),
+            storage: shared.storage.clone(),
         }
     }
 
+    /// Record of a `RETR` or `STOR` of `path` through the data connection `stream`
+    fn transfer(&self, stream: &TcpStream, path: PathBuf, direction: Direction) -> Transfer {
+        Transfer::new(
+            self.connection_token.0,
+            stream.peer_addr().ok(),
+            self.user_id.clone(),
+            path,
+            self.transfer_mode,
+            direction,
+            self.metrics.clone(),
+        )
+    }
+
     fn handle_file_transfer_download(
         &mut self,
         ctx: &mut RequestContext,
-        file: File,
+        file: Box<dyn ReadFile>,
+        path: PathBuf,
     ) -> Result<(), Error> {
         match &mut ctx.request_type {
             RequestType::CommandTransfer(_, _, _, _) | RequestType::Closed(_) => {
                 Err(Error::from(ErrorKind::NotFound))
             }
-            RequestType::FileTransferPassive(_stream, ftt, _)
-            | RequestType::FileTransferActive(_stream, ftt, _) => {
-                *ftt = FileTransferType::FileDownload(file);
+            RequestType::FileTransferPassive(stream, ftt, _)
+            | RequestType::FileTransferActive(stream, ftt, _) => {
+                let transfer = self.transfer(stream, path, Direction::Outgoing);
+                *ftt = FileTransferType::FileDownload(FileToSend::new(
+                    file,
+                    self.transfer_mode,
+                    self.config.transfer.sendfile,
+                    transfer,
+                ));
                 Ok(())
             }
             RequestType::PassiveModePort(_, _) => Err(Error::from(ErrorKind::NotFound)),
@@ -94,59 +126,196 @@ impl HandlerRead {
     fn handle_file_transfer_upload(
         &mut self,
         ctx: &mut RequestContext,
-        file: File,
+        file: Box<dyn WriteFile>,
+        path: PathBuf,
     ) -> Result<(), Error> {
         match &mut ctx.request_type {
             RequestType::CommandTransfer(_, _, _, _) | RequestType::Closed(_) => {
                 Err(Error::from(ErrorKind::NotFound))
             }
-            RequestType::FileTransferPassive(_stream, ftt, _)
-            | RequestType::FileTransferActive(_stream, ftt, _) => {
-                *ftt = FileTransferType::FileUpload(file, None);
+            RequestType::FileTransferPassive(stream, ftt, _)
+            | RequestType::FileTransferActive(stream, ftt, _) => {
+                let transfer = self.transfer(stream, path, Direction::Incoming);
+                *ftt = FileTransferType::FileUpload(file, transfer, None);
                 Ok(())
             }
             RequestType::PassiveModePort(_, _) => Err(Error::from(ErrorKind::NotFound)),
         }
     }
 
-    /// Gets the chroot of the user *non canon
-    pub fn get_user_path(&self) -> Option<String> {
-        let user_id = self.user_id.as_ref().unwrap();
+    /// Home of the user and its current directory, as a virtual path (see `storage::resolve`)
+    fn user_dirs(&self) -> Option<(PathBuf, PathBuf)> {
         let db = self.users_db.lock().unwrap();
-        let user = db.get_user(user_id)?;        
-        Some(user.get_chroot().to_string())
-    } 
+        let user = db.get_user(self.user_id.as_ref()?)?;
+        let cwd = storage::resolve(Path::new("/"), user.get_actual_dir())?;
+        Some((PathBuf::from(user.get_chroot()), cwd))
+    }
 
-    /// Returns the current user total path (non canon)
-    pub fn get_user_path_non_canon(&self) -> String {
-        let user_id = self.user_id.as_ref().unwrap();
+    /// Home of the user and the virtual path of `path`, None if it's outside of the home or in
+    /// its trash
+    fn user_path<P: AsRef<Path>>(&self, path: P) -> Option<(PathBuf, PathBuf)> {
+        let (home, cwd) = self.user_dirs()?;
+        let path = self.config.server.encoding.decode(path.as_ref());
+        let path = storage::resolve(&cwd, &path)?;
+        if trash::is_trash(&path) {
+            return None;
+        }
+        Some((home, path))
+    }
+
+    /// If `DELE` and `RMD` move to the trash for the user, its `trash` overrides the config
+    fn trash_enabled(&self) -> bool {
         let db = self.users_db.lock().unwrap();
-        let user = db.get_user(user_id).unwrap();        
-        user.total_path_and_decano().to_string()
-    } 
+        self.user_id
+            .as_ref()
+            .and_then(|user_id| db.get_user(user_id))
+            .and_then(|user| user.trash())
+            .unwrap_or(self.config.trash.enabled)
+    }
 
-    /// Returns the new path of the user with the specified path
-    pub fn handle_user_path<P: AsRef<Path>>(&self, path: P) -> Result<String, ErrorTypeUser> {
-        let user_id = self.user_id.as_ref().unwrap();
+    /// If the user can remove directories that aren't empty
+    fn recursive_delete(&self) -> bool {
         let db = self.users_db.lock().unwrap();
-        let user = db.get_user(user_id);
-        if let None = user {
-            return Err(ErrorTypeUser::UserNotFound);
+        self.user_id
+            .as_ref()
+            .and_then(|user_id| db.get_user(user_id))
+            .is_some_and(|user| user.recursive_delete())
+    }
+
+    /// Removes the file or directory `path`, to the trash if it's enabled, see `trash::remove`
+    fn remove(&self, home: &Path, path: &Path, removal: Removal) -> Result<(), Error> {
+        trash::remove(self.storage.as_ref(), home, path, removal, self.trash_enabled())
+    }
+
+    /// Moves the user to the parent of `dir` if its current directory was inside of it, after
+    /// `dir` is removed
+    fn leave_removed_dir(&self, dir: &Path) {
+        let (_, cwd) = self.user_dirs().unwrap_or_default();
+        if cwd.starts_with(dir) {
+            self.set_user_dir(dir.parent().unwrap_or_else(|| Path::new("/")));
+        }
+    }
+
+    /// Reply of the `SITE` commands
+    fn site(&self, command: &SiteCommand) -> Vec<u8> {
+        let home = match self.user_dirs() {
+            Some((home, _)) => home,
+            None => return create_response(Response::file_unavailable(), "Home not found."),
+        };
+        match command {
+            SiteCommand::RemoveTree(_) if !self.recursive_delete() => create_response(
+                Response::file_unavailable(),
+                "Requested action not taken. Permission denied.",
+            ),
+            SiteCommand::RemoveTree(dir) => {
+                let removed = self
+                    .user_path(dir)
+                    .filter(|(_, path)| self.remove(&home, path, Removal::Tree).is_ok());
+                match removed {
+                    Some((_, path)) => {
+                        self.leave_removed_dir(&path);
+                        create_response(
+                            Response::file_action_okay(),
+                            "Requested file action okay, completed.",
+                        )
+                    }
+                    None => create_response(
+                        Response::file_unavailable(),
+                        "Requested action not taken. File unavailable, file not found.",
+                    ),
+                }
+            }
+            SiteCommand::Trash => {
+                trash_response(self.storage.as_ref(), &home, self.config.server.encoding)
+            }
+            SiteCommand::Restore(id) => {
+                restore_response(self.storage.as_ref(), &home, id, self.config.server.encoding)
+            }
         }
-        let user = user.unwrap();       
-        User::new_dir(&&user.get_chroot(), &&user.get_actual_dir(), path.as_ref()).map_err(|_| ErrorTypeUser::PathNotFound)        
     }
 
-    /// Handles when the user is actually on a bad directory
-    pub fn safe_change_dir_for_user(&mut self) {
-        let user_id = self.user_id.as_ref().unwrap();
+    /// Sets the current directory of the user to the virtual path `dir`
+    fn set_user_dir(&self, dir: &Path) {
         let mut db = self.users_db.lock().unwrap();
-        let user = db.get_user_mut(user_id);
-        if let None = user {
-            return;
+        if let Some(user) = db.get_user_mut(self.user_id.as_ref().unwrap()) {
+            user.set_actual_dir(Path::new(".").join(storage::relative(dir)));
+        }
+    }
+
+    /// Lines of `LIST`, or of `NLST` if `names`, see `system::list::list`. None if the path is
+    /// outside of the home or it can't be listed
+    fn listing(&self, args: &ListArgs, names: bool) -> Option<Vec<u8>> {
+        let (home, path) = self.user_path(args.path.unwrap_or_else(|| Path::new(".")))?;
+        let options = ListOptions {
+            all: args.all,
+            recursive: args.recursive,
+            max_depth: self.config.limits.list_depth,
+        };
+        let entries = list::list(&path, &options, |dir| {
+            let mut entries = self.storage.list(&home, dir)?;
+            entries.retain(|entry| !trash::is_trash(&dir.join(&entry.name)));
+            Ok(entries)
+        })
+        .ok()?;
+        let encoding = self.config.server.encoding;
+        if names {
+            let (_, cwd) = self.user_dirs()?;
+            Some(name_lines(&cwd, &entries, encoding))
+        } else {
+            let root = self.users_db.lock().unwrap().root().to_path_buf();
+            Some(list_lines(&home, &root, &entries, encoding))
+        }
+    }
+
+    /// Returns the next command line of the connection, reading from the stream only if there isn't a complete
+    /// one in `command_buffer` already. If the command is not complete yet it asks for more data and returns None
+    fn next_command_line(
+        &mut self,
+        stream: &mut TcpStream,
+        command_buffer: &mut CommandBuffer,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        if let Some(line) = command_buffer.next_line() {
+            return Ok(Some(line));
+        }
+
+        // Initialize a big buffer
+        let mut buff = [0; MAX_COMMAND_LENGTH];
+
+        // Read thing into the buffer
+        let read = stream.read(&mut buff)?;
+
+        if read == 0 {
+            self.actions.push((
+                self.connection_token,
+                self.connection.clone(),
+                Interest::READABLE,
+            ));
+            stream.shutdown(Shutdown::Both)?;
+            return Ok(None);
+        }
+
+        log_debug!(session: self.connection_token.0; "[HANDLE_READ] {} bytes read", read);
+
+        command_buffer.extend(&buff[..read]);
+        if let Some(line) = command_buffer.next_line() {
+            return Ok(Some(line));
         }
-        let user = user.unwrap();   
-        user.change_dir_to_recursive_if_doesnt_exist();
+
+        if command_buffer.is_full() {
+            // Let the caller answer that the command is too big
+            return Ok(Some(command_buffer.take()));
+        }
+
+        log_debug!(
+            session: self.connection_token.0;
+            "[HANDLE_READ] Command is not complete yet, waiting for more data"
+        );
+        self.actions.push((
+            self.connection_token,
+            self.connection.clone(),
+            Interest::READABLE,
+        ));
+        Ok(None)
     }
 
     /// This function handles the read of the `request_type`,
@@ -157,6 +326,7 @@ impl HandlerRead {
     pub fn handle_read(
         &mut self,
         request_type: &mut RequestType,
+        command_buffer: &mut CommandBuffer,
         waker: &Arc<Waker>,
         actions: ActionList,
         next_id: usize,
@@ -165,31 +335,15 @@ impl HandlerRead {
             RequestType::CommandTransfer(stream, to_write, data_connection, path_from) => {
                 let _ = stream.flush();
 
-                // Initialize a big buffer
-                let mut buff = [0; 10024];
-
-                // Read thing into the buffer
-                let read = stream.read(&mut buff)?;
+                let line = match self.next_command_line(stream, command_buffer)? {
+                    Some(line) => line,
+                    None => return Ok(None),
+                };
 
-                if read == 0 {
-                    self.actions.push((
-                        self.connection_token,
-                        self.connection.clone(),
-                        Interest::READABLE,
-                    ));
-                    stream.shutdown(Shutdown::Both)?;
-                    return Ok(None);
-                }
-
-                print_stdout!(
-                    "[HANDLE_READ] {} - {} bytes read",
-                    self.connection_token.0, read
-                );
-
-                if read >= buff.len() {
-                    print_stdout!(
-                        "[HANDLE_READ] {} - command is too big, returning bad sequence of commands",
-                        self.connection_token.0
+                if line.len() >= MAX_COMMAND_LENGTH {
+                    log_debug!(
+                        session: self.connection_token.0;
+                        "[HANDLE_READ] command is too big, returning bad sequence of commands"
                     );
                     self.actions.push((
                         self.connection_token,
@@ -201,17 +355,18 @@ impl HandlerRead {
                 }
 
                 // Translate to Command enum
-                let possible_command = Command::try_from(&buff[..read]);
+                let possible_command = Command::parse(line.as_slice(), command_buffer.allows_bare_lf());
 
                 // Check if it's a valid command
                 if let Err(message) = possible_command {
-                    print_stdout!(
-                        "[HANDLE_READ] {} - User sent a bad command {}",
-                        self.connection_token.0, message
+                    log_debug!(
+                        session: self.connection_token.0;
+                        "[HANDLE_READ] User sent a bad command {}",
+                        message
                     );
                     to_write.reset(create_response(
                         Response::bad_sequence_of_commands(),
-                        message,
+                        &message.to_string(),
                     ));
                     self.actions.push((
                         self.connection_token,
@@ -224,6 +379,7 @@ impl HandlerRead {
                 // Get the command
                 let command =
                     possible_command.expect("command parse is not an error, this is safe");
+                self.command = Some(command.redacted());
 
                 if command.is_auth_command() && (self.user_id.is_none() || !self.loged) {
                     self.actions.push((
@@ -242,11 +398,13 @@ impl HandlerRead {
                             self.connection.clone(),
                             Interest::WRITABLE,
                         ));
-                        if let Ok(path) = self.handle_user_path(from) {
-                            *path_from = Some(path);
-                            to_write.reset(create_response(
-                                Response::file_action_pending(), "Requested file action pending further information."));
-                            return Ok(None);
+                        if let Some((home, path)) = self.user_path(from) {
+                            if self.storage.stat(&home, &path).is_ok() {
+                                *path_from = Some(path);
+                                to_write.reset(create_response(
+                                    Response::file_action_pending(), "Requested file action pending further information."));
+                                return Ok(None);
+                            }
                         }
                         to_write.reset(create_response(
                             Response::file_unavailable(), "File unavailable, file not found."));
@@ -259,16 +417,8 @@ impl HandlerRead {
                             Interest::WRITABLE,
                         ));
                         if let Some(from) = path_from.take() {
-                            let mut to_no_child = Path::new(to.clone()).to_path_buf();
-                            to_no_child.pop();
-                            let to_path = self.handle_user_path(to_no_child);                            
-                            let from = Path::new(&from);
-                            let to_child = to.file_name();
-                            if !(to_path.is_err() || to_child.is_none()) {
-                                let to_path = to_path.expect("safe");
-                                let to = format!("{}/{}", to_path, to_child.unwrap().to_str().unwrap());              
-                                let rename_result = system::rename(from.to_str().unwrap(), to.as_str());
-                                if rename_result.is_ok() {
+                            if let Some((home, to)) = self.user_path(to) {
+                                if self.storage.rename(&home, &from, &to).is_ok() {
                                     to_write.reset(create_response(
                                         Response::file_action_okay(),
                                         "Requested file action okay, completed."
@@ -286,35 +436,40 @@ impl HandlerRead {
                             self.connection.clone(),
                             Interest::WRITABLE,
                         ));
-                        let users_db = self.users_db.lock().unwrap();
-                        let user = users_db.get_user_clone(self.user_id.as_ref().unwrap()).unwrap();
-                        drop(users_db);
-                        to_write.reset(
-                            create_response(Response::directory_action_okay(), &user.total_path_and_decano())
-                        );
+                        let (_, cwd) = self.user_dirs().unwrap_or_default();
+                        to_write.reset(create_path_response(
+                            Response::directory_action_okay(),
+                            &cwd,
+                            "is the current directory.",
+                            self.config.server.encoding,
+                        ));
                     }
 
-                    Command::ChangeDirectory(dir) => {
+                    Command::ChangeDirectory(_) | Command::ChangeToParentDirectory => {
+                        // CDUP is the same as `CWD ..`
+                        let dir = match command {
+                            Command::ChangeDirectory(dir) => dir,
+                            _ => Path::new(".."),
+                        };
                         self.actions.push((
                             self.connection_token,
                             self.connection.clone(),
                             Interest::WRITABLE,
                         ));
-                        let mut users_db = self.users_db.lock().unwrap();
-                        let user = users_db.get_user_mut(self.user_id.as_ref().unwrap()).unwrap();
-                        let result = user.change_dir(dir);
-                        drop(user);
-                        drop(users_db);
-                        if result.is_err() {
+                        let is_dir = self.user_path(dir).filter(|(home, path)| {
+                            self.storage.stat(home, path).is_ok_and(|metadata| metadata.is_dir)
+                        });
+                        if let Some((_, path)) = is_dir {
+                            self.set_user_dir(&path);
                             to_write.reset(create_response(
-                                Response::file_unavailable(),
-                                "Requested action not taken. File unavailable, file not found.",
+                                Response::file_action_okay(),
+                                "Requested file action okay, completed.",
                             ));
                             return Ok(None);
-                        } 
+                        }
                         to_write.reset(create_response(
-                            Response::file_action_okay(),
-                            "Requested file action okay, completed.",
+                            Response::file_unavailable(),
+                            "Requested action not taken. File unavailable, file not found.",
                         ));
                         return Ok(None);
                     }
@@ -329,12 +484,10 @@ impl HandlerRead {
                             self.connection.clone(),
                             Interest::WRITABLE,
                         ));                        
-                        let random_port = get_random_port();
-                        if let Some(port) = random_port {
+                        let passive_listener = bind_passive(self.config.passive.port_range());
+                        if let Some((listener, port)) = passive_listener {
                             // Create tcp listener and add it to the connections database
-                            let tcp_listener =
-                                TcpListener::bind(format!("0.0.0.0:{}", port).parse().unwrap())
-                                    .expect("port to be init");
+                            let tcp_listener = TcpListener::from_std(listener);
                             let mut db = self.connection_db.lock().unwrap();
                             // Smart multithread safe pointer where we got a mutex of a socket
                             let arc = Arc::new(Mutex::new(RequestContext::new(
@@ -357,6 +510,18 @@ impl HandlerRead {
                         return Ok(None);
                     }
 
+                    Command::Type(mode) => {
+                        self.actions.push((
+                            self.connection_token,
+                            self.connection.clone(),
+                            Interest::WRITABLE,
+                        ));
+                        to_write.reset(create_response(Response::command_okay(), "Command okay."));
+                        return Ok(Some(Box::new(move |ctx| {
+                            ctx.transfer_mode = mode;
+                        })));
+                    }
+
                     Command::Quit => {
                         self.actions.push((
                             self.connection_token,
@@ -399,7 +564,7 @@ impl HandlerRead {
                                     ctx.loged = true;
                                 })));
                             }
-                            if db.has_passwd(user_id, pwd) {
+                            if !db.is_disabled(user_id) && db.has_passwd(user_id, pwd) {
                                 to_write.reset(create_response(
                                     Response::login_success(),
                                     "User logged in, proceed.",
@@ -416,9 +581,10 @@ impl HandlerRead {
                     }
 
                     Command::User(username) => {                        
-                        print_stdout!(
-                            "[HANDLE_READ] {} - New user {}",
-                            self.connection_token.0, username
+                        log_debug!(
+                            session: self.connection_token.0;
+                            "[HANDLE_READ] New user {}",
+                            username
                         );
                         self.actions.push((
                             self.connection_token,
@@ -442,8 +608,8 @@ impl HandlerRead {
                             self.connection.clone(),
                             Interest::WRITABLE,
                         ));
-                        if let Ok(path) = self.handle_user_path(path) {
-                            let result = fs::remove_file(path);
+                        if let Some((home, path)) = self.user_path(path) {
+                            let result = self.remove(&home, &path, Removal::File);
                             if let Err(_err) = result {
                                 to_write.reset(create_response(
                                     Response::file_unavailable(),
@@ -471,18 +637,19 @@ impl HandlerRead {
                             self.connection.clone(),
                             Interest::WRITABLE,
                         ));
-                        if let Ok(path) = self.handle_user_path(directory) {
-                            let result = fs::remove_dir_all(path);
-                            if let Err(_err) = result {
-                                to_write.reset(create_response(
-                                    Response::file_unavailable(),
-                                    "Requested action not taken. File unavailable, file not found.",
-                                ));
+                        if let Some((home, path)) = self.user_path(directory) {
+                            let result = self.remove(&home, &path, Removal::EmptyDir);
+                            if let Err(err) = result {
+                                let message = match err.kind() {
+                                    ErrorKind::DirectoryNotEmpty => "Requested action not taken. Directory not empty.",
+                                    _ => "Requested action not taken. File unavailable, file not found.",
+                                };
+                                to_write.reset(create_response(Response::file_unavailable(), message));
                                 return Ok(None);
                             } 
 
-                            // Check if the client is a bit dumbass and deleted its own directory
-                            self.safe_change_dir_for_user();
+                            // Check if the client deleted the directory where it is
+                            self.leave_removed_dir(&path);
 
                             to_write.reset(create_response(
                                 Response::file_action_okay(),
@@ -498,6 +665,15 @@ impl HandlerRead {
                         }
                     }
 
+                    Command::Site(command) => {
+                        self.actions.push((
+                            self.connection_token,
+                            self.connection.clone(),
+                            Interest::WRITABLE,
+                        ));
+                        to_write.reset(self.site(&command));
+                    }
+
                     Command::Retr(path) => {
                         self.actions.push((
                             self.connection_token,
@@ -512,8 +688,8 @@ impl HandlerRead {
                             ));
                             return Ok(None);
                         }                    
-                        if let Ok(path) = self.handle_user_path(path) {                        
-                            let file = File::open(path);
+                        if let Some((home, path)) = self.user_path(path) {                        
+                            let file = self.storage.open_read(&home, &path);
                             if let Err(_) = file {
                                 to_write.reset(create_response(
                                     Response::file_unavailable(),
@@ -537,7 +713,7 @@ impl HandlerRead {
                             drop(connection_db);
                             let mut data_transfer_conn_mutex = data_transfer_conn.lock().unwrap();
                             if let Err(_) = self
-                                .handle_file_transfer_download(&mut data_transfer_conn_mutex, file)
+                                .handle_file_transfer_download(&mut data_transfer_conn_mutex, file, home.join(storage::relative(&path)))
                             {
                                 to_write.reset(create_response(
                                     Response::file_unavailable(),
@@ -573,51 +749,21 @@ impl HandlerRead {
                             self.connection.clone(),
                             Interest::WRITABLE
                         ));
-                        let mut callback_error = || {
+                        let created = self
+                            .user_path(path)
+                            .filter(|(home, path)| self.storage.mkdir(home, path).is_ok());
+                        if let Some((_, path)) = created {
+                            to_write.reset(create_path_response(
+                                Response::directory_action_okay(),
+                                &path,
+                                "directory created.",
+                                self.config.server.encoding,
+                            ));
+                        } else {
                             to_write.reset(create_response(
                                 Response::file_unavailable(),
                                 "Requested action not taken. File unavailable, no access.",
                             ));
-                        };
-                        let path_user = self.get_user_path();
-                        if let None = path_user {
-                            callback_error();
-                            return Ok(None);
-                        }
-                        let base = path_user.unwrap();
-                        let root_path = Path::new(base.as_str()).canonicalize().unwrap();
-                        let true_base = root_path.to_str().unwrap();
-                        let mut path = path.to_path_buf();
-                        let child = path.file_name().map(|el| el.to_str().unwrap().to_string()).clone();
-                        path.pop();
-                        let el = path.as_path();
-                        let parent = if el == Path::new("/") { Path::new("./") } else { el };
-                        if child.is_none() {
-                            callback_error();
-                            return Ok(None);
-                        }
-                        let child = child.unwrap();                                            
-                        let parent = Path::new(self.get_user_path_non_canon().as_str()).join(parent);
-                        let path = format!("./{}", parent.to_str().unwrap());                        
-                        let total_path = root_path.join(path).canonicalize();
-                        if let Ok(path) = total_path {
-                            if !path.starts_with(true_base) {
-                                callback_error();
-                                return Ok(None);
-                            }
-                            let end_path = path.join(&child);
-                            let result = std::fs::create_dir(end_path);
-                            if result.is_err() {
-                                callback_error();
-                                return Ok(None);
-                            }
-                            let resp = format!("'{}' directory created.", child);
-                            to_write.reset(create_response(
-                                Response::directory_action_okay(),
-                                resp.as_str(),
-                            ));
-                        } else {
-                            callback_error();
                         }
                         return Ok(None);
                     }
@@ -638,86 +784,48 @@ impl HandlerRead {
                             callback_error();
                             return Ok(None);
                         }
-                        let path_user = self.get_user_path();
-                        if let None = path_user {
-                            callback_error();
-                            return Ok(None);
-                        }
-                        let base = path_user.unwrap();
-                        let root_path = Path::new(base.as_str()).canonicalize();
-                        if root_path.is_err() {                            
-                            callback_error();
-                            return Ok(None);
-                        }                           
-                        let root_path = root_path.unwrap();                 
-                        let true_base = root_path.to_str().unwrap();
-                        let mut path = path.to_path_buf();
-                        let child = path.file_name().map(|el| el.to_str().unwrap().to_string()).clone();
-                        path.pop();
-                        let el = path.as_path();
-                        let parent = if el == Path::new("/") { Path::new("./") } else { el };
-                        if child.is_none() {
-                            callback_error();
-                            return Ok(None);
-                        }
-                        let child = child.unwrap();                                            
-                        let parent = Path::new(self.get_user_path_non_canon().as_str()).join(parent);
-                        let path = format!("./{}", parent.to_str().unwrap());
-                        let total_path = root_path.join(path).canonicalize();
-                        if let Ok(path) = total_path {
-                            if !path.starts_with(true_base) {
+                        let (home, path) = match self.user_path(path) {
+                            Some(user_path) => user_path,
+                            None => {
                                 callback_error();
                                 return Ok(None);
                             }
-                            let end_path = path.join(child);
-                            let _ = fs::remove_file(&end_path);
-                            let file_options = fs::OpenOptions::new()
-                                .append(false)
-                                .create(true)
-                                .write(true)
-                                .open(end_path.clone());
-                            if let Ok(file) = file_options {
-                                // Check that the file is really on a good position to exist
-                                if end_path.canonicalize().is_err() {
+                        };
+                        if let Ok(file) = self.storage.open_write(&home, &path) {
+                            let db = self.connection_db.lock().unwrap();
+                            let token_data = data_connection.take().unwrap();
+                            let conn = db.get(&token_data);
+                            if let Some(conn) = conn {
+                                // Clone Arc because we must drop DB lock
+                                let conn = conn.clone();
+                                drop(db);
+                                let mut conn_lock = conn.lock().unwrap();
+                                let path = home.join(storage::relative(&path));
+                                if let Err(_) =
+                                    self.handle_file_transfer_upload(&mut conn_lock, file, path)
+                                {
                                     callback_error();
                                     return Ok(None);
                                 }
-                                let db = self.connection_db.lock().unwrap();
-                                let token_data = data_connection.take().unwrap();
-                                let conn = db.get(&token_data);
-                                if let Some(conn) = conn {
-                                    // Clone Arc because we must drop DB lock
-                                    let conn = conn.clone();
-                                    drop(db);
-                                    let mut conn_lock = conn.lock().unwrap();
-                                    if let Err(_) =
-                                        self.handle_file_transfer_upload(&mut conn_lock, file)
-                                    {
-                                        callback_error();
-                                        return Ok(None);
-                                    }
-                                    // HEH... I don't know but Rust doesn't get that this really needs to die here!
-                                    drop(conn_lock);
-                                    to_write.reset(create_response(
-                                        Response::file_status_okay(),
-                                        "File status okay; about to open data connection.",
-                                    ));
-                                    to_write.callback_after_sending =
-                                        Some(Box::new(move || {                                         
-                                            let mut actions = actions.lock().unwrap();
-                                            actions.push((token_data, conn, Interest::READABLE));
-                                        }));                            
-                                    return Ok(None);
-                                }
+                                // HEH... I don't know but Rust doesn't get that this really needs to die here!
+                                drop(conn_lock);
+                                to_write.reset(create_response(
+                                    Response::file_status_okay(),
+                                    "File status okay; about to open data connection.",
+                                ));
+                                to_write.callback_after_sending =
+                                    Some(Box::new(move || {                                         
+                                        let mut actions = actions.lock().unwrap();
+                                        actions.push((token_data, conn, Interest::READABLE));
+                                    }));                            
+                                return Ok(None);
                             }
-                            callback_error();
-                            return Ok(None);
-                        } 
+                        }
                         callback_error();       
                         return Ok(None);                 
                     }
 
-                    Command::List(path) => {
+                    Command::List(args) | Command::NameList(args) => {
                         // Inform that we are interested in writing a command again
                         self.actions.push((
                             self.connection_token,
@@ -756,10 +864,9 @@ impl HandlerRead {
                         if let Some(connection) = connection {
                             // Clone the smart reference of this request context
                             let connection = connection.clone();
-                            let res = self.handle_user_path(path);
-                            if let Ok(path) = res {
-
-                                let list = system::ls(path.as_str()).unwrap();
+                            let names = matches!(command, Command::NameList(_));
+                            let res = self.listing(&args, names);
+                            if let Some(list) = res {
                                 // Create a callback that captures everything it needs
                                 let callback = move || {
                                     // Lock the request context
@@ -910,7 +1017,7 @@ impl HandlerRead {
 
             RequestType::FileTransferActive(stream, type_connection, _data_conn_token)
             | RequestType::FileTransferPassive(stream, type_connection, _data_conn_token) => {
-                print_stdout!("[HANDLE_READ] Yeah let's go");
+                log_debug!("[HANDLE_READ] Yeah let's go");
                 if let Ok(should_close) = self.handle_file_type(stream, type_connection) {
                     if should_close {
                         let _ = stream.shutdown(Shutdown::Both);
@@ -932,10 +1039,10 @@ impl HandlerRead {
         transfer_type: &mut FileTransferType,
     ) -> Result<bool, ()> {
         match transfer_type {
-            FileTransferType::FileUpload(file, possible_response) => {
-                print_stdout!(
-                    "[HANDLE_FILE_TYPE] {} - Reading from file transfer...",
-                    self.connection_token.0
+            FileTransferType::FileUpload(file, transfer, possible_response) => {
+                log_debug!(
+                    session: self.connection_token.0;
+                    "[HANDLE_FILE_TYPE] Reading from file transfer..."
                 );
                 let mut buff = [0; 10024];
                 let read_result = stream.read(&mut buff);
@@ -946,6 +1053,15 @@ impl HandlerRead {
                 ));
                 if let Ok(read_bytes) = read_result {                   
                     if read_bytes == 0 {
+                        if let Err(err) = file.commit() {
+                            log_warn!(
+                                session: self.connection_token.0;
+                                "[HANDLE_FILE_TYPE] Error finishing the file {}...",
+                                err
+                            );
+                            *possible_response = Some(b"451 Requested action aborted: local error in processing.\r\n".to_vec());
+                            return Ok(true);
+                        }
                         *possible_response = 
                         Some(create_response(
                             Response::success_uploading_file(), 
@@ -953,24 +1069,27 @@ impl HandlerRead {
                         ));             
                         return Ok(true);
                     }
-                    let err = file.write(&buff[..read_bytes]);
+                    let err = file.write_all(&buff[..read_bytes]);
                     if err.is_err() {
-                        print_stdout!(
-                            "[HANDLE_FILE_TYPE] {} - Error writing to file {}...",
-                            self.connection_token.0,
+                        log_warn!(
+                            session: self.connection_token.0;
+                            "[HANDLE_FILE_TYPE] Error writing to file {}...",
                             err.as_ref().unwrap_err()
                         );
+                        let _ = file.abort();
+                        *possible_response = Some(b"451 Requested action aborted: local error in processing.\r\n".to_vec());
                         return Err(());
                     }                                      
-                    print_stdout!(
-                        "[HANDLE_FILE_TYPE] {} - Successfully read...",
-                        self.connection_token.0
+                    transfer.bytes += read_bytes as u64;
+                    log_debug!(
+                        session: self.connection_token.0;
+                        "[HANDLE_FILE_TYPE] Successfully read..."
                     );
                 } else if let Err(err) = read_result {
                     if err.kind() == ErrorKind::WouldBlock {
-                        print_stdout!(
-                            "[HANDLE_FILE_TYPE] {} - Would block...",
-                            self.connection_token.0
+                        log_debug!(
+                            session: self.connection_token.0;
+                            "[HANDLE_FILE_TYPE] Would block..."
                         );                        
                         self.actions.push((
                             self.connection_token,
@@ -980,11 +1099,12 @@ impl HandlerRead {
                         return Ok(false);
                     }                    
                     *possible_response = Some(b"451 Requested action aborted: local error in processing.\r\n".to_vec());
-                    print_stdout!(
-                        "[HANDLE_FILE_TYPE] {} - Error Reading File: {}...",
-                        self.connection_token.0, err
+                    log_warn!(
+                        session: self.connection_token.0;
+                        "[HANDLE_FILE_TYPE] Error Reading File: {}...",
+                        err
                     );
-                    // NOTE Thinking about doing file cleanup?
+                    let _ = file.abort();
                     return Err(());
                 }
                 Ok(false)
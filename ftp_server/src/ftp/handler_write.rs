@@ -1,9 +1,13 @@
+use super::{
+    command::TransferMode, config::Config, response::Response, FileToSend, FileTransferType,
+};
 use super::{
     create_response, Action, BufferToWrite, HashMutex, RequestContextMutex, RequestType, Token,
 };
-use super::{response::Response, FileTransferType};
+use crate::system;
 use mio::{net::TcpStream, Interest, Waker};
 use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::sync::Arc;
 use std::{io::Error, net::Shutdown};
 
 pub struct HandlerWrite {
@@ -14,6 +18,15 @@ pub struct HandlerWrite {
     pub actions: Vec<Action>,
 
     connection: RequestContextMutex,
+
+    /// If the control connection has another command in its buffer
+    pending_command: bool,
+
+    config: Arc<Config>,
+
+    /// Set when the response was sent and the buffered command should be handled now,
+    /// instead of waiting for the socket to be readable
+    pub run_pending_command: bool,
 }
 
 impl HandlerWrite {
@@ -21,12 +34,17 @@ impl HandlerWrite {
         connection_token: Token,
         connection_db: HashMutex<Token, RequestContextMutex>,
         connection: RequestContextMutex,
+        pending_command: bool,
+        config: Arc<Config>,
     ) -> Self {
         Self {
             connection_token,
             connection_db,
             actions: Vec::new(),
             connection,
+            pending_command,
+            config,
+            run_pending_command: false,
         }
     }
 
@@ -64,20 +82,27 @@ impl HandlerWrite {
             RequestType::CommandTransfer(stream, to_write, _t, _path_from) => {
                 let maybe_error = stream.flush();
                 if let Err(err) = maybe_error {
-                    print_stdout!("[HANDLE_WRITE] CMD Error flushing the stream: {}", err);
+                    log_warn!("[HANDLE_WRITE] CMD Error flushing the stream: {}", err);
                 }
                 let written = stream.write(&to_write.buffer[to_write.offset..]);
                 if let Ok(written) = written {
-                    print_stdout!("[HANDLE_WRITE] CMD Writing {} bytes", written);
+                    log_debug!("[HANDLE_WRITE] CMD Writing {} bytes", written);
                     if written + to_write.offset >= to_write.buffer.len() {
-                        print_stdout!(
-                            "[HANDLE_WRITE] - {} - Going back to readable...",
-                            self.connection_token.0
+                        log_debug!(
+                            session: self.connection_token.0;
+                            "[HANDLE_WRITE] Going back to readable..."
                         );
                         to_write.buffer.clear();
                         to_write.offset = 0;
-                        self.keep_interest(waker, Interest::READABLE)?;
-                        return Ok(to_write.callback_after_sending.take());
+                        let callback = to_write.callback_after_sending.take();
+                        // Responses with a callback start a transfer or close the connection,
+                        // the next command waits until the transfer is answered
+                        if self.pending_command && callback.is_none() {
+                            self.run_pending_command = true;
+                        } else {
+                            self.keep_interest(waker, Interest::READABLE)?;
+                        }
+                        return Ok(callback);
                     // if let Some(callback) = to_write.callback_after_sending.take() {
                     //     callback();
                     // }
@@ -88,15 +113,15 @@ impl HandlerWrite {
                     }
                 } else if let Err(err) = written {
                     if err.kind() == ErrorKind::WouldBlock {
-                        print_stdout!(
-                            "[HANDLE_WRITE] - {} - Got would block error, keep writing",
-                            self.connection_token.0
+                        log_debug!(
+                            session: self.connection_token.0;
+                            "[HANDLE_WRITE] Got would block error, keep writing"
                         );
                         self.keep_interest(waker, Interest::WRITABLE)?;
                     } else {
-                        print_stdout!(
-                            "[HANDLE_WRITE] - {} - Error writing to socket, closing connection. Error: {}",
-                            self.connection_token.0,
+                        log_warn!(
+                            session: self.connection_token.0;
+                            "[HANDLE_WRITE] Error writing to socket, closing connection. Error: {}",
                             err
                         );
                         self.close_connection(stream)?;
@@ -147,69 +172,44 @@ impl HandlerWrite {
                 self.write_buffer_file_transfer(stream, to_write, waker, cmd_connection_token)
             }
 
-            FileTransferType::FileDownload(file) => {
-                let mut buf = [0; 1024];
-                loop {
-                    let read = file.read(&mut buf);
-                    if read.is_err() {
-                        //...
-                        panic!("Unhandled error");
+            FileTransferType::FileDownload(to_send) => {
+                let result = if to_send.zero_copy {
+                    self.send_file_zero_copy(stream, to_send)
+                } else {
+                    self.send_file_buffered(stream, to_send)
+                };
+                match result {
+                    Ok(true) => {
+                        log_debug!(
+                            session: self.connection_token.0;
+                            "[HANDLE_FILE_TRANSFER] Closing connection file transfer"
+                        );
+                        to_send.transfer.finish(true);
+                        let _ = self.close_connection(stream);
+                        self.answer_command(
+                            cmd_connection_token,
+                            "Closing data connection. Requested file action successful. (file transfer)",
+                        );
                     }
-                    let read = read.unwrap();
-                    if read == 0 {
-                        break;
+
+                    Ok(false) => {
+                        log_debug!(
+                            session: self.connection_token.0;
+                            "[HANDLE_FILE_TRANSFER] File not sent yet, let's write again"
+                        );
+                        self.keep_interest(waker, Interest::WRITABLE)?;
                     }
-                    let err = stream.write(&buf[0..read]);
-                    if let Err(err) = &err {
-                        if err.kind() == ErrorKind::WouldBlock {
-                            let err_seek = file.seek(SeekFrom::Current(-(read as i64)));
-                            if err_seek.is_err() {
-                                print_stdout!(
-                                    "[ERROR SEEK] Unknown error with seek :( {:?}",
-                                    err_seek
-                                );
-                                let _ = self.close_connection(stream);
-                                self.answer_command(
-                                    cmd_connection_token,
-                                    "Unknown error with file transfer",
-                                );
-                                return Ok(());
-                            }
-                            print_stdout!(
-                                "[HANDLE_FILE_TRANSFER] {} - Is would block, let's write again",
-                                self.connection_token.0
-                            );
-                            self.actions.push((
-                                self.connection_token,
-                                self.connection.clone(),
-                                Interest::WRITABLE,
-                            ));
-                            return Ok(());
-                        } else {
-                            print_stdout!(
-                                "[HANDLE_FILE_TRANSFER] Error transfering file {:?}",
-                                err
-                            );
-                            let _ = self.close_connection(stream);
-                            self.answer_command(
-                                cmd_connection_token,
-                                "Error with file transfer connection",
-                            );
-                        }
-                    } else {
-                        let read_end = err.unwrap();
-                        assert!(read_end == read);
+
+                    Err(err) => {
+                        log_warn!("[HANDLE_FILE_TRANSFER] Error transfering file {:?}", err);
+                        to_send.transfer.finish(false);
+                        let _ = self.close_connection(stream);
+                        self.answer_command(
+                            cmd_connection_token,
+                            "Error with file transfer connection",
+                        );
                     }
                 }
-                print_stdout!(
-                    "[HANDLE_FILE_TRANSFER] {} - Closing connection file transfer",
-                    self.connection_token.0
-                );
-                let _ = self.close_connection(stream);
-                self.answer_command(
-                    cmd_connection_token,
-                    "Closing data connection. Requested file action successful. (file transfer)",
-                );
                 Ok(())
             }
 
@@ -217,6 +217,88 @@ impl HandlerWrite {
         }
     }
 
+    /// Sends the file with `sendfile(2)` until the socket would block or the file ends.
+    /// Returns true if the whole file was sent. Falls back to a buffered copy
+    /// if the kernel can't do it for this file
+    fn send_file_zero_copy(
+        &mut self,
+        stream: &mut TcpStream,
+        to_send: &mut FileToSend,
+    ) -> Result<bool, Error> {
+        loop {
+            // At most `chunk_size` bytes per call, so a big file doesn't keep a worker busy when
+            // the client reads fast enough
+            let chunk_size = self.config.transfer.chunk_size;
+            // `zero_copy` is only set for files on the disk
+            let file = to_send.file.as_file().expect("zero copy of a file on the disk");
+            match system::sendfile(stream, file, &mut to_send.offset, chunk_size) {
+                Ok(0) => return Ok(true),
+                // Give the other connections a turn, we will be writable again right away
+                Ok(sent) => {
+                    to_send.transfer.bytes += sent as u64;
+                    return Ok(false);
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return Ok(false),
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                // EINVAL and ENOSYS mean that the file or the socket don't support it
+                Err(err)
+                    if !matches!(
+                        err.kind(),
+                        ErrorKind::InvalidInput | ErrorKind::Unsupported | ErrorKind::Other
+                    ) =>
+                {
+                    return Err(err)
+                }
+                Err(err) => {
+                    log_debug!(
+                        session: self.connection_token.0;
+                        "[HANDLE_FILE_TRANSFER] Can't use sendfile ({}), using a buffered copy",
+                        err
+                    );
+                    to_send.zero_copy = false;
+                    return self.send_file_buffered(stream, to_send);
+                }
+            }
+        }
+    }
+
+    /// Reads the file from the saved offset and writes it to the socket, converting the
+    /// line endings if the transfer is ASCII. Returns true if the whole file was sent
+    fn send_file_buffered(
+        &mut self,
+        stream: &mut TcpStream,
+        to_send: &mut FileToSend,
+    ) -> Result<bool, Error> {
+        let mut buf = [0; 16 * 1024];
+        loop {
+            let pending = &mut to_send.pending;
+            while pending.offset < pending.buffer.len() {
+                match stream.write(&pending.buffer[pending.offset..]) {
+                    Ok(written) => {
+                        pending.offset += written;
+                        to_send.transfer.bytes += written as u64;
+                    }
+                    Err(err) if err.kind() == ErrorKind::WouldBlock => return Ok(false),
+                    Err(err) => return Err(err),
+                }
+            }
+            pending.buffer.clear();
+            pending.offset = 0;
+            to_send.file.seek(SeekFrom::Start(to_send.offset))?;
+            let read = to_send.file.read(&mut buf)?;
+            if read == 0 {
+                return Ok(true);
+            }
+            to_send.offset += read as u64;
+            match to_send.mode {
+                TransferMode::Binary => pending.buffer.extend_from_slice(&buf[..read]),
+                TransferMode::Ascii => {
+                    system::to_crlf(&buf[..read], &mut pending.buffer, &mut to_send.last_was_cr)
+                }
+            }
+        }
+    }
+
     fn write_buffer_file_transfer(
         &mut self,
         stream: &mut TcpStream,
@@ -226,9 +308,9 @@ impl HandlerWrite {
     ) -> Result<(), Error> {
         let written = stream.write(&to_write.buffer[to_write.offset..]);
         if let Ok(written) = written {
-            print_stdout!(
-                "[WRITE_BUFFER_FILE_TRANSFER] {} - {} bytes written",
-                self.connection_token.0,
+            log_debug!(
+                session: self.connection_token.0;
+                "[WRITE_BUFFER_FILE_TRANSFER] {} bytes written",
                 written
             );
             if written + to_write.offset >= to_write.buffer.len() {
@@ -242,9 +324,9 @@ impl HandlerWrite {
                         &mut command_connection_mutex.request_type
                     {
                         t.take();
-                        print_stdout!(
-                            "[WRITE_BUFFER_FILE_TRANSFER] {} - Succesfully sending to the client, sending close data connection for token: {:?}", 
-                            self.connection_token.0,
+                        log_debug!(
+                            session: self.connection_token.0;
+                            "[WRITE_BUFFER_FILE_TRANSFER] Succesfully sending to the client, sending close data connection for token: {:?}",
                             cmd_connection_token
                         );
                         buffer_to_write.buffer = create_response(
@@ -253,7 +335,10 @@ impl HandlerWrite {
                         );
                         buffer_to_write.offset = 0;
                     } else {
-                        print_stdout!("[WRITE_BUFFER_FILE_TRANSFER] {} - Unexpected request type for command transfer", self.connection_token.0);
+                        log_warn!(
+                            session: self.connection_token.0;
+                            "[WRITE_BUFFER_FILE_TRANSFER] Unexpected request type for command transfer"
+                        );
                     }
                     drop(command_connection_mutex);
                     self.actions.push((
@@ -262,18 +347,18 @@ impl HandlerWrite {
                         Interest::WRITABLE,
                     ));
                 } else {
-                    print_stdout!(
-                        "[WRITE_BUFFER_FILE_TRANSFER] {} - Not found connection in DB",
-                        self.connection_token.0
+                    log_warn!(
+                        session: self.connection_token.0;
+                        "[WRITE_BUFFER_FILE_TRANSFER] Not found connection in DB"
                     );
                 }
                 return Ok(());
             }
             to_write.offset += written;
             self.keep_interest(waker, Interest::WRITABLE)?;
-            print_stdout!(
-                "[WRITE_BUFFER_FILE_TRANSFER] {} - Keep writing...",
-                self.connection_token.0
+            log_debug!(
+                session: self.connection_token.0;
+                "[WRITE_BUFFER_FILE_TRANSFER] Keep writing..."
             );
         } else if let Err(err) = written {
             if err.kind() == ErrorKind::WouldBlock {
@@ -284,15 +369,15 @@ impl HandlerWrite {
                     .unwrap()
                     .write(format!("\nWRITE {:?}", err).as_bytes())
                     .unwrap();
-                print_stdout!(
-                    "[WRITE_BUFFER_FILE_TRANSFER] {} - Would block error, keep writing",
-                    self.connection_token.0
+                log_debug!(
+                    session: self.connection_token.0;
+                    "[WRITE_BUFFER_FILE_TRANSFER] Would block error, keep writing"
                 );
                 self.keep_interest(waker, Interest::WRITABLE)?;
             } else {
-                print_stdout!(
-                    "[WRITE_BUFFER_FILE_TRANSFER] {} - Closing connection because {}",
-                    self.connection_token.0,
+                log_debug!(
+                    session: self.connection_token.0;
+                    "[WRITE_BUFFER_FILE_TRANSFER] Closing connection because {}",
                     err
                 );
                 self.close_connection(stream)?;
@@ -0,0 +1,22 @@
+use std::net::SocketAddr;
+
+/// Callbacks for the events of the control connections, so an embedder can observe the server.
+/// Every method does nothing by default.
+/// ## Behaviour
+/// * They are called from the event loop or from the workers, so they must not block.
+/// * `on_disconnect` is called once for every control connection that `on_connect` saw.
+pub trait ServerHooks: Send + Sync {
+    /// A client opened a control connection
+    fn on_connect(&self, _peer: Option<SocketAddr>) {}
+
+    /// `user` sent the right password
+    fn on_login(&self, _user: &str) {}
+
+    /// A control connection was closed, `user` is the last user that the client sent
+    fn on_disconnect(&self, _user: Option<&str>) {}
+}
+
+/// Hooks of a server that nobody observes
+pub struct NoHooks;
+
+impl ServerHooks for NoHooks {}
@@ -0,0 +1,260 @@
+//! Structured log of the server.
+//!
+//! Every event is written as a JSON line to stdout and/or a file, the sinks and the minimum level
+//! come from the `[log]` section of the config:
+//! ```text
+//! {"timestamp":"2021-05-01T10:00:00.000Z","level":"info","message":"command","session":3,"remote":"127.0.0.1:50122","user":"bob","command":"RETR a.txt","reply":150,"duration_ms":0.4}
+//! ```
+//! Fields that don't apply to an event are left out. The `log_*!` macros emit events that only
+//! have a message (and maybe a session), `Event` builds the ones with more fields.
+use super::config::LogConfig;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    Trace,
+    Debug,
+    #[default]
+    Info,
+    Warn,
+    Error,
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str(level: &str) -> Result<Self, Self::Err> {
+        match level {
+            "trace" => Ok(Level::Trace),
+            "debug" => Ok(Level::Debug),
+            "info" => Ok(Level::Info),
+            "warn" => Ok(Level::Warn),
+            "error" => Ok(Level::Error),
+            _ => Err(format!("unknown level {:?}", level)),
+        }
+    }
+}
+
+/// Minimum level that is written, as the `u8` of `Level`
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+/// If the events are written to stdout
+static STDOUT: AtomicBool = AtomicBool::new(false);
+
+/// File where the events are also written
+static FILE: Mutex<Option<File>> = Mutex::new(None);
+
+/// If `FILE` has a file, so the workers don't take its lock when there isn't one
+static USE_FILE: AtomicBool = AtomicBool::new(false);
+
+/// Sets the sinks and the level of the log and the file of the xferlog, it affects the whole process
+pub fn init(config: &LogConfig) -> io::Result<()> {
+    super::xferlog::init(config.xferlog.as_deref())?;
+    let file = match &config.file {
+        Some(path) => Some(OpenOptions::new().create(true).append(true).open(path)?),
+        None => None,
+    };
+    USE_FILE.store(file.is_some(), Ordering::Relaxed);
+    *FILE.lock().unwrap() = file;
+    STDOUT.store(config.stdout, Ordering::Relaxed);
+    LEVEL.store(config.level as u8, Ordering::Relaxed);
+    Ok(())
+}
+
+/// If an event of `level` would be written anywhere
+pub fn enabled(level: Level) -> bool {
+    level as u8 >= LEVEL.load(Ordering::Relaxed)
+        && (STDOUT.load(Ordering::Relaxed) || USE_FILE.load(Ordering::Relaxed))
+}
+
+/// One line of the log
+#[derive(Serialize, Debug)]
+pub struct Event<'a> {
+    timestamp: String,
+
+    level: Level,
+
+    message: String,
+
+    /// Token of the control connection
+    #[serde(skip_serializing_if = "Option::is_none")]
+    session: Option<usize>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    remote: Option<SocketAddr>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user: Option<&'a str>,
+
+    /// Command as the client sent it, passwords are hidden
+    #[serde(skip_serializing_if = "Option::is_none")]
+    command: Option<&'a str>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reply: Option<u16>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bytes: Option<u64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duration_ms: Option<f64>,
+}
+
+impl<'a> Event<'a> {
+    pub fn new<M: Into<String>>(level: Level, message: M) -> Self {
+        Self {
+            timestamp: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            level,
+            message: message.into(),
+            session: None,
+            remote: None,
+            user: None,
+            command: None,
+            reply: None,
+            bytes: None,
+            duration_ms: None,
+        }
+    }
+
+    pub fn session(mut self, session: usize) -> Self {
+        self.session = Some(session);
+        self
+    }
+
+    pub fn remote(mut self, remote: Option<SocketAddr>) -> Self {
+        self.remote = remote;
+        self
+    }
+
+    pub fn user(mut self, user: Option<&'a str>) -> Self {
+        self.user = user;
+        self
+    }
+
+    pub fn command(mut self, command: &'a str) -> Self {
+        self.command = Some(command);
+        self
+    }
+
+    pub fn reply(mut self, reply: Option<u16>) -> Self {
+        self.reply = reply;
+        self
+    }
+
+    pub fn bytes(mut self, bytes: u64) -> Self {
+        self.bytes = Some(bytes);
+        self
+    }
+
+    pub fn duration(mut self, duration: Duration) -> Self {
+        self.duration_ms = Some(duration.as_micros() as f64 / 1000.0);
+        self
+    }
+
+    /// Writes the event if its level is enabled
+    pub fn emit(self) {
+        if !enabled(self.level) {
+            return;
+        }
+        let mut line = match serde_json::to_vec(&self) {
+            Ok(line) => line,
+            Err(_) => return,
+        };
+        line.push(b'\n');
+        if STDOUT.load(Ordering::Relaxed) {
+            let _ = io::stdout().write_all(&line);
+        }
+        if USE_FILE.load(Ordering::Relaxed) {
+            if let Some(file) = FILE.lock().unwrap().as_mut() {
+                let _ = file.write_all(&line);
+            }
+        }
+    }
+}
+
+/// Emits an event of `$level` whose message is formatted like `format!`.
+/// `session: token.0;` before the message sets the session of the event.
+/// The message isn't formatted if the level is disabled
+#[macro_export]
+macro_rules! log_event {
+    ($level:expr, session: $session:expr; $($arg:tt)*) => {
+        if $crate::ftp::log::enabled($level) {
+            $crate::ftp::log::Event::new($level, format!($($arg)*))
+                .session($session)
+                .emit();
+        }
+    };
+    ($level:expr, $($arg:tt)*) => {
+        if $crate::ftp::log::enabled($level) {
+            $crate::ftp::log::Event::new($level, format!($($arg)*)).emit();
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! log_debug {
+    ($($arg:tt)*) => ($crate::log_event!($crate::ftp::log::Level::Debug, $($arg)*))
+}
+
+#[macro_export]
+macro_rules! log_info {
+    ($($arg:tt)*) => ($crate::log_event!($crate::ftp::log::Level::Info, $($arg)*))
+}
+
+#[macro_export]
+macro_rules! log_warn {
+    ($($arg:tt)*) => ($crate::log_event!($crate::ftp::log::Level::Warn, $($arg)*))
+}
+
+#[macro_export]
+macro_rules! log_error {
+    ($($arg:tt)*) => ($crate::log_event!($crate::ftp::log::Level::Error, $($arg)*))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Event, Level};
+    use std::time::Duration;
+
+    #[test]
+    fn event_is_a_json_line() {
+        let event = Event::new(Level::Info, "command")
+            .session(3)
+            .remote(Some("127.0.0.1:2121".parse().unwrap()))
+            .user(Some("bob"))
+            .command("RETR a.txt")
+            .reply(Some(150))
+            .bytes(12)
+            .duration(Duration::from_millis(2));
+        let json: serde_json::Value = serde_json::to_value(&event).unwrap();
+        assert_eq!(json["level"], "info");
+        assert_eq!(json["message"], "command");
+        assert_eq!(json["session"], 3);
+        assert_eq!(json["remote"], "127.0.0.1:2121");
+        assert_eq!(json["user"], "bob");
+        assert_eq!(json["command"], "RETR a.txt");
+        assert_eq!(json["reply"], 150);
+        assert_eq!(json["bytes"], 12);
+        assert_eq!(json["duration_ms"], 2.0);
+        assert!(json["timestamp"].as_str().unwrap().ends_with('Z'));
+        let json = serde_json::to_value(&Event::new(Level::Warn, "x")).unwrap();
+        assert_eq!(json.as_object().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn levels_are_ordered() {
+        assert!(Level::Trace < Level::Debug);
+        assert!(Level::Warn < Level::Error);
+        assert_eq!(Level::default(), Level::Info);
+        assert_eq!("warn".parse::<Level>(), Ok(Level::Warn));
+        assert!("loud".parse::<Level>().is_err());
+    }
+}
@@ -0,0 +1,359 @@
+//! Metrics of the server in the Prometheus text format, served on `GET /metrics` of their own
+//! address (`[metrics] bind`). The connection gauges are read when the metrics are scraped, the
+//! rest are updated by the event loop and the workers.
+use super::xferlog::Direction;
+use super::{HashMutex, RequestContextMutex};
+use crate::pool::PoolMetrics;
+use mio::Token;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// Upper bounds of the buckets of the transfer durations, in seconds
+const DURATION_BUCKETS: [f64; 10] = [0.01, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0, 600.0];
+
+/// Longest request that the endpoint reads, scrapers send a few headers
+const MAX_REQUEST_LENGTH: usize = 8 * 1024;
+
+#[derive(Default)]
+struct Histogram {
+    /// Observations of every bucket, not cumulative
+    buckets: [AtomicU64; DURATION_BUCKETS.len()],
+
+    count: AtomicU64,
+
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        if let Some(i) = DURATION_BUCKETS.iter().position(|bound| seconds <= *bound) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let mut cumulative = 0;
+        for (bound, bucket) in DURATION_BUCKETS.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{},le=\"{}\"}} {}",
+                name, labels, bound, cumulative
+            );
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_bucket{{{},le=\"+Inf\"}} {}", name, labels, count);
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, sum);
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, count);
+    }
+}
+
+/// Counters, gauges and histograms of a server. `Default` gives ones that aren't attached to a
+/// server, so their gauges are always 0
+#[derive(Default)]
+pub struct Metrics {
+    /// Every connection of the server, the data connections are the ones that aren't control
+    connections: HashMutex<Token, RequestContextMutex>,
+
+    control_connections: AtomicUsize,
+
+    pool: Arc<PoolMetrics>,
+
+    /// Commands handled by verb and reply code
+    commands: Mutex<BTreeMap<(String, Option<u16>), u64>>,
+
+    received_bytes: AtomicU64,
+
+    sent_bytes: AtomicU64,
+
+    logins: AtomicU64,
+
+    failed_logins: AtomicU64,
+
+    uploads: Histogram,
+
+    downloads: Histogram,
+}
+
+impl Metrics {
+    pub(super) fn new(
+        connections: HashMutex<Token, RequestContextMutex>,
+        pool: Arc<PoolMetrics>,
+    ) -> Self {
+        Self {
+            connections,
+            pool,
+            ..Self::default()
+        }
+    }
+
+    pub(super) fn set_control_connections(&self, control_connections: usize) {
+        self.control_connections
+            .store(control_connections, Ordering::Relaxed);
+    }
+
+    /// Counts a command, `verb` is the first word of the line
+    pub fn command(&self, verb: &str, reply: Option<u16>) {
+        *self
+            .commands
+            .lock()
+            .unwrap()
+            .entry((verb.to_string(), reply))
+            .or_insert(0) += 1;
+    }
+
+    pub fn login(&self, success: bool) {
+        if success {
+            self.logins.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.failed_logins.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Counts a `RETR` or `STOR` that finished or was aborted
+    pub fn transfer(&self, direction: Direction, bytes: u64, duration: Duration) {
+        let (counter, histogram) = match direction {
+            Direction::Incoming => (&self.received_bytes, &self.uploads),
+            Direction::Outgoing => (&self.sent_bytes, &self.downloads),
+        };
+        counter.fetch_add(bytes, Ordering::Relaxed);
+        histogram.observe(duration);
+    }
+
+    /// The metrics in the Prometheus text format
+    pub fn render(&self) -> String {
+        let control = self.control_connections.load(Ordering::Relaxed);
+        let connections = self.connections.lock().unwrap().len();
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: u64| {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            let _ = writeln!(out, "{} {}", name, value);
+        };
+        metric(
+            "ftp_control_connections",
+            "gauge",
+            "Control connections that are open.",
+            control as u64,
+        );
+        metric(
+            "ftp_data_connections",
+            "gauge",
+            "Data connections and passive ports that are open.",
+            connections.saturating_sub(control) as u64,
+        );
+        metric(
+            "ftp_worker_queue_depth",
+            "gauge",
+            "Events that wait for a free worker.",
+            self.pool.queue_depth() as u64,
+        );
+        metric(
+            "ftp_received_bytes_total",
+            "counter",
+            "Bytes of the files that the clients uploaded.",
+            self.received_bytes.load(Ordering::Relaxed),
+        );
+        metric(
+            "ftp_sent_bytes_total",
+            "counter",
+            "Bytes of the files that the clients downloaded.",
+            self.sent_bytes.load(Ordering::Relaxed),
+        );
+        let _ = writeln!(out, "# HELP ftp_logins_total Logins by result.");
+        let _ = writeln!(out, "# TYPE ftp_logins_total counter");
+        let _ = writeln!(
+            out,
+            "ftp_logins_total{{result=\"success\"}} {}",
+            self.logins.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(
+            out,
+            "ftp_logins_total{{result=\"failure\"}} {}",
+            self.failed_logins.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(
+            out,
+            "# HELP ftp_commands_total Commands by verb and reply code."
+        );
+        let _ = writeln!(out, "# TYPE ftp_commands_total counter");
+        for ((verb, reply), count) in self.commands.lock().unwrap().iter() {
+            let reply = reply.map_or("none".to_string(), |reply| reply.to_string());
+            let _ = writeln!(
+                out,
+                "ftp_commands_total{{verb=\"{}\",code=\"{}\"}} {}",
+                verb, reply, count
+            );
+        }
+        let name = "ftp_transfer_duration_seconds";
+        let _ = writeln!(out, "# HELP {} Duration of RETR and STOR.", name);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        self.uploads.render(&mut out, name, "direction=\"upload\"");
+        self.downloads
+            .render(&mut out, name, "direction=\"download\"");
+        out
+    }
+}
+
+/// Thread that serves the metrics, it stops when it's dropped
+pub struct MetricsServer {
+    addr: SocketAddr,
+
+    stop: Arc<AtomicBool>,
+
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MetricsServer {
+    /// Serves `metrics` on `listener` from a new thread
+    pub fn start(listener: TcpListener, metrics: Arc<Metrics>) -> io::Result<Self> {
+        let addr = listener.local_addr()?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_thread = stop.clone();
+        let thread = std::thread::Builder::new()
+            .name("metrics".to_string())
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    if stop_thread.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    if let Ok(stream) = stream {
+                        if let Err(err) = respond(stream, &metrics) {
+                            log_debug!("[METRICS] Error answering a scrape -> {}", err);
+                        }
+                    }
+                }
+            })?;
+        Ok(Self {
+            addr,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for MetricsServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        // Wake up the accept, an unspecified address is reached through the loopback
+        let mut addr = self.addr;
+        if addr.ip().is_unspecified() {
+            addr.set_ip(match addr.ip() {
+                IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+            });
+        }
+        let _ = TcpStream::connect_timeout(&addr, Duration::from_secs(1));
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Reads one HTTP request and answers it, only `GET /metrics` is found
+fn respond(mut stream: TcpStream, metrics: &Metrics) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|end| end == b"\r\n\r\n") {
+        let read = stream.read(&mut buf)?;
+        if read == 0 || request.len() + read > MAX_REQUEST_LENGTH {
+            break;
+        }
+        request.extend_from_slice(&buf[..read]);
+    }
+    let mut request_line = request.split(|byte| *byte == b' ');
+    let (status, body) = match (request_line.next(), request_line.next()) {
+        (Some(b"GET"), Some(b"/metrics")) => ("200 OK", metrics.render()),
+        _ => (
+            "404 Not Found",
+            "Not found, the metrics are in /metrics\n".to_string(),
+        ),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Metrics, MetricsServer};
+    use crate::ftp::xferlog::Direction;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn metrics_are_rendered() {
+        let metrics = Metrics::default();
+        metrics.command("USER", Some(331));
+        metrics.command("USER", Some(331));
+        metrics.command("PASS", Some(530));
+        metrics.login(false);
+        metrics.transfer(Direction::Incoming, 10, Duration::from_millis(200));
+        metrics.transfer(Direction::Outgoing, 5, Duration::from_secs(2));
+        let text = metrics.render();
+        let lines: Vec<&str> = text.lines().collect();
+        for line in &[
+            "ftp_control_connections 0",
+            "ftp_received_bytes_total 10",
+            "ftp_sent_bytes_total 5",
+            "ftp_logins_total{result=\"success\"} 0",
+            "ftp_logins_total{result=\"failure\"} 1",
+            "ftp_commands_total{verb=\"PASS\",code=\"530\"} 1",
+            "ftp_commands_total{verb=\"USER\",code=\"331\"} 2",
+            "ftp_transfer_duration_seconds_bucket{direction=\"upload\",le=\"0.1\"} 0",
+            "ftp_transfer_duration_seconds_bucket{direction=\"upload\",le=\"0.5\"} 1",
+            "ftp_transfer_duration_seconds_bucket{direction=\"upload\",le=\"+Inf\"} 1",
+            "ftp_transfer_duration_seconds_sum{direction=\"upload\"} 0.2",
+            "ftp_transfer_duration_seconds_bucket{direction=\"download\",le=\"1\"} 0",
+            "ftp_transfer_duration_seconds_bucket{direction=\"download\",le=\"5\"} 1",
+            "ftp_transfer_duration_seconds_count{direction=\"download\"} 1",
+        ] {
+            assert!(lines.contains(line), "{} not in\n{}", line, text);
+        }
+    }
+
+    #[test]
+    fn metrics_are_served_over_http() {
+        let metrics = Arc::new(Metrics::default());
+        metrics.login(true);
+        let server =
+            MetricsServer::start(TcpListener::bind("127.0.0.1:0").unwrap(), metrics).unwrap();
+        let get = |path: &str| {
+            let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+            write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+        let response = get("/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.contains("\r\n\r\n# HELP"));
+        assert!(response.contains("\nftp_logins_total{result=\"success\"} 1\n"));
+        assert!(get("/").starts_with("HTTP/1.1 404 Not Found\r\n"));
+        // Dropping it stops the thread
+        drop(server);
+    }
+}
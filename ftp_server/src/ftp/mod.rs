@@ -1,16 +1,27 @@
-use std::{
-    collections::HashMap,
-    fs::{self, File},
-    io::Write,
-    path::Path,
-};
-
-mod command;
+use std::{collections::{HashMap, HashSet}, io::Write};
+
+pub mod command;
+mod command_buffer;
+use command::TransferMode;
+use command_buffer::CommandBuffer;
 #[macro_use]
+pub mod log;
 pub mod config;
+#[cfg(unix)]
+pub mod admin;
+use log::Level;
+use config::{Config, ConfigLoader, FilenameEncoding, LimitsConfig, PartialUploads};
 mod handler_read;
 mod handler_write;
+pub mod hooks;
+pub mod metrics;
 mod response;
+pub mod xferlog;
+#[cfg(feature = "tokio-engine")]
+pub mod session;
+#[cfg(test)]
+pub(crate) mod testing;
+use hooks::{NoHooks, ServerHooks};
 use response::Response;
 use user_manage::SystemUsers;
 
@@ -19,10 +30,18 @@ use mio::net::{TcpListener, TcpStream};
 use mio::{event::Event, Interest, Poll, Token, Waker};
 use std::io::{Error, ErrorKind};
 use std::net::Shutdown;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
-use std::thread::spawn;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use metrics::Metrics;
+use xferlog::Transfer;
 
-use crate::tcp::TCPImplementation;
+use crate::pool::ThreadPool;
+use crate::storage::trash::{self, TrashPurger};
+use crate::storage::{self, DirEntry, LocalStorage, ReadFile, StorageBackend, WriteFile};
+use std::{ffi::OsStr, path::{Path, PathBuf}};
+use crate::tcp::{KillHandle, ReloadHandle, TCPImplementation};
 
 use self::{handler_read::HandlerRead, handler_write::HandlerWrite};
 
@@ -30,6 +49,133 @@ fn create_response(response_code: Response, message: &str) -> Vec<u8> {
     format!("{} {}\r\n", response_code.0, message).into_bytes()
 }
 
+/// Same as `create_response` with `name` inside of the message, in the encoding of the client.
+/// `name` can't have CR or LF because the paths of the commands can't
+fn create_name_response(
+    response_code: Response,
+    before: &str,
+    name: &OsStr,
+    after: &str,
+    encoding: FilenameEncoding,
+) -> Vec<u8> {
+    let mut response = format!("{} {}", response_code.0, before).into_bytes();
+    response.extend(encoding.encode(name));
+    response.extend(format!("{}\r\n", after).as_bytes());
+    response
+}
+
+/// Reply with the virtual `path` first, in double quotes like RFC 959 asks for `257`. The quotes
+/// of the path are doubled so the clients can find where it ends
+fn create_path_response(
+    response_code: Response,
+    path: &Path,
+    message: &str,
+    encoding: FilenameEncoding,
+) -> Vec<u8> {
+    let mut response = format!("{} \"", response_code.0).into_bytes();
+    for byte in encoding.encode(path.as_os_str()) {
+        response.push(byte);
+        if byte == b'"' {
+            response.push(b'"');
+        }
+    }
+    response.extend(format!("\" {}\r\n", message).as_bytes());
+    response
+}
+
+/// Reply of `SITE TRASH`, the entries of the trash of `home` with one line each
+fn trash_response(storage: &dyn StorageBackend, home: &Path, encoding: FilenameEncoding) -> Vec<u8> {
+    let entries = match trash::list(storage, home) {
+        Ok(entries) => entries,
+        Err(_) => return create_response(Response::file_unavailable(), "Trash unavailable."),
+    };
+    let mut reply = format!("{}-Trash:\r\n", Response::command_okay().0).into_bytes();
+    for entry in &entries {
+        reply.extend(format!(
+            " {} {} ",
+            entry.id,
+            entry.deleted.format("%Y-%m-%d %H:%M:%S")
+        ).as_bytes());
+        reply.extend(encoding.encode(entry.original.as_os_str()));
+        reply.extend_from_slice(if entry.is_dir { b"/\r\n" } else { b"\r\n" });
+    }
+    reply.extend(format!(
+        "{} {} entries.\r\n",
+        Response::command_okay().0,
+        entries.len()
+    ).as_bytes());
+    reply
+}
+
+/// Reply of `SITE RESTORE`, after moving the entry `id` of the trash of `home` back
+fn restore_response(
+    storage: &dyn StorageBackend,
+    home: &Path,
+    id: &str,
+    encoding: FilenameEncoding,
+) -> Vec<u8> {
+    match trash::restore(storage, home, id) {
+        Ok(path) => create_name_response(
+            Response::file_action_okay(),
+            "Restored ",
+            path.as_os_str(),
+            ".",
+            encoding,
+        ),
+        Err(err) if err.kind() == ErrorKind::AlreadyExists => create_response(
+            Response::file_unavailable(),
+            "Requested action not taken. Something is where it was.",
+        ),
+        Err(_) => create_response(
+            Response::file_unavailable(),
+            "Requested action not taken. Not found in the trash.",
+        ),
+    }
+}
+
+/// Lines of `LIST` for `entries`, listed with their virtual paths in `home`. Every entry is shown
+/// with its path from `root` (the directory of the homes), like `user/docs/a.txt`
+fn list_lines(
+    home: &Path,
+    root: &Path,
+    entries: &[(PathBuf, DirEntry)],
+    encoding: FilenameEncoding,
+) -> Vec<u8> {
+    let prefix = match (home.canonicalize(), root.canonicalize()) {
+        (Ok(home), Ok(root)) => home.strip_prefix(root).map(Path::to_path_buf),
+        _ => home.strip_prefix(root).map(Path::to_path_buf),
+    }
+    .unwrap_or_default();
+    let paths = entries.iter().map(|(path, _)| prefix.join(storage::relative(path)));
+    path_lines(paths, encoding)
+}
+
+/// Lines of `NLST` for `entries`, with their paths from the current directory `cwd` (just the
+/// names for the entries of `cwd`) or the virtual ones when they are outside of it
+fn name_lines(cwd: &Path, entries: &[(PathBuf, DirEntry)], encoding: FilenameEncoding) -> Vec<u8> {
+    let paths = entries.iter().map(|(path, _)| {
+        path.strip_prefix(cwd)
+            .map(Path::to_path_buf)
+            .unwrap_or_else(|_| path.clone())
+    });
+    path_lines(paths, encoding)
+}
+
+/// A line for every path. Names with CR or LF are left out, they would break the lines and no
+/// command can use them
+fn path_lines<I: Iterator<Item = PathBuf>>(paths: I, encoding: FilenameEncoding) -> Vec<u8> {
+    let mut buff = vec![];
+    for path in paths {
+        let line = encoding.encode(path.as_os_str());
+        if line.contains(&b'\r') || line.contains(&b'\n') {
+            continue;
+        }
+        buff.extend(line);
+        buff.extend_from_slice(b"\r\n");
+    }
+    buff
+}
+
 /// Buffer that is really useful to set to a writable request_context
 pub struct BufferToWrite {
     /// Total data that this buffer is gonna send
@@ -74,13 +220,56 @@ impl BufferToWrite {
     }
 }
 
+/// File that the server is sending to the client
+pub struct FileToSend {
+    file: Box<dyn ReadFile>,
+
+    /// Position of the next byte of the file that needs to be sent, we keep it ourselves
+    /// instead of seeking back when the socket would block
+    offset: u64,
+
+    /// If the file can go straight from the page cache to the socket with `sendfile(2)`.
+    /// Only binary transfers of files on the disk can, ASCII ones need to rewrite the line endings
+    zero_copy: bool,
+
+    /// Data that was read from the file (and converted) but not written yet, only used on buffered copies
+    pending: BufferToWrite,
+
+    /// If the last byte that was converted to ASCII was a CR
+    last_was_cr: bool,
+
+    mode: TransferMode,
+
+    transfer: Transfer,
+}
+
+impl FileToSend {
+    /// `sendfile` is false if the file must be copied with a buffer even if it could use `sendfile(2)`
+    fn new(file: Box<dyn ReadFile>, mode: TransferMode, sendfile: bool, transfer: Transfer) -> Self {
+        let on_disk = file.as_file().is_some();
+        Self {
+            file,
+            offset: 0,
+            zero_copy: sendfile
+                && on_disk
+                && cfg!(target_os = "linux")
+                && mode == TransferMode::Binary,
+            pending: BufferToWrite::default(),
+            last_was_cr: false,
+            mode,
+            transfer,
+        }
+    }
+}
+
 // #[derive(Debug)]
 pub enum FileTransferType {
     /// This kind of operation is when the server is saving a file from the client, Response is when there is a response, if there is none when closing, it assumes an error
-    FileUpload(File, Option<Vec<u8>>),
+    /// The file is aborted if the upload doesn't finish
+    FileUpload(Box<dyn WriteFile>, Transfer, Option<Vec<u8>>),
 
     /// This kind of operation is when the server is serving a file to the client
-    FileDownload(File),
+    FileDownload(FileToSend),
 
     /// This kind of operation is when the server is just writing some data to the client
     Buffer(BufferToWrite),
@@ -104,7 +293,8 @@ pub enum RequestType {
     /// TcpStream of the connection
     /// BufferToWrite is the buffer that is gonna be written on Write mode
     /// Option<Token> is the opened PassiveModePort/FileTransferActive/FileTransferPassive
-    CommandTransfer(TcpStream, BufferToWrite, Option<Token>, Option<String>),
+    /// Option<PathBuf> is the virtual path of the last `RNFR`
+    CommandTransfer(TcpStream, BufferToWrite, Option<Token>, Option<std::path::PathBuf>),
 
     /// This is the passive mode port that will accept connections
     /// It has a token where it references the CommandTransfer request_ctx
@@ -117,6 +307,16 @@ pub struct RequestContext {
     user_id: Option<String>,
 
     loged: bool,
+
+    /// Type set by the `TYPE` command, used by the next `RETR`
+    transfer_mode: TransferMode,
+
+    /// Bytes of the control connection that weren't handled yet, clients may split
+    /// a command in multiple packets or send multiple commands at once
+    command_buffer: CommandBuffer,
+
+    /// Address of the client, only set for control connections
+    remote: Option<SocketAddr>,
 }
 
 impl RequestContext {
@@ -125,6 +325,21 @@ impl RequestContext {
             request_type,
             user_id: None,
             loged: false,
+            transfer_mode: TransferMode::default(),
+            command_buffer: CommandBuffer::new(),
+            remote: None,
+        }
+    }
+
+    /// Reply code of the response that the control connection is sending
+    fn reply_code(&self) -> Option<u16> {
+        match &self.request_type {
+            RequestType::CommandTransfer(_, to_write, _, _) => to_write
+                .buffer
+                .get(..3)
+                .and_then(|code| std::str::from_utf8(code).ok())
+                .and_then(|code| code.parse().ok()),
+            _ => None,
         }
     }
 }
@@ -142,49 +357,215 @@ pub struct FTPServer {
 
     actions: ActionList,
 
-    current_id: usize,
-
-    // Maximum connections
-    max_connections: usize,
+    /// Last id given to a connection, it's shared with the workers because they may run a buffered
+    /// command that opens a new connection
+    current_id: Arc<AtomicUsize>,
 
     // Current connections
     current_connections: usize,
 
     user_repository: Arc<Mutex<SystemUsers>>,
+
+    /// Workers that run the read/write handlers
+    pool: ThreadPool,
+
+    config: Arc<Config>,
+
+    /// Gives the new config on a reload, without it only the users are loaded again
+    config_loader: Option<ConfigLoader>,
+
+    hooks: Arc<dyn ServerHooks>,
+
+    metrics: Arc<Metrics>,
+
+    storage: Arc<dyn StorageBackend>,
+
+    /// Replies of uploads whose data connection was closed that a worker didn't give to the
+    /// control connection yet, `drain` waits for them
+    pending_replies: Arc<AtomicUsize>,
+
+    /// Purges the trash of the homes, see `start_trash_purger`
+    trash_purger: Option<TrashPurger>,
 }
 
-pub const ROOT: &'static str = "./root";
+/// Part of the server that the jobs of the workers need, every field is shared with the `FTPServer`
+#[derive(Clone)]
+struct Shared {
+    connections: HashMutex<Token, RequestContextMutex>,
+
+    users: Arc<Mutex<SystemUsers>>,
+
+    actions: ActionList,
+
+    config: Arc<Config>,
+
+    hooks: Arc<dyn ServerHooks>,
+
+    metrics: Arc<Metrics>,
+
+    storage: Arc<dyn StorageBackend>,
+}
 
 impl FTPServer {
     pub fn new() -> Self {
-        if !Path::new(ROOT).exists() {
-            fs::create_dir(ROOT).expect("root dir hasn't been created");
-        }
+        Self::with_config(Config::default()).expect("didn't work")
+    }
+
+    /// Creates the server with a maximum of `max_connections` control connections and a pool of
+    /// `workers` threads, where at most `queue_size` events can wait for a free worker
+    pub fn with_connection_capacity(
+        max_connections: usize,
+        workers: usize,
+        queue_size: usize,
+    ) -> Self {
+        let config = Config {
+            limits: LimitsConfig {
+                max_connections,
+                workers,
+                queue_size,
+                ..LimitsConfig::default()
+            },
+            ..Config::default()
+        };
+        Self::with_config(config).expect("didn't work")
+    }
+
+    /// Creates the server loading the users from the paths of `config`
+    pub fn with_config(config: Config) -> Result<Self, Box<dyn std::error::Error>> {
+        let users = SystemUsers::load(&config.users)?;
+        Ok(Self::with_users(config, users))
+    }
+
+    /// Same as `with_config` but with users that are already loaded
+    pub fn with_users(config: Config, users: SystemUsers) -> Self {
+        let connections = Arc::new(Mutex::new(HashMap::new()));
+        let pool = ThreadPool::new(config.limits.workers, config.limits.queue_size);
+        let metrics = Arc::new(Metrics::new(connections.clone(), pool.metrics()));
         Self {
-            connections: Arc::new(Mutex::new(HashMap::new())),
-            current_id: 0,
-            max_connections: 50,
+            connections,
+            current_id: Arc::new(AtomicUsize::new(0)),
             current_connections: 0,
             actions: Arc::new(Mutex::new(Vec::new())),
-            user_repository: Arc::new(Mutex::new(
-                SystemUsers::load_data("./etc/users.json").expect("didn't work"),
-            )),
+            user_repository: Arc::new(Mutex::new(users)),
+            pool,
+            config: Arc::new(config),
+            config_loader: None,
+            hooks: Arc::new(NoHooks),
+            metrics,
+            storage: Arc::new(LocalStorage),
+            pending_replies: Arc::new(AtomicUsize::new(0)),
+            trash_purger: None,
         }
     }
 
-    pub fn with_connection_capacity(max_connections: usize) -> Self {
-        if !Path::new(ROOT).exists() {
-            fs::create_dir(ROOT).expect("root dir hasn't been created");
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Metrics of the server, see `metrics::MetricsServer` to serve them
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    /// Admin commands of this server, see `admin::AdminServer` to serve them. The handles are the
+    /// ones of the `Server` that runs it
+    #[cfg(unix)]
+    pub fn admin(&self, kill: KillHandle, reload: ReloadHandle) -> admin::Admin {
+        admin::Admin::new(
+            self.connections.clone(),
+            self.user_repository.clone(),
+            kill,
+            reload,
+        )
+    }
+
+    /// Sets the callbacks that are called on the events of the control connections
+    pub fn set_hooks(&mut self, hooks: Arc<dyn ServerHooks>) {
+        self.hooks = hooks;
+    }
+
+    /// Sets where the files of the users are kept, the local disk by default
+    pub fn set_storage(&mut self, storage: Arc<dyn StorageBackend>) {
+        self.storage = storage;
+    }
+
+    /// Starts purging the trash of the homes of `storage` with the retention of `[trash]`, it
+    /// replaces the thread that was purging it. A reload that changes `[trash]` calls it again
+    pub fn start_trash_purger(&mut self) {
+        self.trash_purger = None;
+        let retention = match self.config.trash.retention() {
+            Some(retention) => retention,
+            None => return,
+        };
+        let purger = TrashPurger::start(
+            self.storage.clone(),
+            self.user_repository.clone(),
+            retention,
+        );
+        match purger {
+            Ok(purger) => self.trash_purger = Some(purger),
+            Err(err) => log_warn!("[TRASH] Couldn't start purging the trash -> {}", err),
         }
-        Self {
-            connections: Arc::new(Mutex::new(HashMap::new())),
-            current_id: 0,
-            max_connections,
-            current_connections: 0,
-            actions: Arc::new(Mutex::new(Vec::new())),
-            user_repository: Arc::new(Mutex::new(
-                SystemUsers::load_data("./etc/users.json").expect("didn't work"),
-            )),
+    }
+
+    /// Sets where the config comes from when the server reloads
+    pub fn set_config_loader(&mut self, loader: ConfigLoader) {
+        self.config_loader = Some(loader);
+    }
+
+    /// Loads the config and the users again. The changes apply to the commands and connections
+    /// that come after it, the sessions and transfers that are running keep going.
+    /// If anything can't be loaded the server keeps the old config and users
+    pub fn reload(&mut self) {
+        let new_config = match &self.config_loader {
+            Some(loader) => match loader() {
+                Ok(config) => config,
+                Err(err) => {
+                    log_warn!("[RELOAD] Keeping the current config -> {}", err);
+                    return;
+                }
+            },
+            None => (*self.config).clone(),
+        };
+        let reload = self.config.reloaded(new_config);
+        let users = match SystemUsers::load(&reload.config.users) {
+            Ok(users) => users,
+            Err(err) => {
+                log_warn!("[RELOAD] Keeping the current config and users -> {}", err);
+                return;
+            }
+        };
+        if reload.applied.contains(&"log") {
+            if let Err(err) = log::init(&reload.config.log) {
+                log_warn!("[RELOAD] Error with the new log file -> {}", err);
+            }
+        }
+        log_info!("[RELOAD] Applied: {:?}", reload.applied);
+        if !reload.ignored.is_empty() {
+            log_info!("[RELOAD] Changed but need a restart: {:?}", reload.ignored);
+        }
+        self.config = Arc::new(reload.config);
+        if reload.applied.contains(&"trash") {
+            self.start_trash_purger();
+        }
+        let changes = self.user_repository.lock().unwrap().replace_users(users);
+        log_info!(
+            "[RELOAD] Users added: {:?}, removed: {:?}, changed: {:?}",
+            changes.added,
+            changes.removed,
+            changes.changed
+        );
+    }
+
+    fn shared(&self) -> Shared {
+        Shared {
+            connections: self.connections.clone(),
+            users: self.user_repository.clone(),
+            actions: self.actions.clone(),
+            config: self.config.clone(),
+            hooks: self.hooks.clone(),
+            metrics: self.metrics.clone(),
+            storage: self.storage.clone(),
         }
     }
 
@@ -250,6 +631,27 @@ impl FTPServer {
         FTPServer::shutdown(rc)?;
         Ok(())
     }
+
+    /// Copy of the connections, so each one can be locked without holding the map
+    fn connection_list(&self) -> Vec<(Token, RequestContextMutex)> {
+        self.connections
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(token, ctx)| (*token, ctx.clone()))
+            .collect()
+    }
+
+    /// Tells the client of a control connection that the server is going down, the connection is
+    /// closed right after so there is no need to wait for the socket to be writable
+    fn send_service_not_available(rc: &mut RequestContext) {
+        if let RequestType::CommandTransfer(stream, _, _, _) = &mut rc.request_type {
+            let _ = stream.write_all(&create_response(
+                Response::service_not_available(),
+                "Service not available, closing control connection.",
+            ));
+        }
+    }
 }
 
 impl TCPImplementation for FTPServer {
@@ -258,8 +660,152 @@ impl TCPImplementation for FTPServer {
     }
 
     fn next_id(&mut self) -> usize {
-        self.current_id += 1;
-        self.current_id
+        self.current_id.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    fn reload(&mut self) {
+        FTPServer::reload(self);
+    }
+
+    fn drain_timeout(&mut self) -> Duration {
+        log_info!(
+            "[DRAIN] Not accepting connections anymore, waiting {} seconds for the transfers",
+            self.config.shutdown.timeout
+        );
+        Duration::from_secs(self.config.shutdown.timeout)
+    }
+
+    /// Closes the control connections that are waiting for a command, the ones with a transfer
+    /// or with a worker handling them are closed on a later call, once they are idle too
+    fn drain(&mut self, poll: &Poll, waker: &Arc<Waker>) -> bool {
+        // The control connection of that upload looks idle until it has the reply
+        if self.pending_replies.load(Ordering::SeqCst) > 0 {
+            return false;
+        }
+        // Data connections and the control connection they belong to
+        let mut transfers = Vec::new();
+        // Idle control connections and the data connection that they prepared with `PORT` or
+        // `PASV`, the transfer commands take it so it's None once a transfer started
+        let mut idle = HashMap::new();
+        for (token, ctx) in self.connection_list() {
+            let ctx = match ctx.try_lock() {
+                Ok(ctx) => ctx,
+                // A worker has it, so it may be a transfer of any of the idle connections
+                Err(_) => return false,
+            };
+            match &ctx.request_type {
+                RequestType::FileTransferActive(_, _, command)
+                | RequestType::FileTransferPassive(_, _, command) => {
+                    transfers.push((token, *command));
+                }
+                RequestType::CommandTransfer(_, to_write, prepared, _)
+                    if to_write.offset >= to_write.buffer.len() && !ctx.command_buffer.has_line() =>
+                {
+                    idle.insert(token, *prepared);
+                }
+                RequestType::Closed(_) => {
+                    idle.insert(token, None);
+                }
+                _ => {}
+            }
+        }
+        let busy: HashSet<Token> = transfers
+            .into_iter()
+            .filter(|(data, command)| idle.get(command) != Some(&Some(*data)))
+            .map(|(_, command)| command)
+            .collect();
+        for token in idle.into_keys() {
+            if busy.contains(&token) {
+                continue;
+            }
+            let ctx = self.connections.lock().unwrap().get(&token).cloned();
+            if let Some(ctx) = ctx {
+                FTPServer::send_service_not_available(&mut ctx.lock().unwrap());
+            }
+            log_info!(session: token.0; "[DRAIN] Closing idle control connection");
+            let _ = self.close_connection(poll, token, waker);
+        }
+        self.connections.lock().unwrap().is_empty()
+    }
+
+    fn force_close(&mut self, poll: &Poll, _waker: &Arc<Waker>) {
+        let policy = self.config.shutdown.partial_uploads;
+        for (token, ctx) in self.connection_list() {
+            let mut ctx = ctx.lock().unwrap();
+            match &mut ctx.request_type {
+                RequestType::FileTransferActive(_, FileTransferType::FileUpload(file, transfer, _), _)
+                | RequestType::FileTransferPassive(
+                    _,
+                    FileTransferType::FileUpload(file, transfer, _),
+                    _,
+                ) => {
+                    log_info!(
+                        session: token.0;
+                        "[DRAIN] Closing unfinished upload {:?} ({:?})",
+                        transfer.path,
+                        policy
+                    );
+                    transfer.finish(false);
+                    let _ = match policy {
+                        PartialUploads::Keep => file.keep_partial(),
+                        PartialUploads::Delete => file.abort(),
+                    };
+                }
+                RequestType::FileTransferActive(_, FileTransferType::FileDownload(to_send), _)
+                | RequestType::FileTransferPassive(
+                    _,
+                    FileTransferType::FileDownload(to_send),
+                    _,
+                ) => to_send.transfer.finish(false),
+                RequestType::CommandTransfer(_, _, _, _) => {
+                    self.hooks.on_disconnect(ctx.user_id.as_deref());
+                    FTPServer::send_service_not_available(&mut ctx);
+                }
+                _ => {}
+            }
+            let _ = self.deregister_and_shutdown(poll, &mut ctx);
+        }
+        self.connections.lock().unwrap().clear();
+        self.current_connections = 0;
+        self.metrics.set_control_connections(0);
+    }
+
+    /// Closes a control connection with a `421`, and its data connections without waiting for
+    /// their transfers, which are logged as aborted. Other kinds of connections aren't sessions
+    /// so they are left alone
+    fn kill(&mut self, poll: &Poll, token: Token, waker: &Arc<Waker>) {
+        let ctx = match self.connections.lock().unwrap().get(&token).cloned() {
+            Some(ctx) => ctx,
+            None => return,
+        };
+        {
+            let mut ctx = ctx.lock().unwrap();
+            if !matches!(ctx.request_type, RequestType::CommandTransfer(_, _, _, _)) {
+                return;
+            }
+            FTPServer::send_service_not_available(&mut ctx);
+        }
+        for (data_token, data) in self.connection_list() {
+            let mut data = data.lock().unwrap();
+            match &mut data.request_type {
+                RequestType::FileTransferActive(_, transfer_type, command)
+                | RequestType::FileTransferPassive(_, transfer_type, command)
+                    if *command == token =>
+                {
+                    match transfer_type {
+                        FileTransferType::FileUpload(_, transfer, _) => transfer.finish(false),
+                        FileTransferType::FileDownload(to_send) => to_send.transfer.finish(false),
+                        FileTransferType::Buffer(_) => {}
+                    }
+                }
+                _ => continue,
+            }
+            let _ = self.deregister_and_shutdown(poll, &mut data);
+            drop(data);
+            self.connections.lock().unwrap().remove(&data_token);
+        }
+        log_info!(session: token.0; "[KILL] Closing the session");
+        let _ = self.close_connection(poll, token, waker);
     }
 
     fn new_connection(
@@ -269,15 +815,15 @@ impl TCPImplementation for FTPServer {
         poll: &Poll,
         mut stream: TcpStream,
     ) -> Result<(), std::io::Error> {
-        print_stdout!(
-            "[NEW_CONNECTION] {} - There is a brand new connection - Current connections: {} ",
-            token.0,
+        log_debug!(
+            session: token.0;
+            "[NEW_CONNECTION] There is a brand new connection - Current connections: {}",
             self.current_connections + 1
         );
-        if self.max_connections <= self.current_connections {
-            print_stdout!(
-                "[NEW_CONNECTION] {} - Closing connection because it surpasses the maximum connections",
-                token.0
+        if self.config.limits.max_connections <= self.current_connections {
+            log_warn!(
+                session: token.0;
+                "[NEW_CONNECTION] Closing connection because it surpasses the maximum connections"
             );
             poll.registry()
                 .register(&mut stream, token, Interest::WRITABLE)?;
@@ -285,20 +831,30 @@ impl TCPImplementation for FTPServer {
             return Ok(());
         }
         self.current_connections += 1;
+        self.metrics.set_control_connections(self.current_connections);
+        let remote = stream.peer_addr().ok();
+        self.hooks.on_connect(remote);
+        log::Event::new(Level::Info, "connected")
+            .session(token.0)
+            .remote(remote)
+            .emit();
         poll.registry()
             .register(&mut stream, token, Interest::WRITABLE)?;
-        self.add_connection(
-            token,
-            RequestType::CommandTransfer(
-                stream,
-                BufferToWrite::new(create_response(
-                    Response::service_ready(),
-                    "Service ready for new user.",
-                )),
-                None,
-                None,
-            ),
-        );
+        let mut request_context = RequestContext::new(RequestType::CommandTransfer(
+            stream,
+            BufferToWrite::new(create_response(
+                Response::service_ready(),
+                &self.config.server.banner,
+            )),
+            None,
+            None,
+        ));
+        request_context.command_buffer = CommandBuffer::with_bare_lf(self.config.server.allow_bare_lf);
+        request_context.remote = remote;
+        self.connections
+            .lock()
+            .unwrap()
+            .insert(token, Arc::new(Mutex::new(request_context)));
         Ok(())
     }
 
@@ -309,9 +865,8 @@ impl TCPImplementation for FTPServer {
         event: &Event,
     ) -> Result<(), Error> {
         let token = event.token();
-        print_stdout!("[WRITE_CONNECTION] - {} - Start Writing", token.0);
-        let map_conn_arc = self.connections.clone();
-        let map_conn = map_conn_arc.lock().unwrap();
+        log_debug!(session: token.0; "[WRITE_CONNECTION] Start Writing");
+        let map_conn = self.connections.lock().unwrap();
         let connection = {
             let connection = map_conn.get(&token).ok_or(ErrorKind::NotFound)?;
             let arc = connection.clone();
@@ -321,28 +876,53 @@ impl TCPImplementation for FTPServer {
         let mut connection_mutex = connection.lock().unwrap();
         self.deregister(poll, &mut connection_mutex)?;
         drop(connection_mutex);
-        let actions_ref = self.action_list();
-        spawn(move || {
+        let shared = self.shared();
+        let ids = self.current_id.clone();
+        log_debug!(
+            session: token.0;
+            "[WRITE_CONNECTION] Queue depth: {}",
+            self.pool.metrics().queue_depth()
+        );
+        self.pool.execute(move || {
             let mut conn = connection.lock().unwrap();
-            let mut handler = HandlerWrite::new(token, map_conn_arc.clone(), connection.clone());
+            let pending_command = conn.command_buffer.has_line();
+            let mut handler = HandlerWrite::new(
+                token,
+                shared.connections.clone(),
+                connection.clone(),
+                pending_command,
+                shared.config.clone(),
+            );
             let write_result = handler.handle_write(&mut conn.request_type, &waker);
             if let Err(err) = &write_result {
-                print_stdout!("[WRITE_CONNECTION] - {} - Fatal error -> {}", token.0, err);
+                log_warn!(session: token.0; "[WRITE_CONNECTION] Fatal error -> {}", err);
                 return;
             }
             // We drop the connection mutex here because we are promising the callback that it's 100% safe to take
             // any kind of mutex without getting a deadlock
             drop(conn);
-            if let Some(write_callback) = write_result.unwrap() {
-                write_callback();
-            }
-            let mut actions_locked = actions_ref.lock().unwrap();
+            // The handler actions must be queued before running the callback, the callback might start a transfer
+            // that finishes (and asks this connection to be writable) before this job ends, if we pushed our
+            // interest after that one we would overwrite it
+            let mut actions_locked = shared.actions.lock().unwrap();
             for action in handler.actions {
                 actions_locked.push(action);
             }
             drop(actions_locked);
-            let _ = waker.wake();
-            print_stdout!("[WRITE_CONNECTION] - {} - Finished task", token.0);
+            if let Some(write_callback) = write_result.unwrap() {
+                write_callback();
+            }
+            if handler.run_pending_command {
+                log_debug!(
+                    session: token.0;
+                    "[WRITE_CONNECTION] Handling the next buffered command"
+                );
+                let next_id = ids.fetch_add(1, Ordering::SeqCst) + 1;
+                read_job(token, connection, shared, waker, next_id);
+            } else {
+                let _ = waker.wake();
+            }
+            log_debug!(session: token.0; "[WRITE_CONNECTION] Finished task");
         });
         Ok(())
     }
@@ -353,7 +933,7 @@ impl TCPImplementation for FTPServer {
         waker: Arc<Waker>,
         event: &Event,
     ) -> Result<(), Error> {
-        print_stdout!("[READ_CONNECTION] - {} - Start read", event.token().0);
+        log_debug!(session: event.token().0; "[READ_CONNECTION] Start read");
         // Connection database reference
         let map_conn = self.connections.clone();
         let map_conn = map_conn.lock().unwrap();
@@ -365,80 +945,20 @@ impl TCPImplementation for FTPServer {
         };
         let token = event.token();
         drop(map_conn);
-        // Get the handler read component, basically in charge of reading and interpreting what is
-        // getting sent by the client
-        let mut handler_read = {
-            let conn_ref = &mut conn.lock().unwrap();
-            self.deregister(poll, conn_ref)?;
-            HandlerRead::new(
-                token,
-                self.connections.clone(),
-                conn.clone(),
-                self.user_repository.clone(),
-                conn_ref.user_id.clone(),
-                conn_ref.loged,
-            )
-        };
-        // Get action list mutex
-        let actions = self.action_list();
+        let mut connection_mutex = conn.lock().unwrap();
+        self.deregister(poll, &mut connection_mutex)?;
+        drop(connection_mutex);
         // Next connection ID if we accept a new connection
         let next_id = self.next_id();
-        // Spawn thread
-        spawn(move || {
-            let connection_arc = conn.clone();
-            let mut connection_mutex = connection_arc.lock().unwrap();
-            let response = handler_read.handle_read(
-                &mut connection_mutex.request_type,
-                &waker,
-                actions.clone(),
-                next_id,
-            );
-            let is_err = response.is_err();
-            let mut is_would_block = false;
-            if let Err(err) = response.as_ref() {
-                is_would_block = err.kind() == ErrorKind::WouldBlock;
-            }
-            let is_error_for_closing_connection = is_err && !is_would_block;
-            if is_error_for_closing_connection {
-                if let Err(err) = response {
-                    print_stdout!(
-                        "[READ_CONNECTION] - {} - Closing connection because error, {}",
-                        token.0,
-                        err
-                    );
-                    let _ = FTPServer::shutdown(&mut connection_mutex);
-                    drop(connection_mutex);
-                    let _ = waker.wake();
-                }
-            } else if is_would_block {
-                drop(connection_mutex);
-                print_stdout!("[READ_CONNECTION] - {} - Would block", token.0);
-                let mut actions = actions.lock().unwrap();
-                actions.push((
-                    handler_read.connection_token,
-                    connection_arc.clone(),
-                    Interest::READABLE,
-                ));
-                let _ = waker.wake();
-                drop(actions);
-            } else {
-                let callback = response.unwrap();
-                // This means that the function needs to do additional stuff inside the `request_context`,
-                // not the `request_type`
-                if let Some(callback) = callback {
-                    callback(&mut connection_mutex);
-                }
-                // Finally drop the mutex
-                drop(connection_mutex);
-                print_stdout!("[READ_CONNECTION] - {} - Adding actions", token.0);
-                let mut actions = actions.lock().unwrap();
-                for action in handler_read.actions {
-                    actions.push(action);
-                }
-                drop(actions);
-                let _ = waker.wake();
-            }
-            print_stdout!("[READ_CONNECTION] - {} - Finishing task", token.0);
+        let shared = self.shared();
+        log_debug!(
+            session: token.0;
+            "[READ_CONNECTION] Queue depth: {}",
+            self.pool.metrics().queue_depth()
+        );
+        // Queue the handler on the worker pool
+        self.pool.execute(move || {
+            read_job(token, conn, shared, waker, next_id);
         });
         Ok(())
     }
@@ -455,7 +975,7 @@ impl TCPImplementation for FTPServer {
         token: Token,
         waker: &Arc<Waker>,
     ) -> Result<(), Error> {
-        print_stdout!("[CLOSE_CONNECTION] - {} - Closing connection", token.0);
+        log_debug!(session: token.0; "[CLOSE_CONNECTION] Closing connection");
         let map_conn_arc = self.connections.clone();
         let map_conn = map_conn_arc.lock().unwrap();
         let conn = {
@@ -469,41 +989,56 @@ impl TCPImplementation for FTPServer {
         drop(map_conn);
         let mut conn = conn.lock().unwrap();
         let user_name = conn.user_id.clone();
+        let remote = conn.remote;
         match &mut conn.request_type {
             RequestType::Closed(stream) => {
                 let _ = poll.registry().deregister(stream);
                 let _ = stream.flush();
                 let _ = stream.shutdown(Shutdown::Both);
-                print_stdout!(
-                    "[CLOSE_CONNECTION] - {} - Closing connection because maximum connections reached",
-                    token.0
+                log_warn!(
+                    session: token.0;
+                    "[CLOSE_CONNECTION] Closing connection because maximum connections reached"
                 );
             }
 
             RequestType::FileTransferActive(stream, t, conn)
             | RequestType::FileTransferPassive(stream, t, conn) => {
-                if let FileTransferType::FileUpload(_, data_to_be_sent) = t {
+                // Downloads that were sent are already logged, this one was cut
+                if let FileTransferType::FileDownload(to_send) = t {
+                    to_send.transfer.finish(false);
+                }
+                if let FileTransferType::FileUpload(_, transfer, data_to_be_sent) = t {
                     // As said in the function header, we shouldn't close this connection because
                     // we wanna keep reading
                     if data_to_be_sent.is_none() {
                         return Err(Error::from(ErrorKind::WriteZero));
                     }
+                    // The response is a 226 if the whole file arrived and a 451 if reading failed
+                    let response = data_to_be_sent.as_ref().and_then(|data| data.first());
+                    transfer.finish(response == Some(&b'2'));
                     let db = self.connections.clone();
                     let actions = self.actions.clone();
                     let conn = *conn;
                     let data = data_to_be_sent.clone().unwrap();
                     // We need the waker to send actions
                     let waker = waker.clone();
+                    let pending_replies = self.pending_replies.clone();
+                    pending_replies.fetch_add(1, Ordering::SeqCst);
                     // Tell the command socket to send some stuff
-                    spawn(move || {
-                        print_stdout!(
-                            "[CLOSE_CONNECTION] - {} - Closing connection File Upload - {}",
-                            token.0,
+                    self.pool.execute(move || {
+                        log_debug!(
+                            session: token.0;
+                            "[CLOSE_CONNECTION] Closing connection File Upload - {}",
                             std::str::from_utf8(&data).unwrap()
                         );
                         let db = db.lock().unwrap();
-                        let command_conn = db.get(&conn)?;
-                        let command_conn = command_conn.clone();
+                        let command_conn = match db.get(&conn) {
+                            Some(command_conn) => command_conn.clone(),
+                            None => {
+                                pending_replies.fetch_sub(1, Ordering::SeqCst);
+                                return;
+                            }
+                        };
                         drop(db);
                         let mut actions = actions.lock().unwrap();
                         let mut cmd = command_conn.lock().unwrap();
@@ -512,36 +1047,36 @@ impl TCPImplementation for FTPServer {
                         {
                             to_write.reset(data);
                         }
+                        pending_replies.fetch_sub(1, Ordering::SeqCst);
                         drop(cmd);
                         actions.push((conn, command_conn, Interest::WRITABLE));
                         let _ = waker.wake();
-                        Some(())
                     });
                 }
-                print_stdout!(
-                    "[CLOSE_CONNECTION] - {} - Closing connection FTA or FTP",
-                    token.0
-                );
+                log_debug!(session: token.0; "[CLOSE_CONNECTION] Closing connection FTA or FTP");
                 let _ = poll.registry().deregister(stream);
                 let _ = stream.flush();
                 let _ = stream.shutdown(Shutdown::Both);
             }
 
             RequestType::CommandTransfer(stream, _, conn, _) => {
-                print_stdout!(
-                    "[CLOSE_CONNECTION] - {} - Closing connection command",
-                    token.0
-                );
+                log_debug!(session: token.0; "[CLOSE_CONNECTION] Closing connection command");
                 // Ignore error to be honest, don't care if we try to close twice
                 let _ = poll.registry().deregister(stream);
                 let _ = stream.flush();
                 let _ = stream.shutdown(Shutdown::Both);
                 let conn = conn.take();
+                self.hooks.on_disconnect(user_name.as_deref());
+                log::Event::new(Level::Info, "disconnected")
+                    .session(token.0)
+                    .remote(remote)
+                    .user(user_name.as_deref())
+                    .emit();
                 if let Some(user_name) = user_name {
                     let mut user_db = self.user_repository.lock().unwrap();
                     let u = user_db.get_user_mut(&user_name);
                     if let Some(user) = u {
-                        let _ = user.change_dir("/");
+                        user.set_actual_dir("./");
                     }
                 }
 
@@ -549,9 +1084,9 @@ impl TCPImplementation for FTPServer {
                     let mut map_conn = map_conn_arc.lock().unwrap();
                     let connection = map_conn.get_mut(conn);
                     if let Some(connection) = connection {
-                        print_stdout!(
-                            "[CLOSE_CONNECTION] - {} - Closing dangling connection",
-                            token.0
+                        log_debug!(
+                            session: token.0;
+                            "[CLOSE_CONNECTION] Closing dangling connection"
                         );
                         let mut connection = connection.lock().unwrap();
                         // Don't care if we close twice
@@ -563,7 +1098,7 @@ impl TCPImplementation for FTPServer {
             }
 
             RequestType::PassiveModePort(stream, _) => {
-                print_stdout!("[CLOSE_CONNECTION] - {} - Closing port", token.0);
+                log_debug!(session: token.0; "[CLOSE_CONNECTION] Closing port");
                 // We actually just deregister when we write
                 poll.registry().deregister(stream)?;
             }
@@ -571,17 +1106,18 @@ impl TCPImplementation for FTPServer {
 
         // Now delete it from the database
         if let Some(_) = self.connections.lock().unwrap().remove(&token) {
-            print_stdout!("[CLOSE_CONNECTION] Successfully removing the connection.");
+            log_debug!("[CLOSE_CONNECTION] Successfully removing the connection.");
             if let RequestType::CommandTransfer(_, _, _, _) = &conn.request_type {
                 self.current_connections -= 1;
+                self.metrics.set_control_connections(self.current_connections);
             }
-            print_stdout!(
+            log_debug!(
                 "[CLOSE_CONNECTION] Current control connections - {}",
                 self.current_connections
             );
         }
 
-        print_stdout!(
+        log_debug!(
             "[CLOSE_CONNECTION] Current overall connections - {}",
             self.connections.lock().unwrap().len()
         );
@@ -590,8 +1126,120 @@ impl TCPImplementation for FTPServer {
     }
 }
 
+/// Reads and handles a command of the `conn` control connection, it's executed by a worker.
+/// It's also used by the write job when a command was already buffered, because the socket won't
+/// be readable for data that we already read
+fn read_job(
+    token: Token,
+    conn: RequestContextMutex,
+    shared: Shared,
+    waker: Arc<Waker>,
+    next_id: usize,
+) {
+    let started = Instant::now();
+    let connection_arc = conn.clone();
+    let mut connection_mutex = connection_arc.lock().unwrap();
+    let was_logged = connection_mutex.loged;
+    // Get the handler read component, basically in charge of reading and interpreting what is
+    // getting sent by the client
+    let mut handler_read = HandlerRead::new(token, conn.clone(), &shared, &connection_mutex);
+    let Shared {
+        actions,
+        hooks,
+        metrics,
+        ..
+    } = shared;
+    let ctx = &mut *connection_mutex;
+    let response = handler_read.handle_read(
+        &mut ctx.request_type,
+        &mut ctx.command_buffer,
+        &waker,
+        actions.clone(),
+        next_id,
+    );
+    let is_err = response.is_err();
+    let mut is_would_block = false;
+    if let Err(err) = response.as_ref() {
+        is_would_block = err.kind() == ErrorKind::WouldBlock;
+    }
+    let is_error_for_closing_connection = is_err && !is_would_block;
+    if is_error_for_closing_connection {
+        if let Err(err) = response {
+            log_warn!(
+                session: token.0;
+                "[READ_CONNECTION] Closing connection because error, {}",
+                err
+            );
+            let _ = FTPServer::shutdown(&mut connection_mutex);
+            drop(connection_mutex);
+            let _ = waker.wake();
+        }
+    } else if is_would_block {
+        drop(connection_mutex);
+        log_debug!(session: token.0; "[READ_CONNECTION] Would block");
+        let mut actions = actions.lock().unwrap();
+        actions.push((
+            handler_read.connection_token,
+            connection_arc.clone(),
+            Interest::READABLE,
+        ));
+        let _ = waker.wake();
+        drop(actions);
+    } else {
+        let callback = response.unwrap();
+        // This means that the function needs to do additional stuff inside the `request_context`,
+        // not the `request_type`
+        if let Some(callback) = callback {
+            callback(&mut connection_mutex);
+        }
+        let ctx = &*connection_mutex;
+        let reply = ctx.reply_code();
+        if let Some(command) = &handler_read.command {
+            let verb = command.split(' ').next().unwrap_or_default();
+            metrics.command(verb, reply);
+            log::Event::new(Level::Info, "command")
+                .session(token.0)
+                .remote(ctx.remote)
+                .user(ctx.user_id.as_deref())
+                .command(command)
+                .reply(reply)
+                .duration(started.elapsed())
+                .emit();
+        }
+        if !was_logged && ctx.loged {
+            if let Some(user) = &ctx.user_id {
+                hooks.on_login(user);
+                metrics.login(true);
+                log::Event::new(Level::Info, "login")
+                    .session(token.0)
+                    .remote(ctx.remote)
+                    .user(Some(user))
+                    .emit();
+            }
+        } else if !ctx.loged && reply == Some(530) {
+            metrics.login(false);
+            log::Event::new(Level::Warn, "login failed")
+                .session(token.0)
+                .remote(ctx.remote)
+                .user(ctx.user_id.as_deref())
+                .emit();
+        }
+        // Finally drop the mutex
+        drop(connection_mutex);
+        log_debug!(session: token.0; "[READ_CONNECTION] Adding actions");
+        let mut actions = actions.lock().unwrap();
+        for action in handler_read.actions {
+            actions.push(action);
+        }
+        drop(actions);
+        let _ = waker.wake();
+    }
+    log_debug!(session: token.0; "[READ_CONNECTION] Finishing task");
+}
+
 #[cfg(test)]
 mod ftp_server_testing {
+    use super::testing::TestServer;
     use crate::port;
     use std::io::{BufRead, BufReader, Write};
     use std::net::TcpListener;
@@ -610,6 +1258,13 @@ mod ftp_server_testing {
         assert_eq!(response_expects, str);
     }
 
+    /// Same as `expect_response` for replies that aren't UTF-8
+    fn expect_bytes(stream: &mut TcpStream, expected: &[u8]) {
+        let mut line = Vec::new();
+        BufReader::new(stream).read_until(b'\n', &mut line).unwrap();
+        assert_eq!(expected, &line[..], "{}", String::from_utf8_lossy(&line));
+    }
+
     fn log_in(stream: &mut TcpStream, username: &str, password: &str) {
         stream
             .write_all(&format!("USER {}\r\n", username).as_bytes())
@@ -623,36 +1278,44 @@ mod ftp_server_testing {
 
     use crate::system;
 
+    /// Listener for an active data connection and the `PORT` command that points to it
+    fn data_listener() -> (TcpListener, String) {
+        let srv = TcpListener::bind("127.0.0.1:0").expect("to create server");
+        let (first, second) = port::get_ftp_port_pair(srv.local_addr().unwrap().port());
+        (srv, format!("PORT 127,0,0,1,{},{}\r\n", first, second))
+    }
+
     #[test]
     fn it_works() {
+        let server = TestServer::start(&["user_012"]);
         for _ in 0..100 {
-            let result = TcpStream::connect("127.0.0.1:8080");
+            let result = TcpStream::connect(server.addr());
             if let Err(err) = result {
                 panic!("{}", err);
             }
             let mut stream = result.unwrap();
             expect_response(&mut stream, "220 Service ready for new user.\r\n");
             log_in(&mut stream, "user_012", "123456");
-            let srv = TcpListener::bind("127.0.0.1:2234").expect("to create server");
-            // print_stdout!("expect writing everything");
+            let (srv, port_command) = data_listener();
+            // log_debug!("expect writing everything");
             stream
-                .write_all(&"PORT 127,0,0,1,8,186\r\n".as_bytes())
+                .write_all(port_command.as_bytes())
                 .expect("writing everything");
+            let expected = system::ls(server.home("user_012"), server.root()).unwrap();
             let join = std::thread::spawn(move || {
-                // print_stdout!("accept conn");
+                // log_debug!("accept conn");
                 let (mut conn, _) = srv.accept().expect("expect to receive connection");
                 let mut buff = [0; 1024];
-                // print_stdout!("read 1st");
+                // log_debug!("read 1st");
                 let read = conn.read(&mut buff).expect("to have read");
-                let v = system::ls("./root/user_012").unwrap();
-                assert_eq!(v, &buff[..read]);
-                // print_stdout!("read 2nd");
+                assert_eq!(expected, &buff[..read]);
+                // log_debug!("read 2nd");
                 let possible_err = conn.read(&mut buff);
                 assert!(possible_err.unwrap() == 0);
             });
-            // print_stdout!("Command okay");
+            // log_debug!("Command okay");
             expect_response(&mut stream, "200 Command okay.\r\n");
-            // print_stdout!("List");
+            // log_debug!("List");
             stream
                 .write_all(&"LIST\r\n".as_bytes())
                 .expect("writing everything");
@@ -660,13 +1323,13 @@ mod ftp_server_testing {
                 &mut stream,
                 "150 File status okay; about to open data connection.\r\n",
             );
-            // print_stdout!("Closing");
+            // log_debug!("Closing");
             expect_response(&mut stream, "226 Closing data connection. Requested file action successful (for example, file transfer or file abort).\r\n");
             join.join().unwrap();
             std::thread::sleep(Duration::from_millis(20));
-            let srv = TcpListener::bind("127.0.0.1:2234").expect("to create server");
+            let (srv, port_command) = data_listener();
             stream
-                .write_all(&"PORT 127,0,0,1,8,186\r\n".as_bytes())
+                .write_all(port_command.as_bytes())
                 .expect("writing everything");
             let join = std::thread::spawn(move || {
                 let (mut conn, _) = srv.accept().expect("expect to receive connection");
@@ -690,26 +1353,27 @@ mod ftp_server_testing {
 
     #[test]
     fn it_works2() {
+        let server = TestServer::start(&["user_test_it_works_2"]);
         for _ in 0..100 {
-            let result = TcpStream::connect("127.0.0.1:8080");
+            let result = TcpStream::connect(server.addr());
             if let Err(err) = result {
                 panic!("{}", err);
             }
             let mut stream = result.unwrap();
             expect_response(&mut stream, "220 Service ready for new user.\r\n");
             log_in(&mut stream, "user_test_it_works_2", "123456");
-            let srv = TcpListener::bind("127.0.0.1:2235").expect("to create server");
+            let (srv, port_command) = data_listener();
             stream
-                .write_all(&"PORT 127,0,0,1,8,187\r\n".as_bytes())
+                .write_all(port_command.as_bytes())
                 .expect("writing everything");
+            let expected = system::ls(server.home("user_test_it_works_2"), server.root()).unwrap();
             let join = std::thread::spawn(move || {
                 let (mut conn, _) = srv.accept().expect("expect to receive connection");
                 let mut buff = [0; 1024];
-                // print_stdout!("read 1st");
+                // log_debug!("read 1st");
                 let read = conn.read(&mut buff).expect("to have read");
-                let v = system::ls("./root/user_test_it_works_2").unwrap();
-                assert_eq!(v, &buff[..read]);
-                // print_stdout!("read 2nd");
+                assert_eq!(expected, &buff[..read]);
+                // log_debug!("read 2nd");
                 let possible_err = conn.read(&mut buff);
                 assert!(possible_err.unwrap() == 0);
             });
@@ -727,9 +1391,9 @@ mod ftp_server_testing {
             expect_response(&mut stream, "226 Closing data connection. Requested file action successful (for example, file transfer or file abort).\r\n");
             join.join().unwrap();
             std::thread::sleep(Duration::from_millis(20));
-            let srv = TcpListener::bind("127.0.0.1:2235").expect("to create server");
+            let (srv, port_command) = data_listener();
             stream
-                .write_all(&"PORT 127,0,0,1,8,187\r\n".as_bytes())
+                .write_all(port_command.as_bytes())
                 .expect("writing everything");
             let join = std::thread::spawn(move || {
                 let (mut conn, _) = srv.accept().expect("expect to receive connection");
@@ -753,21 +1417,22 @@ mod ftp_server_testing {
 
     #[test]
     fn it_works3() {
+        let server = TestServer::start(&["user_test_it_works_3"]);
         for _ in 0..100 {
-            let result = TcpStream::connect("127.0.0.1:8080");
+            let result = TcpStream::connect(server.addr());
             let mut stream = result.unwrap();
             expect_response(&mut stream, "220 Service ready for new user.\r\n");
             log_in(&mut stream, "user_test_it_works_3", "123456");
-            let srv = TcpListener::bind("127.0.0.1:2232").expect("to create server");
+            let (srv, port_command) = data_listener();
             stream
-                .write_all(&"PORT 127,0,0,1,8,184\r\n".as_bytes())
+                .write_all(port_command.as_bytes())
                 .expect("writing everything");
+            let expected = system::ls(server.home("user_test_it_works_3"), server.root()).unwrap();
             let join = std::thread::spawn(move || {
                 let (mut conn, _) = srv.accept().expect("expect to receive connection");
                 let mut buff = [0; 1024];
                 let read = conn.read(&mut buff).expect("to have read");
-                let v = system::ls("./root/user_test_it_works_3").unwrap();
-                assert_eq!(v, &buff[..read]);
+                assert_eq!(expected, &buff[..read]);
                 let possible_err = conn.read(&mut buff);
                 assert!(possible_err.unwrap() == 0);
             });
@@ -792,22 +1457,24 @@ mod ftp_server_testing {
 
     #[test]
     fn image_transfer() {
-        let result = TcpStream::connect("127.0.0.1:8080");
+        let server = TestServer::start(&["user_test_image_transfer"]);
+        let result = TcpStream::connect(server.addr());
         if let Err(err) = result {
             panic!("{}", err);
         }
         let mut stream = result.unwrap();
         expect_response(&mut stream, "220 Service ready for new user.\r\n");
         log_in(&mut stream, "user_test_image_transfer", "123456");
-        let srv = TcpListener::bind("127.0.0.1:2233").expect("to create server");
+        let (srv, port_command) = data_listener();
         stream
-            .write_all(&"PORT 127,0,0,1,8,185\r\n".as_bytes())
+            .write_all(port_command.as_bytes())
             .expect("writing everything");
+        let download = server.path("2.jpg");
         let join = std::thread::spawn(move || {
             let mut f = std::fs::OpenOptions::new()
                 .write(true)
                 .create(true)
-                .open("./2.jpg")
+                .open(download)
                 .unwrap();
             let (mut conn, _) = srv.accept().expect("expect to receive connection");
             let mut buff = [0; 1024];
@@ -835,30 +1502,28 @@ mod ftp_server_testing {
 
     #[test]
     fn image_transfer_02() {
+        let server = TestServer::start(&["user_test_image_transfer_02"]);
         for _i in 0..100 {
-            let result = TcpStream::connect("127.0.0.1:8080");
+            let result = TcpStream::connect(server.addr());
             if let Err(err) = result {
                 panic!("{}", err);
             }
             let mut stream = result.unwrap();
             expect_response(&mut stream, "220 Service ready for new user.\r\n");
             log_in(&mut stream, "user_test_image_transfer_02", "123456");
-            let srv = TcpListener::bind("127.0.0.1:2253").expect("to create server");
+            let (srv, port_command) = data_listener();
             stream
-                .write_all(&"PORT 127,0,0,1,8,205\r\n".as_bytes())
+                .write_all(port_command.as_bytes())
                 .expect("writing everything");
             upload_hello_world(srv, &mut stream);
             std::thread::sleep(Duration::from_millis(20));
         }
     }
 
-    fn upload_active<'a>(stream: &mut TcpStream, to: &'a str, from: &'static str, port: u16) {
-        let conn = format!("127.0.0.1:{}", port);
-        let srv = TcpListener::bind(conn).expect("to create server");
-        let (first, second) = port::get_ftp_port_pair(port);
-        let command = format!("PORT 127,0,0,1,{},{}\r\n", first, second);
+    fn upload_active<'a>(stream: &mut TcpStream, to: &'a str, from: &'static str) {
+        let (srv, port_command) = data_listener();
         stream
-            .write_all(&command.as_bytes())
+            .write_all(port_command.as_bytes())
             .expect("writing everything");
         let join = std::thread::spawn(move || {
             let mut f = std::fs::OpenOptions::new().read(true).open(from).unwrap();
@@ -890,13 +1555,10 @@ mod ftp_server_testing {
         join.join().unwrap();
     }
 
-    fn recv_active<'a>(stream: &mut TcpStream, to: &'a str, from: &'static str, port: u16) {
-        let conn = format!("127.0.0.1:{}", port);
-        let srv = TcpListener::bind(conn).expect("to create server");
-        let (first, second) = port::get_ftp_port_pair(port);
-        let command = format!("PORT 127,0,0,1,{},{}\r\n", first, second);
+    fn recv_active<'a>(stream: &mut TcpStream, to: &'a str, from: PathBuf) {
+        let (srv, port_command) = data_listener();
         stream
-            .write_all(&command.as_bytes())
+            .write_all(port_command.as_bytes())
             .expect("writing everything");
         let join = std::thread::spawn(move || {
             let mut f = std::fs::OpenOptions::new()
@@ -931,14 +1593,15 @@ mod ftp_server_testing {
 
     #[test]
     fn mkdir() {
-        let result = TcpStream::connect("127.0.0.1:8080");
+        let server = TestServer::start(&["user_test_mkdir_01"]);
+        let result = TcpStream::connect(server.addr());
         let mut stream = result.unwrap();
         expect_response(&mut stream, "220 Service ready for new user.\r\n");
         log_in(&mut stream, "user_test_mkdir_01", "123456");
         stream
             .write_all(&"MKD /test\r\n".as_bytes())
             .expect("writing everything");
-        expect_response(&mut stream, "257 'test' directory created.\r\n");
+        expect_response(&mut stream, "257 \"/test\" directory created.\r\n");
         stream
             .write_all(&"RMD /test\r\n".as_bytes())
             .expect("writing everything");
@@ -974,19 +1637,20 @@ mod ftp_server_testing {
 
     #[test]
     fn cwd_test() {
-        let result = TcpStream::connect("127.0.0.1:8080");
+        let server = TestServer::start(&["user_test_cwd_test"]);
+        let result = TcpStream::connect(server.addr());
         let mut stream = result.unwrap();
         expect_response(&mut stream, "220 Service ready for new user.\r\n");
         log_in(&mut stream, "user_test_cwd_test", "123456");
-        let srv = TcpListener::bind("127.0.0.1:2302").expect("to create server");
+        let (srv, port_command) = data_listener();
         stream
-            .write_all(&"PORT 127,0,0,1,8,254\r\n".as_bytes())
+            .write_all(port_command.as_bytes())
             .expect("writing everything");
         upload_hello_world(srv, &mut stream);
         stream
             .write_all(&"MKD /test\r\n".as_bytes())
             .expect("writing everything");
-        expect_response(&mut stream, "257 'test' directory created.\r\n");
+        expect_response(&mut stream, "257 \"/test\" directory created.\r\n");
         std::thread::sleep(Duration::from_micros(100));
         stream
             .write_all(&"CWD ./test\r\n".as_bytes())
@@ -995,9 +1659,9 @@ mod ftp_server_testing {
             &mut stream,
             "250 Requested file action okay, completed.\r\n",
         );
-        let srv = TcpListener::bind("127.0.0.1:2302").expect("to create server");
+        let (srv, port_command) = data_listener();
         stream
-            .write_all(&"PORT 127,0,0,1,8,254\r\n".as_bytes())
+            .write_all(port_command.as_bytes())
             .expect("writing everything");
         upload_hello_world(srv, &mut stream);
         stream
@@ -1014,6 +1678,7 @@ mod ftp_server_testing {
             &mut stream,
             "250 Requested file action okay, completed.\r\n",
         );
+        dele(&mut stream, "./test/thing.txt");
         stream
             .write_all(&"RMD ./test\r\n".as_bytes())
             .expect("writing everything");
@@ -1025,13 +1690,14 @@ mod ftp_server_testing {
 
     #[test]
     fn create_file_delete() {
-        let result = TcpStream::connect("127.0.0.1:8080");
+        let server = TestServer::start(&["user_test_create_file_delete"]);
+        let result = TcpStream::connect(server.addr());
         let mut stream = result.unwrap();
         expect_response(&mut stream, "220 Service ready for new user.\r\n");
         log_in(&mut stream, "user_test_create_file_delete", "123456");
-        let srv = TcpListener::bind("127.0.0.1:2303").expect("to create server");
+        let (srv, port_command) = data_listener();
         stream
-            .write_all(&"PORT 127,0,0,1,8,255\r\n".as_bytes())
+            .write_all(port_command.as_bytes())
             .expect("writing everything");
         upload_hello_world(srv, &mut stream);
         stream
@@ -1073,18 +1739,24 @@ mod ftp_server_testing {
 
     fn pwd(stream: &mut TcpStream, expected: &str) {
         stream.write_all(&"PWD\r\n".as_bytes()).unwrap();
-        expect_response(stream, format!("257 {}\r\n", expected).as_str());
+        let expected = format!(
+            "257 \"{}\" is the current directory.\r\n",
+            expected.replace('"', "\"\"")
+        );
+        expect_response(stream, &expected);
     }
 
-    use std::path::Path;
+    use crate::ftp::config::{FilenameEncoding, S3Config};
+    use crate::storage::s3_testing::S3TestServer;
+    use crate::storage::S3Storage;
+    use std::path::{Path, PathBuf};
 
     fn mkd(stream: &mut TcpStream, path: &str) {
         let to_send = format!("{} {}\r\n", "MKD", path);
         stream
             .write_all(to_send.as_bytes())
             .expect("writing everything");
-        let s = Path::new(path).file_name().unwrap().to_str().unwrap();
-        let expected = format!("257 '{}' directory created.\r\n", s);
+        let expected = format!("257 \"{}\" directory created.\r\n", path);
         expect_response(stream, &expected);
     }
 
@@ -1096,10 +1768,205 @@ mod ftp_server_testing {
         expect_response(stream, "250 Requested file action okay, completed.\r\n");
     }
 
+    #[test]
+    fn reload_users() {
+        let server = TestServer::start(&["user_reload"]);
+        let mut before = TcpStream::connect(server.addr()).unwrap();
+        expect_response(&mut before, "220 Service ready for new user.\r\n");
+        log_in(&mut before, "user_reload", "123456");
+        mkd(&mut before, "/dir");
+        cwd(&mut before, "/dir");
+        let users_file = server.path("etc/users.json");
+        let users = std::fs::read_to_string(&users_file).unwrap();
+        std::fs::write(&users_file, users.replace("123456", "new_password")).unwrap();
+        server.reload();
+        // The session that was already logged in keeps going where it was
+        pwd(&mut before, "/dir");
+        let mut old_password = TcpStream::connect(server.addr()).unwrap();
+        expect_response(&mut old_password, "220 Service ready for new user.\r\n");
+        old_password.write_all(b"USER user_reload\r\nPASS 123456\r\n").unwrap();
+        expect_response(&mut old_password, "331 User name okay, need password.\r\n");
+        expect_response(&mut old_password, "530 Not logged in.\r\n");
+        let mut new_password = TcpStream::connect(server.addr()).unwrap();
+        expect_response(&mut new_password, "220 Service ready for new user.\r\n");
+        log_in(&mut new_password, "user_reload", "new_password");
+        pwd(&mut new_password, "/dir");
+    }
+
+    /// Starts an active upload of `to` whose data connection sends `first`, then waits for the
+    /// receiver before sending `rest`
+    fn slow_upload(
+        stream: &mut TcpStream,
+        to: &str,
+        first: &'static [u8],
+        rest: &'static [u8],
+    ) -> (std::sync::mpsc::Sender<()>, std::thread::JoinHandle<()>) {
+        let (srv, port_command) = data_listener();
+        let (continue_sender, continue_receiver) = std::sync::mpsc::channel();
+        let join = std::thread::spawn(move || {
+            let (mut conn, _) = srv.accept().expect("expect to receive connection");
+            conn.write_all(first).unwrap();
+            continue_receiver.recv().unwrap();
+            // The server may have closed the connection already
+            let _ = conn.write_all(rest);
+        });
+        stream.write_all(port_command.as_bytes()).unwrap();
+        expect_response(stream, "200 Command okay.\r\n");
+        stream
+            .write_all(format!("STOR {}\r\n", to).as_bytes())
+            .unwrap();
+        expect_response(
+            stream,
+            "150 File status okay; about to open data connection.\r\n",
+        );
+        (continue_sender, join)
+    }
+
+    /// Files of the uploads that are running in `dir`, waits up to a second for one to appear
+    fn wait_for_upload(dir: &Path) -> Vec<String> {
+        let uploads = || -> Vec<String> {
+            std::fs::read_dir(dir)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                .filter(|name| name.ends_with(".ftp-upload"))
+                .collect()
+        };
+        for _ in 0..100 {
+            if !uploads().is_empty() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        uploads()
+    }
+
+    #[test]
+    fn uploads_replace_the_file_when_they_finish() {
+        let server = TestServer::start(&["user_atomic"]);
+        let home = server.home("user_atomic");
+        let mut uploading = TcpStream::connect(server.addr()).unwrap();
+        expect_response(&mut uploading, "220 Service ready for new user.\r\n");
+        log_in(&mut uploading, "user_atomic", "123456");
+        let (finish, join) = slow_upload(&mut uploading, "testfile.txt", b"Bye ", b"world!");
+        assert_eq!(wait_for_upload(&home).len(), 1);
+        // Other clients get the old file while the upload runs
+        let mut reading = TcpStream::connect(server.addr()).unwrap();
+        expect_response(&mut reading, "220 Service ready for new user.\r\n");
+        log_in(&mut reading, "user_atomic", "123456");
+        let download = server.path("testfile.txt");
+        recv_active(&mut reading, "testfile.txt", download.clone());
+        assert_eq!(std::fs::read_to_string(&download).unwrap(), "Hello world!");
+        finish.send(()).unwrap();
+        join.join().unwrap();
+        expect_response(
+            &mut uploading,
+            "226 Closing data connection. Requested file action successful (for example, file transfer or file abort).\r\n",
+        );
+        let uploaded = std::fs::read_to_string(home.join("testfile.txt"));
+        assert_eq!(uploaded.unwrap(), "Bye world!");
+        assert!(wait_for_upload(&home).is_empty());
+    }
+
+    /// Expects `responses` and then the 421 that the server sends before closing the connection
+    fn expect_closed(stream: &mut TcpStream, responses: &str) {
+        let mut rest = String::new();
+        stream.read_to_string(&mut rest).unwrap();
+        assert_eq!(
+            rest,
+            format!(
+                "{}421 Service not available, closing control connection.\r\n",
+                responses
+            )
+        );
+    }
+
+    #[test]
+    fn graceful_shutdown_drains() {
+        let mut server = TestServer::start(&["user_drain"]);
+        let mut idle = TcpStream::connect(server.addr()).unwrap();
+        expect_response(&mut idle, "220 Service ready for new user.\r\n");
+        log_in(&mut idle, "user_drain", "123456");
+        let mut uploading = TcpStream::connect(server.addr()).unwrap();
+        expect_response(&mut uploading, "220 Service ready for new user.\r\n");
+        log_in(&mut uploading, "user_drain", "123456");
+        let (finish, join) = slow_upload(&mut uploading, "drain.txt", b"Hello ", b"world!");
+        server.handle().graceful_shutdown();
+        expect_closed(&mut idle, "");
+        assert!(TcpStream::connect(server.addr()).is_err());
+        finish.send(()).unwrap();
+        join.join().unwrap();
+        expect_closed(
+            &mut uploading,
+            "226 Closing data connection. Requested file action successful (for example, file transfer or file abort).\r\n",
+        );
+        server.join().unwrap();
+        let uploaded = std::fs::read_to_string(server.home("user_drain").join("drain.txt"));
+        assert_eq!(uploaded.unwrap(), "Hello world!");
+    }
+
+    #[test]
+    fn graceful_shutdown_deletes_partial_uploads() {
+        let mut server = TestServer::start_with(&["user_drain_timeout"], |builder| {
+            builder
+                .shutdown_timeout(Duration::from_secs(1))
+                .partial_uploads(crate::ftp::config::PartialUploads::Delete)
+        });
+        let mut uploading = TcpStream::connect(server.addr()).unwrap();
+        expect_response(&mut uploading, "220 Service ready for new user.\r\n");
+        log_in(&mut uploading, "user_drain_timeout", "123456");
+        let (finish, join) = slow_upload(&mut uploading, "partial.txt", b"Hello ", b"world!");
+        let home = server.home("user_drain_timeout");
+        let partial = home.join("partial.txt");
+        // The upload is written to a hidden file until it finishes
+        assert_eq!(wait_for_upload(&home).len(), 1);
+        assert!(!partial.exists());
+        server.handle().graceful_shutdown();
+        // The upload doesn't finish before the timeout
+        server.join().unwrap();
+        expect_closed(&mut uploading, "");
+        assert!(!partial.exists());
+        assert!(wait_for_upload(&home).is_empty());
+        finish.send(()).unwrap();
+        join.join().unwrap();
+    }
+
+    #[test]
+    fn graceful_shutdown_keeps_partial_uploads_apart() {
+        let mut server = TestServer::start_with(&["user_drain_keep"], |builder| {
+            builder
+                .shutdown_timeout(Duration::from_secs(0))
+                .partial_uploads(crate::ftp::config::PartialUploads::Keep)
+        });
+        let mut uploading = TcpStream::connect(server.addr()).unwrap();
+        expect_response(&mut uploading, "220 Service ready for new user.\r\n");
+        log_in(&mut uploading, "user_drain_keep", "123456");
+        let (finish, join) = slow_upload(&mut uploading, "testfile.txt", b"Bye ", b"world!");
+        let home = server.home("user_drain_keep");
+        let upload = home.join(&wait_for_upload(&home)[0]);
+        for _ in 0..100 {
+            if std::fs::metadata(&upload).unwrap().len() == 4 {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        server.handle().graceful_shutdown();
+        server.join().unwrap();
+        expect_closed(&mut uploading, "");
+        // The file that was there stays as it was, what was received is next to it
+        let file = std::fs::read_to_string(home.join("testfile.txt"));
+        assert_eq!(file.unwrap(), "Hello world!");
+        let partial = std::fs::read_to_string(home.join("testfile.txt.partial"));
+        assert_eq!(partial.unwrap(), "Bye ");
+        assert!(wait_for_upload(&home).is_empty());
+        finish.send(()).unwrap();
+        join.join().unwrap();
+    }
+
     //cargo test --package ftp_server --bin ftp_server -- ftp::ftp_server_testing::pwd_test --exact --nocapture
     #[test]
     fn pwd_test() {
-        let result = TcpStream::connect("127.0.0.1:8080");
+        let server = TestServer::start(&["user_pwd_test"]);
+        let result = TcpStream::connect(server.addr());
         let mut stream = result.unwrap();
         expect_response(&mut stream, "220 Service ready for new user.\r\n");
         log_in(&mut stream, "user_pwd_test", "123456");
@@ -1108,64 +1975,127 @@ mod ftp_server_testing {
         mkd(&mut stream, "/thing/thing2");
         mkd(&mut stream, "/thing/thing2/thing3");
         cwd(&mut stream, "/thing");
-        mkd(&mut stream, "./thing4");
+        // The reply has the absolute path of the new directory
+        stream.write_all(b"MKD ./thing4\r\n").unwrap();
+        expect_response(&mut stream, "257 \"/thing/thing4\" directory created.\r\n");
         rmd(&mut stream, "./thing4");
         pwd(&mut stream, "/thing");
         cwd(&mut stream, "./thing2");
         pwd(&mut stream, "/thing/thing2");
         cwd(&mut stream, "/thing/thing2");
         pwd(&mut stream, "/thing/thing2");
+        stream.write_all(b"CDUP\r\n").unwrap();
+        expect_response(&mut stream, "250 Requested file action okay, completed.\r\n");
+        pwd(&mut stream, "/thing");
+        // The commands of RFC 775 are the same ones
+        stream.write_all(b"XCWD thing2\r\n").unwrap();
+        expect_response(&mut stream, "250 Requested file action okay, completed.\r\n");
+        stream.write_all(b"XPWD\r\n").unwrap();
+        expect_response(&mut stream, "257 \"/thing/thing2\" is the current directory.\r\n");
+        stream.write_all(b"XMKD thing5\r\n").unwrap();
+        expect_response(&mut stream, "257 \"/thing/thing2/thing5\" directory created.\r\n");
+        stream.write_all(b"XRMD thing5\r\n").unwrap();
+        expect_response(&mut stream, "250 Requested file action okay, completed.\r\n");
         cwd(&mut stream, "../../");
+        stream.write_all(b"CDUP\r\n").unwrap();
+        expect_response(
+            &mut stream,
+            "550 Requested action not taken. File unavailable, file not found.\r\n",
+        );
+        pwd(&mut stream, "/");
+        rmd(&mut stream, "/thing/thing2/thing3");
+        rmd(&mut stream, "/thing/thing2");
         rmd(&mut stream, "/thing");
     }
 
     #[test]
     fn recv_test() {
-        let result = TcpStream::connect("127.0.0.1:8080");
+        let server = TestServer::start(&["user_recv_test"]);
+        let result = TcpStream::connect(server.addr());
         let mut stream = result.unwrap();
         expect_response(&mut stream, "220 Service ready for new user.\r\n");
         log_in(&mut stream, "user_recv_test", "123456");
-        upload_active(&mut stream, "./1.jpeg", "./test_files/1.jpeg", 1887);
-        recv_active(&mut stream, "./1.jpeg", "./test_files/2.jpeg", 1887);
+        upload_active(&mut stream, "./1.jpeg", "./test_files/1.jpeg");
+        recv_active(&mut stream, "./1.jpeg", server.path("2.jpeg"));
         dele(&mut stream, "/1.jpeg");
     }
 
+    #[test]
+    fn xferlog_has_the_transfers() {
+        let server = TestServer::start(&["user_xferlog"]);
+        let xferlog = server.path("xferlog");
+        super::xferlog::init(Some(&xferlog)).unwrap();
+        let mut stream = TcpStream::connect(server.addr()).unwrap();
+        expect_response(&mut stream, "220 Service ready for new user.\r\n");
+        log_in(&mut stream, "user_xferlog", "123456");
+        upload_active(&mut stream, "./hola.txt", "./test_files/hola.txt");
+        recv_active(&mut stream, "./hola.txt", server.path("hola.txt"));
+        let size = std::fs::metadata("./test_files/hola.txt").unwrap().len();
+        // Other tests may be writing their transfers too
+        let lines: Vec<Vec<String>> = std::fs::read_to_string(&xferlog)
+            .unwrap()
+            .lines()
+            .map(|line| {
+                line.split(' ')
+                    .filter(|field| !field.is_empty())
+                    .map(String::from)
+                    .collect()
+            })
+            .filter(|fields: &Vec<String>| {
+                fields.get(13).map(String::as_str) == Some("user_xferlog")
+            })
+            .collect();
+        assert_eq!(lines.len(), 2, "{:?}", lines);
+        for (fields, direction) in lines.iter().zip(&["i", "o"]) {
+            // The day of the month is padded with a space, so it's 5 fields
+            assert_eq!(fields.len(), 18);
+            assert_eq!(fields[6], "127.0.0.1");
+            assert_eq!(fields[7], size.to_string());
+            assert!(fields[8].ends_with("hola.txt"));
+            assert_eq!(
+                &fields[9..],
+                &["b", "_", direction, "r", "user_xferlog", "ftp", "0", "*", "c"]
+            );
+        }
+    }
+
     #[test]
     fn store_text_test() {
-        let result = TcpStream::connect("127.0.0.1:8080");
+        let server = TestServer::start(&["user_store_text_test"]);
+        let result = TcpStream::connect(server.addr());
         let mut stream = result.unwrap();
         expect_response(&mut stream, "220 Service ready for new user.\r\n");
         log_in(&mut stream, "user_store_text_test", "123456");
-        upload_active(&mut stream, "./t.txt", "./test_files/hola.txt", 2777);
+        upload_active(&mut stream, "./t.txt", "./test_files/hola.txt");
     }
 
     #[test]
     fn store_test() {
-        let result = TcpStream::connect("127.0.0.1:8080");
+        let server = TestServer::start(&["user_store_test"]);
+        let result = TcpStream::connect(server.addr());
         let mut stream = result.unwrap();
         expect_response(&mut stream, "220 Service ready for new user.\r\n");
         log_in(&mut stream, "user_store_test", "123456");
         mkd(&mut stream, "/thing");
         mkd(&mut stream, "/thing/thing2");
-        upload_active(&mut stream, "./thing/1.jpeg", "./test_files/1.jpeg", 1777);
-        upload_active(
-            &mut stream,
-            "./thing/thing2/1.jpeg",
-            "./test_files/1.jpeg",
-            1777,
-        );
+        upload_active(&mut stream, "./thing/1.jpeg", "./test_files/1.jpeg");
+        upload_active(&mut stream, "./thing/thing2/1.jpeg", "./test_files/1.jpeg");
+        dele(&mut stream, "./thing/thing2/1.jpeg");
+        rmd(&mut stream, "/thing/thing2");
+        dele(&mut stream, "./thing/1.jpeg");
         rmd(&mut stream, "/thing");
     }
 
     #[test]
     fn store_2_test() {
-        let result = TcpStream::connect("127.0.0.1:8080");
+        let server = TestServer::start(&["user_store_2_test"]);
+        let result = TcpStream::connect(server.addr());
         let mut stream = result.unwrap();
         expect_response(&mut stream, "220 Service ready for new user.\r\n");
         log_in(&mut stream, "user_store_2_test", "123456");
         for i in 0..100 {
             let s = format!("./{}.jpeg", i);
-            upload_active(&mut stream, &s, "./test_files/1.jpeg", 1778);
+            upload_active(&mut stream, &s, "./test_files/1.jpeg");
         }
 
         for i in 0..100 {
@@ -1176,7 +2106,8 @@ mod ftp_server_testing {
 
     #[test]
     fn rnto_test() {
-        let result = TcpStream::connect("127.0.0.1:8080");
+        let server = TestServer::start(&["user_rnto_test"]);
+        let result = TcpStream::connect(server.addr());
         let mut stream = result.unwrap();
         expect_response(&mut stream, "220 Service ready for new user.\r\n");
         log_in(&mut stream, "user_rnto_test", "123456");
@@ -1186,13 +2117,15 @@ mod ftp_server_testing {
         mkd(&mut stream, "/thing/thing2/thing3");
         rnto(&mut stream, "/thing", "/thing5");
         rnto(&mut stream, "/thing5/thing2", "/thing2");
+        rmd(&mut stream, "/thing2/thing3");
         rmd(&mut stream, "/thing2");
         rmd(&mut stream, "/thing5");
     }
 
     #[test]
     fn delete_its_own_directory_test() {
-        let result = TcpStream::connect("127.0.0.1:8080");
+        let server = TestServer::start(&["user_delete_its_own_directory_test"]);
+        let result = TcpStream::connect(server.addr());
         let mut stream = result.unwrap();
         expect_response(&mut stream, "220 Service ready for new user.\r\n");
         log_in(&mut stream, "user_delete_its_own_directory_test", "123456");
@@ -1209,11 +2142,310 @@ mod ftp_server_testing {
         pwd(&mut stream, "/");
     }
 
+    #[test]
+    fn in_memory_storage() {
+        let server = TestServer::start_in_memory(&["user_in_memory"]);
+        let home = server.home("user_in_memory");
+        let result = TcpStream::connect(server.addr());
+        let mut stream = result.unwrap();
+        expect_response(&mut stream, "220 Service ready for new user.\r\n");
+        log_in(&mut stream, "user_in_memory", "123456");
+        mkd(&mut stream, "/thing");
+        mkd(&mut stream, "/thing/thing2");
+        cwd(&mut stream, "/thing");
+        pwd(&mut stream, "/thing");
+        upload_active(&mut stream, "./1.jpeg", "./test_files/1.jpeg");
+        let download = server.path("2.jpeg");
+        recv_active(&mut stream, "../1.jpeg", download.clone());
+        assert_eq!(
+            std::fs::read(&download).unwrap(),
+            std::fs::read("./test_files/1.jpeg").unwrap()
+        );
+        rnto(&mut stream, "./thing2", "/thing3");
+        dele(&mut stream, "/testfile.txt");
+        assert_eq!(
+            server.storage().tree(&home),
+            ["/1.jpeg", "/thing/", "/thing/1.jpeg", "/thing3/"]
+        );
+        assert_eq!(
+            server.storage().read(&home, Path::new("/thing/1.jpeg")),
+            std::fs::read("./test_files/1.jpeg").ok()
+        );
+        // Nothing was written to the home on the disk
+        assert_eq!(std::fs::read_dir(&home).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn s3_storage() {
+        let s3 = S3TestServer::start();
+        // 1.jpeg is uploaded in 4 parts
+        let config = S3Config {
+            part_size: 512 * 1024,
+            ..s3.config()
+        };
+        let server = TestServer::start_with(&["user_s3"], |builder| {
+            builder.storage(S3Storage::new(config).unwrap())
+        });
+        let mut stream = TcpStream::connect(server.addr()).unwrap();
+        expect_response(&mut stream, "220 Service ready for new user.\r\n");
+        log_in(&mut stream, "user_s3", "123456");
+        mkd(&mut stream, "/thing");
+        mkd(&mut stream, "/thing/thing2");
+        cwd(&mut stream, "/thing");
+        upload_active(&mut stream, "./1.jpeg", "./test_files/1.jpeg");
+        let download = server.path("2.jpeg");
+        recv_active(&mut stream, "/thing/1.jpeg", download.clone());
+        let jpeg = std::fs::read("./test_files/1.jpeg").unwrap();
+        assert_eq!(std::fs::read(&download).unwrap(), jpeg);
+        rnto(&mut stream, "./1.jpeg", "/1.jpeg");
+        rnto(&mut stream, "./thing2", "/thing3");
+        assert_eq!(
+            s3.keys(),
+            ["ftp/user_s3/1.jpeg", "ftp/user_s3/thing/", "ftp/user_s3/thing3/"]
+        );
+        assert_eq!(s3.object("ftp/user_s3/1.jpeg"), Some(jpeg));
+        dele(&mut stream, "/1.jpeg");
+        assert!(s3
+            .requests()
+            .iter()
+            .any(|request| request == "POST ftp/user_s3/thing/1.jpeg?uploads"));
+        assert_eq!(s3.keys(), ["ftp/user_s3/thing/", "ftp/user_s3/thing3/"]);
+    }
+
+    /// `LIST` of `path` over an active data connection, returns what was sent
+    fn list_active(stream: &mut TcpStream, path: &str) -> String {
+        String::from_utf8(listing_active(stream, format!("LIST {}", path).as_bytes())).unwrap()
+    }
+
+    /// Sends `command` (`LIST` or `NLST` with its arguments) and returns what it sends on the
+    /// data connection, the names don't have to be UTF-8
+    fn listing_active(stream: &mut TcpStream, command: &[u8]) -> Vec<u8> {
+        let (srv, port_command) = data_listener();
+        stream.write_all(port_command.as_bytes()).unwrap();
+        expect_response(stream, "200 Command okay.\r\n");
+        let join = std::thread::spawn(move || {
+            let (mut conn, _) = srv.accept().unwrap();
+            let mut listing = Vec::new();
+            conn.read_to_end(&mut listing).unwrap();
+            listing
+        });
+        stream
+            .write_all(&[command, b"\r\n"].concat())
+            .unwrap();
+        expect_response(
+            stream,
+            "150 File status okay; about to open data connection.\r\n",
+        );
+        expect_response(stream, "226 Closing data connection. Requested file action successful (for example, file transfer or file abort).\r\n");
+        join.join().unwrap()
+    }
+
+    /// Sends `command` and reads its reply until the last line, the one with a space after the code
+    fn multiline_reply(stream: &mut TcpStream, command: &str) -> Vec<String> {
+        stream.write_all(command.as_bytes()).unwrap();
+        let mut lines = Vec::new();
+        let mut line = Vec::new();
+        let mut byte = [0];
+        loop {
+            stream.read_exact(&mut byte).unwrap();
+            line.push(byte[0]);
+            if line.ends_with(b"\r\n") {
+                let done = line.get(3) == Some(&b' ');
+                lines.push(String::from_utf8(line.split_off(0)).unwrap());
+                if done {
+                    return lines;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn trash() {
+        let server = TestServer::start_with(&["user_trash"], |builder| builder.trash(true));
+        let home = server.home("user_trash");
+        let mut stream = TcpStream::connect(server.addr()).unwrap();
+        expect_response(&mut stream, "220 Service ready for new user.\r\n");
+        log_in(&mut stream, "user_trash", "123456");
+        dele(&mut stream, "testfile.txt");
+        mkd(&mut stream, "/dir");
+        rmd(&mut stream, "/dir");
+        assert!(!home.join("testfile.txt").exists());
+        assert!(!home.join("dir").exists());
+        let reply = multiline_reply(&mut stream, "SITE TRASH\r\n");
+        assert_eq!(reply.len(), 4);
+        assert_eq!(reply[0], "200-Trash:\r\n");
+        let file = reply.iter().find(|line| line.ends_with(" /testfile.txt\r\n"));
+        assert!(reply.iter().any(|line| line.ends_with(" /dir/\r\n")));
+        assert_eq!(reply[3], "200 2 entries.\r\n");
+
+        // The trash can't be reached with the other commands
+        stream.write_all(b"CWD /.trash\r\n").unwrap();
+        expect_response(
+            &mut stream,
+            "550 Requested action not taken. File unavailable, file not found.\r\n",
+        );
+        assert!(!list_active(&mut stream, "/").contains(".trash"));
+
+        let id = file.unwrap().split(' ').nth(1).unwrap();
+        let restore = format!("SITE RESTORE {}\r\n", id);
+        stream.write_all(restore.as_bytes()).unwrap();
+        expect_response(&mut stream, "250 Restored /testfile.txt.\r\n");
+        let restored = std::fs::read_to_string(home.join("testfile.txt"));
+        assert_eq!(restored.unwrap(), "Hello world!");
+        stream.write_all(restore.as_bytes()).unwrap();
+        expect_response(
+            &mut stream,
+            "550 Requested action not taken. Not found in the trash.\r\n",
+        );
+        let reply = multiline_reply(&mut stream, "SITE TRASH\r\n");
+        assert_eq!(reply.last().unwrap(), "200 1 entries.\r\n");
+    }
+
+    #[test]
+    fn rmd_only_removes_empty_directories() {
+        let server = TestServer::start(&["user_rmdir"]);
+        let mut stream = TcpStream::connect(server.addr()).unwrap();
+        expect_response(&mut stream, "220 Service ready for new user.\r\n");
+        log_in(&mut stream, "user_rmdir", "123456");
+        mkd(&mut stream, "/thing");
+        mkd(&mut stream, "/thing/thing2");
+        cwd(&mut stream, "/thing/thing2");
+        stream.write_all(b"RMD /thing\r\n").unwrap();
+        expect_response(
+            &mut stream,
+            "550 Requested action not taken. Directory not empty.\r\n",
+        );
+        stream.write_all(b"SITE RMDIR -r /thing\r\n").unwrap();
+        expect_response(
+            &mut stream,
+            "550 Requested action not taken. Permission denied.\r\n",
+        );
+        assert!(server.home("user_rmdir").join("thing/thing2").is_dir());
+
+        // The users with `recursive_delete` can remove the whole directory
+        let users_file = server.path("etc/users.json");
+        let users = std::fs::read_to_string(&users_file).unwrap();
+        let users = users.replace(r#""uid":0"#, r#""uid":0,"recursive_delete":true"#);
+        std::fs::write(&users_file, users).unwrap();
+        server.reload();
+        stream.write_all(b"SITE RMDIR -r /thing\r\n").unwrap();
+        expect_response(&mut stream, "250 Requested file action okay, completed.\r\n");
+        assert!(!server.home("user_rmdir").join("thing").exists());
+        pwd(&mut stream, "/");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn file_names_are_bytes() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+        let server = TestServer::start(&["user_names"]);
+        let home = server.home("user_names");
+        std::fs::write(home.join(OsStr::from_bytes(b"caf\xe9.txt")), "latin1").unwrap();
+        std::fs::write(home.join("bad\nname"), "").unwrap();
+        let mut stream = TcpStream::connect(server.addr()).unwrap();
+        expect_response(&mut stream, "220 Service ready for new user.\r\n");
+        log_in(&mut stream, "user_names", "123456");
+
+        // Spaces and quotes are part of the name
+        stream.write_all(b"MKD  \"quoted\" dir\r\n").unwrap();
+        expect_response(&mut stream, "257 \"/ \"\"quoted\"\" dir\" directory created.\r\n");
+        assert!(home.join(" \"quoted\" dir").is_dir());
+        cwd(&mut stream, " \"quoted\" dir");
+        pwd(&mut stream, "/ \"quoted\" dir");
+        cwd(&mut stream, "/");
+
+        // A CR can't sneak in another command
+        stream.write_all(b"DELE a\rDELE b\r\n").unwrap();
+        expect_response(&mut stream, "503 Paths can't have NUL, CR or LF\r\n");
+
+        let listing = listing_active(&mut stream, b"LIST /");
+        let has_line = |line: &[u8]| listing.split(|byte| *byte == b'\n').any(|l| l == line);
+        assert!(has_line(b"user_names/caf\xe9.txt\r"));
+        assert!(has_line(b"user_names/ \"quoted\" dir\r"));
+        assert!(!listing.windows(3).any(|window| window == b"bad"));
+        stream.write_all(b"DELE caf\xe9.txt\r\n").unwrap();
+        expect_response(&mut stream, "250 Requested file action okay, completed.\r\n");
+        assert!(!home.join(OsStr::from_bytes(b"caf\xe9.txt")).exists());
+    }
+
+    #[test]
+    fn latin1_clients() {
+        let server = TestServer::start_with(&["user_latin1"], |builder| {
+            builder.encoding(FilenameEncoding::Latin1)
+        });
+        let home = server.home("user_latin1");
+        std::fs::write(home.join("\u{65e5}.txt"), "").unwrap();
+        let mut stream = TcpStream::connect(server.addr()).unwrap();
+        expect_response(&mut stream, "220 Service ready for new user.\r\n");
+        log_in(&mut stream, "user_latin1", "123456");
+
+        stream.write_all(b"MKD caf\xe9\r\n").unwrap();
+        expect_bytes(&mut stream, b"257 \"/caf\xe9\" directory created.\r\n");
+        assert!(home.join("caf\u{e9}").is_dir());
+        stream.write_all(b"CWD caf\xe9\r\n").unwrap();
+        expect_response(&mut stream, "250 Requested file action okay, completed.\r\n");
+        stream.write_all(b"PWD\r\n").unwrap();
+        expect_bytes(&mut stream, b"257 \"/caf\xe9\" is the current directory.\r\n");
+
+        // Latin-1 doesn't have the characters of the other names
+        let listing = listing_active(&mut stream, b"LIST /");
+        let lines: Vec<&[u8]> = listing.split_inclusive(|byte| *byte == b'\n').collect();
+        assert!(lines.contains(&&b"user_latin1/?.txt\r\n"[..]));
+        assert!(lines.contains(&&b"user_latin1/caf\xe9\r\n"[..]));
+    }
+
+    #[test]
+    fn list_flags_and_patterns() {
+        let server = TestServer::start(&["user_globs"]);
+        let home = server.home("user_globs");
+        std::fs::create_dir_all(home.join("docs/deep")).unwrap();
+        for file in &["a.csv", ".hidden", "docs/b.csv", "docs/deep/c.csv"] {
+            std::fs::write(home.join(file), "").unwrap();
+        }
+        let mut stream = TcpStream::connect(server.addr()).unwrap();
+        expect_response(&mut stream, "220 Service ready for new user.\r\n");
+        log_in(&mut stream, "user_globs", "123456");
+        let nlst = |stream: &mut TcpStream, args: &str| {
+            String::from_utf8(listing_active(stream, format!("NLST {}", args).as_bytes())).unwrap()
+        };
+
+        assert_eq!(nlst(&mut stream, "*.csv"), "a.csv\r\n");
+        assert_eq!(nlst(&mut stream, "*/*.csv"), "docs/b.csv\r\n");
+        assert!(!nlst(&mut stream, "").contains(".hidden"));
+        assert!(nlst(&mut stream, "-la").contains(".hidden\r\n"));
+        assert_eq!(
+            nlst(&mut stream, "-R docs"),
+            "docs/b.csv\r\ndocs/deep\r\ndocs/deep/c.csv\r\n"
+        );
+        cwd(&mut stream, "docs");
+        assert_eq!(nlst(&mut stream, "-R"), "b.csv\r\ndeep\r\ndeep/c.csv\r\n");
+        assert_eq!(nlst(&mut stream, "/*.csv"), "/a.csv\r\n");
+        assert_eq!(
+            list_active(&mut stream, "-lR"),
+            "user_globs/docs/b.csv\r\nuser_globs/docs/deep\r\nuser_globs/docs/deep/c.csv\r\n"
+        );
+
+        // Patterns stay in the home
+        for args in &["../../*", "/docs/../../*"] {
+            let (_srv, port_command) = data_listener();
+            stream.write_all(port_command.as_bytes()).unwrap();
+            expect_response(&mut stream, "200 Command okay.\r\n");
+            stream
+                .write_all(format!("NLST {}\r\n", args).as_bytes())
+                .unwrap();
+            expect_response(
+                &mut stream,
+                "550 Requested action not taken. File unavailable, no access.\r\n",
+            );
+        }
+    }
+
     #[test]
     fn passive_connection() {
+        let server = TestServer::start(&["user_test_image_transfer_02"]);
         // We could reduce these steps to functions and reuse them but its ok
         // at the moment
-        let result = TcpStream::connect("127.0.0.1:8080");
+        let result = TcpStream::connect(server.addr());
         let mut stream = result.unwrap();
         expect_response(&mut stream, "220 Service ready for new user.\r\n");
         log_in(&mut stream, "user_test_image_transfer_02", "123456");
@@ -1239,11 +2471,12 @@ mod ftp_server_testing {
             TcpStream::connect_timeout(ip.parse().as_ref().unwrap(), Duration::from_micros(1000))
                 .unwrap();
         expect_response(&mut stream, "200 Command okay.\r\n");
+        let download = server.path("2.jpg");
         let join = std::thread::spawn(move || {
             let mut f = std::fs::OpenOptions::new()
                 .write(true)
                 .create(true)
-                .open("./2.jpg")
+                .open(download)
                 .unwrap();
             let mut buff = [0; 1024];
             loop {
@@ -1265,4 +2498,63 @@ mod ftp_server_testing {
         join.join().unwrap();
         std::thread::sleep(Duration::from_millis(20));
     }
+
+    #[test]
+    fn command_split_in_bytes() {
+        let server = TestServer::start(&["user_01"]);
+        let mut stream = TcpStream::connect(server.addr()).unwrap();
+        stream.set_nodelay(true).unwrap();
+        expect_response(&mut stream, "220 Service ready for new user.\r\n");
+        for (command, response) in [
+            ("USER user_01\r\n", "331 User name okay, need password.\r\n"),
+            ("PASS 123456\r\n", "230 User logged in, proceed.\r\n"),
+        ]
+        .iter()
+        {
+            for byte in command.as_bytes() {
+                stream.write_all(&[*byte]).unwrap();
+                std::thread::sleep(Duration::from_millis(2));
+            }
+            expect_response(&mut stream, response);
+        }
+        pwd(&mut stream, "/");
+    }
+
+    #[test]
+    fn pipelined_commands() {
+        let server = TestServer::start(&["user_01"]);
+        let mut stream = TcpStream::connect(server.addr()).unwrap();
+        // A single reader, the responses may arrive together
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut expect = |expected: &str| {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            assert_eq!(expected, line);
+        };
+        expect("220 Service ready for new user.\r\n");
+        stream
+            .write_all(b"USER user_01\r\nPASS 123456\r\nPWD\r\nTYPE I\r\n")
+            .unwrap();
+        expect("331 User name okay, need password.\r\n");
+        expect("230 User logged in, proceed.\r\n");
+        expect("257 \"/\" is the current directory.\r\n");
+        expect("200 Command okay.\r\n");
+        // The command after the transfer waits until it's answered
+        let (srv, port_command) = data_listener();
+        let expected = system::ls(server.home("user_01"), server.root()).unwrap();
+        let join = std::thread::spawn(move || {
+            let (mut conn, _) = srv.accept().expect("expect to receive connection");
+            let mut list = vec![];
+            conn.read_to_end(&mut list).unwrap();
+            assert_eq!(expected, list);
+        });
+        stream
+            .write_all(format!("{}LIST\r\nPWD\r\n", port_command).as_bytes())
+            .unwrap();
+        expect("200 Command okay.\r\n");
+        expect("150 File status okay; about to open data connection.\r\n");
+        expect("226 Closing data connection. Requested file action successful (for example, file transfer or file abort).\r\n");
+        expect("257 \"/\" is the current directory.\r\n");
+        join.join().unwrap();
+    }
 }
@@ -103,6 +103,14 @@ impl Response {
         Response::new_from_enums(CodeFirst::Positive, CodeSecond::FileSystem, 7)
     }
 
+    pub fn service_not_available() -> Response {
+        Response::new_from_enums(
+            CodeFirst::TransientNegativeCompletion,
+            CodeSecond::Connections,
+            1,
+        )
+    }
+
     pub fn cant_open_data_connection() -> Response {
         Response::new_from_enums(
             CodeFirst::TransientNegativeCompletion,
@@ -0,0 +1,1148 @@
+//! Alternative connection layer built on tokio.
+//!
+//! Every control connection is a task that owns its whole state (user, data connection,
+//! pending rename...), so there are no shared request contexts, action lists or wakers.
+//! Data connections are sub-tasks spawned by the session, and the session waits for them
+//! before answering the command that started them.
+//! It reuses the `Command` parser and the `Response` codes of the mio engine, and keeps the files
+//! in the same `StorageBackend`, which is used from the blocking threads of tokio, with the same
+//! trash.
+//!
+//! The metrics, the admin socket, the xferlog and TLS are only served by the mio engine, a config
+//! that asks for them makes `create_server` fail.
+use super::{
+    command::{Command, SiteCommand, TransferMode},
+    command_buffer::MAX_COMMAND_LENGTH,
+    config::{Config, ConfigError},
+    create_path_response, create_response, list_lines, name_lines,
+    response::Response,
+    restore_response, trash_response,
+};
+use crate::port::{bind_passive, get_ftp_port_pair};
+use crate::storage::trash::{self, Removal, TrashPurger};
+use crate::storage::{self, StorageBackend};
+use crate::system::{
+    self,
+    list::{self, ListOptions},
+};
+use std::error::Error;
+use std::future::pending;
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use user_manage::{SystemUsers, User};
+
+/// Creates the tokio runtime with `config.limits.workers` threads and serves every address of
+/// `config.server.bind` until an error happens. Fails if the config has something that only the
+/// mio engine serves
+pub fn create_server(config: Config) -> Result<(), Box<dyn Error>> {
+    config.validate()?;
+    if let Some(message) = unsupported(&config) {
+        return Err(Box::new(ConfigError::Invalid(message)));
+    }
+    let users = SystemUsers::load(&config.users)?;
+    let storage = storage::from_config(&config.storage)?;
+    match storage.clean_up(users.root()) {
+        Ok(0) => {}
+        Ok(removed) => log_info!("[STORAGE] Removed {} unfinished uploads", removed),
+        Err(err) => log_warn!("[STORAGE] Couldn't remove the unfinished uploads: {}", err),
+    }
+    let users = Arc::new(Mutex::new(users));
+    // It's stopped when the server stops
+    let _trash_purger = match config.trash.retention() {
+        Some(retention) => Some(TrashPurger::start(
+            storage.clone(),
+            users.clone(),
+            retention,
+        )?),
+        None => None,
+    };
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(config.limits.workers)
+        .enable_all()
+        .build()?;
+    runtime.block_on(async {
+        let mut listeners = Vec::with_capacity(config.server.bind.len());
+        for addr in &config.server.bind {
+            listeners.push(TcpListener::bind(addr.as_str()).await?);
+        }
+        serve(listeners, Arc::new(config), users, storage).await?;
+        Ok(())
+    })
+}
+
+/// Why the tokio engine can't serve `config`, None if it can
+fn unsupported(config: &Config) -> Option<&'static str> {
+    if config.metrics.bind.is_some() {
+        Some("the tokio engine doesn't serve [metrics], remove metrics.bind")
+    } else if config.admin.socket.is_some() {
+        Some("the tokio engine doesn't serve [admin], remove admin.socket")
+    } else if config.log.xferlog.is_some() {
+        Some("the tokio engine doesn't write the xferlog, remove log.xferlog")
+    } else if config.tls.is_some() {
+        Some("TLS is not supported by the server yet, remove [tls]")
+    } else {
+        None
+    }
+}
+
+/// Accepts connections on every listener until one of them fails, spawning a session task for
+/// each connection. `config.limits.max_connections` counts the sessions of all of them, and the
+/// files of the users are kept in `storage`
+pub async fn serve(
+    listeners: Vec<TcpListener>,
+    config: Arc<Config>,
+    users: Arc<Mutex<SystemUsers>>,
+    storage: Arc<dyn StorageBackend>,
+) -> io::Result<()> {
+    let current_connections = Arc::new(AtomicUsize::new(0));
+    let (errors, mut failed) = mpsc::unbounded_channel();
+    for listener in listeners {
+        let accepted = accept(
+            listener,
+            config.clone(),
+            users.clone(),
+            storage.clone(),
+            current_connections.clone(),
+        );
+        let errors = errors.clone();
+        tokio::spawn(async move {
+            let _ = errors.send(accepted.await);
+        });
+    }
+    drop(errors);
+    failed.recv().await.unwrap_or(Ok(()))
+}
+
+/// Accepts the connections of `listener` forever, it only returns if accepting fails
+async fn accept(
+    listener: TcpListener,
+    config: Arc<Config>,
+    users: Arc<Mutex<SystemUsers>>,
+    storage: Arc<dyn StorageBackend>,
+    current_connections: Arc<AtomicUsize>,
+) -> io::Result<()> {
+    let max_connections = config.limits.max_connections;
+    loop {
+        let (mut stream, addr) = listener.accept().await?;
+        let connections = current_connections.fetch_add(1, Ordering::SeqCst) + 1;
+        log_debug!(
+            "[SESSION] {} - There is a brand new connection - Current connections: {}",
+            addr,
+            connections
+        );
+        if connections > max_connections {
+            current_connections.fetch_sub(1, Ordering::SeqCst);
+            log_warn!(
+                "[SESSION] {} - Closing connection because it surpasses the maximum connections",
+                addr
+            );
+            tokio::spawn(async move {
+                let _ = stream.write_all(b"Bye...").await;
+                let _ = stream.shutdown().await;
+            });
+            continue;
+        }
+        let config = config.clone();
+        let users = users.clone();
+        let storage = storage.clone();
+        let current_connections = current_connections.clone();
+        tokio::spawn(async move {
+            if let Err(err) = Session::run(stream, config, users, storage).await {
+                log_warn!(
+                    "[SESSION] {} - Closing connection because error, {}",
+                    addr,
+                    err
+                );
+            }
+            current_connections.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+}
+
+/// What the session should do after answering a command
+enum Flow {
+    Continue,
+    Quit,
+}
+
+/// State of a single control connection
+struct Session {
+    writer: OwnedWriteHalf,
+
+    users_db: Arc<Mutex<SystemUsers>>,
+
+    user_id: Option<String>,
+
+    loged: bool,
+
+    /// Data connection opened by `PORT` or accepted after `PASV`
+    data_connection: Option<TcpStream>,
+
+    /// Task accepting the data connection after a `PASV`
+    passive_accept: Option<JoinHandle<io::Result<TcpStream>>>,
+
+    /// Virtual path set by `RNFR`
+    path_from: Option<PathBuf>,
+
+    /// Type set by `TYPE`
+    transfer_mode: TransferMode,
+
+    config: Arc<Config>,
+
+    storage: Arc<dyn StorageBackend>,
+}
+
+impl Session {
+    async fn run(
+        stream: TcpStream,
+        config: Arc<Config>,
+        users_db: Arc<Mutex<SystemUsers>>,
+        storage: Arc<dyn StorageBackend>,
+    ) -> io::Result<()> {
+        let (reader, writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+        let mut session = Session {
+            writer,
+            users_db,
+            user_id: None,
+            loged: false,
+            data_connection: None,
+            passive_accept: None,
+            path_from: None,
+            transfer_mode: TransferMode::default(),
+            config,
+            storage,
+        };
+        let banner = create_response(Response::service_ready(), &session.config.server.banner);
+        session.writer.write_all(&banner).await?;
+        let result = session.command_loop(&mut reader).await;
+        session.set_user_dir(Path::new("/"));
+        let _ = session.writer.shutdown().await;
+        result
+    }
+
+    async fn command_loop(&mut self, reader: &mut BufReader<OwnedReadHalf>) -> io::Result<()> {
+        let mut line = Vec::with_capacity(128);
+        loop {
+            let passive_accept = &mut self.passive_accept;
+            let accepted = async {
+                match passive_accept {
+                    Some(task) => task.await,
+                    None => pending().await,
+                }
+            };
+            // `read_until` keeps the bytes it read if the accept finishes first, so `line` is only
+            // cleared once a full command was handled
+            let mut limited = (&mut *reader).take((MAX_COMMAND_LENGTH - line.len()) as u64);
+            tokio::select! {
+                read = limited.read_until(b'\n', &mut line) => {
+                    if read? == 0 {
+                        return Ok(());
+                    }
+                }
+                accepted = accepted => {
+                    self.passive_accept = None;
+                    match accepted {
+                        Ok(Ok(stream)) => {
+                            self.data_connection = Some(stream);
+                            self.reply(Response::command_okay(), "Command okay.").await?;
+                        }
+                        _ => {
+                            self.reply(Response::cant_open_data_connection(), "Can't open data connection.").await?;
+                        }
+                    }
+                    continue;
+                }
+            }
+            if !line.ends_with(b"\n") {
+                self.writer
+                    .write_all(b"503 Bad sequence of commands.\r\n")
+                    .await?;
+                // Discard the rest of the line as it arrives, a line without LF can't take memory
+                line.clear();
+                loop {
+                    let buf = reader.fill_buf().await?;
+                    if buf.is_empty() {
+                        return Ok(());
+                    }
+                    match buf.iter().position(|byte| *byte == b'\n') {
+                        Some(end) => {
+                            reader.consume(end + 1);
+                            break;
+                        }
+                        None => {
+                            let read = buf.len();
+                            reader.consume(read);
+                        }
+                    }
+                }
+                continue;
+            }
+            let flow = self.handle_command(&line).await?;
+            line.clear();
+            if let Flow::Quit = flow {
+                return Ok(());
+            }
+        }
+    }
+
+    async fn reply(&mut self, response: Response, message: &str) -> io::Result<()> {
+        self.writer
+            .write_all(&create_response(response, message))
+            .await
+    }
+
+    async fn reply_str(&mut self, message: &str) -> io::Result<()> {
+        self.writer.write_all(message.as_bytes()).await
+    }
+
+    async fn file_unavailable(&mut self, message: &str) -> io::Result<()> {
+        self.reply(Response::file_unavailable(), message).await
+    }
+
+    async fn file_action_okay(&mut self) -> io::Result<()> {
+        self.reply(
+            Response::file_action_okay(),
+            "Requested file action okay, completed.",
+        )
+        .await
+    }
+
+    async fn handle_command(&mut self, line: &[u8]) -> io::Result<Flow> {
+        let command = match Command::parse(line, self.config.server.allow_bare_lf) {
+            Ok(command) => command,
+            Err(message) => {
+                self.reply(Response::bad_sequence_of_commands(), &message.to_string())
+                    .await?;
+                return Ok(Flow::Continue);
+            }
+        };
+        if command.is_auth_command() && (self.user_id.is_none() || !self.loged) {
+            self.reply_str("531 Unauthorized.\r\n").await?;
+            return Ok(Flow::Continue);
+        }
+        match command {
+            Command::User(username) => {
+                log_debug!("[SESSION] New user {}", username);
+                self.user_id = Some(username.to_string());
+                self.loged = false;
+                self.reply(Response::username_okay(), "User name okay, need password.")
+                    .await?;
+            }
+
+            Command::Password(pwd) => {
+                let loged = self.log_in(pwd);
+                if loged {
+                    self.loged = true;
+                    self.reply(Response::login_success(), "User logged in, proceed.")
+                        .await?;
+                } else {
+                    self.reply_str("530 Not logged in.\r\n").await?;
+                }
+            }
+
+            Command::Quit => {
+                self.reply_str("221 Service closing control connection.\r\n")
+                    .await?;
+                return Ok(Flow::Quit);
+            }
+
+            Command::CurrentDirectory => match self.user_dirs() {
+                Some((_, cwd)) => {
+                    let response = create_path_response(
+                        Response::directory_action_okay(),
+                        &cwd,
+                        "is the current directory.",
+                        self.config.server.encoding,
+                    );
+                    self.writer.write_all(&response).await?
+                }
+                None => {
+                    self.file_unavailable(
+                        "Requested action not taken. File unavailable, no access.",
+                    )
+                    .await?
+                }
+            },
+
+            Command::ChangeDirectory(_) | Command::ChangeToParentDirectory => {
+                // CDUP is the same as `CWD ..`
+                let dir = match command {
+                    Command::ChangeDirectory(dir) => dir,
+                    _ => Path::new(".."),
+                };
+                match self
+                    .on_path(dir, |storage, home, path| storage.stat(home, path))
+                    .await
+                {
+                    Some((path, metadata)) if metadata.is_dir => {
+                        self.set_user_dir(&path);
+                        self.file_action_okay().await?;
+                    }
+                    _ => {
+                        self.file_unavailable(
+                            "Requested action not taken. File unavailable, file not found.",
+                        )
+                        .await?;
+                    }
+                }
+            }
+
+            Command::Mkdir(path) => {
+                match self
+                    .on_path(path, |storage, home, path| storage.mkdir(home, path))
+                    .await
+                {
+                    Some((path, ())) => {
+                        let response = create_path_response(
+                            Response::directory_action_okay(),
+                            &path,
+                            "directory created.",
+                            self.config.server.encoding,
+                        );
+                        self.writer.write_all(&response).await?;
+                    }
+                    None => {
+                        self.file_unavailable(
+                            "Requested action not taken. File unavailable, no access.",
+                        )
+                        .await?
+                    }
+                }
+            }
+
+            Command::RemoveDirectory(directory) => {
+                match self.remove(directory, Removal::EmptyDir).await {
+                    Some((path, Ok(()))) => {
+                        self.leave_removed_dir(&path);
+                        self.file_action_okay().await?;
+                    }
+                    Some((_, Err(err))) if err.kind() == io::ErrorKind::DirectoryNotEmpty => {
+                        self.file_unavailable("Requested action not taken. Directory not empty.")
+                            .await?;
+                    }
+                    _ => {
+                        self.file_unavailable(
+                            "Requested action not taken. File unavailable, file not found.",
+                        )
+                        .await?;
+                    }
+                }
+            }
+
+            Command::Delete(path) => {
+                if let Some((_, Ok(()))) = self.remove(path, Removal::File).await {
+                    self.file_action_okay().await?;
+                } else {
+                    self.file_unavailable(
+                        "Requested action not taken. File unavailable, file not found.",
+                    )
+                    .await?;
+                }
+            }
+
+            Command::RenameFrom(from) => {
+                let metadata = self
+                    .on_path(from, |storage, home, path| storage.stat(home, path))
+                    .await;
+                if let Some((path, _)) = metadata {
+                    self.path_from = Some(path);
+                    self.reply(
+                        Response::file_action_pending(),
+                        "Requested file action pending further information.",
+                    )
+                    .await?;
+                } else {
+                    self.file_unavailable("File unavailable, file not found.")
+                        .await?;
+                }
+            }
+
+            Command::RenameTo(to) => {
+                let renamed = match self.path_from.take() {
+                    Some(from) => self
+                        .on_path(to, move |storage, home, to| storage.rename(home, &from, to))
+                        .await
+                        .is_some(),
+                    None => false,
+                };
+                if renamed {
+                    self.file_action_okay().await?;
+                } else {
+                    self.reply_str("553 Requested action not taken. File name not allowed.\r\n")
+                        .await?;
+                }
+            }
+
+            Command::Port(ip, port) => match TcpStream::connect((ip, port)).await {
+                Ok(stream) => {
+                    self.data_connection = Some(stream);
+                    self.reply(Response::command_okay(), "Command okay.")
+                        .await?;
+                }
+                Err(_) => {
+                    self.reply(
+                        Response::bad_sequence_of_commands(),
+                        "Bad sequence of commands.",
+                    )
+                    .await?
+                }
+            },
+
+            Command::Passive => match bind_passive(self.config.passive.port_range()) {
+                Some((listener, port)) => {
+                    let listener = TcpListener::from_std(listener)?;
+                    // The listener only accepts one connection, it's dropped when the task ends
+                    self.passive_accept = Some(tokio::spawn(async move {
+                        listener.accept().await.map(|(stream, _)| stream)
+                    }));
+                    let (first_part, second_part) = get_ftp_port_pair(port);
+                    self.reply_str(&format!(
+                        "227 Entering Passive Mode (0,0,0,0,{},{})\r\n",
+                        first_part, second_part
+                    ))
+                    .await?;
+                }
+                None => self.reply_str("541 All ports are taken.\r\n").await?,
+            },
+
+            Command::Site(command) => {
+                let response = self.site(command).await;
+                self.writer.write_all(&response).await?;
+            }
+
+            Command::Type(mode) => {
+                self.transfer_mode = mode;
+                self.reply(Response::command_okay(), "Command okay.")
+                    .await?;
+            }
+
+            Command::List(args) | Command::NameList(args) => {
+                let data_connection = match self.data_connection.take() {
+                    Some(data_connection) => data_connection,
+                    None => {
+                        self.reply(
+                            Response::bad_sequence_of_commands(),
+                            "Bad sequence of commands.",
+                        )
+                        .await?;
+                        return Ok(Flow::Continue);
+                    }
+                };
+                let root = self.users_db.lock().unwrap().root().to_path_buf();
+                let options = ListOptions {
+                    all: args.all,
+                    recursive: args.recursive,
+                    max_depth: self.config.limits.list_depth,
+                };
+                let path = args.path.unwrap_or_else(|| Path::new("."));
+                let listed = self
+                    .on_path(path, move |storage, home, path| {
+                        list::list(path, &options, |dir| {
+                            let mut entries = storage.list(home, dir)?;
+                            entries.retain(|entry| !trash::is_trash(&dir.join(&entry.name)));
+                            Ok(entries)
+                        })
+                    })
+                    .await;
+                let list = match (self.user_dirs(), listed) {
+                    (Some((_, cwd)), Some((_, entries)))
+                        if matches!(command, Command::NameList(_)) =>
+                    {
+                        name_lines(&cwd, &entries, self.config.server.encoding)
+                    }
+                    (Some((home, _)), Some((_, entries))) => {
+                        list_lines(&home, &root, &entries, self.config.server.encoding)
+                    }
+                    _ => {
+                        self.file_unavailable(
+                            "Requested action not taken. File unavailable, no access.",
+                        )
+                        .await?;
+                        return Ok(Flow::Continue);
+                    }
+                };
+                self.reply(
+                    Response::file_status_okay(),
+                    "File status okay; about to open data connection.",
+                )
+                .await?;
+                let transfer = tokio::spawn(async move {
+                    let mut data_connection = data_connection;
+                    data_connection.write_all(&list).await?;
+                    data_connection.shutdown().await
+                });
+                self.finish_transfer(
+                    transfer,
+                    "Closing data connection. Requested file action successful (for example, file transfer or file abort).",
+                )
+                .await?;
+            }
+
+            Command::Retr(path) => {
+                if self.data_connection.is_none() {
+                    self.reply(
+                        Response::bad_sequence_of_commands(),
+                        "Bad sequence of commands.",
+                    )
+                    .await?;
+                    return Ok(Flow::Continue);
+                }
+                let file = self
+                    .on_path(path, |storage, home, path| storage.open_read(home, path))
+                    .await;
+                let mut file = match file {
+                    Some((_, file)) => file,
+                    None => {
+                        self.file_unavailable(
+                            "Requested action not taken. File unavailable, file not found.",
+                        )
+                        .await?;
+                        return Ok(Flow::Continue);
+                    }
+                };
+                let data_connection = self.data_connection.take().unwrap();
+                self.reply(Response::file_status_okay(), "File download starts!")
+                    .await?;
+                let mode = self.transfer_mode;
+                let transfer = tokio::task::spawn_blocking(move || {
+                    let mut data_connection = blocking(data_connection)?;
+                    match mode {
+                        TransferMode::Binary => {
+                            io::copy(&mut file, &mut data_connection)?;
+                        }
+                        TransferMode::Ascii => {
+                            let mut buf = vec![0; 8192];
+                            let mut converted = Vec::with_capacity(buf.len() * 2);
+                            let mut last_was_cr = false;
+                            loop {
+                                let read = file.read(&mut buf)?;
+                                if read == 0 {
+                                    break;
+                                }
+                                converted.clear();
+                                system::to_crlf(&buf[..read], &mut converted, &mut last_was_cr);
+                                data_connection.write_all(&converted)?;
+                            }
+                        }
+                    }
+                    data_connection.shutdown(Shutdown::Write)
+                });
+                self.finish_transfer(
+                    transfer,
+                    "Closing data connection. Requested file action successful. (file transfer)",
+                )
+                .await?;
+            }
+
+            Command::Store(path) => {
+                let file = match &self.data_connection {
+                    Some(_) => {
+                        self.on_path(path, |storage, home, path| storage.open_write(home, path))
+                            .await
+                    }
+                    None => None,
+                };
+                let mut file = match file {
+                    Some((_, file)) => file,
+                    None => {
+                        self.file_unavailable(
+                            "Requested action not taken. File unavailable, no access.",
+                        )
+                        .await?;
+                        return Ok(Flow::Continue);
+                    }
+                };
+                let data_connection = self.data_connection.take().unwrap();
+                self.reply(
+                    Response::file_status_okay(),
+                    "File status okay; about to open data connection.",
+                )
+                .await?;
+                // The file only replaces the old one if every byte arrived, like in the mio engine
+                let transfer = tokio::task::spawn_blocking(move || {
+                    let mut data_connection = blocking(data_connection)?;
+                    match io::copy(&mut data_connection, &mut file) {
+                        Ok(_) => file.commit(),
+                        Err(err) => {
+                            let _ = file.abort();
+                            Err(err)
+                        }
+                    }
+                });
+                self.finish_transfer(
+                    transfer,
+                    "Closing data connection. Requested file action successful (for example, file transfer or file abort).",
+                )
+                .await?;
+            }
+        }
+        Ok(Flow::Continue)
+    }
+
+    /// Removes `path` like `trash::remove`, to the trash if it's enabled for the user. Returns the
+    /// virtual path and the result of the removal, None if `path` is outside of the home
+    async fn remove(&self, path: &Path, removal: Removal) -> Option<(PathBuf, io::Result<()>)> {
+        let to_trash = self.trash_enabled();
+        self.on_path(path, move |storage, home, path| {
+            Ok(trash::remove(storage, home, path, removal, to_trash))
+        })
+        .await
+    }
+
+    /// Moves the user to the parent of `dir` if its current directory was inside of it, after
+    /// `dir` is removed
+    fn leave_removed_dir(&self, dir: &Path) {
+        let (_, cwd) = self.user_dirs().unwrap_or_default();
+        if cwd.starts_with(dir) {
+            self.set_user_dir(dir.parent().unwrap_or_else(|| Path::new("/")));
+        }
+    }
+
+    /// Reply of the `SITE` commands, like the mio engine
+    async fn site(&self, command: SiteCommand<'_>) -> Vec<u8> {
+        let not_found = || {
+            create_response(
+                Response::file_unavailable(),
+                "Requested action not taken. File unavailable, file not found.",
+            )
+        };
+        match command {
+            SiteCommand::RemoveTree(_)
+                if !self.with_user(User::recursive_delete).unwrap_or(false) =>
+            {
+                create_response(
+                    Response::file_unavailable(),
+                    "Requested action not taken. Permission denied.",
+                )
+            }
+            SiteCommand::RemoveTree(dir) => match self.remove(dir, Removal::Tree).await {
+                Some((path, Ok(()))) => {
+                    self.leave_removed_dir(&path);
+                    create_response(
+                        Response::file_action_okay(),
+                        "Requested file action okay, completed.",
+                    )
+                }
+                _ => not_found(),
+            },
+            SiteCommand::Trash | SiteCommand::Restore(_) => {
+                let home = match self.user_dirs() {
+                    Some((home, _)) => home,
+                    None => {
+                        return create_response(Response::file_unavailable(), "Home not found.")
+                    }
+                };
+                let id = match command {
+                    SiteCommand::Restore(id) => Some(id.to_string()),
+                    _ => None,
+                };
+                let storage = self.storage.clone();
+                let encoding = self.config.server.encoding;
+                tokio::task::spawn_blocking(move || match id {
+                    Some(id) => restore_response(storage.as_ref(), &home, &id, encoding),
+                    None => trash_response(storage.as_ref(), &home, encoding),
+                })
+                .await
+                .unwrap_or_else(|_| not_found())
+            }
+        }
+    }
+
+    /// Waits for the data connection task and answers the command that started it
+    async fn finish_transfer(
+        &mut self,
+        transfer: JoinHandle<io::Result<()>>,
+        message: &str,
+    ) -> io::Result<()> {
+        match transfer.await {
+            Ok(Ok(())) => {
+                self.reply(Response::closing_data_connection(), message)
+                    .await
+            }
+            _ => {
+                self.reply_str("451 Requested action aborted: local error in processing.\r\n")
+                    .await
+            }
+        }
+    }
+
+    /// Checks the password, creating the user if it doesn't exist like the mio engine does
+    fn log_in(&mut self, pwd: &str) -> bool {
+        let user_id = match &self.user_id {
+            Some(user_id) => user_id,
+            None => return false,
+        };
+        let mut db = self.users_db.lock().unwrap();
+        if !db.user_exists(user_id) {
+            return db.create_user(user_id, pwd).is_ok();
+        }
+        !db.is_disabled(user_id) && db.has_passwd(user_id, pwd)
+    }
+
+    fn with_user<T, F: FnOnce(&User) -> T>(&self, f: F) -> Option<T> {
+        let user_id = self.user_id.as_ref()?;
+        let db = self.users_db.lock().unwrap();
+        db.get_user(user_id).map(f)
+    }
+
+    /// If `DELE` and `RMD` move to the trash for the user, its `trash` overrides the config
+    fn trash_enabled(&self) -> bool {
+        self.with_user(User::trash)
+            .flatten()
+            .unwrap_or(self.config.trash.enabled)
+    }
+
+    /// Home of the user and its current directory, as a virtual path (see `storage::resolve`)
+    fn user_dirs(&self) -> Option<(PathBuf, PathBuf)> {
+        self.with_user(|user| {
+            let cwd = storage::resolve(Path::new("/"), user.get_actual_dir())?;
+            Some((PathBuf::from(user.get_chroot()), cwd))
+        })?
+    }
+
+    /// Runs `f` with the storage, the home of the user and the virtual path of `path`, on the
+    /// blocking threads like `tokio::fs` does. Returns the virtual path and what `f` returned, None
+    /// if `path` is outside of the home or in its trash, or `f` failed
+    async fn on_path<T, F>(&self, path: &Path, f: F) -> Option<(PathBuf, T)>
+    where
+        T: Send + 'static,
+        F: FnOnce(&dyn StorageBackend, &Path, &Path) -> io::Result<T> + Send + 'static,
+    {
+        let (home, cwd) = self.user_dirs()?;
+        let path = storage::resolve(&cwd, &self.config.server.encoding.decode(path))?;
+        if trash::is_trash(&path) {
+            return None;
+        }
+        let storage = self.storage.clone();
+        tokio::task::spawn_blocking(move || {
+            let result = f(storage.as_ref(), &home, &path)?;
+            Ok::<_, io::Error>((path, result))
+        })
+        .await
+        .ok()?
+        .ok()
+    }
+
+    /// Sets the current directory of the user to the virtual path `dir`
+    fn set_user_dir(&self, dir: &Path) {
+        if let Some(user_id) = &self.user_id {
+            let mut db = self.users_db.lock().unwrap();
+            if let Some(user) = db.get_user_mut(user_id) {
+                user.set_actual_dir(Path::new(".").join(storage::relative(dir)));
+            }
+        }
+    }
+}
+
+/// `stream` as a blocking socket of the standard library, so a transfer can use the files of the
+/// `StorageBackend` from a blocking thread
+fn blocking(stream: TcpStream) -> io::Result<std::net::TcpStream> {
+    let stream = stream.into_std()?;
+    stream.set_nonblocking(false)?;
+    Ok(stream)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{create_server, serve};
+    use crate::ftp::command_buffer::MAX_COMMAND_LENGTH;
+    use crate::ftp::config::Config;
+    use crate::ftp::testing::{write_users, write_users_in};
+    use crate::storage::{LocalStorage, MemoryStorage};
+    use std::path::Path;
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+    use tokio::net::{TcpListener, TcpStream};
+
+    async fn expect_response<R: AsyncBufReadExt + Unpin>(reader: &mut R, expected: &str) {
+        let mut line = String::new();
+        reader.read_line(&mut line).await.expect("to read a line");
+        assert_eq!(expected, line);
+    }
+
+    #[tokio::test]
+    async fn session_works() {
+        let dir = tempfile::tempdir().unwrap();
+        let users = write_users(dir.path(), &["user_async_session_test"]);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let other = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let other_addr = other.local_addr().unwrap();
+        // A free port for the passive connections
+        let passive_port = std::net::TcpListener::bind("0.0.0.0:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let mut config = Config::default();
+        config.server.banner = "Welcome to tokio".to_string();
+        config.passive.min_port = Some(passive_port);
+        config.passive.max_port = Some(passive_port);
+        tokio::spawn(serve(
+            vec![listener, other],
+            Arc::new(config),
+            Arc::new(Mutex::new(users)),
+            Arc::new(LocalStorage),
+        ));
+
+        // Every address is served
+        let mut other_reader = BufReader::new(TcpStream::connect(other_addr).await.unwrap());
+        expect_response(&mut other_reader, "220 Welcome to tokio\r\n").await;
+        let (reader, mut writer) = TcpStream::connect(addr).await.unwrap().into_split();
+        let mut reader = BufReader::new(reader);
+        expect_response(&mut reader, "220 Welcome to tokio\r\n").await;
+        // Both commands in the same write, the session must answer both
+        writer
+            .write_all(b"USER user_async_session_test\r\nPASS 123456\r\n")
+            .await
+            .unwrap();
+        expect_response(&mut reader, "331 User name okay, need password.\r\n").await;
+        expect_response(&mut reader, "230 User logged in, proceed.\r\n").await;
+        writer.write_all(b"PWD\r\n").await.unwrap();
+        expect_response(&mut reader, "257 \"/\" is the current directory.\r\n").await;
+        writer.write_all(b"MKD /test\r\n").await.unwrap();
+        expect_response(&mut reader, "257 \"/test\" directory created.\r\n").await;
+
+        // The passive listener is on the port of the range
+        writer.write_all(b"PASV\r\n").await.unwrap();
+        expect_response(
+            &mut reader,
+            &format!(
+                "227 Entering Passive Mode (0,0,0,0,{},{})\r\n",
+                passive_port / 256,
+                passive_port % 256
+            ),
+        )
+        .await;
+        let _passive = TcpStream::connect(("127.0.0.1", passive_port))
+            .await
+            .unwrap();
+        expect_response(&mut reader, "200 Command okay.\r\n").await;
+
+        let data = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = data.local_addr().unwrap().port();
+        writer
+            .write_all(format!("PORT 127,0,0,1,{},{}\r\n", port / 256, port % 256).as_bytes())
+            .await
+            .unwrap();
+        let (mut data_connection, _) = data.accept().await.unwrap();
+        expect_response(&mut reader, "200 Command okay.\r\n").await;
+        writer.write_all(b"LIST\r\n").await.unwrap();
+        expect_response(
+            &mut reader,
+            "150 File status okay; about to open data connection.\r\n",
+        )
+        .await;
+        let mut list = Vec::new();
+        data_connection.read_to_end(&mut list).await.unwrap();
+        assert!(std::str::from_utf8(&list).unwrap().contains("test"));
+        expect_response(&mut reader, "226 Closing data connection. Requested file action successful (for example, file transfer or file abort).\r\n").await;
+
+        writer.write_all(b"RMD /test\r\n").await.unwrap();
+        expect_response(
+            &mut reader,
+            "250 Requested file action okay, completed.\r\n",
+        )
+        .await;
+        writer.write_all(b"QUIT\r\n").await.unwrap();
+        expect_response(&mut reader, "221 Service closing control connection.\r\n").await;
+    }
+
+    /// Sends `PORT` and returns the data connection that the session opened
+    async fn port(reader: &mut BufReader<OwnedReadHalf>, writer: &mut OwnedWriteHalf) -> TcpStream {
+        let data = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = data.local_addr().unwrap().port();
+        writer
+            .write_all(format!("PORT 127,0,0,1,{},{}\r\n", port / 256, port % 256).as_bytes())
+            .await
+            .unwrap();
+        let (data_connection, _) = data.accept().await.unwrap();
+        expect_response(reader, "200 Command okay.\r\n").await;
+        data_connection
+    }
+
+    #[tokio::test]
+    async fn session_uses_the_storage() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = MemoryStorage::new();
+        let users = write_users_in(dir.path(), &["user_async_storage"], &storage);
+        let home = dir.path().join("root").join("user_async_storage");
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(
+            vec![listener],
+            Arc::new(Config::default()),
+            Arc::new(Mutex::new(users)),
+            Arc::new(storage.clone()),
+        ));
+
+        let (reader, mut writer) = TcpStream::connect(addr).await.unwrap().into_split();
+        let mut reader = BufReader::new(reader);
+        expect_response(&mut reader, "220 Service ready for new user.\r\n").await;
+        writer
+            .write_all(b"USER user_async_storage\r\nPASS 123456\r\n")
+            .await
+            .unwrap();
+        expect_response(&mut reader, "331 User name okay, need password.\r\n").await;
+        expect_response(&mut reader, "230 User logged in, proceed.\r\n").await;
+
+        let mut data_connection = port(&mut reader, &mut writer).await;
+        writer.write_all(b"STOR new.txt\r\n").await.unwrap();
+        expect_response(
+            &mut reader,
+            "150 File status okay; about to open data connection.\r\n",
+        )
+        .await;
+        data_connection.write_all(b"Hello tokio").await.unwrap();
+        drop(data_connection);
+        expect_response(&mut reader, "226 Closing data connection. Requested file action successful (for example, file transfer or file abort).\r\n").await;
+        assert_eq!(
+            storage.read(&home, Path::new("/new.txt")),
+            Some(b"Hello tokio".to_vec())
+        );
+
+        let mut data_connection = port(&mut reader, &mut writer).await;
+        writer.write_all(b"RETR testfile.txt\r\n").await.unwrap();
+        expect_response(&mut reader, "150 File download starts!\r\n").await;
+        let mut file = Vec::new();
+        data_connection.read_to_end(&mut file).await.unwrap();
+        assert_eq!(file, b"Hello world!");
+        expect_response(
+            &mut reader,
+            "226 Closing data connection. Requested file action successful. (file transfer)\r\n",
+        )
+        .await;
+
+        writer.write_all(b"DELE new.txt\r\n").await.unwrap();
+        expect_response(
+            &mut reader,
+            "250 Requested file action okay, completed.\r\n",
+        )
+        .await;
+        assert_eq!(storage.read(&home, Path::new("/new.txt")), None);
+        // Nothing was written to the disk
+        assert!(!home.join("new.txt").exists());
+    }
+
+    #[tokio::test]
+    async fn session_uses_the_trash() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = MemoryStorage::new();
+        let users = write_users_in(dir.path(), &["user_async_trash"], &storage);
+        let home = dir.path().join("root").join("user_async_trash");
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut config = Config::default();
+        config.trash.enabled = true;
+        tokio::spawn(serve(
+            vec![listener],
+            Arc::new(config),
+            Arc::new(Mutex::new(users)),
+            Arc::new(storage.clone()),
+        ));
+
+        let (reader, mut writer) = TcpStream::connect(addr).await.unwrap().into_split();
+        let mut reader = BufReader::new(reader);
+        expect_response(&mut reader, "220 Service ready for new user.\r\n").await;
+        writer
+            .write_all(b"USER user_async_trash\r\nPASS 123456\r\n")
+            .await
+            .unwrap();
+        expect_response(&mut reader, "331 User name okay, need password.\r\n").await;
+        expect_response(&mut reader, "230 User logged in, proceed.\r\n").await;
+        writer.write_all(b"DELE testfile.txt\r\n").await.unwrap();
+        expect_response(
+            &mut reader,
+            "250 Requested file action okay, completed.\r\n",
+        )
+        .await;
+        assert_eq!(storage.read(&home, Path::new("/testfile.txt")), None);
+
+        writer.write_all(b"SITE TRASH\r\n").await.unwrap();
+        expect_response(&mut reader, "200-Trash:\r\n").await;
+        let mut entry = String::new();
+        reader.read_line(&mut entry).await.unwrap();
+        assert!(entry.ends_with(" /testfile.txt\r\n"), "{}", entry);
+        expect_response(&mut reader, "200 1 entries.\r\n").await;
+
+        // The trash can't be reached with the other commands
+        writer.write_all(b"CWD /.trash\r\n").await.unwrap();
+        expect_response(
+            &mut reader,
+            "550 Requested action not taken. File unavailable, file not found.\r\n",
+        )
+        .await;
+        let mut data_connection = port(&mut reader, &mut writer).await;
+        writer.write_all(b"NLST -a\r\n").await.unwrap();
+        expect_response(
+            &mut reader,
+            "150 File status okay; about to open data connection.\r\n",
+        )
+        .await;
+        let mut list = Vec::new();
+        data_connection.read_to_end(&mut list).await.unwrap();
+        assert!(!String::from_utf8(list).unwrap().contains(".trash"));
+        expect_response(&mut reader, "226 Closing data connection. Requested file action successful (for example, file transfer or file abort).\r\n").await;
+
+        let id = entry.split(' ').nth(1).unwrap();
+        writer
+            .write_all(format!("SITE RESTORE {}\r\n", id).as_bytes())
+            .await
+            .unwrap();
+        expect_response(&mut reader, "250 Restored /testfile.txt.\r\n").await;
+        assert_eq!(
+            storage.read(&home, Path::new("/testfile.txt")),
+            Some(b"Hello world!".to_vec())
+        );
+    }
+
+    #[tokio::test]
+    async fn long_lines_are_discarded() {
+        let dir = tempfile::tempdir().unwrap();
+        let users = write_users(dir.path(), &["user_async_long_line"]);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(
+            vec![listener],
+            Arc::new(Config::default()),
+            Arc::new(Mutex::new(users)),
+            Arc::new(LocalStorage),
+        ));
+
+        let (reader, mut writer) = TcpStream::connect(addr).await.unwrap().into_split();
+        let mut reader = BufReader::new(reader);
+        expect_response(&mut reader, "220 Service ready for new user.\r\n").await;
+        // Much longer than a command can be, sent in pieces
+        let chunk = vec![b'a'; MAX_COMMAND_LENGTH];
+        writer.write_all(b"USER ").await.unwrap();
+        writer.write_all(&chunk).await.unwrap();
+        expect_response(&mut reader, "503 Bad sequence of commands.\r\n").await;
+        for _ in 0..64 {
+            writer.write_all(&chunk).await.unwrap();
+        }
+        writer.write_all(b"\r\nQUIT\r\n").await.unwrap();
+        expect_response(&mut reader, "221 Service closing control connection.\r\n").await;
+    }
+
+    #[test]
+    fn unsupported_settings_are_refused() {
+        let refused = |content: &str| {
+            let err = create_server(Config::parse(content).unwrap()).unwrap_err();
+            assert!(err.to_string().contains("remove"), "{}", err);
+        };
+        refused("[metrics]\nbind = \"127.0.0.1:0\"");
+        refused("[admin]\nsocket = \"./var/ftp_server.sock\"");
+        refused("[log]\nxferlog = \"./var/xferlog\"");
+        refused("[tls]\ncertificate = \"cert.pem\"\nprivate_key = \"key.pem\"");
+    }
+}
@@ -0,0 +1,171 @@
+//! In-process server for the integration tests.
+//!
+//! Every `TestServer` listens on an ephemeral port of 127.0.0.1 and keeps its users file, log and
+//! homes in its own temporary directory, so the tests can run in parallel without a server
+//! already running and without touching `./etc`, `./var` or `./root`. With `start_in_memory` the
+//! files of the homes are in a `MemoryStorage` instead.
+use crate::builder::{ServerBuilder, ServerHandle};
+use crate::storage::MemoryStorage;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+use user_manage::SystemUsers;
+
+/// Password of every user created by `write_users`
+pub const PASSWORD: &str = "123456";
+
+/// Workers of the pool of a `TestServer`
+const WORKERS: usize = 4;
+
+/// Writes `dir/etc/users.json` with `users` (homes in `dir/root`) and loads it.
+/// Every home has a `testfile.txt` with `Hello world!` and a copy of `test_files/1.jpeg`
+pub fn write_users(dir: &Path, users: &[&str]) -> SystemUsers {
+    write_users_with(dir, users, |home| {
+        fs::create_dir_all(home).unwrap();
+        fs::write(home.join("testfile.txt"), "Hello world!").unwrap();
+        fs::copy("./test_files/1.jpeg", home.join("1.jpeg")).unwrap();
+    })
+}
+
+/// Same as `write_users` but the files of the homes are written to `storage`
+pub fn write_users_in(dir: &Path, users: &[&str], storage: &MemoryStorage) -> SystemUsers {
+    let jpeg = fs::read("./test_files/1.jpeg").unwrap();
+    write_users_with(dir, users, |home| {
+        storage
+            .write(home, Path::new("/testfile.txt"), "Hello world!")
+            .unwrap();
+        storage.write(home, Path::new("/1.jpeg"), &jpeg).unwrap();
+    })
+}
+
+/// Writes and loads the users file, `fill` creates the files of every home
+fn write_users_with<F: FnMut(&Path)>(dir: &Path, users: &[&str], mut fill: F) -> SystemUsers {
+    let root = dir.join("root");
+    fs::create_dir_all(dir.join("etc")).unwrap();
+    fs::create_dir_all(dir.join("var")).unwrap();
+    let mut data = serde_json::Map::new();
+    for (uid, user) in users.iter().enumerate() {
+        let home = root.join(user);
+        fill(&home);
+        data.insert(
+            user.to_string(),
+            serde_json::json!({
+                "passwd": PASSWORD,
+                "chroot": home.to_str().unwrap(),
+                "uid": uid,
+            }),
+        );
+    }
+    let users_file = dir.join("etc").join("users.json");
+    fs::write(&users_file, serde_json::Value::Object(data).to_string()).unwrap();
+    SystemUsers::load_data_in(
+        users_file.to_str().unwrap(),
+        dir.join("var").join("ftpserver.log"),
+        &root,
+    )
+    .unwrap()
+}
+
+/// Server running in a thread, it's shut down when it's dropped
+pub struct TestServer {
+    dir: TempDir,
+    handle: Option<ServerHandle>,
+    storage: Option<MemoryStorage>,
+}
+
+impl TestServer {
+    /// Starts a server whose only users are `users`, see `write_users`
+    pub fn start(users: &[&str]) -> Self {
+        Self::start_with(users, |builder| builder)
+    }
+
+    /// Same as `start` but `configure` can change the builder before the server starts
+    pub fn start_with<F: FnOnce(ServerBuilder) -> ServerBuilder>(
+        users: &[&str],
+        configure: F,
+    ) -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        let users = write_users(dir.path(), users);
+        let builder = ServerBuilder::new()
+            .bind("127.0.0.1:0")
+            .users(users)
+            .max_connections(50)
+            .workers(WORKERS)
+            .queue_size(WORKERS * 64);
+        let handle = configure(builder).spawn().expect("server to start");
+        Self {
+            dir,
+            handle: Some(handle),
+            storage: None,
+        }
+    }
+
+    /// Same as `start` but the files are in a `MemoryStorage`, see `storage`
+    pub fn start_in_memory(users: &[&str]) -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = MemoryStorage::new();
+        let users = write_users_in(dir.path(), users, &storage);
+        let handle = ServerBuilder::new()
+            .bind("127.0.0.1:0")
+            .users(users)
+            .workers(WORKERS)
+            .queue_size(WORKERS * 64)
+            .storage(storage.clone())
+            .spawn()
+            .expect("server to start");
+        Self {
+            dir,
+            handle: Some(handle),
+            storage: Some(storage),
+        }
+    }
+
+    /// Files of a server of `start_in_memory`
+    pub fn storage(&self) -> &MemoryStorage {
+        self.storage
+            .as_ref()
+            .expect("a server with its files in memory")
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.handle.as_ref().unwrap().local_addr()
+    }
+
+    /// Directory that contains the homes of the users
+    pub fn root(&self) -> PathBuf {
+        self.dir.path().join("root")
+    }
+
+    pub fn home(&self, user: &str) -> PathBuf {
+        self.root().join(user)
+    }
+
+    pub fn handle(&self) -> &ServerHandle {
+        self.handle.as_ref().unwrap()
+    }
+
+    /// Waits until the server stops by itself, e.g. after a graceful shutdown
+    pub fn join(&mut self) -> Result<(), String> {
+        self.handle.take().unwrap().join()
+    }
+
+    /// Loads the users of `etc/users.json` again, see `FTPServer::reload`
+    pub fn reload(&self) {
+        self.handle.as_ref().unwrap().reload();
+    }
+
+    /// Path inside the temporary directory, for files that the tests download
+    pub fn path(&self, name: &str) -> PathBuf {
+        self.dir.path().join(name)
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            handle.shutdown();
+            let _ = handle.join();
+        }
+    }
+}
@@ -0,0 +1,214 @@
+//! Transfer log in the `xferlog` format of wu-ftpd and vsftpd, one line per `RETR` or `STOR`:
+//! ```text
+//! Mon May  3 10:00:00 2021 1 127.0.0.1 1024 /srv/ftp/bob/a.txt b _ o r bob ftp 0 * c
+//! ```
+//! The fields are the time, the duration in seconds, the remote host, the bytes, the path, the
+//! type (`a`scii or `b`inary), the special action (always `_`), the direction (`o`utgoing or
+//! `i`ncoming), the access mode (always `r`eal), the user, the service, the authentication method,
+//! the authenticated user id and the status (`c`omplete or `i`ncomplete). Whitespace in the path
+//! is written as `_` so the line can be split on spaces.
+use super::command::TransferMode;
+use super::log::{self, Level};
+use super::metrics::Metrics;
+use chrono::{DateTime, Local};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// File where the transfers are written
+static FILE: Mutex<Option<File>> = Mutex::new(None);
+
+/// If `FILE` has a file, so the workers don't take its lock when there isn't one
+static USE_FILE: AtomicBool = AtomicBool::new(false);
+
+/// Sets the file of the transfer log, `None` disables it. It affects the whole process
+pub fn init(path: Option<&Path>) -> io::Result<()> {
+    let file = match path {
+        Some(path) => Some(OpenOptions::new().create(true).append(true).open(path)?),
+        None => None,
+    };
+    USE_FILE.store(file.is_some(), Ordering::Relaxed);
+    *FILE.lock().unwrap() = file;
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    /// `STOR`, the client sends the file
+    Incoming,
+
+    /// `RETR`, the server sends the file
+    Outgoing,
+}
+
+/// A `RETR` or `STOR` that is running, it's logged when it finishes or it's aborted
+pub struct Transfer {
+    /// Token of the control connection
+    session: usize,
+
+    remote: Option<SocketAddr>,
+
+    user: Option<String>,
+
+    /// Where the file is on the disk
+    pub path: PathBuf,
+
+    mode: TransferMode,
+
+    direction: Direction,
+
+    started: Instant,
+
+    /// Bytes that went through the data connection
+    pub bytes: u64,
+
+    /// If it was already logged
+    finished: bool,
+
+    metrics: Arc<Metrics>,
+}
+
+impl Transfer {
+    pub fn new(
+        session: usize,
+        remote: Option<SocketAddr>,
+        user: Option<String>,
+        path: PathBuf,
+        mode: TransferMode,
+        direction: Direction,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        Self {
+            session,
+            remote,
+            user,
+            path,
+            mode,
+            direction,
+            started: Instant::now(),
+            bytes: 0,
+            finished: false,
+            metrics,
+        }
+    }
+
+    pub fn direction(&self) -> Direction {
+        self.direction
+    }
+
+    /// Time since the transfer started
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Writes the transfer to the xferlog, the server log and the metrics, only the first call
+    /// does so a transfer that was logged as complete isn't logged again when its connection is
+    /// closed
+    pub fn finish(&mut self, complete: bool) {
+        if self.finished {
+            return;
+        }
+        self.finished = true;
+        self.metrics
+            .transfer(self.direction, self.bytes, self.started.elapsed());
+        log::Event::new(
+            Level::Info,
+            if complete {
+                "transfer complete"
+            } else {
+                "transfer aborted"
+            },
+        )
+        .session(self.session)
+        .remote(self.remote)
+        .user(self.user.as_deref())
+        .bytes(self.bytes)
+        .duration(self.started.elapsed())
+        .emit();
+        if !USE_FILE.load(Ordering::Relaxed) {
+            return;
+        }
+        let line = self.line(Local::now(), complete);
+        if let Some(file) = FILE.lock().unwrap().as_mut() {
+            let _ = file.write_all(line.as_bytes());
+        }
+    }
+
+    fn line(&self, now: DateTime<Local>, complete: bool) -> String {
+        // Some tools divide by the duration, wu-ftpd never writes 0
+        let seconds = (self.started.elapsed().as_secs_f64().round() as u64).max(1);
+        let host = match self.remote {
+            Some(remote) => remote.ip().to_string(),
+            None => "unknown".to_string(),
+        };
+        let path: String = self
+            .path
+            .to_string_lossy()
+            .chars()
+            .map(|c| if c.is_whitespace() { '_' } else { c })
+            .collect();
+        format!(
+            "{} {} {} {} {} {} _ {} r {} ftp 0 * {}\n",
+            now.format("%a %b %e %H:%M:%S %Y"),
+            seconds,
+            host,
+            self.bytes,
+            path,
+            match self.mode {
+                TransferMode::Ascii => 'a',
+                TransferMode::Binary => 'b',
+            },
+            match self.direction {
+                Direction::Incoming => 'i',
+                Direction::Outgoing => 'o',
+            },
+            self.user.as_deref().unwrap_or("*"),
+            if complete { 'c' } else { 'i' },
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Direction, Transfer};
+    use crate::ftp::command::TransferMode;
+    use chrono::{Local, TimeZone};
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    #[test]
+    fn line_has_the_xferlog_fields() {
+        let mut transfer = Transfer::new(
+            1,
+            Some("127.0.0.1:50122".parse().unwrap()),
+            Some("bob".to_string()),
+            PathBuf::from("/srv/ftp/bob/my file.txt"),
+            TransferMode::Binary,
+            Direction::Outgoing,
+            Arc::default(),
+        );
+        transfer.bytes = 1024;
+        let now = Local.ymd(2021, 5, 3).and_hms(10, 0, 0);
+        assert_eq!(
+            transfer.line(now, true),
+            "Mon May  3 10:00:00 2021 1 127.0.0.1 1024 /srv/ftp/bob/my_file.txt b _ o r bob ftp 0 * c\n"
+        );
+        let transfer = Transfer::new(
+            1,
+            None,
+            None,
+            PathBuf::from("/srv/ftp/a.txt"),
+            TransferMode::Ascii,
+            Direction::Incoming,
+            Arc::default(),
+        );
+        assert_eq!(
+            transfer.line(now, false),
+            "Mon May  3 10:00:00 2021 1 unknown 0 /srv/ftp/a.txt a _ i r * ftp 0 * i\n"
+        );
+    }
+}
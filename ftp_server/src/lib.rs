@@ -0,0 +1,26 @@
+//! FTP server with multithreading and non-blocking behaviour in mind.
+//!
+//! The server can be embedded with `ServerBuilder`:
+//!
+//! ```no_run
+//! let handle = ftp_server::ServerBuilder::new()
+//!     .bind("127.0.0.1:2121")
+//!     .root("./root")
+//!     .users_file("./etc/users.json")
+//!     .spawn()
+//!     .expect("server to start");
+//! // ...
+//! handle.shutdown();
+//! handle.join().expect("server to stop cleanly");
+//! ```
+#[macro_use]
+pub mod ftp;
+pub mod builder;
+pub mod pool;
+pub mod port;
+pub mod storage;
+pub mod system;
+pub mod tcp;
+
+pub use builder::{ServerBuilder, ServerHandle};
+pub use ftp::hooks::ServerHooks;
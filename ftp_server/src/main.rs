@@ -1,22 +1,35 @@
-pub mod ftp;
-pub mod port;
-pub mod system;
-pub mod tcp;
-use std::fs::OpenOptions;
+use ftp_server::ftp;
+use ftp_server::ftp::config::{Config, ConfigError};
+#[cfg(all(unix, not(feature = "tokio-engine")))]
+use ftp_server::tcp::{ReloadHandle, ShutdownHandle};
+#[cfg(all(unix, not(feature = "tokio-engine")))]
+use signal_hook::{
+    consts::{SIGHUP, SIGINT, SIGTERM},
+    iterator::Signals,
+};
+use std::fmt::Display;
+use std::str::FromStr;
 
-use clap::{App, Arg};
+use clap::{App, Arg, ArgMatches};
 fn main() {
     let matches = App::new("FTP Server")
         .version("1.0")
         .author("Gabriel Villalonga @gabivlj\nRodrigo Pereira @_\nDaniel Gracia @DaniGMX")
         .about("Simple to use FTP server, with multithreading and non-blocking behaviour in mind for maximum concurrent file transfers for multiple users.")
+        .arg(
+            Arg::with_name("config")
+                .help("TOML config file, the other options override its values")
+                .short("C")
+                .long("config")
+                .value_name("CONFIG")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("port")
                 .short("p")
                 .long("port")
                 .value_name("PORT")
-                .default_value("8080")
-                .help("Set port")
+                .help("Set port, it listens on 0.0.0.0 instead of the addresses of the config")
                 .takes_value(true),
         )
         .arg(
@@ -24,35 +37,200 @@ fn main() {
                 .help("Sets maximum concurrent connections")
                 .short("c")
                 .long("capacity")
-                .value_name("CAPACITY")
-                .default_value("500"),
-        )               
+                .value_name("CAPACITY"),
+        )
+        .arg(
+            Arg::with_name("workers")
+                .help("Sets the number of worker threads that handle the connections")
+                .short("w")
+                .long("workers")
+                .value_name("WORKERS"),
+        )
+        .arg(
+            Arg::with_name("queue_size")
+                .help("Sets how many events can wait for a free worker before the server stops taking new ones")
+                .short("q")
+                .long("queue_size")
+                .value_name("QUEUE_SIZE"),
+        )
+        .arg(
+            Arg::with_name("bare_lf")
+                .help("If commands that end with a bare LF instead of CRLF are accepted")
+                .short("b")
+                .long("bare_lf")
+                .value_name("BARE_LF"),
+        )
+        .arg(
+            Arg::with_name("encoding")
+                .help("Encoding of the file names of the clients: utf-8 or latin1")
+                .long("encoding")
+                .value_name("ENCODING"),
+        )
         .arg(
             Arg::with_name("debug")
                 .help("If it should write to stdout the logs")
                 .short("d")
                 .long("debug")
-                .value_name("DEBUG")
-                .default_value("true"),
+                .value_name("DEBUG"),
         )
         .arg(
             Arg::with_name("log_file")
-                .help("If it should write to the specified file the logs, don't pass anything to not use a log file.")
+                .help("If it should also write the logs to the specified file")
                 .short("l")
                 .long("log_file")
-                .value_name("LOG_FILE")
-                .default_value("--none--"),
+                .value_name("LOG_FILE"),
+        )
+        .arg(
+            Arg::with_name("log_level")
+                .help("Minimum level of the logged events: trace, debug, info, warn or error")
+                .long("log_level")
+                .value_name("LOG_LEVEL"),
+        )
+        .arg(
+            Arg::with_name("xferlog")
+                .help("File where the transfers are written in the xferlog format")
+                .long("xferlog")
+                .value_name("XFERLOG"),
+        )
+        .arg(
+            Arg::with_name("metrics")
+                .help("Address where the Prometheus metrics are served on GET /metrics")
+                .long("metrics")
+                .value_name("METRICS"),
+        )
+        .arg(
+            Arg::with_name("admin_socket")
+                .help("Unix socket where the commands of ftp_admin are accepted")
+                .long("admin_socket")
+                .value_name("ADMIN_SOCKET"),
+        )
+        .arg(
+            Arg::with_name("storage")
+                .help("Where the files of the users are kept: local, memory or s3")
+                .long("storage")
+                .value_name("STORAGE"),
+        )
+        .arg(
+            Arg::with_name("root")
+                .help("Directory that contains the homes of the users")
+                .short("r")
+                .long("root")
+                .value_name("ROOT"),
+        )
+        .arg(
+            Arg::with_name("users")
+                .help("JSON file with the users")
+                .short("u")
+                .long("users")
+                .value_name("USERS"),
         )
         .get_matches();
-    let debug: bool = matches.value_of("debug").unwrap().parse().unwrap(); 
-    let log_file: &str = matches.value_of("log_file").unwrap(); 
-    if log_file != "--none--" {
-        ftp::config::use_stdout(OpenOptions::new().write(true).open(log_file).expect("Error with log file"));
-    }
-    ftp::config::set_debug(debug);
-    let port = matches.value_of("port").unwrap();
-    let capacity: usize = matches.value_of("capacity").unwrap().parse().unwrap();
-    let ip = format!("0.0.0.0:{}", port);
-    let mut ftp_server = ftp::FTPServer::with_connection_capacity(capacity);
-    tcp::create_server(ip.as_str(), &mut ftp_server).expect("server returned an error");
+    let config = load_config(&matches).unwrap_or_else(|err| exit("config", err));
+    ftp::log::init(&config.log).expect("Error with log file");
+    #[cfg(feature = "tokio-engine")]
+    {
+        ftp::session::create_server(config).unwrap_or_else(|err| exit("server", err));
+    }
+    #[cfg(not(feature = "tokio-engine"))]
+    {
+        let server = ftp_server::ServerBuilder::from_config(config)
+            .config_loader(move || load_config(&matches))
+            .build()
+            .expect("server couldn't start");
+        #[cfg(unix)]
+        handle_signals(server.reload_handle(), server.shutdown_handle())
+            .expect("couldn't handle the signals");
+        server.run().expect("server returned an error");
+    }
+}
+
+/// Config file of `--config` (or the defaults) with the other options applied over it
+fn load_config(matches: &ArgMatches) -> Result<Config, ConfigError> {
+    let mut config = match matches.value_of("config") {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    if let Some(port) = matches.value_of("port") {
+        config.server.bind = vec![format!("0.0.0.0:{}", port)];
+    }
+    if let Some(capacity) = parse(matches, "capacity") {
+        config.limits.max_connections = capacity;
+    }
+    if let Some(workers) = parse(matches, "workers") {
+        config.limits.workers = workers;
+    }
+    if let Some(queue_size) = parse(matches, "queue_size") {
+        config.limits.queue_size = queue_size;
+    }
+    if let Some(bare_lf) = parse(matches, "bare_lf") {
+        config.server.allow_bare_lf = bare_lf;
+    }
+    if let Some(encoding) = parse(matches, "encoding") {
+        config.server.encoding = encoding;
+    }
+    if let Some(debug) = parse(matches, "debug") {
+        config.log.stdout = debug;
+    }
+    if let Some(log_file) = matches.value_of("log_file") {
+        config.log.file = Some(log_file.into());
+    }
+    if let Some(level) = parse(matches, "log_level") {
+        config.log.level = level;
+    }
+    if let Some(xferlog) = matches.value_of("xferlog") {
+        config.log.xferlog = Some(xferlog.into());
+    }
+    if let Some(metrics) = matches.value_of("metrics") {
+        config.metrics.bind = Some(metrics.to_string());
+    }
+    if let Some(admin_socket) = matches.value_of("admin_socket") {
+        config.admin.socket = Some(admin_socket.into());
+    }
+    if let Some(backend) = parse(matches, "storage") {
+        config.storage.backend = backend;
+    }
+    if let Some(root) = matches.value_of("root") {
+        config.users.root = root.into();
+    }
+    if let Some(users) = matches.value_of("users") {
+        config.users.file = users.into();
+    }
+    Ok(config)
+}
+
+/// Reloads the config and the users every time the process gets SIGHUP. The first SIGTERM or
+/// SIGINT starts a graceful shutdown, a second one stops the server right away
+#[cfg(all(unix, not(feature = "tokio-engine")))]
+fn handle_signals(reload: ReloadHandle, shutdown: ShutdownHandle) -> std::io::Result<()> {
+    let mut signals = Signals::new(&[SIGHUP, SIGTERM, SIGINT])?;
+    std::thread::Builder::new()
+        .name("signals".to_string())
+        .spawn(move || {
+            let mut draining = false;
+            for signal in signals.forever() {
+                match signal {
+                    SIGHUP => reload.reload(),
+                    _ if draining => shutdown.shutdown(),
+                    _ => {
+                        draining = true;
+                        shutdown.graceful_shutdown();
+                    }
+                }
+            }
+        })?;
+    Ok(())
+}
+
+/// Value of the option `name` if it was passed, exits if it's invalid
+fn parse<T: FromStr>(matches: &ArgMatches, name: &str) -> Option<T>
+where
+    T::Err: Display,
+{
+    let value = matches.value_of(name)?;
+    Some(value.parse().unwrap_or_else(|err| exit(name, err)))
+}
+
+fn exit<E: Display>(what: &str, err: E) -> ! {
+    eprintln!("{}: {}", what, err);
+    std::process::exit(2)
 }
@@ -0,0 +1,225 @@
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+/// Default number of worker threads
+pub const DEFAULT_WORKERS: usize = 8;
+
+/// Default number of jobs that can wait in the queue before `execute` starts blocking
+pub const DEFAULT_QUEUE_SIZE: usize = 1024;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// Counters of the pool, they are updated by the pool and its workers
+/// so they can be read at any time from any thread.
+#[derive(Debug, Default)]
+pub struct PoolMetrics {
+    /// Jobs that are waiting in the queue
+    queued: AtomicUsize,
+
+    /// Maximum queue depth seen since the pool was created
+    max_queued: AtomicUsize,
+
+    /// Jobs that are being executed right now
+    running: AtomicUsize,
+
+    /// Jobs that finished (even if they panicked)
+    completed: AtomicUsize,
+
+    /// Times that `execute` had to wait because the queue was full
+    blocked: AtomicUsize,
+}
+
+impl PoolMetrics {
+    pub fn queue_depth(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    pub fn max_queue_depth(&self) -> usize {
+        self.max_queued.load(Ordering::Relaxed)
+    }
+
+    pub fn running(&self) -> usize {
+        self.running.load(Ordering::Relaxed)
+    }
+
+    pub fn completed(&self) -> usize {
+        self.completed.load(Ordering::Relaxed)
+    }
+
+    pub fn times_blocked(&self) -> usize {
+        self.blocked.load(Ordering::Relaxed)
+    }
+}
+
+/// Fixed size pool of worker threads fed by a bounded job queue.
+/// ## Behaviour
+/// * When the queue is full `execute` blocks until a worker takes a job (backpressure),
+///   so the event loop stops taking new events instead of piling up threads.
+/// * A job that panics doesn't kill its worker.
+/// * Dropping the pool waits for every queued job to finish.
+pub struct ThreadPool {
+    sender: Option<SyncSender<Job>>,
+
+    workers: Vec<JoinHandle<()>>,
+
+    metrics: Arc<PoolMetrics>,
+}
+
+impl ThreadPool {
+    pub fn new(workers: usize, queue_size: usize) -> Self {
+        assert!(workers > 0, "the pool needs at least one worker");
+        let (sender, receiver) = sync_channel::<Job>(queue_size);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let metrics = Arc::new(PoolMetrics::default());
+        let workers = (0..workers)
+            .map(|id| {
+                let receiver = receiver.clone();
+                let metrics = metrics.clone();
+                thread::Builder::new()
+                    .name(format!("ftp-worker-{}", id))
+                    .spawn(move || worker_loop(id, receiver, metrics))
+                    .expect("worker thread to be spawned")
+            })
+            .collect();
+        Self {
+            sender: Some(sender),
+            workers,
+            metrics,
+        }
+    }
+
+    /// Queues a job, blocks if the queue is full
+    pub fn execute<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let sender = self.sender.as_ref().expect("pool is alive");
+        let depth = self.metrics.queued.fetch_add(1, Ordering::Relaxed) + 1;
+        self.metrics.max_queued.fetch_max(depth, Ordering::Relaxed);
+        match sender.try_send(Box::new(job)) {
+            Ok(()) => {}
+            Err(TrySendError::Full(job)) => {
+                self.metrics.blocked.fetch_add(1, Ordering::Relaxed);
+                log_warn!(
+                    "[WORKER_POOL] Queue is full ({} jobs), waiting for a free worker",
+                    depth - 1
+                );
+                if sender.send(job).is_err() {
+                    self.metrics.queued.fetch_sub(1, Ordering::Relaxed);
+                }
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                self.metrics.queued.fetch_sub(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub fn metrics(&self) -> Arc<PoolMetrics> {
+        self.metrics.clone()
+    }
+
+    pub fn workers(&self) -> usize {
+        self.workers.len()
+    }
+}
+
+impl Default for ThreadPool {
+    fn default() -> Self {
+        Self::new(DEFAULT_WORKERS, DEFAULT_QUEUE_SIZE)
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        // Closing the channel makes every worker exit when the queue is empty
+        drop(self.sender.take());
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn worker_loop(id: usize, receiver: Arc<Mutex<Receiver<Job>>>, metrics: Arc<PoolMetrics>) {
+    loop {
+        // The lock is released at the end of the statement, so other workers can wait on the
+        // queue while this one is running a job
+        let job = receiver.lock().unwrap().recv();
+        let job = match job {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+        metrics.queued.fetch_sub(1, Ordering::Relaxed);
+        metrics.running.fetch_add(1, Ordering::Relaxed);
+        if catch_unwind(AssertUnwindSafe(job)).is_err() {
+            log_error!("[WORKER_POOL] Worker {} - A job panicked", id);
+        }
+        metrics.running.fetch_sub(1, Ordering::Relaxed);
+        metrics.completed.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ThreadPool;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{mpsc, Arc};
+    use std::time::Duration;
+
+    #[test]
+    fn test_runs_every_job() {
+        let counter = Arc::new(AtomicUsize::new(0));
+        let pool = ThreadPool::new(4, 2);
+        let metrics = pool.metrics();
+        for _ in 0..100 {
+            let counter = counter.clone();
+            pool.execute(move || {
+                counter.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        drop(pool);
+        assert_eq!(counter.load(Ordering::SeqCst), 100);
+        assert_eq!(metrics.completed(), 100);
+        assert_eq!(metrics.queue_depth(), 0);
+        assert!(metrics.max_queue_depth() >= 1);
+    }
+
+    #[test]
+    fn test_backpressure_when_full() {
+        let pool = ThreadPool::new(1, 1);
+        let metrics = pool.metrics();
+        let (release, wait) = mpsc::channel::<()>();
+        // Keep the only worker busy
+        pool.execute(move || {
+            let _ = wait.recv_timeout(Duration::from_secs(5));
+        });
+        while metrics.running() == 0 {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        // This one fills the queue
+        pool.execute(|| {});
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            let _ = release.send(());
+        });
+        // And this one needs to wait for the worker
+        pool.execute(|| {});
+        assert_eq!(metrics.times_blocked(), 1);
+        drop(pool);
+        assert_eq!(metrics.completed(), 3);
+    }
+
+    #[test]
+    fn test_survives_panics() {
+        let pool = ThreadPool::new(1, 4);
+        let metrics = pool.metrics();
+        pool.execute(|| panic!("expected panic"));
+        let (tx, rx) = mpsc::channel();
+        pool.execute(move || tx.send(1).unwrap());
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), 1);
+        drop(pool);
+        assert_eq!(metrics.completed(), 2);
+    }
+}
@@ -1,4 +1,5 @@
 use std::net::TcpListener;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 /// Returns a random port, if it's none it means that every port in the machine is taken.
 pub fn get_random_port() -> Option<u16> {
@@ -11,6 +12,29 @@ pub fn get_random_port() -> Option<u16> {
     )
 }
 
+/// Binds a listener for a passive data connection on a port of `range` (inclusive), or on any
+/// free port without a range. The search starts at a different port each time so concurrent
+/// `PASV` commands don't race for the same one. Returns the non-blocking listener and its port
+pub fn bind_passive(range: Option<(u16, u16)>) -> Option<(TcpListener, u16)> {
+    let listener = match range {
+        None => TcpListener::bind("0.0.0.0:0").ok()?,
+        Some((min, max)) => {
+            let len = u32::from(max - min) + 1;
+            let start = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|now| now.subsec_nanos())
+                .unwrap_or(0)
+                % len;
+            (0..len)
+                .map(|i| min + ((start + i) % len) as u16)
+                .find_map(|port| TcpListener::bind(("0.0.0.0", port)).ok())?
+        }
+    };
+    listener.set_nonblocking(true).ok()?;
+    let port = listener.local_addr().ok()?.port();
+    Some((listener, port))
+}
+
 pub fn get_ftp_port_pair(port: u16) -> (u8, u8) {
     let first_part = port / 256;
     let second_part = port - first_part * 256;
@@ -24,4 +48,16 @@ mod test {
         use super::get_random_port;
         get_random_port().expect("to work");
     }
+
+    #[test]
+    fn test_bind_passive() {
+        use super::bind_passive;
+        let (any, port) = bind_passive(None).expect("to work");
+        assert_ne!(port, 0);
+        // The only port of the range is taken
+        assert!(bind_passive(Some((port, port))).is_none());
+        drop(any);
+        let (_listener, bound) = bind_passive(Some((port, port))).expect("to work");
+        assert_eq!(bound, port);
+    }
 }
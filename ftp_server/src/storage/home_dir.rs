@@ -0,0 +1,679 @@
+//! Paths of the local disk resolved inside a home, so no command can leave it.
+//!
+//! `HomeDir` opens the home once and every virtual path is walked from it one component at a
+//! time, each directory opened from the descriptor of the previous one without following
+//! symlinks (`openat` with `O_NOFOLLOW`). Symlinks are resolved here instead: a relative target
+//! is walked from the directory of the link and an absolute one has to be inside the home, and
+//! `..` goes back to the directory that was opened before it instead of asking the disk. So a
+//! symlink or a rename that happens in the middle of a command can't take it out of the home,
+//! the worst it can do is make the command fail.
+//!
+//! The operations on the last component (create, remove, rename) are done on the descriptor of
+//! its directory, so they act on the symlink itself and never on its target.
+use super::{relative, DirEntry, Metadata};
+use std::collections::VecDeque;
+use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::io::{self, ErrorKind};
+use std::path::{Component, Path, PathBuf};
+
+/// Symlinks that a single path can go through, like the `ELOOP` limit of Linux
+const MAX_SYMLINKS: usize = 40;
+
+fn outside() -> io::Error {
+    io::Error::new(
+        ErrorKind::PermissionDenied,
+        "the path is outside of the home",
+    )
+}
+
+/// Where a path ends, see `HomeDir::walk`
+enum Entry {
+    /// The path is this directory, like `/` or a symlink to `..`
+    Dir(sys::Dir),
+
+    /// The entry `name` of the directory, which may not exist
+    Name(sys::Dir, OsString),
+}
+
+/// Home opened on the disk, see the module docs
+pub struct HomeDir {
+    dir: sys::Dir,
+
+    /// Canonical path of the home, absolute symlinks must start with it
+    real: PathBuf,
+}
+
+impl HomeDir {
+    /// Opens the home, the path itself is trusted and may go through symlinks
+    pub fn open(home: &Path) -> io::Result<Self> {
+        let real = home.canonicalize()?;
+        Ok(Self {
+            dir: sys::Dir::open(&real)?,
+            real,
+        })
+    }
+
+    /// Walks the virtual `path` from the home. The symlinks of the directories are followed, and
+    /// the one of the last component only if `follow` is true
+    fn walk(&self, path: &Path, follow: bool) -> io::Result<Entry> {
+        let mut dirs = vec![self.dir.try_clone()?];
+        let mut pending: VecDeque<OsString> = names(relative(path)).collect();
+        let mut symlinks = 0;
+        while let Some(name) = pending.pop_front() {
+            if name == ".." {
+                if dirs.len() == 1 {
+                    return Err(outside());
+                }
+                dirs.pop();
+                continue;
+            }
+            let dir = dirs.last().unwrap();
+            let target = if pending.is_empty() {
+                match follow.then(|| dir.read_link(&name).ok()).flatten() {
+                    Some(target) => target,
+                    None => return Ok(Entry::Name(dirs.pop().unwrap(), name)),
+                }
+            } else {
+                match dir.open_dir(&name) {
+                    Ok(child) => {
+                        dirs.push(child);
+                        continue;
+                    }
+                    // It can't be opened because it's a symlink, or it's an error
+                    Err(err) => dir.read_link(&name).map_err(|_| err)?,
+                }
+            };
+            symlinks += 1;
+            if symlinks > MAX_SYMLINKS {
+                return Err(io::Error::other("too many levels of symbolic links"));
+            }
+            let target = if target.is_absolute() {
+                dirs.truncate(1);
+                target
+                    .strip_prefix(&self.real)
+                    .map_err(|_| outside())?
+                    .to_path_buf()
+            } else {
+                target
+            };
+            for name in names(&target).rev() {
+                pending.push_front(name);
+            }
+        }
+        Ok(Entry::Dir(dirs.pop().unwrap()))
+    }
+
+    /// Directory of the last component of `path` and its name, the home itself has none
+    fn parent(&self, path: &Path) -> io::Result<(sys::Dir, OsString)> {
+        match self.walk(path, false)? {
+            Entry::Name(dir, name) => Ok((dir, name)),
+            Entry::Dir(_) => Err(io::Error::new(
+                ErrorKind::PermissionDenied,
+                "the home can't be changed",
+            )),
+        }
+    }
+
+    /// The directory `path`
+    fn dir(&self, path: &Path) -> io::Result<sys::Dir> {
+        match self.walk(path, true)? {
+            Entry::Dir(dir) => Ok(dir),
+            Entry::Name(dir, name) => dir.open_dir(&name),
+        }
+    }
+
+    /// Opens the existing file `path` for reading
+    pub fn open_file(&self, path: &Path) -> io::Result<File> {
+        match self.walk(path, true)? {
+            Entry::Name(dir, name) => dir.open_file(&name, sys::Open::Read),
+            Entry::Dir(_) => Err(io::Error::other("is a directory")),
+        }
+    }
+
+    /// Creates the file `path`, or truncates it if it exists. A symlink in its place isn't
+    /// followed, it fails instead
+    pub fn create(&self, path: &Path) -> io::Result<File> {
+        let (dir, name) = self.parent(path)?;
+        dir.open_file(&name, sys::Open::Truncate)
+    }
+
+    /// Creates the file `name` in the directory of `path`, it fails if it exists. Returns the file
+    /// and the directory, see `DirHandle`
+    pub fn create_next_to(&self, path: &Path, name: &OsStr) -> io::Result<(File, DirHandle)> {
+        let (dir, target) = self.parent(path)?;
+        if dir.stat(&target).is_ok_and(|metadata| metadata.is_dir) {
+            return Err(io::Error::other("is a directory"));
+        }
+        let file = dir.open_file(name, sys::Open::CreateNew)?;
+        Ok((file, DirHandle(dir)))
+    }
+
+    /// Metadata of `path`, following its symlinks
+    pub fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        match self.walk(path, true)? {
+            Entry::Dir(dir) => dir.metadata(),
+            Entry::Name(dir, name) => dir.stat(&name),
+        }
+    }
+
+    /// Entries of the directory `path`, the symlinks inside of it aren't followed
+    pub fn read_dir(&self, path: &Path) -> io::Result<Vec<DirEntry>> {
+        let dir = self.dir(path)?;
+        let mut entries = Vec::new();
+        for name in dir.names()? {
+            // Entries that disappear while they are listed are skipped
+            if let Ok(metadata) = dir.stat(&name) {
+                entries.push(DirEntry { name, metadata });
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Creates the directory `path`, its parent must exist
+    pub fn create_dir(&self, path: &Path) -> io::Result<()> {
+        let (dir, name) = self.parent(path)?;
+        dir.create_dir(&name)
+    }
+
+    /// Removes the file `path`, or the symlink if it's one
+    pub fn remove_file(&self, path: &Path) -> io::Result<()> {
+        let (dir, name) = self.parent(path)?;
+        dir.remove(&name, false)
+    }
+
+    /// Removes the empty directory `path`
+    pub fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        let (dir, name) = self.parent(path)?;
+        dir.remove(&name, true)
+    }
+
+    /// Removes the directory `path` and everything inside of it, the symlinks inside are removed
+    /// and not followed
+    pub fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+        let (dir, name) = self.parent(path)?;
+        remove_tree(&dir, &name)?;
+        dir.remove(&name, true)
+    }
+
+    /// Moves `from` to `to`, `to` must not exist
+    pub fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let (from_dir, from_name) = self.parent(from)?;
+        let (to_dir, to_name) = self.parent(to)?;
+        if to_dir.stat(&to_name).is_ok() {
+            return Err(io::Error::from(ErrorKind::AlreadyExists));
+        }
+        from_dir.rename(&from_name, &to_dir, &to_name)
+    }
+}
+
+/// Removes what is inside the directory `name` of `dir`
+fn remove_tree(dir: &sys::Dir, name: &OsStr) -> io::Result<()> {
+    let inner = dir.open_dir(name)?;
+    for entry in inner.names()? {
+        if inner.stat(&entry)?.is_dir {
+            remove_tree(&inner, &entry)?;
+            inner.remove(&entry, true)?;
+        } else {
+            inner.remove(&entry, false)?;
+        }
+    }
+    Ok(())
+}
+
+/// Names of the components of `path`, `..` included and `.` skipped
+fn names(path: &Path) -> impl DoubleEndedIterator<Item = OsString> + '_ {
+    path.components().filter_map(|component| match component {
+        Component::Normal(name) => Some(name.to_os_string()),
+        Component::ParentDir => Some(OsString::from("..")),
+        Component::RootDir | Component::CurDir | Component::Prefix(_) => None,
+    })
+}
+
+/// Directory that was opened by `HomeDir::create_next_to`, to rename or remove what was created
+/// in it even if the directory was moved since then
+pub struct DirHandle(sys::Dir);
+
+impl DirHandle {
+    /// Moves its entry `from` to `to`, replacing `to`
+    pub fn rename(&self, from: &OsStr, to: &OsStr) -> io::Result<()> {
+        self.0.rename(from, &self.0, to)
+    }
+
+    pub fn remove_file(&self, name: &OsStr) -> io::Result<()> {
+        self.0.remove(name, false)
+    }
+}
+
+#[cfg(unix)]
+mod sys {
+    use super::super::Metadata;
+    use std::convert::TryFrom;
+    use std::ffi::{CStr, CString, OsStr, OsString};
+    use std::fs::File;
+    use std::io;
+    use std::os::unix::ffi::{OsStrExt, OsStringExt};
+    use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd};
+    use std::path::{Path, PathBuf};
+    use std::time::{Duration, SystemTime};
+
+    /// How `Dir::open_file` opens a file
+    pub enum Open {
+        Read,
+        Truncate,
+        CreateNew,
+    }
+
+    /// Directory opened on the disk
+    pub struct Dir(OwnedFd);
+
+    fn c_name(name: &OsStr) -> io::Result<CString> {
+        CString::new(name.as_bytes()).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))
+    }
+
+    /// Turns the result of a call into an error if it's negative
+    fn check(result: libc::c_int) -> io::Result<libc::c_int> {
+        match result {
+            result if result < 0 => Err(io::Error::last_os_error()),
+            result => Ok(result),
+        }
+    }
+
+    fn metadata(stat: &libc::stat) -> Metadata {
+        let is_dir = stat.st_mode & libc::S_IFMT == libc::S_IFDIR;
+        let modified = u64::try_from(stat.st_mtime)
+            .ok()
+            .map(|secs| SystemTime::UNIX_EPOCH + Duration::new(secs, stat.st_mtime_nsec as u32));
+        Metadata {
+            is_dir,
+            len: if is_dir { 0 } else { stat.st_size as u64 },
+            modified,
+        }
+    }
+
+    impl Dir {
+        pub fn open(path: &Path) -> io::Result<Self> {
+            let path = c_name(path.as_os_str())?;
+            // Safe because `path` is a valid C string
+            let fd = check(unsafe {
+                libc::open(
+                    path.as_ptr(),
+                    libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC,
+                )
+            })?;
+            // Safe because the descriptor was just opened and nothing else owns it
+            Ok(Self(unsafe { OwnedFd::from_raw_fd(fd) }))
+        }
+
+        pub fn try_clone(&self) -> io::Result<Self> {
+            Ok(Self(self.0.try_clone()?))
+        }
+
+        /// Opens `name` relative to this directory, never following it if it's a symlink
+        fn open_at(&self, name: &OsStr, flags: libc::c_int) -> io::Result<OwnedFd> {
+            let name = c_name(name)?;
+            // Safe because the descriptor is alive and `name` is a valid C string
+            let fd = check(unsafe {
+                libc::openat(
+                    self.0.as_raw_fd(),
+                    name.as_ptr(),
+                    flags | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+                    0o666 as libc::c_uint,
+                )
+            })?;
+            // Safe because the descriptor was just opened and nothing else owns it
+            Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+        }
+
+        pub fn open_dir(&self, name: &OsStr) -> io::Result<Self> {
+            self.open_at(name, libc::O_RDONLY | libc::O_DIRECTORY)
+                .map(Self)
+        }
+
+        pub fn open_file(&self, name: &OsStr, open: Open) -> io::Result<File> {
+            let flags = match open {
+                // A FIFO would block the worker
+                Open::Read => libc::O_RDONLY | libc::O_NONBLOCK,
+                Open::Truncate => libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC,
+                Open::CreateNew => libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL,
+            };
+            let file = File::from(self.open_at(name, flags)?);
+            if let Open::Read = open {
+                // Back to blocking reads, the worker waits for the disk like with `File::open`
+                // Safe because the descriptor is alive
+                let fd = file.as_raw_fd();
+                unsafe {
+                    let flags = check(libc::fcntl(fd, libc::F_GETFL))?;
+                    check(libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_NONBLOCK))?;
+                }
+            }
+            Ok(file)
+        }
+
+        /// Target of the symlink `name`, it fails if it isn't a symlink
+        pub fn read_link(&self, name: &OsStr) -> io::Result<PathBuf> {
+            let name = c_name(name)?;
+            let mut buffer = vec![0_u8; libc::PATH_MAX as usize];
+            // Safe because the descriptor is alive, `name` is a valid C string and `buffer` has
+            // the size that is passed
+            let read = unsafe {
+                libc::readlinkat(
+                    self.0.as_raw_fd(),
+                    name.as_ptr(),
+                    buffer.as_mut_ptr() as *mut libc::c_char,
+                    buffer.len(),
+                )
+            };
+            if read < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            buffer.truncate(read as usize);
+            Ok(PathBuf::from(OsString::from_vec(buffer)))
+        }
+
+        /// Metadata of `name`, without following it if it's a symlink
+        pub fn stat(&self, name: &OsStr) -> io::Result<Metadata> {
+            let name = c_name(name)?;
+            let mut stat = std::mem::MaybeUninit::<libc::stat>::uninit();
+            // Safe because the descriptor is alive, `name` is a valid C string and `stat` is
+            // only read after the call fills it
+            check(unsafe {
+                libc::fstatat(
+                    self.0.as_raw_fd(),
+                    name.as_ptr(),
+                    stat.as_mut_ptr(),
+                    libc::AT_SYMLINK_NOFOLLOW,
+                )
+            })?;
+            Ok(metadata(unsafe { &stat.assume_init() }))
+        }
+
+        /// Metadata of the directory itself
+        pub fn metadata(&self) -> io::Result<Metadata> {
+            let mut stat = std::mem::MaybeUninit::<libc::stat>::uninit();
+            // Safe because the descriptor is alive and `stat` is only read after the call
+            // fills it
+            check(unsafe { libc::fstat(self.0.as_raw_fd(), stat.as_mut_ptr()) })?;
+            Ok(metadata(unsafe { &stat.assume_init() }))
+        }
+
+        /// Names of the entries, without `.` and `..`
+        pub fn names(&self) -> io::Result<Vec<OsString>> {
+            // `fdopendir` takes the descriptor, so it gets a copy that starts at the first entry
+            let fd = self.0.try_clone()?.into_raw_fd();
+            // Safe because `fd` is open until `closedir`, or `close` if the stream can't be
+            // created
+            let stream = unsafe {
+                libc::lseek(fd, 0, libc::SEEK_SET);
+                libc::fdopendir(fd)
+            };
+            if stream.is_null() {
+                let err = io::Error::last_os_error();
+                unsafe { libc::close(fd) };
+                return Err(err);
+            }
+            let mut names = Vec::new();
+            loop {
+                // Safe because `stream` is open until `closedir`, and the entry is copied before
+                // the next call
+                let entry = unsafe { libc::readdir(stream) };
+                if entry.is_null() {
+                    break;
+                }
+                let name = unsafe { CStr::from_ptr((*entry).d_name.as_ptr()) }.to_bytes();
+                if name != b"." && name != b".." {
+                    names.push(OsString::from_vec(name.to_vec()));
+                }
+            }
+            unsafe { libc::closedir(stream) };
+            Ok(names)
+        }
+
+        pub fn create_dir(&self, name: &OsStr) -> io::Result<()> {
+            let name = c_name(name)?;
+            // Safe because the descriptor is alive and `name` is a valid C string
+            check(unsafe { libc::mkdirat(self.0.as_raw_fd(), name.as_ptr(), 0o777) }).map(drop)
+        }
+
+        /// Removes the entry `name`, which must be an empty directory if `is_dir`
+        pub fn remove(&self, name: &OsStr, is_dir: bool) -> io::Result<()> {
+            let name = c_name(name)?;
+            let flags = if is_dir { libc::AT_REMOVEDIR } else { 0 };
+            // Safe because the descriptor is alive and `name` is a valid C string
+            check(unsafe { libc::unlinkat(self.0.as_raw_fd(), name.as_ptr(), flags) }).map(drop)
+        }
+
+        pub fn rename(&self, from: &OsStr, to_dir: &Dir, to: &OsStr) -> io::Result<()> {
+            let (from, to) = (c_name(from)?, c_name(to)?);
+            // Safe because both descriptors are alive and the names are valid C strings
+            check(unsafe {
+                libc::renameat(
+                    self.0.as_raw_fd(),
+                    from.as_ptr(),
+                    to_dir.0.as_raw_fd(),
+                    to.as_ptr(),
+                )
+            })
+            .map(drop)
+        }
+    }
+}
+
+/// Without `openat` the directories are paths, so the checks can race with the changes of
+/// the disk
+#[cfg(not(unix))]
+mod sys {
+    use super::super::Metadata;
+    use std::ffi::{OsStr, OsString};
+    use std::fs::{self, File};
+    use std::io::{self, ErrorKind};
+    use std::path::{Path, PathBuf};
+
+    pub enum Open {
+        Read,
+        Truncate,
+        CreateNew,
+    }
+
+    pub struct Dir(PathBuf);
+
+    fn metadata(metadata: fs::Metadata) -> Metadata {
+        Metadata {
+            is_dir: metadata.is_dir(),
+            len: if metadata.is_dir() { 0 } else { metadata.len() },
+            modified: metadata.modified().ok(),
+        }
+    }
+
+    impl Dir {
+        pub fn open(path: &Path) -> io::Result<Self> {
+            Ok(Self(path.to_path_buf()))
+        }
+
+        pub fn try_clone(&self) -> io::Result<Self> {
+            Ok(Self(self.0.clone()))
+        }
+
+        /// `name` if it isn't a symlink
+        fn no_follow(&self, name: &OsStr) -> io::Result<PathBuf> {
+            let path = self.0.join(name);
+            match fs::symlink_metadata(&path) {
+                Ok(metadata) if metadata.file_type().is_symlink() => {
+                    Err(io::Error::other("it's a symlink"))
+                }
+                _ => Ok(path),
+            }
+        }
+
+        pub fn open_dir(&self, name: &OsStr) -> io::Result<Self> {
+            let path = self.no_follow(name)?;
+            if !fs::metadata(&path)?.is_dir() {
+                return Err(io::Error::other("not a directory"));
+            }
+            Ok(Self(path))
+        }
+
+        pub fn open_file(&self, name: &OsStr, open: Open) -> io::Result<File> {
+            let path = self.no_follow(name)?;
+            match open {
+                Open::Read => File::open(path),
+                Open::Truncate => File::create(path),
+                Open::CreateNew => File::options().write(true).create_new(true).open(path),
+            }
+        }
+
+        pub fn read_link(&self, name: &OsStr) -> io::Result<PathBuf> {
+            fs::read_link(self.0.join(name))
+        }
+
+        pub fn stat(&self, name: &OsStr) -> io::Result<Metadata> {
+            fs::symlink_metadata(self.0.join(name)).map(metadata)
+        }
+
+        pub fn metadata(&self) -> io::Result<Metadata> {
+            fs::metadata(&self.0).map(metadata)
+        }
+
+        pub fn names(&self) -> io::Result<Vec<OsString>> {
+            fs::read_dir(&self.0)?
+                .map(|entry| entry.map(|entry| entry.file_name()))
+                .collect()
+        }
+
+        pub fn create_dir(&self, name: &OsStr) -> io::Result<()> {
+            fs::create_dir(self.0.join(name))
+        }
+
+        pub fn remove(&self, name: &OsStr, is_dir: bool) -> io::Result<()> {
+            match is_dir {
+                true => fs::remove_dir(self.0.join(name)),
+                false => fs::remove_file(self.0.join(name)),
+            }
+        }
+
+        pub fn rename(&self, from: &OsStr, to_dir: &Dir, to: &OsStr) -> io::Result<()> {
+            fs::rename(self.0.join(from), to_dir.0.join(to))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::HomeDir;
+    use std::io::{ErrorKind, Read};
+    use std::path::Path;
+
+    #[test]
+    fn paths_stay_in_the_home() {
+        let dir = tempfile::tempdir().unwrap();
+        let home = dir.path().join("home");
+        std::fs::create_dir_all(home.join("docs/inner")).unwrap();
+        std::fs::write(home.join("docs/a.txt"), "a").unwrap();
+        std::fs::write(dir.path().join("secret.txt"), "secret").unwrap();
+        let home_dir = HomeDir::open(&home).unwrap();
+        let read = |path: &str| {
+            let mut content = String::new();
+            home_dir
+                .open_file(Path::new(path))
+                .and_then(|mut file| file.read_to_string(&mut content))
+                .map(|_| content)
+        };
+        assert_eq!(read("/docs/a.txt").unwrap(), "a");
+        assert_eq!(read("docs/inner/../a.txt").unwrap(), "a");
+        assert_eq!(
+            read("/docs/../../secret.txt").unwrap_err().kind(),
+            ErrorKind::PermissionDenied
+        );
+        assert!(read("/docs").is_err());
+        assert!(home_dir.metadata(Path::new("/")).unwrap().is_dir);
+        assert_eq!(home_dir.read_dir(Path::new("/docs")).unwrap().len(), 2);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::symlink;
+            let link = |target: &Path, name: &str| symlink(target, home.join(name)).unwrap();
+            // Symlinks are followed while they stay inside of the home
+            link(Path::new("docs/a.txt"), "relative");
+            link(&home.join("docs"), "absolute");
+            link(Path::new("../../docs/inner/.."), "docs/inner/up");
+            assert_eq!(read("/relative").unwrap(), "a");
+            assert_eq!(read("/absolute/a.txt").unwrap(), "a");
+            assert_eq!(read("/docs/inner/up/a.txt").unwrap(), "a");
+            // and not when they leave it
+            link(&dir.path().join("secret.txt"), "escape");
+            link(Path::new("../secret.txt"), "escape_relative");
+            link(dir.path(), "escape_dir");
+            link(Path::new("docs/../.."), "escape_up");
+            for path in &[
+                "/escape",
+                "/escape_relative",
+                "/escape_dir/secret.txt",
+                "/escape_up/secret.txt",
+            ] {
+                assert_eq!(
+                    read(path).unwrap_err().kind(),
+                    ErrorKind::PermissionDenied,
+                    "{}",
+                    path
+                );
+            }
+            assert!(home_dir.read_dir(Path::new("/escape_dir")).is_err());
+            assert!(home_dir.create(Path::new("/escape_dir/new.txt")).is_err());
+            assert!(home_dir.create_dir(Path::new("/escape_dir/new")).is_err());
+            link(Path::new("loop"), "loop");
+            assert!(read("/loop").is_err());
+            // Writing to a symlink fails instead of writing to its target
+            assert!(home_dir.create(Path::new("/escape")).is_err());
+            assert_eq!(
+                std::fs::read_to_string(dir.path().join("secret.txt")).unwrap(),
+                "secret"
+            );
+            // and removing or renaming it changes the symlink
+            home_dir.remove_file(Path::new("/escape")).unwrap();
+            home_dir
+                .rename(Path::new("/escape_dir"), Path::new("/docs/moved"))
+                .unwrap();
+            home_dir.remove_dir_all(Path::new("/docs")).unwrap();
+            assert!(dir.path().join("secret.txt").exists());
+            let err = home_dir
+                .rename(Path::new("/relative"), Path::new("/absolute"))
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+        }
+    }
+
+    /// A directory of the path is swapped for a symlink that leaves the home while the paths are
+    /// resolved, what is read never comes from outside
+    #[cfg(unix)]
+    #[test]
+    fn swapped_directories_never_leave_the_home() {
+        let dir = tempfile::tempdir().unwrap();
+        let home = dir.path().join("home");
+        let outside = dir.path().join("outside");
+        std::fs::create_dir_all(home.join("dir")).unwrap();
+        std::fs::create_dir(&outside).unwrap();
+        std::fs::write(home.join("dir/file.txt"), "inside").unwrap();
+        std::fs::write(outside.join("file.txt"), "outside").unwrap();
+        std::os::unix::fs::symlink(&outside, home.join("link")).unwrap();
+        let swapping = {
+            let home = home.clone();
+            std::thread::spawn(move || {
+                for _ in 0..2000 {
+                    std::fs::rename(home.join("dir"), home.join("real")).unwrap();
+                    std::fs::rename(home.join("link"), home.join("dir")).unwrap();
+                    std::fs::rename(home.join("dir"), home.join("link")).unwrap();
+                    std::fs::rename(home.join("real"), home.join("dir")).unwrap();
+                }
+            })
+        };
+        let home_dir = HomeDir::open(&home).unwrap();
+        while !swapping.is_finished() {
+            if let Ok(mut file) = home_dir.open_file(Path::new("/dir/file.txt")) {
+                let mut content = String::new();
+                file.read_to_string(&mut content).unwrap();
+                assert_eq!(content, "inside");
+            }
+        }
+        swapping.join().unwrap();
+    }
+}
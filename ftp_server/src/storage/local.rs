@@ -0,0 +1,335 @@
+//! Files in the homes of the users on the local disk.
+//!
+//! Uploads are written to a hidden file next to the target, `.<name>.<pid>-<n>.ftp-upload`, which
+//! is renamed over it when the upload is committed. Other clients never see a file that is half
+//! written, and an upload that fails leaves the old file as it was. `LIST` hides those files and
+//! `clean_up` removes the ones that a crash left behind.
+//!
+//! Every path is resolved by `HomeDir`, so symlinks and renames can't take a command out of
+//! the home.
+use super::home_dir::{DirHandle, HomeDir};
+use super::{
+    already_finished, DirEntry, Metadata, ReadFile, StorageBackend, WriteFile, PARTIAL_SUFFIX,
+};
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File};
+use std::io::{self, ErrorKind, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// End of the names of the files of the uploads that are running
+const UPLOAD_SUFFIX: &str = ".ftp-upload";
+
+/// Makes the names of the uploads of this process unique
+static UPLOADS: AtomicUsize = AtomicUsize::new(0);
+
+/// Longest file name that the file systems take, `NAME_MAX`
+const NAME_MAX: usize = 255;
+
+/// Backend of the local disk, the homes are directories. Symlinks are followed as long as they
+/// stay inside the home
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LocalStorage;
+
+/// Start of `name` that leaves `room` bytes for the rest of a name of at most `NAME_MAX` bytes
+fn shorten(name: &str, room: usize) -> &str {
+    let mut end = name.len().min(NAME_MAX.saturating_sub(room));
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    &name[..end]
+}
+
+/// Name of the hidden file where an upload to `name` is written until it's committed. Long names
+/// are cut so it's still a valid name
+fn upload_name(name: &OsStr) -> OsString {
+    let upload = UPLOADS.fetch_add(1, Ordering::Relaxed);
+    let end = format!(".{}-{}{}", std::process::id(), upload, UPLOAD_SUFFIX);
+    let name = name.to_string_lossy();
+    OsString::from(format!(".{}{}", shorten(&name, end.len() + 1), end))
+}
+
+/// Name that the bytes of an upload to `name` that didn't finish are kept with
+fn partial_name(name: &OsStr) -> OsString {
+    let mut partial = name.to_os_string();
+    if partial.len() + PARTIAL_SUFFIX.len() > NAME_MAX {
+        partial = shorten(&name.to_string_lossy(), PARTIAL_SUFFIX.len()).into();
+    }
+    partial.push(PARTIAL_SUFFIX);
+    partial
+}
+
+fn is_upload(name: &OsStr) -> bool {
+    let name = name.to_string_lossy();
+    name.starts_with('.') && name.ends_with(UPLOAD_SUFFIX)
+}
+
+/// Removes the files of uploads inside `dir`, at any depth. Symlinks aren't followed
+fn remove_uploads(dir: &Path) -> io::Result<usize> {
+    let mut removed = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            removed += remove_uploads(&entry.path())?;
+        } else if file_type.is_file() && is_upload(&entry.file_name()) {
+            fs::remove_file(entry.path())?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+impl ReadFile for File {
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn as_file(&self) -> Option<&File> {
+        Some(self)
+    }
+}
+
+/// Upload that is written to `upload` and renamed to `name` by `commit`, both in `dir`. It's
+/// removed if the upload is aborted or dropped before
+struct LocalWriteFile {
+    file: File,
+
+    dir: DirHandle,
+
+    upload: OsString,
+
+    name: OsString,
+
+    finished: bool,
+}
+
+impl Write for LocalWriteFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl WriteFile for LocalWriteFile {
+    fn commit(&mut self) -> io::Result<()> {
+        if self.finished {
+            return Err(already_finished());
+        }
+        self.finished = true;
+        let renamed = self
+            .file
+            .sync_all()
+            .and_then(|_| self.dir.rename(&self.upload, &self.name));
+        if renamed.is_err() {
+            let _ = self.dir.remove_file(&self.upload);
+        }
+        renamed
+    }
+
+    fn abort(&mut self) -> io::Result<()> {
+        self.finished = true;
+        self.dir.remove_file(&self.upload)
+    }
+
+    fn keep_partial(&mut self) -> io::Result<()> {
+        if self.finished {
+            return Err(already_finished());
+        }
+        self.finished = true;
+        let partial = partial_name(&self.name);
+        let renamed = self
+            .file
+            .sync_all()
+            .and_then(|_| self.dir.rename(&self.upload, &partial));
+        if renamed.is_err() {
+            let _ = self.dir.remove_file(&self.upload);
+        }
+        renamed
+    }
+}
+
+impl Drop for LocalWriteFile {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = self.abort();
+        }
+    }
+}
+
+impl StorageBackend for LocalStorage {
+    fn open_read(&self, home: &Path, path: &Path) -> io::Result<Box<dyn ReadFile>> {
+        Ok(Box::new(HomeDir::open(home)?.open_file(path)?))
+    }
+
+    fn open_write(&self, home: &Path, path: &Path) -> io::Result<Box<dyn WriteFile>> {
+        let name = path.file_name().unwrap_or_default().to_os_string();
+        // The rename replaces a symlink instead of writing to its target, which may be outside of
+        // the home
+        let upload = upload_name(&name);
+        let (file, dir) = HomeDir::open(home)?.create_next_to(path, &upload)?;
+        Ok(Box::new(LocalWriteFile {
+            file,
+            dir,
+            upload,
+            name,
+            finished: false,
+        }))
+    }
+
+    fn list(&self, home: &Path, path: &Path) -> io::Result<Vec<DirEntry>> {
+        let mut entries = HomeDir::open(home)?.read_dir(path)?;
+        entries.retain(|entry| !is_upload(&entry.name));
+        Ok(entries)
+    }
+
+    fn stat(&self, home: &Path, path: &Path) -> io::Result<Metadata> {
+        HomeDir::open(home)?.metadata(path)
+    }
+
+    fn mkdir(&self, home: &Path, path: &Path) -> io::Result<()> {
+        HomeDir::open(home)?.create_dir(path)
+    }
+
+    fn rmdir(&self, home: &Path, path: &Path, recursive: bool) -> io::Result<()> {
+        let home = HomeDir::open(home)?;
+        if recursive {
+            home.remove_dir_all(path)
+        } else {
+            home.remove_dir(path)
+        }
+    }
+
+    fn delete(&self, home: &Path, path: &Path) -> io::Result<()> {
+        HomeDir::open(home)?.remove_file(path)
+    }
+
+    fn rename(&self, home: &Path, from: &Path, to: &Path) -> io::Result<()> {
+        HomeDir::open(home)?.rename(from, to)
+    }
+
+    fn clean_up(&self, root: &Path) -> io::Result<usize> {
+        match remove_uploads(root) {
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(0),
+            result => result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::LocalStorage;
+    use crate::storage::StorageBackend;
+    use std::io::{ErrorKind, Read, Write};
+    use std::path::Path;
+
+    #[test]
+    fn local_storage_stays_in_the_home() {
+        let dir = tempfile::tempdir().unwrap();
+        let home = dir.path().join("home");
+        std::fs::create_dir(&home).unwrap();
+        std::fs::write(dir.path().join("secret.txt"), "secret").unwrap();
+        let storage = LocalStorage;
+        storage.mkdir(&home, Path::new("/docs")).unwrap();
+        let mut file = storage.open_write(&home, Path::new("/docs/a.txt")).unwrap();
+        file.write_all(b"Hello").unwrap();
+        file.commit().unwrap();
+        let mut read = String::new();
+        let mut file = storage.open_read(&home, Path::new("/docs/a.txt")).unwrap();
+        file.read_to_string(&mut read).unwrap();
+        assert_eq!(read, "Hello");
+        assert_eq!(file.size().unwrap(), 5);
+        assert!(file.as_file().is_some());
+        let entries = storage.list(&home, Path::new("/docs")).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].name, "a.txt");
+        assert!(storage.stat(&home, Path::new("/docs")).unwrap().is_dir);
+        storage
+            .rename(&home, Path::new("/docs/a.txt"), Path::new("/b.txt"))
+            .unwrap();
+        assert_eq!(storage.stat(&home, Path::new("/b.txt")).unwrap().len, 5);
+        // Removing a directory that isn't empty needs `recursive`
+        storage.mkdir(&home, Path::new("/docs/inner")).unwrap();
+        assert!(storage.rmdir(&home, Path::new("/docs"), false).is_err());
+        storage.rmdir(&home, Path::new("/docs"), true).unwrap();
+        // Uploads replace the file when they are committed, and not before
+        let mut file = storage.open_write(&home, Path::new("/b.txt")).unwrap();
+        file.write_all(b"World").unwrap();
+        assert_eq!(storage.list(&home, Path::new("/")).unwrap().len(), 1);
+        assert_eq!(
+            std::fs::read_to_string(home.join("b.txt")).unwrap(),
+            "Hello"
+        );
+        file.commit().unwrap();
+        assert_eq!(
+            std::fs::read_to_string(home.join("b.txt")).unwrap(),
+            "World"
+        );
+        let mut file = storage.open_write(&home, Path::new("/b.txt")).unwrap();
+        file.write_all(b"partial").unwrap();
+        file.abort().unwrap();
+        assert!(file.commit().is_err());
+        let file = storage.open_write(&home, Path::new("/c.txt")).unwrap();
+        drop(file);
+        assert_eq!(std::fs::read_dir(&home).unwrap().count(), 1);
+        assert_eq!(
+            std::fs::read_to_string(home.join("b.txt")).unwrap(),
+            "World"
+        );
+        // What a crash left behind is removed when the server starts
+        std::fs::create_dir(home.join("docs")).unwrap();
+        std::fs::write(home.join("docs/.d.txt.1-0.ftp-upload"), "partial").unwrap();
+        std::fs::write(home.join("docs/.hidden"), "kept").unwrap();
+        assert_eq!(storage.clean_up(dir.path()).unwrap(), 1);
+        assert_eq!(std::fs::read_dir(home.join("docs")).unwrap().count(), 1);
+        std::fs::remove_dir_all(home.join("docs")).unwrap();
+        storage.delete(&home, Path::new("/b.txt")).unwrap();
+        assert!(storage.list(&home, Path::new("/")).unwrap().is_empty());
+        assert!(storage.rmdir(&home, Path::new("/"), true).is_err());
+        // A symlink can't take a client out of its home
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(dir.path(), home.join("out")).unwrap();
+            let err = storage
+                .open_read(&home, Path::new("/out/secret.txt"))
+                .err()
+                .unwrap();
+            assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+            assert!(storage.list(&home, Path::new("/out")).is_err());
+            assert!(storage
+                .open_write(&home, Path::new("/out/new.txt"))
+                .is_err());
+            assert!(!dir.path().join("new.txt").exists());
+        }
+    }
+    #[test]
+    fn local_storage_keeps_partial_uploads_apart() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = LocalStorage;
+        std::fs::write(dir.path().join("a.txt"), "old").unwrap();
+        let mut file = storage.open_write(dir.path(), Path::new("/a.txt")).unwrap();
+        file.write_all(b"new, but half").unwrap();
+        file.keep_partial().unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("a.txt")).unwrap(),
+            "old"
+        );
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("a.txt.partial")).unwrap(),
+            "new, but half"
+        );
+        // The names of the upload and of what's kept are cut to fit in `NAME_MAX`
+        let long = format!("/{}", "é".repeat(127));
+        let mut file = storage.open_write(dir.path(), Path::new(&long)).unwrap();
+        file.write_all(b"long").unwrap();
+        file.keep_partial().unwrap();
+        let mut file = storage.open_write(dir.path(), Path::new(&long)).unwrap();
+        file.write_all(b"long").unwrap();
+        file.commit().unwrap();
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 4);
+        assert_eq!(std::fs::read(dir.path().join(&long[1..])).unwrap(), b"long");
+    }
+}
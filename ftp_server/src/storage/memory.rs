@@ -0,0 +1,420 @@
+//! Files kept in memory, nothing is written to the disk. For the tests and for servers whose files
+//! don't need to outlive them
+use super::{
+    already_finished, DirEntry, Metadata, ReadFile, StorageBackend, WriteFile, PARTIAL_SUFFIX,
+};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Cursor, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+#[derive(Debug, Clone)]
+enum Node {
+    Dir(SystemTime),
+
+    File(Arc<[u8]>, SystemTime),
+}
+
+impl Node {
+    fn metadata(&self) -> Metadata {
+        match self {
+            Node::Dir(modified) => Metadata {
+                is_dir: true,
+                len: 0,
+                modified: Some(*modified),
+            },
+            Node::File(data, modified) => Metadata {
+                is_dir: false,
+                len: data.len() as u64,
+                modified: Some(*modified),
+            },
+        }
+    }
+}
+
+/// Entries of a home by their virtual path, the home itself (`/`) isn't in it
+type Tree = BTreeMap<PathBuf, Node>;
+
+/// Backend that keeps every home in memory. A home exists, empty, as soon as it's used, so the
+/// users don't need anything on the disk. Clones share the files, a test can keep one to look at
+/// what the clients did
+#[derive(Debug, Default, Clone)]
+pub struct MemoryStorage {
+    homes: Arc<Mutex<HashMap<PathBuf, Tree>>>,
+}
+
+fn not_found() -> io::Error {
+    io::Error::from(ErrorKind::NotFound)
+}
+
+fn is_root(path: &Path) -> bool {
+    path.parent().is_none()
+}
+
+fn node(tree: &Tree, path: &Path) -> io::Result<Node> {
+    if is_root(path) {
+        return Ok(Node::Dir(SystemTime::UNIX_EPOCH));
+    }
+    tree.get(path).cloned().ok_or_else(not_found)
+}
+
+/// Checks that the directory where `path` would be exists
+fn check_parent(tree: &Tree, path: &Path) -> io::Result<()> {
+    match path.parent().map(|parent| node(tree, parent)) {
+        Some(Ok(Node::Dir(_))) => Ok(()),
+        Some(Ok(Node::File(..))) => Err(io::Error::other("the parent is not a directory")),
+        Some(Err(err)) => Err(err),
+        None => Err(io::Error::new(
+            ErrorKind::PermissionDenied,
+            "the home can't be changed",
+        )),
+    }
+}
+
+/// Paths of `tree` inside the directory `path`, at any depth
+fn descendants(tree: &Tree, path: &Path) -> Vec<PathBuf> {
+    tree.keys()
+        .filter(|key| key.starts_with(path) && *key != path)
+        .cloned()
+        .collect()
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn with_tree<T, F: FnOnce(&mut Tree) -> io::Result<T>>(
+        &self,
+        home: &Path,
+        f: F,
+    ) -> io::Result<T> {
+        let mut homes = self.homes.lock().unwrap();
+        f(homes.entry(home.to_path_buf()).or_default())
+    }
+
+    /// Creates the file `path` of `home` with `contents`, and its directories if they don't exist
+    pub fn write<C: AsRef<[u8]>>(&self, home: &Path, path: &Path, contents: C) -> io::Result<()> {
+        self.with_tree(home, |tree| {
+            let now = SystemTime::now();
+            for dir in path.ancestors().skip(1).filter(|dir| !is_root(dir)) {
+                match tree.get(dir) {
+                    Some(Node::File(..)) => return Err(io::Error::from(ErrorKind::AlreadyExists)),
+                    Some(Node::Dir(_)) => {}
+                    None => {
+                        tree.insert(dir.to_path_buf(), Node::Dir(now));
+                    }
+                }
+            }
+            if let Some(Node::Dir(_)) = tree.get(path) {
+                return Err(io::Error::from(ErrorKind::AlreadyExists));
+            }
+            tree.insert(
+                path.to_path_buf(),
+                Node::File(contents.as_ref().into(), now),
+            );
+            Ok(())
+        })
+    }
+
+    /// Contents of the file `path` of `home`
+    pub fn read(&self, home: &Path, path: &Path) -> Option<Vec<u8>> {
+        match self.with_tree(home, |tree| node(tree, path)) {
+            Ok(Node::File(data, _)) => Some(data.to_vec()),
+            _ => None,
+        }
+    }
+
+    /// Every path of `home` in order, directories end with `/`. E.g. `["/docs/", "/docs/a.txt"]`
+    pub fn tree(&self, home: &Path) -> Vec<String> {
+        let homes = self.homes.lock().unwrap();
+        let tree = match homes.get(home) {
+            Some(tree) => tree,
+            None => return Vec::new(),
+        };
+        tree.iter()
+            .map(|(path, node)| match node {
+                Node::Dir(_) => format!("{}/", path.to_string_lossy()),
+                Node::File(..) => path.to_string_lossy().into_owned(),
+            })
+            .collect()
+    }
+}
+
+/// Snapshot of a file, later uploads to the same path don't change a download that started
+struct MemoryReadFile(Cursor<Arc<[u8]>>);
+
+impl Read for MemoryReadFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Seek for MemoryReadFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.0.seek(pos)
+    }
+}
+
+impl ReadFile for MemoryReadFile {
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.0.get_ref().len() as u64)
+    }
+}
+
+/// Upload that is kept in a buffer until it's committed, so the file is replaced at once. An
+/// upload that is dropped without `commit` is aborted
+struct MemoryWriteFile {
+    storage: MemoryStorage,
+
+    home: PathBuf,
+
+    path: PathBuf,
+
+    buffer: Vec<u8>,
+
+    finished: bool,
+}
+
+impl MemoryWriteFile {
+    fn store(&mut self, path: PathBuf) -> io::Result<()> {
+        if self.finished {
+            return Err(already_finished());
+        }
+        self.finished = true;
+        let data = std::mem::take(&mut self.buffer);
+        self.storage.with_tree(&self.home, |tree| {
+            // The directory could be gone by now, or the file could be a directory
+            check_parent(tree, &path)?;
+            if let Some(Node::Dir(_)) = tree.get(&path) {
+                return Err(io::Error::other("is a directory"));
+            }
+            tree.insert(path, Node::File(data.into(), SystemTime::now()));
+            Ok(())
+        })
+    }
+}
+
+impl Write for MemoryWriteFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl WriteFile for MemoryWriteFile {
+    fn commit(&mut self) -> io::Result<()> {
+        self.store(self.path.clone())
+    }
+
+    fn abort(&mut self) -> io::Result<()> {
+        self.finished = true;
+        self.buffer.clear();
+        Ok(())
+    }
+
+    fn keep_partial(&mut self) -> io::Result<()> {
+        let mut partial = self.path.clone().into_os_string();
+        partial.push(PARTIAL_SUFFIX);
+        self.store(partial.into())
+    }
+}
+
+impl Drop for MemoryWriteFile {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = self.abort();
+        }
+    }
+}
+
+impl StorageBackend for MemoryStorage {
+    fn open_read(&self, home: &Path, path: &Path) -> io::Result<Box<dyn ReadFile>> {
+        match self.with_tree(home, |tree| node(tree, path))? {
+            Node::File(data, _) => Ok(Box::new(MemoryReadFile(Cursor::new(data)))),
+            Node::Dir(_) => Err(io::Error::other("is a directory")),
+        }
+    }
+
+    fn open_write(&self, home: &Path, path: &Path) -> io::Result<Box<dyn WriteFile>> {
+        self.with_tree(home, |tree| {
+            check_parent(tree, path)?;
+            if let Some(Node::Dir(_)) = tree.get(path) {
+                return Err(io::Error::other("is a directory"));
+            }
+            Ok(())
+        })?;
+        Ok(Box::new(MemoryWriteFile {
+            storage: self.clone(),
+            home: home.to_path_buf(),
+            path: path.to_path_buf(),
+            buffer: Vec::new(),
+            finished: false,
+        }))
+    }
+
+    fn list(&self, home: &Path, path: &Path) -> io::Result<Vec<DirEntry>> {
+        self.with_tree(home, |tree| {
+            if let Node::File(..) = node(tree, path)? {
+                return Err(io::Error::other("not a directory"));
+            }
+            Ok(tree
+                .iter()
+                .filter(|(key, _)| key.parent() == Some(path))
+                .map(|(key, node)| DirEntry {
+                    name: key.file_name().unwrap_or_default().to_os_string(),
+                    metadata: node.metadata(),
+                })
+                .collect())
+        })
+    }
+
+    fn stat(&self, home: &Path, path: &Path) -> io::Result<Metadata> {
+        self.with_tree(home, |tree| Ok(node(tree, path)?.metadata()))
+    }
+
+    fn mkdir(&self, home: &Path, path: &Path) -> io::Result<()> {
+        self.with_tree(home, |tree| {
+            check_parent(tree, path)?;
+            if tree.contains_key(path) {
+                return Err(io::Error::from(ErrorKind::AlreadyExists));
+            }
+            tree.insert(path.to_path_buf(), Node::Dir(SystemTime::now()));
+            Ok(())
+        })
+    }
+
+    fn rmdir(&self, home: &Path, path: &Path, recursive: bool) -> io::Result<()> {
+        self.with_tree(home, |tree| {
+            check_parent(tree, path)?;
+            match tree.get(path) {
+                Some(Node::Dir(_)) => {}
+                Some(Node::File(..)) => return Err(io::Error::other("not a directory")),
+                None => return Err(not_found()),
+            }
+            let inside = descendants(tree, path);
+            if !inside.is_empty() && !recursive {
+                return Err(io::Error::other("the directory is not empty"));
+            }
+            for key in inside {
+                tree.remove(&key);
+            }
+            tree.remove(path);
+            Ok(())
+        })
+    }
+
+    fn delete(&self, home: &Path, path: &Path) -> io::Result<()> {
+        self.with_tree(home, |tree| match tree.get(path) {
+            Some(Node::File(..)) => {
+                tree.remove(path);
+                Ok(())
+            }
+            Some(Node::Dir(_)) => Err(io::Error::other("is a directory")),
+            None => Err(not_found()),
+        })
+    }
+
+    fn rename(&self, home: &Path, from: &Path, to: &Path) -> io::Result<()> {
+        self.with_tree(home, |tree| {
+            check_parent(tree, from)?;
+            check_parent(tree, to)?;
+            let node = tree.get(from).cloned().ok_or_else(not_found)?;
+            if tree.contains_key(to) {
+                return Err(io::Error::from(ErrorKind::AlreadyExists));
+            }
+            if to.starts_with(from) {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    "a directory can't be moved inside itself",
+                ));
+            }
+            for key in descendants(tree, from) {
+                let moved = to.join(key.strip_prefix(from).unwrap());
+                if let Some(node) = tree.remove(&key) {
+                    tree.insert(moved, node);
+                }
+            }
+            tree.remove(from);
+            tree.insert(to.to_path_buf(), node);
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::MemoryStorage;
+    use crate::storage::StorageBackend;
+    use std::io::{Read, Write};
+    use std::path::Path;
+
+    #[test]
+    fn memory_storage_keeps_a_tree() {
+        let storage = MemoryStorage::new();
+        let home = Path::new("/homes/bob");
+        let path = |path: &'static str| Path::new(path);
+        storage.write(home, path("/docs/a.txt"), "Hello").unwrap();
+        assert_eq!(storage.tree(home), ["/docs/", "/docs/a.txt"]);
+        // Other homes don't see it
+        assert!(storage.tree(path("/homes/alice")).is_empty());
+
+        let mut file = storage.open_write(home, path("/b.txt")).unwrap();
+        file.write_all(b"World").unwrap();
+        // Nothing is there until the upload is committed
+        assert!(storage.read(home, path("/b.txt")).is_none());
+        file.commit().unwrap();
+        let mut read = String::new();
+        let mut file = storage.open_read(home, path("/b.txt")).unwrap();
+        file.read_to_string(&mut read).unwrap();
+        assert_eq!(read, "World");
+        assert_eq!(file.size().unwrap(), 5);
+        assert!(file.as_file().is_none());
+        let mut file = storage.open_write(home, path("/b.txt")).unwrap();
+        file.write_all(b"partial").unwrap();
+        file.abort().unwrap();
+        let mut file = storage.open_write(home, path("/c.txt")).unwrap();
+        file.write_all(b"partial").unwrap();
+        drop(file);
+        assert_eq!(storage.read(home, path("/b.txt")).unwrap(), b"World");
+        assert!(storage.read(home, path("/c.txt")).is_none());
+        assert!(storage.open_write(home, path("/missing/c.txt")).is_err());
+
+        storage.mkdir(home, path("/docs/inner")).unwrap();
+        assert!(storage.mkdir(home, path("/docs")).is_err());
+        storage
+            .rename(home, path("/docs"), path("/papers"))
+            .unwrap();
+        assert!(storage
+            .rename(home, path("/papers"), path("/papers/in"))
+            .is_err());
+        assert_eq!(
+            storage.tree(home),
+            ["/b.txt", "/papers/", "/papers/a.txt", "/papers/inner/"]
+        );
+        let names: Vec<_> = storage
+            .list(home, path("/papers"))
+            .unwrap()
+            .into_iter()
+            .map(|entry| (entry.name.into_string().unwrap(), entry.metadata.is_dir))
+            .collect();
+        assert_eq!(
+            names,
+            [("a.txt".to_string(), false), ("inner".to_string(), true)]
+        );
+        assert_eq!(storage.stat(home, path("/papers/a.txt")).unwrap().len, 5);
+        assert!(storage.stat(home, path("/")).unwrap().is_dir);
+
+        assert!(storage.delete(home, path("/papers")).is_err());
+        assert!(storage.rmdir(home, path("/papers"), false).is_err());
+        storage.rmdir(home, path("/papers"), true).unwrap();
+        storage.delete(home, path("/b.txt")).unwrap();
+        assert!(storage.rmdir(home, path("/"), true).is_err());
+        assert!(storage.tree(home).is_empty());
+    }
+}
@@ -0,0 +1,172 @@
+//! Where the files of the users are kept. Every command of the mio engine that touches a file goes
+//! through a `StorageBackend`, the local disk (`LocalStorage`) is the default one.
+//!
+//! The backends get the home of the user and a virtual path: absolute and without `.` or `..`,
+//! like `/docs/a.txt` (see `resolve`). They map it inside the home and make sure that it doesn't
+//! leave it, so a backend is the only place where the chroot of the users is enforced.
+use crate::ftp::config::{Backend, StorageConfig};
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{self, Read, Seek, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+pub mod home_dir;
+pub mod local;
+pub mod memory;
+pub mod s3;
+#[cfg(test)]
+pub(crate) mod s3_testing;
+pub mod trash;
+
+pub use local::LocalStorage;
+pub use memory::MemoryStorage;
+pub use s3::S3Storage;
+
+/// End of the name that the bytes of an upload that didn't finish are kept with, next to its file
+pub const PARTIAL_SUFFIX: &str = ".partial";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Metadata {
+    pub is_dir: bool,
+
+    /// Size in bytes, 0 for directories
+    pub len: u64,
+
+    pub modified: Option<SystemTime>,
+}
+
+/// Entry of a directory, see `StorageBackend::list`
+#[derive(Debug, Clone, PartialEq)]
+pub struct DirEntry {
+    pub name: OsString,
+
+    pub metadata: Metadata,
+}
+
+/// File opened for a download
+pub trait ReadFile: Read + Seek + Send {
+    /// Size in bytes
+    fn size(&self) -> io::Result<u64>;
+
+    /// The file on the disk, so it can be sent with `sendfile(2)`
+    fn as_file(&self) -> Option<&File> {
+        None
+    }
+}
+
+/// Error of `commit` and `keep_partial` on an upload that was already committed or aborted
+fn already_finished() -> io::Error {
+    io::Error::other("the upload already finished")
+}
+
+/// File opened for an upload. The file isn't replaced until `commit`, so other clients never
+/// read an upload that is running, and dropping it without `commit` aborts the upload
+pub trait WriteFile: Write + Send {
+    /// Every byte of the upload was written, the file is replaced with them. It fails if the
+    /// upload was already committed or aborted
+    fn commit(&mut self) -> io::Result<()> {
+        self.flush()
+    }
+
+    /// The upload didn't finish, what was written is thrown away and the file stays as it was
+    fn abort(&mut self) -> io::Result<()>;
+
+    /// The upload didn't finish, what was written is kept as `<name>.partial` next to the file,
+    /// which stays as it was. Backends that can't keep it throw it away
+    fn keep_partial(&mut self) -> io::Result<()> {
+        self.abort()
+    }
+}
+
+/// Files of the users, shared by every worker. Paths are virtual paths inside `home`, errors
+/// are `NotFound` for paths that don't exist and `PermissionDenied` for ones outside of `home`
+pub trait StorageBackend: Send + Sync {
+    fn open_read(&self, home: &Path, path: &Path) -> io::Result<Box<dyn ReadFile>>;
+
+    /// Starts an upload to the file, which is created or replaced when it's committed. Its
+    /// directory must exist
+    fn open_write(&self, home: &Path, path: &Path) -> io::Result<Box<dyn WriteFile>>;
+
+    /// Entries of the directory `path`, in no particular order
+    fn list(&self, home: &Path, path: &Path) -> io::Result<Vec<DirEntry>>;
+
+    fn stat(&self, home: &Path, path: &Path) -> io::Result<Metadata>;
+
+    /// Creates the directory, its parent must exist
+    fn mkdir(&self, home: &Path, path: &Path) -> io::Result<()>;
+
+    /// Removes the directory, which must be empty unless `recursive`
+    fn rmdir(&self, home: &Path, path: &Path, recursive: bool) -> io::Result<()>;
+
+    /// Removes the file
+    fn delete(&self, home: &Path, path: &Path) -> io::Result<()>;
+
+    /// Moves a file or a directory, `to` must not exist
+    fn rename(&self, home: &Path, from: &Path, to: &Path) -> io::Result<()>;
+
+    /// Removes what the uploads that didn't finish left in the homes inside `root`, it's called
+    /// when the server starts. Returns how many files were removed
+    fn clean_up(&self, _root: &Path) -> io::Result<usize> {
+        Ok(0)
+    }
+}
+
+/// Backend of the `[storage]` section, fails if the `s3` one has no credentials
+pub fn from_config(config: &StorageConfig) -> io::Result<Arc<dyn StorageBackend>> {
+    Ok(match (config.backend, &config.s3) {
+        (Backend::Local, _) => Arc::new(LocalStorage),
+        (Backend::Memory, _) => Arc::new(MemoryStorage::new()),
+        (Backend::S3, Some(s3)) => Arc::new(S3Storage::new(s3.clone())?),
+        (Backend::S3, None) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the s3 storage needs a [storage.s3] section",
+            ))
+        }
+    })
+}
+
+/// Virtual path of `path` for a client whose current directory is `cwd`, absolute paths start at
+/// the home. Returns None if `..` goes above the home
+pub fn resolve(cwd: &Path, path: &Path) -> Option<PathBuf> {
+    let mut resolved = PathBuf::from("/");
+    for component in cwd.join(path).components() {
+        match component {
+            Component::Normal(name) => resolved.push(name),
+            Component::ParentDir => {
+                if !resolved.pop() {
+                    return None;
+                }
+            }
+            Component::RootDir | Component::CurDir | Component::Prefix(_) => {}
+        }
+    }
+    Some(resolved)
+}
+
+/// `path` without its leading `/`, to join it to a home
+pub fn relative(path: &Path) -> &Path {
+    path.strip_prefix("/").unwrap_or(path)
+}
+
+#[cfg(test)]
+mod test {
+    use super::resolve;
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn paths_are_resolved_inside_the_home() {
+        let resolved = |cwd: &str, path: &str| resolve(Path::new(cwd), Path::new(path));
+        assert_eq!(resolved("/", "a.txt"), Some(PathBuf::from("/a.txt")));
+        assert_eq!(
+            resolved("/docs", "./a/../b"),
+            Some(PathBuf::from("/docs/b"))
+        );
+        assert_eq!(resolved("/docs", "/b"), Some(PathBuf::from("/b")));
+        assert_eq!(resolved("/docs", ".."), Some(PathBuf::from("/")));
+        assert_eq!(resolved("/docs", "../.."), None);
+        assert_eq!(resolved("/", "a//b/"), Some(PathBuf::from("/a/b")));
+    }
+}
@@ -0,0 +1,482 @@
+//! S3-compatible stand-in for the tests of `S3Storage`.
+//!
+//! `S3TestServer` listens on an ephemeral port of 127.0.0.1 and keeps one bucket in memory. It
+//! answers the requests that `S3Storage` makes, one connection at a time: `ListObjectsV2` (in
+//! pages of `PAGE` entries, so the continuation is tested too), `HEAD`, ranged `GET`, `PUT`,
+//! copies, multipart uploads and `DELETE`. Requests whose SigV4 signature isn't the one of the
+//! credentials of `config` are refused with a 403.
+use super::s3::{sign, Credentials};
+use crate::ftp::config::S3Config;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+/// Entries of every page of a listing
+const PAGE: usize = 2;
+
+/// Bytes of every part of the uploads of `config`, tiny so small files are multipart uploads
+const PART_SIZE: usize = 16;
+
+#[derive(Default)]
+struct Bucket {
+    objects: BTreeMap<String, Vec<u8>>,
+
+    /// Parts of the multipart uploads that are in progress, by upload id
+    uploads: HashMap<String, BTreeMap<u32, Vec<u8>>>,
+
+    next_upload: usize,
+
+    /// `<method> <key>[?<query names>]` of every request
+    requests: Vec<String>,
+}
+
+pub struct S3TestServer {
+    address: SocketAddr,
+
+    bucket: Arc<Mutex<Bucket>>,
+
+    stop: Arc<AtomicBool>,
+
+    thread: Option<JoinHandle<()>>,
+}
+
+impl S3TestServer {
+    pub fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let bucket = Arc::new(Mutex::new(Bucket::default()));
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let (bucket, stop) = (bucket.clone(), stop.clone());
+            std::thread::spawn(move || {
+                for stream in listener.incoming() {
+                    if stop.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    if let Ok(stream) = stream {
+                        let _ = serve(stream, &bucket);
+                    }
+                }
+            })
+        };
+        Self {
+            address,
+            bucket,
+            stop,
+            thread: Some(thread),
+        }
+    }
+
+    /// Config of a `S3Storage` for this server, with the prefix `ftp`
+    pub fn config(&self) -> S3Config {
+        S3Config {
+            endpoint: format!("http://{}", self.address),
+            bucket: "ftp-test".to_string(),
+            region: "us-east-1".to_string(),
+            access_key: Some("test".to_string()),
+            secret_key: Some("secret".to_string()),
+            prefix: "ftp".to_string(),
+            part_size: PART_SIZE,
+        }
+    }
+
+    /// Keys of every object, sorted
+    pub fn keys(&self) -> Vec<String> {
+        self.bucket
+            .lock()
+            .unwrap()
+            .objects
+            .keys()
+            .cloned()
+            .collect()
+    }
+
+    pub fn object(&self, key: &str) -> Option<Vec<u8>> {
+        self.bucket.lock().unwrap().objects.get(key).cloned()
+    }
+
+    pub fn requests(&self) -> Vec<String> {
+        self.bucket.lock().unwrap().requests.clone()
+    }
+}
+
+impl Drop for S3TestServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        // Wakes up the `accept`
+        let _ = TcpStream::connect(self.address);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+struct Request {
+    method: String,
+
+    /// Path and query as they were sent, to check the signature
+    path: String,
+
+    raw_query: String,
+
+    key: String,
+
+    query: Vec<(String, String)>,
+
+    headers: HashMap<String, String>,
+
+    body: Vec<u8>,
+}
+
+impl Request {
+    fn query(&self, name: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).map(String::as_str)
+    }
+}
+
+struct Response {
+    status: u16,
+
+    headers: Vec<(String, String)>,
+
+    body: Vec<u8>,
+
+    /// Length of the body for a `HEAD`, which has none
+    len: Option<usize>,
+}
+
+fn response(status: u16, body: impl Into<Vec<u8>>) -> Response {
+    Response {
+        status,
+        headers: Vec::new(),
+        body: body.into(),
+        len: None,
+    }
+}
+
+fn serve(stream: TcpStream, bucket: &Mutex<Bucket>) -> std::io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_string();
+    let target = parts.next().unwrap_or_default().to_string();
+    let mut headers = HashMap::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.insert(name.trim().to_ascii_lowercase(), value.trim().to_string());
+        }
+    }
+    let len = headers
+        .get("content-length")
+        .and_then(|len| len.parse().ok())
+        .unwrap_or(0);
+    let mut body = vec![0; len];
+    reader.read_exact(&mut body)?;
+    let (path, query_string) = target.split_once('?').unwrap_or((&target, ""));
+    let key = decode(path)
+        .trim_start_matches('/')
+        .split_once('/')
+        .map(|(_, key)| key.to_string())
+        .unwrap_or_default();
+    let query = query_string
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (decode(name), decode(value))
+        })
+        .collect();
+    let request = Request {
+        method,
+        path: path.to_string(),
+        raw_query: query_string.to_string(),
+        key,
+        query,
+        headers,
+        body,
+    };
+    let response = handle(&request, &mut bucket.lock().unwrap());
+    let mut stream = stream;
+    let reason = match response.status {
+        200 => "OK",
+        204 => "No Content",
+        206 => "Partial Content",
+        403 => "Forbidden",
+        404 => "Not Found",
+        _ => "Error",
+    };
+    let mut head = format!(
+        "HTTP/1.1 {} {}\r\nConnection: close\r\nContent-Length: {}\r\n",
+        response.status,
+        reason,
+        response.len.unwrap_or(response.body.len())
+    );
+    for (name, value) in &response.headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(&response.body)?;
+    stream.flush()
+}
+
+fn handle(request: &Request, bucket: &mut Bucket) -> Response {
+    let mut names: Vec<&str> = request
+        .query
+        .iter()
+        .map(|(name, _)| name.as_str())
+        .collect();
+    names.sort_unstable();
+    let mut logged = format!("{} {}", request.method, request.key);
+    if !names.is_empty() {
+        logged.push('?');
+        logged.push_str(&names.join("&"));
+    }
+    bucket.requests.push(logged);
+    if !signed(request) {
+        return response(403, "<Error><Code>AccessDenied</Code></Error>");
+    }
+    let key = request.key.as_str();
+    match request.method.as_str() {
+        "GET" if key.is_empty() => list(request, bucket),
+        "GET" | "HEAD" => {
+            let object = match bucket.objects.get(key) {
+                Some(object) => object,
+                None => return response(404, ""),
+            };
+            let mut answer = match request.header("range").and_then(range) {
+                Some((first, last)) => {
+                    let last = last.min(object.len().saturating_sub(1));
+                    response(206, object.get(first..=last).unwrap_or_default())
+                }
+                None => response(200, object.clone()),
+            };
+            let modified = chrono::Utc::now().to_rfc2822();
+            answer.headers.push(("Last-Modified".to_string(), modified));
+            if request.method == "HEAD" {
+                answer.len = Some(object.len());
+                answer.body.clear();
+            }
+            answer
+        }
+        "PUT" => {
+            if let Some(source) = request.header("x-amz-copy-source") {
+                let source = decode(source);
+                let source = source.trim_start_matches('/').split_once('/').unwrap().1;
+                return match bucket.objects.get(source).cloned() {
+                    Some(object) => {
+                        bucket.objects.insert(key.to_string(), object);
+                        response(200, "<CopyObjectResult></CopyObjectResult>")
+                    }
+                    None => response(404, ""),
+                };
+            }
+            if let (Some(number), Some(id)) =
+                (request.query("partNumber"), request.query("uploadId"))
+            {
+                return match bucket.uploads.get_mut(id) {
+                    Some(parts) => {
+                        let number: u32 = number.parse().unwrap();
+                        parts.insert(number, request.body.clone());
+                        let mut answer = response(200, "");
+                        let etag = format!("\"{}-{}\"", id, number);
+                        answer.headers.push(("ETag".to_string(), etag));
+                        answer
+                    }
+                    None => response(404, ""),
+                };
+            }
+            bucket.objects.insert(key.to_string(), request.body.clone());
+            response(200, "")
+        }
+        "POST" if request.query("uploads").is_some() => {
+            bucket.next_upload += 1;
+            let id = format!("upload{}", bucket.next_upload);
+            bucket.uploads.insert(id.clone(), BTreeMap::new());
+            response(
+                200,
+                format!(
+                    "<InitiateMultipartUploadResult><Key>{}</Key><UploadId>{}</UploadId>\
+                     </InitiateMultipartUploadResult>",
+                    escape(key),
+                    id
+                ),
+            )
+        }
+        "POST" => match request
+            .query("uploadId")
+            .and_then(|id| bucket.uploads.remove(id))
+        {
+            Some(parts) => {
+                bucket
+                    .objects
+                    .insert(key.to_string(), parts.into_values().flatten().collect());
+                response(
+                    200,
+                    "<CompleteMultipartUploadResult></CompleteMultipartUploadResult>",
+                )
+            }
+            None => response(404, ""),
+        },
+        "DELETE" => {
+            match request.query("uploadId") {
+                Some(id) => {
+                    bucket.uploads.remove(id);
+                }
+                None => {
+                    bucket.objects.remove(key);
+                }
+            }
+            response(204, "")
+        }
+        _ => response(400, ""),
+    }
+}
+
+fn list(request: &Request, bucket: &Bucket) -> Response {
+    let prefix = request.query("prefix").unwrap_or_default();
+    let delimiter = request.query("delimiter");
+    let max_keys = request
+        .query("max-keys")
+        .map_or(PAGE, |max| max.parse::<usize>().unwrap().min(PAGE));
+    // Keys and common prefixes, sorted together like S3 does
+    let mut entries = BTreeSet::new();
+    for key in bucket.objects.keys().filter(|key| key.starts_with(prefix)) {
+        let rest = &key[prefix.len()..];
+        match delimiter.and_then(|delimiter| rest.find(delimiter).map(|i| i + delimiter.len())) {
+            Some(end) => entries.insert((format!("{}{}", prefix, &rest[..end]), true)),
+            None => entries.insert((key.clone(), false)),
+        };
+    }
+    let after = request.query("continuation-token").unwrap_or_default();
+    let remaining: Vec<_> = entries
+        .into_iter()
+        .filter(|(name, _)| name.as_str() > after)
+        .collect();
+    let mut xml = String::from("<ListBucketResult>");
+    for (name, is_prefix) in remaining.iter().take(max_keys) {
+        if *is_prefix {
+            xml.push_str(&format!(
+                "<CommonPrefixes><Prefix>{}</Prefix></CommonPrefixes>",
+                escape(name)
+            ));
+        } else {
+            xml.push_str(&format!(
+                "<Contents><Key>{}</Key><LastModified>{}</LastModified><Size>{}</Size></Contents>",
+                escape(name),
+                chrono::Utc::now().to_rfc3339(),
+                bucket.objects[name].len()
+            ));
+        }
+    }
+    if remaining.len() > max_keys {
+        xml.push_str(&format!(
+            "<IsTruncated>true</IsTruncated><NextContinuationToken>{}</NextContinuationToken>",
+            escape(&remaining[max_keys - 1].0)
+        ));
+    } else {
+        xml.push_str("<IsTruncated>false</IsTruncated>");
+    }
+    xml.push_str("</ListBucketResult>");
+    response(200, xml)
+}
+
+/// Signs the request again with the credentials of `config` and compares it with its
+/// `Authorization`, and the body with its `x-amz-content-sha256`
+fn signed(request: &Request) -> bool {
+    let (authorization, amz_date, payload_hash) = match (
+        request.header("authorization"),
+        request.header("x-amz-date"),
+        request.header("x-amz-content-sha256"),
+    ) {
+        (Some(authorization), Some(amz_date), Some(payload_hash)) => {
+            (authorization, amz_date, payload_hash)
+        }
+        _ => return false,
+    };
+    if hex::encode(Sha256::digest(&request.body)) != payload_hash || amz_date.len() < 8 {
+        return false;
+    }
+    let signed_headers = authorization
+        .split("SignedHeaders=")
+        .nth(1)
+        .and_then(|rest| rest.split(',').next())
+        .unwrap_or_default();
+    let headers: Vec<(String, String)> = signed_headers
+        .split(';')
+        .map(|name| {
+            let value = request.header(name).unwrap_or_default();
+            (name.to_string(), value.to_string())
+        })
+        .collect();
+    let mut query: Vec<&str> = request
+        .raw_query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .collect();
+    query.sort_unstable();
+    let credentials = Credentials {
+        access_key: "test".to_string(),
+        secret_key: "secret".to_string(),
+        region: "us-east-1".to_string(),
+    };
+    let expected = sign(
+        &credentials,
+        &request.method,
+        &request.path,
+        &query.join("&"),
+        &headers,
+        payload_hash,
+        amz_date,
+    );
+    expected == authorization
+}
+
+/// First and last byte of a `Range: bytes=<first>-<last>`
+fn range(header: &str) -> Option<(usize, usize)> {
+    let (first, last) = header.strip_prefix("bytes=")?.split_once('-')?;
+    Some((first.parse().ok()?, last.parse().ok()?))
+}
+
+fn decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap();
+                decoded.push(u8::from_str_radix(hex, 16).unwrap());
+                i += 3;
+            }
+            byte => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(decoded).unwrap()
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
@@ -0,0 +1,365 @@
+//! Trash of the homes, so `DELE` and `RMD` can be undone.
+//!
+//! What is removed is moved to `/.trash/files/<id>` of the home, and `/.trash/info/<id>` keeps
+//! the path where it was. The id is the time of the removal and the name, like
+//! `20240131T120000Z-a.txt`, so `SITE TRASH` lists them in order and `purge` knows their age
+//! without reading them. Everything goes through a `StorageBackend`, so every backend has a
+//! trash. The clients can't reach `/.trash` with the other commands.
+use super::StorageBackend;
+use crate::system;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use std::io::{self, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use user_manage::SystemUsers;
+
+/// Directory of the trash in every home
+pub const TRASH_DIR: &str = "/.trash";
+
+/// Time at the start of every id
+const ID_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+
+/// Time between two purges of `TrashPurger`
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// What `remove` removes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Removal {
+    /// `DELE`
+    File,
+
+    /// `RMD`, it fails if the directory isn't empty
+    EmptyDir,
+
+    /// `SITE RMDIR -r`
+    Tree,
+}
+
+/// Entry of the trash, see `list`
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrashEntry {
+    /// Name of the entry in the trash, `restore` takes it
+    pub id: String,
+
+    /// Virtual path where it was
+    pub original: PathBuf,
+
+    pub deleted: DateTime<Utc>,
+
+    pub is_dir: bool,
+}
+
+/// If `path` is the trash or something inside of it
+pub fn is_trash(path: &Path) -> bool {
+    path.starts_with(TRASH_DIR)
+}
+
+fn files(id: &str) -> PathBuf {
+    Path::new(TRASH_DIR).join("files").join(id)
+}
+
+fn info(id: &str) -> PathBuf {
+    Path::new(TRASH_DIR).join("info").join(id)
+}
+
+/// Creates the directory `path` unless it exists
+fn create_dir(storage: &dyn StorageBackend, home: &Path, path: &Path) -> io::Result<()> {
+    match storage.stat(home, path) {
+        Ok(metadata) if metadata.is_dir => Ok(()),
+        _ => storage.mkdir(home, path),
+    }
+}
+
+/// Time of the removal of the entry `id`
+fn deleted_at(id: &str) -> Option<DateTime<Utc>> {
+    let time = NaiveDateTime::parse_from_str(id.get(..16)?, ID_FORMAT).ok()?;
+    Some(DateTime::from_utc(time, Utc))
+}
+
+/// Path that the entry `id` had, as it's written in its info
+fn original(storage: &dyn StorageBackend, home: &Path, id: &str) -> io::Result<PathBuf> {
+    let mut content = Vec::new();
+    storage
+        .open_read(home, &info(id))?
+        .read_to_end(&mut content)?;
+    if content.last() == Some(&b'\n') {
+        content.pop();
+    }
+    Ok(system::path_from_bytes(content))
+}
+
+/// Moves the file or directory `path` to the trash, returns its id
+pub fn move_to_trash(
+    storage: &dyn StorageBackend,
+    home: &Path,
+    path: &Path,
+    now: DateTime<Utc>,
+) -> io::Result<String> {
+    let name = match path.file_name() {
+        Some(name) if !is_trash(path) => name.to_string_lossy(),
+        _ => {
+            return Err(io::Error::new(
+                ErrorKind::PermissionDenied,
+                "it can't be moved to the trash",
+            ))
+        }
+    };
+    storage.stat(home, path)?;
+    for dir in &[TRASH_DIR, "/.trash/files", "/.trash/info"] {
+        create_dir(storage, home, Path::new(dir))?;
+    }
+    let stamp = now.format(ID_FORMAT);
+    let mut id = format!("{}-{}", stamp, name);
+    let mut copy = 1;
+    while storage.stat(home, &files(&id)).is_ok() || storage.stat(home, &info(&id)).is_ok() {
+        id = format!("{}.{}-{}", stamp, copy, name);
+        copy += 1;
+    }
+    let mut file = storage.open_write(home, &info(&id))?;
+    file.write_all(&system::os_bytes(path.as_os_str()))?;
+    file.write_all(b"\n")?;
+    file.commit()?;
+    if let Err(err) = storage.rename(home, path, &files(&id)) {
+        let _ = storage.delete(home, &info(&id));
+        return Err(err);
+    }
+    Ok(id)
+}
+
+/// Removes the file or directory `path`, to the trash if `to_trash`. Fails with
+/// `DirectoryNotEmpty` if `removal` is `EmptyDir` and there is something inside
+pub fn remove(
+    storage: &dyn StorageBackend,
+    home: &Path,
+    path: &Path,
+    removal: Removal,
+    to_trash: bool,
+) -> io::Result<()> {
+    let is_dir = removal != Removal::File;
+    if removal == Removal::EmptyDir && !storage.list(home, path)?.is_empty() {
+        return Err(io::Error::from(ErrorKind::DirectoryNotEmpty));
+    }
+    if !to_trash {
+        return match is_dir {
+            true => storage.rmdir(home, path, removal == Removal::Tree),
+            false => storage.delete(home, path),
+        };
+    }
+    if storage.stat(home, path)?.is_dir != is_dir {
+        return Err(io::Error::from(ErrorKind::InvalidInput));
+    }
+    let id = move_to_trash(storage, home, path, Utc::now())?;
+    log_debug!("[TRASH] {} moved to the trash as {}", path.display(), id);
+    Ok(())
+}
+
+/// Entries of the trash of `home`, the oldest first
+pub fn list(storage: &dyn StorageBackend, home: &Path) -> io::Result<Vec<TrashEntry>> {
+    let entries = match storage.list(home, &files("")) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+    let mut trash = Vec::new();
+    for entry in entries {
+        let id = entry.name.to_string_lossy().into_owned();
+        // Entries without an info weren't moved by the server
+        let (original, deleted) = match (original(storage, home, &id), deleted_at(&id)) {
+            (Ok(original), Some(deleted)) => (original, deleted),
+            _ => continue,
+        };
+        trash.push(TrashEntry {
+            id,
+            original,
+            deleted,
+            is_dir: entry.metadata.is_dir,
+        });
+    }
+    trash.sort_by(|a, b| a.id.cmp(&b.id));
+    Ok(trash)
+}
+
+/// Moves the entry `id` back to where it was, returns that path. It fails if something is there
+/// now or if its directory doesn't exist anymore
+pub fn restore(storage: &dyn StorageBackend, home: &Path, id: &str) -> io::Result<PathBuf> {
+    if id.is_empty() || id.contains('/') || id == "." || id == ".." {
+        return Err(io::Error::from(ErrorKind::NotFound));
+    }
+    let original = original(storage, home, id)?;
+    if storage.stat(home, &original).is_ok() {
+        return Err(io::Error::from(ErrorKind::AlreadyExists));
+    }
+    storage.rename(home, &files(id), &original)?;
+    let _ = storage.delete(home, &info(id));
+    Ok(original)
+}
+
+/// Removes for good the entries that are in the trash since before `now - retention`, returns
+/// how many were removed
+pub fn purge(
+    storage: &dyn StorageBackend,
+    home: &Path,
+    retention: Duration,
+    now: DateTime<Utc>,
+) -> io::Result<usize> {
+    let retention = chrono::Duration::from_std(retention).unwrap_or(chrono::Duration::max_value());
+    let mut purged = 0;
+    for entry in list(storage, home)? {
+        if now.signed_duration_since(entry.deleted) < retention {
+            continue;
+        }
+        if entry.is_dir {
+            storage.rmdir(home, &files(&entry.id), true)?;
+        } else {
+            storage.delete(home, &files(&entry.id))?;
+        }
+        storage.delete(home, &info(&entry.id))?;
+        purged += 1;
+    }
+    Ok(purged)
+}
+
+/// Thread that purges the trash of every home now and every hour, it stops when it's dropped
+pub struct TrashPurger {
+    stop: Option<Sender<()>>,
+
+    thread: Option<JoinHandle<()>>,
+}
+
+impl TrashPurger {
+    /// Purges the entries older than `retention` from the homes of `users`, the users are read
+    /// on every purge so the ones of a reload are purged too
+    pub fn start(
+        storage: Arc<dyn StorageBackend>,
+        users: Arc<Mutex<SystemUsers>>,
+        retention: Duration,
+    ) -> io::Result<Self> {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let thread = std::thread::Builder::new()
+            .name("trash-purger".to_string())
+            .spawn(move || loop {
+                let homes = users.lock().unwrap().homes();
+                for home in homes {
+                    match purge(storage.as_ref(), &home, retention, Utc::now()) {
+                        Ok(0) => {}
+                        Ok(purged) => log_info!(
+                            "[TRASH] Purged {} entries from the trash of {}",
+                            purged,
+                            home.display()
+                        ),
+                        Err(err) => log_warn!(
+                            "[TRASH] Error purging the trash of {} -> {}",
+                            home.display(),
+                            err
+                        ),
+                    }
+                }
+                match stopped.recv_timeout(PURGE_INTERVAL) {
+                    Err(RecvTimeoutError::Timeout) => {}
+                    _ => break,
+                }
+            })?;
+        Ok(Self {
+            stop: Some(stop),
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for TrashPurger {
+    fn drop(&mut self) {
+        // Dropping the sender wakes the thread up
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{list, move_to_trash, purge, restore};
+    use crate::storage::MemoryStorage;
+    use chrono::{Duration as ChronoDuration, TimeZone, Utc};
+    use std::io::ErrorKind;
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
+
+    #[test]
+    fn trash_keeps_what_is_removed() {
+        let storage = MemoryStorage::new();
+        let home = Path::new("/homes/bob");
+        let path = |path: &'static str| Path::new(path);
+        storage.write(home, path("/docs/a.txt"), "a").unwrap();
+        storage.write(home, path("/a.txt"), "top").unwrap();
+        let now = Utc.ymd(2024, 1, 31).and_hms(12, 0, 0);
+
+        let id = move_to_trash(&storage, home, path("/docs/a.txt"), now).unwrap();
+        assert_eq!(id, "20240131T120000Z-a.txt");
+        let later = now + ChronoDuration::hours(1);
+        assert_eq!(
+            move_to_trash(&storage, home, path("/a.txt"), later).unwrap(),
+            "20240131T130000Z-a.txt"
+        );
+        assert_eq!(
+            move_to_trash(&storage, home, path("/docs"), later).unwrap(),
+            "20240131T130000Z-docs"
+        );
+        assert!(move_to_trash(&storage, home, path("/missing"), now).is_err());
+        assert!(move_to_trash(&storage, home, path("/.trash/info"), now).is_err());
+        let entries = list(&storage, home).unwrap();
+        let summary: Vec<_> = entries
+            .iter()
+            .map(|entry| (entry.id.as_str(), entry.original.clone(), entry.is_dir))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (
+                    "20240131T120000Z-a.txt",
+                    PathBuf::from("/docs/a.txt"),
+                    false
+                ),
+                ("20240131T130000Z-a.txt", PathBuf::from("/a.txt"), false),
+                ("20240131T130000Z-docs", PathBuf::from("/docs"), true),
+            ]
+        );
+        assert_eq!(entries[0].deleted, now);
+
+        // Its directory is in the trash too
+        let err = restore(&storage, home, "20240131T120000Z-a.txt").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        assert_eq!(
+            restore(&storage, home, "20240131T130000Z-docs").unwrap(),
+            PathBuf::from("/docs")
+        );
+        storage.write(home, path("/a.txt"), "new").unwrap();
+        let err = restore(&storage, home, "20240131T130000Z-a.txt").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+        assert!(restore(&storage, home, "../a.txt").is_err());
+        restore(&storage, home, "20240131T120000Z-a.txt").unwrap();
+        assert_eq!(storage.read(home, path("/docs/a.txt")).unwrap(), b"a");
+
+        // Only what is older than the retention is purged
+        let day = Duration::from_secs(24 * 60 * 60);
+        assert_eq!(purge(&storage, home, day, later).unwrap(), 0);
+        assert_eq!(list(&storage, home).unwrap().len(), 1);
+        let tomorrow = later + ChronoDuration::days(1);
+        assert_eq!(purge(&storage, home, day, tomorrow).unwrap(), 1);
+        assert!(list(&storage, home).unwrap().is_empty());
+        assert_eq!(
+            storage.tree(home),
+            [
+                "/.trash/",
+                "/.trash/files/",
+                "/.trash/info/",
+                "/a.txt",
+                "/docs/",
+                "/docs/a.txt"
+            ]
+        );
+    }
+}
@@ -0,0 +1,347 @@
+//! Entries of `LIST` and `NLST`: glob patterns, hidden files and recursive listings.
+//!
+//! Everything works on virtual paths (see `storage::resolve`), and the directories are read with
+//! a callback so every storage backend and both engines list the same way. The patterns are only
+//! matched against the names that the callback returns and never given to the file system, so
+//! they can't reach anything that a path without them couldn't.
+use crate::storage::DirEntry;
+use std::{
+    ffi::OsStr,
+    io,
+    path::{Component, Path, PathBuf},
+};
+
+use super::os_bytes;
+
+/// How the directories are listed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ListOptions {
+    /// Names that start with a dot are listed too
+    pub all: bool,
+
+    /// Directories are listed with what is inside of them
+    pub recursive: bool,
+
+    /// Levels below the listed directory that a recursive listing goes down
+    pub max_depth: usize,
+}
+
+/// If `name` has `*`, `?` or `[`
+pub fn has_glob(name: &OsStr) -> bool {
+    os_bytes(name)
+        .iter()
+        .any(|byte| matches!(byte, b'*' | b'?' | b'['))
+}
+
+/// If `name` matches the shell pattern `pattern`: `*` is any number of bytes, `?` a single one
+/// and `[a-z]` (or `[!a-z]`) one of a class. Like the shell, a leading dot is only matched by a
+/// pattern that starts with a dot
+pub fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    if name.first() == Some(&b'.') && pattern.first() != Some(&b'.') {
+        return false;
+    }
+    let (mut p, mut n) = (0, 0);
+    // Last `*` of the pattern and where the name was when it was found, to try again from there
+    let mut star = None;
+    while n < name.len() {
+        let step = match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p, n));
+                p += 1;
+                continue;
+            }
+            Some(b'?') => Some(1),
+            Some(b'[') => match class(&pattern[p..], name[n]) {
+                Some((true, len)) => Some(len),
+                Some((false, _)) => None,
+                // A `[` that isn't closed is just a `[`
+                None if name[n] == b'[' => Some(1),
+                None => None,
+            },
+            Some(&byte) if byte == name[n] => Some(1),
+            _ => None,
+        };
+        match (step, star) {
+            (Some(len), _) => {
+                p += len;
+                n += 1;
+            }
+            (None, Some((star_p, star_n))) => {
+                p = star_p + 1;
+                n = star_n + 1;
+                star = Some((star_p, star_n + 1));
+            }
+            (None, None) => return false,
+        }
+    }
+    pattern[p..].iter().all(|byte| *byte == b'*')
+}
+
+/// Matches `byte` with the class at the start of `pattern`, returns if it matched and the length
+/// of the class. None if the class isn't closed
+fn class(pattern: &[u8], byte: u8) -> Option<(bool, usize)> {
+    let mut i = 1;
+    let negated = matches!(pattern.get(i), Some(b'!') | Some(b'^'));
+    if negated {
+        i += 1;
+    }
+    let mut matched = false;
+    let mut first = true;
+    loop {
+        let start = *pattern.get(i)?;
+        // A `]` right after the `[` is part of the class
+        if start == b']' && !first {
+            break;
+        }
+        first = false;
+        match (pattern.get(i + 1), pattern.get(i + 2)) {
+            (Some(b'-'), Some(&end)) if end != b']' => {
+                matched |= (start..=end).contains(&byte);
+                i += 3;
+            }
+            _ => {
+                matched |= start == byte;
+                i += 1;
+            }
+        }
+    }
+    Some((matched != negated, i + 1))
+}
+
+/// Entries to list for the virtual `path`, each one with its virtual path:
+/// - a directory lists what is inside of it, a file lists itself;
+/// - a path with patterns lists the entries that match it, it can have them in every component
+///   (`*/2021-*.csv`). If nothing matches it's taken as a name, so files with `[` can be listed;
+/// - hidden names are left out of the directories unless `options.all`, and with
+///   `options.recursive` the directories are listed down to `options.max_depth` levels.
+///
+/// `read_dir` returns the entries of a directory, with the metadata of the symlinks and not of
+/// their targets, so a recursive listing never follows them
+pub fn list<F>(
+    path: &Path,
+    options: &ListOptions,
+    mut read_dir: F,
+) -> io::Result<Vec<(PathBuf, DirEntry)>>
+where
+    F: FnMut(&Path) -> io::Result<Vec<DirEntry>>,
+{
+    let mut listed = Vec::new();
+    if path.iter().any(has_glob) {
+        let matches = expand(path, &mut read_dir);
+        if !matches.is_empty() {
+            for (path, entry) in matches {
+                let is_dir = entry.metadata.is_dir;
+                listed.push((path.clone(), entry));
+                if is_dir && options.recursive {
+                    walk(&path, 1, options, &mut read_dir, &mut listed)?;
+                }
+            }
+            return Ok(listed);
+        }
+    }
+    match read_dir(path) {
+        Ok(_) => walk(path, 0, options, &mut read_dir, &mut listed)?,
+        Err(err) => match lookup(path, &mut read_dir) {
+            Some(entry) if !entry.metadata.is_dir => listed.push((path.to_path_buf(), entry)),
+            _ => return Err(err),
+        },
+    }
+    Ok(listed)
+}
+
+/// Adds the entries of the directory `dir`, `depth` levels below the listed one
+fn walk<F>(
+    dir: &Path,
+    depth: usize,
+    options: &ListOptions,
+    read_dir: &mut F,
+    listed: &mut Vec<(PathBuf, DirEntry)>,
+) -> io::Result<()>
+where
+    F: FnMut(&Path) -> io::Result<Vec<DirEntry>>,
+{
+    let mut entries = read_dir(dir)?;
+    entries.retain(|entry| options.all || os_bytes(&entry.name).first() != Some(&b'.'));
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    for entry in entries {
+        let path = dir.join(&entry.name);
+        let is_dir = entry.metadata.is_dir;
+        listed.push((path.clone(), entry));
+        if is_dir && options.recursive && depth < options.max_depth {
+            // A directory that can't be read is listed without what is inside of it
+            let _ = walk(&path, depth + 1, options, read_dir, listed);
+        }
+    }
+    Ok(())
+}
+
+/// Existing paths that match `path`, in order
+fn expand<F>(path: &Path, read_dir: &mut F) -> Vec<(PathBuf, DirEntry)>
+where
+    F: FnMut(&Path) -> io::Result<Vec<DirEntry>>,
+{
+    let mut matches = vec![PathBuf::from("/")];
+    let names: Vec<&OsStr> = path
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(name),
+            _ => None,
+        })
+        .collect();
+    for name in names {
+        let mut next = Vec::new();
+        for dir in matches {
+            if has_glob(name) {
+                let pattern = os_bytes(name);
+                let mut entries = read_dir(&dir).unwrap_or_default();
+                entries.retain(|entry| glob_match(&pattern, &os_bytes(&entry.name)));
+                entries.sort_by(|a, b| a.name.cmp(&b.name));
+                next.extend(entries.iter().map(|entry| dir.join(&entry.name)));
+            } else {
+                next.push(dir.join(name));
+            }
+        }
+        matches = next;
+    }
+    matches
+        .into_iter()
+        .filter_map(|path| Some((path.clone(), lookup(&path, read_dir)?)))
+        .collect()
+}
+
+/// Entry of `path` in its parent directory
+fn lookup<F>(path: &Path, read_dir: &mut F) -> Option<DirEntry>
+where
+    F: FnMut(&Path) -> io::Result<Vec<DirEntry>>,
+{
+    let name = path.file_name()?;
+    read_dir(path.parent()?)
+        .ok()?
+        .into_iter()
+        .find(|entry| entry.name == name)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{glob_match, list, ListOptions};
+    use crate::storage::{DirEntry, Metadata};
+    use std::{
+        collections::BTreeMap,
+        io::{self, ErrorKind},
+        path::{Path, PathBuf},
+    };
+
+    #[test]
+    fn patterns_match_like_the_shell() {
+        let tests: &[(&str, &str, bool)] = &[
+            ("*.csv", "a.csv", true),
+            ("*.csv", "a.csv.bak", false),
+            ("*", ".profile", false),
+            (".*", ".profile", true),
+            ("a?c", "abc", true),
+            ("a?c", "ac", false),
+            ("*a*b", "xxaxxb", true),
+            ("*a*b", "xxaxxbx", false),
+            ("[a-c]1", "b1", true),
+            ("[!a-c]1", "b1", false),
+            ("[]]", "]", true),
+            ("a[", "a[", true),
+            ("**", "", true),
+        ];
+        for (pattern, name, expected) in tests {
+            assert_eq!(
+                glob_match(pattern.as_bytes(), name.as_bytes()),
+                *expected,
+                "{} {}",
+                pattern,
+                name
+            );
+        }
+    }
+
+    /// `read_dir` of a tree made of `paths`, the ones that end with `/` are directories
+    fn tree(paths: &[&str]) -> impl FnMut(&Path) -> io::Result<Vec<DirEntry>> {
+        let mut dirs: BTreeMap<PathBuf, Vec<DirEntry>> = BTreeMap::new();
+        dirs.insert(PathBuf::from("/"), Vec::new());
+        for path in paths {
+            let is_dir = path.ends_with('/');
+            let path = Path::new(path.trim_end_matches('/'));
+            if is_dir {
+                dirs.entry(path.to_path_buf()).or_default();
+            }
+            let entry = DirEntry {
+                name: path.file_name().unwrap().to_os_string(),
+                metadata: Metadata {
+                    is_dir,
+                    len: 0,
+                    modified: None,
+                },
+            };
+            dirs.entry(path.parent().unwrap().to_path_buf())
+                .or_default()
+                .push(entry);
+        }
+        move |dir| {
+            dirs.get(dir)
+                .cloned()
+                .ok_or_else(|| io::Error::from(ErrorKind::NotFound))
+        }
+    }
+
+    fn listed(path: &str, all: bool, recursive: bool) -> Vec<String> {
+        let read_dir = tree(&[
+            "/a.csv",
+            "/b.txt",
+            "/.hidden",
+            "/docs/",
+            "/docs/c.csv",
+            "/docs/deep/",
+            "/docs/deep/d.csv",
+            "/docs/deep/deeper/",
+            "/docs/deep/deeper/e.csv",
+            "/x[1].txt",
+        ]);
+        let options = ListOptions {
+            all,
+            recursive,
+            max_depth: 1,
+        };
+        match list(Path::new(path), &options, read_dir) {
+            Ok(entries) => entries
+                .iter()
+                .map(|(path, _)| path.to_string_lossy().into_owned())
+                .collect(),
+            Err(_) => vec!["error".to_string()],
+        }
+    }
+
+    #[test]
+    fn listings() {
+        assert_eq!(
+            listed("/", false, false),
+            ["/a.csv", "/b.txt", "/docs", "/x[1].txt"]
+        );
+        assert_eq!(listed("/", true, false)[0], "/.hidden");
+        assert_eq!(listed("/b.txt", false, false), ["/b.txt"]);
+        assert_eq!(listed("/nope", false, false), ["error"]);
+        assert_eq!(listed("/*.csv", false, false), ["/a.csv"]);
+        assert_eq!(listed("/*/*.csv", false, false), ["/docs/c.csv"]);
+        assert_eq!(listed("/.h*", false, false), ["/.hidden"]);
+        assert_eq!(listed("/x[1].txt", false, false), ["/x[1].txt"]);
+        assert_eq!(listed("/*.pdf", false, false), ["error"]);
+        // The depth limit stops before `deeper`
+        assert_eq!(
+            listed("/docs", false, true),
+            [
+                "/docs/c.csv",
+                "/docs/deep",
+                "/docs/deep/d.csv",
+                "/docs/deep/deeper"
+            ]
+        );
+        assert_eq!(
+            listed("/d*", false, true),
+            ["/docs", "/docs/c.csv", "/docs/deep"]
+        );
+    }
+}
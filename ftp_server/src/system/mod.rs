@@ -1,4 +1,6 @@
 use std::{
+    borrow::Cow,
+    ffi::OsStr,
     io::{self, Error, ErrorKind},
     path::{Component, Components, Path},
 };
@@ -7,18 +9,54 @@ use std::fs;
 
 use std::path::PathBuf;
 
-pub fn ls(path: &str) -> Result<Vec<u8>, std::io::Error> {
+pub mod list;
+
+/// Bytes of `name` as they are on the disk. Only Unix can have names that aren't Unicode, other
+/// systems get them as UTF-8
+#[cfg(unix)]
+pub fn os_bytes(name: &OsStr) -> Cow<'_, [u8]> {
+    use std::os::unix::ffi::OsStrExt;
+    Cow::Borrowed(name.as_bytes())
+}
+
+#[cfg(not(unix))]
+pub fn os_bytes(name: &OsStr) -> Cow<'_, [u8]> {
+    match name.to_string_lossy() {
+        Cow::Borrowed(name) => Cow::Borrowed(name.as_bytes()),
+        Cow::Owned(name) => Cow::Owned(name.into_bytes()),
+    }
+}
+
+/// Path with the bytes of `os_bytes`
+#[cfg(unix)]
+pub fn path_from_bytes(bytes: Vec<u8>) -> PathBuf {
+    use std::{ffi::OsString, os::unix::ffi::OsStringExt};
+    PathBuf::from(OsString::from_vec(bytes))
+}
+
+#[cfg(not(unix))]
+pub fn path_from_bytes(bytes: Vec<u8>) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// Lists the entries of the directory `path` by name, one per line and without the hidden ones.
+/// Every entry is written relative to `root` (the directory that contains the homes of the users)
+pub fn ls<P: AsRef<Path>, R: AsRef<Path>>(path: P, root: R) -> Result<Vec<u8>, std::io::Error> {
+    let root = root.as_ref().canonicalize()?;
     let mut buff = vec![];
-    std::fs::read_dir(clear_path(path))?.for_each(|now| {
-        if now.is_err() {
-            return;
-        }
-        let now = now.unwrap();
-        let p = PathBuf::from(now.path());
-        let c = p.components();
-        let end_path = c.skip(2).collect::<PathBuf>();
-        let str = format!("{}\r\n", end_path.to_str().unwrap());
-        buff.extend(str.as_bytes().iter());
+    let mut entries: Vec<_> = std::fs::read_dir(path.as_ref().canonicalize()?)?
+        .filter_map(Result::ok)
+        .filter(|entry| os_bytes(&entry.file_name()).first() != Some(&b'.'))
+        .collect();
+    entries.sort_by_key(|entry| entry.file_name());
+    entries.iter().for_each(|now| {
+        let p = now.path();
+        let end_path = match p.strip_prefix(&root) {
+            Ok(end_path) => end_path.to_path_buf(),
+            Err(_) => PathBuf::from(now.file_name()),
+        };
+        buff.extend_from_slice(&os_bytes(end_path.as_os_str()));
+        buff.extend_from_slice(b"\r\n");
     });
     Ok(buff)
 }
@@ -90,19 +128,99 @@ pub fn rename<P: AsRef<Path>>(from: P, to: P) -> io::Result<()> {
     }
 }
 
-fn clear_path(path: &str) -> String {
-    let p = Path::new("./").canonicalize().unwrap();
-    let path = path.replacen(p.to_str().unwrap(), ".", 1);
-    path
+/// Sends up to `count` bytes of `file` starting at `offset` straight from the page cache to `socket`
+/// with `sendfile(2)`, updating `offset` with the bytes that were sent.
+/// Returns 0 when the end of the file is reached.
+#[cfg(target_os = "linux")]
+pub fn sendfile<S: std::os::unix::io::AsRawFd>(
+    socket: &S,
+    file: &fs::File,
+    offset: &mut u64,
+    count: usize,
+) -> io::Result<usize> {
+    use std::os::unix::io::AsRawFd;
+    let mut file_offset = *offset as libc::off_t;
+    // Safe because both descriptors are alive while they are borrowed, and sendfile only
+    // writes to `file_offset`
+    let sent = unsafe {
+        libc::sendfile(
+            socket.as_raw_fd(),
+            file.as_raw_fd(),
+            &mut file_offset,
+            count,
+        )
+    };
+    if sent < 0 {
+        return Err(Error::last_os_error());
+    }
+    *offset = file_offset as u64;
+    Ok(sent as usize)
+}
+
+/// `sendfile(2)` is only used on Linux, other systems should use a buffered copy
+#[cfg(not(target_os = "linux"))]
+pub fn sendfile<S>(_: &S, _: &fs::File, _: &mut u64, _: usize) -> io::Result<usize> {
+    Err(Error::from(ErrorKind::Other))
+}
+
+/// Appends `input` to `output` turning every bare LF into CRLF, as the ASCII type requires.
+/// `last_was_cr` keeps track of a CR at the end of the previous chunk, so a CRLF split in
+/// two chunks isn't converted twice.
+pub fn to_crlf(input: &[u8], output: &mut Vec<u8>, last_was_cr: &mut bool) {
+    output.reserve(input.len());
+    for &byte in input {
+        if byte == b'\n' && !*last_was_cr {
+            output.push(b'\r');
+        }
+        output.push(byte);
+        *last_was_cr = byte == b'\r';
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use super::clear_path;
-    use std::path::Path;
+    use super::{ls, to_crlf};
+    #[test]
+    fn testing_ls() {
+        let dir = tempfile::tempdir().unwrap();
+        let home = dir.path().join("root").join("user_01");
+        std::fs::create_dir_all(home.join("thing")).unwrap();
+        let list = ls(&home, dir.path().join("root")).unwrap();
+        assert_eq!(list, b"user_01/thing\r\n");
+        // Entries outside of the root only show their name
+        let list = ls(home.join("thing/.."), dir.path()).unwrap();
+        assert_eq!(list, b"root/user_01/thing\r\n");
+        std::fs::create_dir(dir.path().join("other")).unwrap();
+        let list = ls(&home, dir.path().join("other")).unwrap();
+        assert_eq!(list, b"thing\r\n");
+    }
+
+    #[test]
+    fn testing_to_crlf() {
+        let mut output = vec![];
+        let mut last_was_cr = false;
+        to_crlf(b"a\nb\r", &mut output, &mut last_was_cr);
+        to_crlf(b"\nc\n", &mut output, &mut last_was_cr);
+        assert_eq!(output, b"a\r\nb\r\nc\r\n");
+    }
+
+    #[cfg(target_os = "linux")]
     #[test]
-    fn testing_clear_path() {
-        let p = Path::new("./root/user_01").canonicalize().unwrap();
-        assert_eq!(clear_path(p.to_str().unwrap()), "./root/user_01");
+    fn testing_sendfile() {
+        use super::sendfile;
+        use std::io::Read;
+        use std::net::{TcpListener, TcpStream};
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        let file = std::fs::File::open("./test_files/1.jpeg").unwrap();
+        let expected = std::fs::read("./test_files/1.jpeg").unwrap();
+        let mut offset = 10;
+        while sendfile(&server, &file, &mut offset, 4096).unwrap() > 0 {}
+        assert_eq!(offset, expected.len() as u64);
+        drop(server);
+        let mut received = vec![];
+        client.read_to_end(&mut received).unwrap();
+        assert_eq!(received, &expected[10..]);
     }
 }
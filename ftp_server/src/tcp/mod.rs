@@ -7,13 +7,35 @@ use mio::{
 };
 use std::error::Error;
 use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 // use crate::stats::program_information;
 
-const SERVER: Token = Token(0);
 const THREAD: Token = Token(2_147_483_647);
 
+/// How often the connections are checked while the server drains, workers finish transfers
+/// without waking the event loop
+const DRAIN_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Token of the listener with index `index`, they are counted down from `THREAD` so they never
+/// collide with the ids of the connections
+fn listener_token(index: usize) -> Token {
+    Token(THREAD.0 - 1 - index)
+}
+
+/// Index of the listener that has `token`, if it's one of the `listeners` first ones
+fn listener_index(token: Token, listeners: usize) -> Option<usize> {
+    let index = THREAD.0.checked_sub(token.0 + 1)?;
+    if index < listeners {
+        Some(index)
+    } else {
+        None
+    }
+}
+
 // pub fn convert_to_server(id: u64) -> u64 {
 //     id | (1 << 63)
 // }
@@ -74,6 +96,30 @@ pub trait TCPImplementation {
 
     /// Function that will be called when the server needs a new id for the next connection
     fn next_id(&mut self) -> usize;
+
+    /// Called from the event loop after `ReloadHandle::reload`, before handling more events
+    fn reload(&mut self) {}
+
+    /// Called from the event loop after `KillHandle::kill`, it closes the connection `id` and
+    /// the ones that belong to it
+    fn kill(&mut self, poll: &Poll, id: Token, waker: &Arc<Waker>) {
+        let _ = self.close_connection(poll, id, waker);
+    }
+
+    /// Called once when a graceful shutdown starts, the listeners are already closed.
+    /// Returns how long the busy connections have to finish
+    fn drain_timeout(&mut self) -> Duration {
+        Duration::from_secs(0)
+    }
+
+    /// Called after every poll while draining, it closes the connections that aren't busy.
+    /// Returns true when there isn't any connection left, so the loop can return
+    fn drain(&mut self, _poll: &Poll, _waker: &Arc<Waker>) -> bool {
+        true
+    }
+
+    /// The drain timeout passed, every connection that is still open must be closed
+    fn force_close(&mut self, _poll: &Poll, _waker: &Arc<Waker>) {}
 }
 
 fn handle_request_type(
@@ -98,130 +144,294 @@ fn handle_request_type(
     Ok(())
 }
 
-pub fn create_server<T: AsRef<str>>(
-    addr: T,
-    tcp_implementation: &mut dyn TCPImplementation,
-) -> Result<(), Box<dyn Error>> {
-    // Create a poll instance.
-    let mut poll = Poll::new()?;
-    // Create storage for events.
-    let mut events = Events::with_capacity(128);
-    // Unique id for a connection
-    let mut id = tcp_implementation.next_id();
-    // Setup the server socket.
-    let addr = addr.as_ref().parse()?;
-    // Main server listener, even though you can create more bindings
-    let mut server = TcpListener::bind(addr)?;
-    // Start listening for incoming connections.
-    poll.registry()
-        .register(&mut server, SERVER, Interest::READABLE)?;
-    // We need this so we can wake up the poll from another thread when we add new events
-    let waker = Arc::new(Waker::new(poll.registry(), THREAD)?);
-    loop {
-        {
-            let actions = tcp_implementation.action_list();
-            let actions = actions.lock();
-            if let Ok(mut actions) = actions {
-                for (token, mut request, type_action) in actions.drain(..) {
-                    handle_request_type(&mut request, &poll, type_action, token)?;
-                }
-            }
+/// Makes a running `Server` return from `run`, it can be used from any thread
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    stop: Arc<AtomicBool>,
+    drain: Arc<AtomicBool>,
+    waker: Arc<Waker>,
+}
+
+impl ShutdownHandle {
+    /// Stops the event loop after the events it's handling right now
+    pub fn shutdown(&self) {
+        self.stop.store(true, Ordering::SeqCst);
+        let _ = self.waker.wake();
+    }
+
+    /// Closes the listeners and lets the connections finish, see `TCPImplementation::drain`.
+    /// `run` returns when every connection is closed or the drain timeout passes
+    pub fn graceful_shutdown(&self) {
+        self.drain.store(true, Ordering::SeqCst);
+        let _ = self.waker.wake();
+    }
+}
+
+/// Makes a running `Server` call `TCPImplementation::reload`, it can be used from any thread
+#[derive(Clone)]
+pub struct ReloadHandle {
+    reload: Arc<AtomicBool>,
+    waker: Arc<Waker>,
+}
+
+impl ReloadHandle {
+    /// The reload happens in the event loop, connections accepted after this call see its result
+    pub fn reload(&self) {
+        self.reload.store(true, Ordering::SeqCst);
+        let _ = self.waker.wake();
+    }
+}
+
+/// Makes a running `Server` call `TCPImplementation::kill`, it can be used from any thread
+#[derive(Clone)]
+pub struct KillHandle {
+    kills: Arc<Mutex<Vec<Token>>>,
+    waker: Arc<Waker>,
+}
+
+impl KillHandle {
+    /// The connection with the id `id` is closed by the event loop, soon after this call
+    pub fn kill(&self, id: usize) {
+        self.kills.lock().unwrap().push(Token(id));
+        let _ = self.waker.wake();
+    }
+}
+
+/// Listening sockets bound to one or more addresses, the event loop starts with `run`
+pub struct Server {
+    poll: Poll,
+    listeners: Vec<TcpListener>,
+    waker: Arc<Waker>,
+    stop: Arc<AtomicBool>,
+    drain: Arc<AtomicBool>,
+    reload: Arc<AtomicBool>,
+    kills: Arc<Mutex<Vec<Token>>>,
+}
+
+impl Server {
+    /// Binds `addr`, use port 0 to let the system choose a free port
+    pub fn bind<T: AsRef<str>>(addr: T) -> Result<Self, Box<dyn Error>> {
+        Self::bind_all(&[addr])
+    }
+
+    /// Binds every address of `addrs`, the connections of all of them are handled by the same loop
+    pub fn bind_all<T: AsRef<str>>(addrs: &[T]) -> Result<Self, Box<dyn Error>> {
+        if addrs.is_empty() {
+            return Err("there isn't any address to bind".into());
+        }
+        // Create a poll instance.
+        let poll = Poll::new()?;
+        let mut listeners = Vec::with_capacity(addrs.len());
+        for (index, addr) in addrs.iter().enumerate() {
+            // Setup the server socket.
+            let addr = addr.as_ref().parse()?;
+            let mut listener = TcpListener::bind(addr)?;
+            // Start listening for incoming connections.
+            poll.registry()
+                .register(&mut listener, listener_token(index), Interest::READABLE)?;
+            listeners.push(listener);
         }
+        // We need this so we can wake up the poll from another thread when we add new events
+        let waker = Arc::new(Waker::new(poll.registry(), THREAD)?);
+        Ok(Self {
+            poll,
+            listeners,
+            waker,
+            stop: Arc::new(AtomicBool::new(false)),
+            drain: Arc::new(AtomicBool::new(false)),
+            reload: Arc::new(AtomicBool::new(false)),
+            kills: Arc::new(Mutex::new(Vec::new())),
+        })
+    }
 
-        // Poll Mio for events, blocking until we get an event.
-        poll.poll(&mut events, None)?;
+    /// Address of the first listener
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.listeners[0].local_addr()
+    }
 
-        // Process each event.
-        for event in events.iter() {
-            if event.is_error() || event.is_read_closed() || event.is_write_closed() {
-                let res = tcp_implementation.close_connection(&poll, event.token(), &waker);
-                // If there was an error closing it means that the user doesn't want to close this connection yet
-                if !res.is_err() {
+    /// Addresses of every listener, in the order they were bound
+    pub fn local_addrs(&self) -> std::io::Result<Vec<SocketAddr>> {
+        self.listeners
+            .iter()
+            .map(|listener| listener.local_addr())
+            .collect()
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            stop: self.stop.clone(),
+            drain: self.drain.clone(),
+            waker: self.waker.clone(),
+        }
+    }
+
+    pub fn reload_handle(&self) -> ReloadHandle {
+        ReloadHandle {
+            reload: self.reload.clone(),
+            waker: self.waker.clone(),
+        }
+    }
+
+    pub fn kill_handle(&self) -> KillHandle {
+        KillHandle {
+            kills: self.kills.clone(),
+            waker: self.waker.clone(),
+        }
+    }
+
+    /// Handles the connections until an error happens, `ShutdownHandle::shutdown` is called or a
+    /// graceful shutdown finishes
+    pub fn run(
+        mut self,
+        tcp_implementation: &mut dyn TCPImplementation,
+    ) -> Result<(), Box<dyn Error>> {
+        // Create storage for events.
+        let mut events = Events::with_capacity(128);
+        // Unique id for a connection
+        let mut id = tcp_implementation.next_id();
+        let poll = &mut self.poll;
+        let waker = self.waker.clone();
+        // Deadline of the drain, once it started
+        let mut draining: Option<Instant> = None;
+        loop {
+            {
+                let actions = tcp_implementation.action_list();
+                let actions = actions.lock();
+                if let Ok(mut actions) = actions {
+                    for (token, mut request, type_action) in actions.drain(..) {
+                        handle_request_type(&mut request, poll, type_action, token)?;
+                    }
+                }
+            }
+
+            // Poll Mio for events, blocking until we get an event.
+            let timeout = draining.map(|_| DRAIN_INTERVAL);
+            if let Err(err) = poll.poll(&mut events, timeout) {
+                // A signal arrived while waiting, the handles wake the loop again if they need it
+                if err.kind() == ErrorKind::Interrupted {
                     continue;
                 }
+                return Err(err.into());
+            }
+            if self.stop.load(Ordering::SeqCst) {
+                return Ok(());
             }
-            // We can use the token we previously provided to `register` to
-            // determine for which socket the event is.
-            match event.token() {
-                SERVER => {
+            if self.reload.swap(false, Ordering::SeqCst) {
+                tcp_implementation.reload();
+            }
+            let kills = std::mem::take(&mut *self.kills.lock().unwrap());
+            for token in kills {
+                tcp_implementation.kill(poll, token, &waker);
+            }
+            if draining.is_none() && self.drain.load(Ordering::SeqCst) {
+                for listener in self.listeners.iter_mut() {
+                    let _ = poll.registry().deregister(listener);
+                }
+                // Dropping them closes the sockets, so new clients are refused
+                self.listeners.clear();
+                draining = Some(Instant::now() + tcp_implementation.drain_timeout());
+            }
+
+            // Process each event.
+            for event in events.iter() {
+                if event.is_error() || event.is_read_closed() || event.is_write_closed() {
+                    let res = tcp_implementation.close_connection(poll, event.token(), &waker);
+                    // If there was an error closing it means that the user doesn't want to close this connection yet
+                    if !res.is_err() {
+                        continue;
+                    }
+                }
+                if let Some(index) = listener_index(event.token(), self.listeners.len()) {
+                    let listener = &self.listeners[index];
                     // If this is an event for the server, it means a connection
                     // is ready to be accepted.
-                    loop {
-                        match server.accept() {
-                            Ok((stream, _)) => {
-                                if let Err(_) = tcp_implementation.new_connection(
-                                    SERVER,
-                                    Token(id),
-                                    &poll,
-                                    stream,
-                                ) {
-                                    let _ = tcp_implementation.close_connection(
-                                        &poll,
-                                        Token(id),
-                                        &waker,
-                                    );
-                                }
-                                id = tcp_implementation.next_id();
-                            }
-                            _ => break,
+                    while let Ok((stream, _)) = listener.accept() {
+                        if tcp_implementation
+                            .new_connection(event.token(), Token(id), poll, stream)
+                            .is_err()
+                        {
+                            let _ = tcp_implementation.close_connection(poll, Token(id), &waker);
                         }
+                        id = tcp_implementation.next_id();
                     }
-                }
-                THREAD => {
                     continue;
                 }
-                Token(_) => {
-                    if event.is_writable() {
-                        if let Err(err) =
-                            tcp_implementation.write_connection(&poll, waker.clone(), event)
-                        {
-                            match err.kind() {
-                                ErrorKind::WouldBlock => {
-                                    continue;
-                                }
-                                _ => {
-                                    let _ = tcp_implementation.close_connection(
-                                        &poll,
-                                        event.token(),
-                                        &waker,
-                                    );
+                // We can use the token we previously provided to `register` to
+                // determine for which socket the event is.
+                match event.token() {
+                    THREAD => {
+                        continue;
+                    }
+                    Token(_) => {
+                        if event.is_writable() {
+                            if let Err(err) =
+                                tcp_implementation.write_connection(poll, waker.clone(), event)
+                            {
+                                match err.kind() {
+                                    ErrorKind::WouldBlock => {
+                                        continue;
+                                    }
+                                    _ => {
+                                        let _ = tcp_implementation.close_connection(
+                                            poll,
+                                            event.token(),
+                                            &waker,
+                                        );
+                                    }
                                 }
                             }
-                        }
-                    } else if event.is_readable() {
-                        if let Err(err) =
-                            tcp_implementation.read_connection(&poll, waker.clone(), event)
-                        {
-                            match err.kind() {
-                                ErrorKind::WouldBlock => {
-                                    continue;
-                                }
-                                _ => {
-                                    if let Err(_err) = tcp_implementation.close_connection(
-                                        &poll,
-                                        event.token(),
-                                        &waker,
-                                    ) {
-                                        // println!(
-                                        //     "something happened when closing a socket: {}",
-                                        //     err
-                                        // );
+                        } else if event.is_readable() {
+                            if let Err(err) =
+                                tcp_implementation.read_connection(poll, waker.clone(), event)
+                            {
+                                match err.kind() {
+                                    ErrorKind::WouldBlock => {
+                                        continue;
+                                    }
+                                    _ => {
+                                        if let Err(_err) = tcp_implementation.close_connection(
+                                            poll,
+                                            event.token(),
+                                            &waker,
+                                        ) {
+                                            // println!(
+                                            //     "something happened when closing a socket: {}",
+                                            //     err
+                                            // );
+                                        }
                                     }
                                 }
                             }
                         }
+                        // if event.is_error() || (event.is_read_closed()) || (event.is_write_closed()) {
+                        //     println!("{:?}", event);
+                        //     let _ = tcp_implementation.close_connection(poll, event.token(), &waker);
+                        //     continue;
+                        // }
                     }
-                    // if event.is_error() || (event.is_read_closed()) || (event.is_write_closed()) {
-                    //     println!("{:?}", event);
-                    //     let _ = tcp_implementation.close_connection(&poll, event.token(), &waker);
-                    //     continue;
-                    // }
+                }
+            }
+
+            if let Some(deadline) = draining {
+                if tcp_implementation.drain(poll, &waker) {
+                    return Ok(());
+                }
+                if Instant::now() >= deadline {
+                    tcp_implementation.force_close(poll, &waker);
+                    return Ok(());
                 }
             }
         }
     }
 }
 
+pub fn create_server<T: AsRef<str>>(
+    addr: T,
+    tcp_implementation: &mut dyn TCPImplementation,
+) -> Result<(), Box<dyn Error>> {
+    Server::bind(addr)?.run(tcp_implementation)
+}
+
 /// Run those callbacks depending on the result
 /// Passes the `Ok` `result` on the `if_ok`
 /// callback and the `Err` `result` on the `if_err` callback
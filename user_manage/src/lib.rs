@@ -1,16 +1,41 @@
 use serde::{Deserialize, Serialize};
 
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     error::Error,
     fs::OpenOptions,
     fs::{self, File},
     io::Write,
-    path::{Path, PathBuf},
+    path::{Component, Path, PathBuf},
 };
 
 pub const USER_PATH: &'static str = "./etc/users.json";
 pub const LOG_PATH: &'static str = "./var/ftpserver.log";
+pub const ROOT_PATH: &'static str = "./root";
+
+/// Where the users are stored
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct UsersConfig {
+    /// JSON file with the users, new users are also saved there
+    pub file: PathBuf,
+
+    /// Directory where the homes of new users are created
+    pub root: PathBuf,
+
+    /// File where every lookup and change of the users is logged
+    pub log: PathBuf,
+}
+
+impl Default for UsersConfig {
+    fn default() -> Self {
+        Self {
+            file: PathBuf::from(USER_PATH),
+            root: PathBuf::from(ROOT_PATH),
+            log: PathBuf::from(LOG_PATH),
+        }
+    }
+}
 
 /// Structure that stores the user data of a connection
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -19,8 +44,26 @@ pub struct User {
     chroot: String,
     uid: u16,
 
+    /// If `DELE` and `RMD` move to the trash for this user, without it the server decides
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    trash: Option<bool>,
+
+    /// If the user can remove a directory with everything inside of it (`SITE RMDIR -r`)
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    recursive_delete: bool,
+
     #[serde(skip)]
-    actual_dir: String,
+    actual_dir: PathBuf,
+}
+
+/// `dir` as a path relative to the home, like `./docs`, even when it's absolute
+fn inside_home(dir: &Path) -> PathBuf {
+    let mut inside = PathBuf::from(".");
+    inside.extend(
+        dir.components()
+            .filter(|c| !matches!(c, Component::RootDir | Component::Prefix(_))),
+    );
+    inside
 }
 
 impl User {
@@ -29,13 +72,20 @@ impl User {
     }
 
     pub fn new(username: &str, passwd: &str, uid: u16) -> Self {
-        let _ = fs::create_dir(format!("./root/{}", username));
-        let chroot = "./root/".to_string() + username;
+        Self::new_in(ROOT_PATH, username, passwd, uid)
+    }
+
+    /// Creates the user with its home in `root/username`
+    pub fn new_in<P: AsRef<Path>>(root: P, username: &str, passwd: &str, uid: u16) -> Self {
+        let chroot = root.as_ref().join(username);
+        let _ = fs::create_dir(&chroot);
         Self {
             passwd: passwd.to_string(),
-            chroot: chroot.clone(),
+            chroot: chroot.to_string_lossy().into_owned(),
             uid,
-            actual_dir: "./".to_string(),
+            trash: None,
+            recursive_delete: false,
+            actual_dir: PathBuf::from("./"),
         }
     }
 
@@ -46,9 +96,9 @@ impl User {
     /// Same behaviour as change_dir but returning the expected path
     pub fn new_dir<'a>(
         chroot: &'a str,
-        actual_dir: &str,
+        actual_dir: &Path,
         new_dir: &'a Path,
-    ) -> Result<String, &'static str> {
+    ) -> Result<PathBuf, &'static str> {
         // Get root
         let root = Path::new(chroot);
         // Get total path root
@@ -58,16 +108,15 @@ impl User {
         path_buf.push(actual_dir);
         path_buf.push(new_dir);
         // Final path making sure it's not absolute
-        let final_path = format!("./{}", path_buf.to_str().unwrap());
+        let final_path = inside_home(&path_buf);
         // Join with root
-        let path = root.join(final_path.clone());
+        let path = root.join(&final_path);
         // Get total path
         let total_path = path.canonicalize().map_err(|_| "Directory not found")?;
         // Check if it's valid (doesn't exit the chroot)
         let valid_dir = total_path.starts_with(&expected_root);
         if valid_dir {
-            let p = total_path.to_str().unwrap();
-            Ok(p.to_string())
+            Ok(total_path)
         } else {
             Err("Invalid directory")
         }
@@ -83,9 +132,9 @@ impl User {
         path_buf.push(&self.actual_dir);
         path_buf.push(new_dir);
         // Final path making sure it's not absolute
-        let final_path = format!("./{}", path_buf.to_str().unwrap());
+        let final_path = inside_home(&path_buf);
         // Join with root
-        let path = root.join(final_path.clone());
+        let path = root.join(&final_path);
         // Get total path
         let total_path = path.canonicalize().map_err(|_| "Directory not found")?;
         // Check if it's valid (doesn't exit the chroot)
@@ -103,10 +152,16 @@ impl User {
         self.passwd == passwd
     }
 
-    pub fn get_actual_dir(&self) -> &String {
+    pub fn get_actual_dir(&self) -> &Path {
         &self.actual_dir
     }
 
+    /// Sets the current directory, `dir` is relative to the home like `./docs/a`. It isn't
+    /// checked, the caller must know that it's inside the home
+    pub fn set_actual_dir<P: Into<PathBuf>>(&mut self, dir: P) {
+        self.actual_dir = dir.into();
+    }
+
     pub fn get_chroot(&self) -> &String {
         &self.chroot
     }
@@ -119,45 +174,38 @@ impl User {
             .unwrap()
     }
 
-    pub fn total_path_and_decano(&self) -> String {
-        let t = self.total_path().to_str().unwrap().replacen(
-            Path::new(&self.chroot)
-                .canonicalize()
-                .unwrap()
-                .to_str()
-                .unwrap(),
-            "",
-            1,
-        );
-        if t == "" {
-            return String::from("/");
+    pub fn total_path_and_decano(&self) -> PathBuf {
+        let root = Path::new(&self.chroot).canonicalize().unwrap();
+        match self.total_path().strip_prefix(&root) {
+            Ok(inside) => Path::new("/").join(inside),
+            Err(_) => PathBuf::from("/"),
         }
-        t
     }
 
     // Gets the total path of the user (in the system)
-    pub fn total_path_non_canon(&self) -> String {
-        Path::new(&self.chroot)
-            .join(&self.actual_dir)
-            .to_str()
-            .unwrap()
-            .to_string()
+    pub fn total_path_non_canon(&self) -> PathBuf {
+        Path::new(&self.chroot).join(&self.actual_dir)
     }
 
     pub fn change_dir_to_recursive_if_doesnt_exist(&mut self) {
-        while Path::new(&self.total_path_non_canon())
-            .canonicalize()
-            .is_err()
-        {
-            let mut p_buf = PathBuf::from(&self.actual_dir);
-            p_buf.pop();
-            self.actual_dir = p_buf.to_str().unwrap().to_string();
+        while self.total_path_non_canon().canonicalize().is_err() {
+            self.actual_dir.pop();
         }
     }
 
     pub fn get_uid(&self) -> u16 {
         self.uid
     }
+
+    /// The `trash` of the user, None if it isn't set
+    pub fn trash(&self) -> Option<bool> {
+        self.trash
+    }
+
+    /// If the user can remove directories that aren't empty
+    pub fn recursive_delete(&self) -> bool {
+        self.recursive_delete
+    }
 }
 
 /// Structure that stores all users
@@ -168,27 +216,133 @@ pub struct SystemUsers {
     config_path: String,
     users_data: HashMap<String, User>,
     log_file: File,
+    log_path: PathBuf,
+
+    /// Directory where the homes of new users are created
+    root: PathBuf,
+
+    /// Users that can't log in until they are enabled again, it isn't saved to the file and a
+    /// reload keeps it
+    disabled: HashSet<String>,
+}
+
+/// Names of the users that differ between two stores, see `SystemUsers::replace_users`
+#[derive(Debug, Default, PartialEq)]
+pub struct UsersChanges {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+
+    /// Users whose password, home, uid, trash or recursive_delete are different
+    pub changed: Vec<String>,
 }
 
 impl SystemUsers {
     pub fn load_data(filename: &str) -> Result<Self, Box<dyn Error>> {
+        Self::load_data_in(filename, LOG_PATH, ROOT_PATH)
+    }
+
+    /// Loads the users with the paths of `config`
+    pub fn load(config: &UsersConfig) -> Result<Self, Box<dyn Error>> {
+        let file = config.file.to_str().ok_or("the users file must be UTF-8")?;
+        Self::load_data_in(file, &config.log, &config.root)
+    }
+
+    /// Loads the users of `filename`, writing the log to `log_path` and creating the homes of new
+    /// users inside `root`
+    pub fn load_data_in<P: AsRef<Path>, Q: AsRef<Path>>(
+        filename: &str,
+        log_path: P,
+        root: Q,
+    ) -> Result<Self, Box<dyn Error>> {
         let content = fs::read_to_string(filename)?;
         let mut users_data: HashMap<String, User> = serde_json::from_str(&content)?;
 
+        fs::create_dir_all(&root)?;
         users_data.iter_mut().for_each(|(_, user)| {
-            user.actual_dir = "./".to_string();
+            user.actual_dir = PathBuf::from("./");
             user.create_dir();
         });
 
-        let log_file = OpenOptions::new().write(true).append(true).open(LOG_PATH)?;
+        let log_file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&log_path)?;
 
         Ok(Self {
             config_path: filename.to_string(),
             users_data,
             log_file,
+            log_path: log_path.as_ref().to_path_buf(),
+            root: root.as_ref().to_path_buf(),
+            disabled: HashSet::new(),
         })
     }
 
+    /// Directory where the homes of new users are created
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Paths this store was loaded from
+    pub fn config(&self) -> UsersConfig {
+        UsersConfig {
+            file: PathBuf::from(&self.config_path),
+            root: self.root.clone(),
+            log: self.log_path.clone(),
+        }
+    }
+
+    /// Takes the users and paths of `other`, a store loaded again after its file was edited.
+    /// Users whose home didn't change keep their current directory, so the sessions that are
+    /// logged in with them aren't moved back to their home
+    pub fn replace_users(&mut self, other: SystemUsers) -> UsersChanges {
+        let mut changes = UsersChanges::default();
+        let mut old_users = std::mem::replace(&mut self.users_data, other.users_data);
+        for (name, user) in self.users_data.iter_mut() {
+            match old_users.remove(name) {
+                None => changes.added.push(name.clone()),
+                Some(old) => {
+                    if old.chroot == user.chroot {
+                        user.actual_dir = old.actual_dir;
+                    }
+                    if old.passwd != user.passwd
+                        || old.chroot != user.chroot
+                        || old.uid != user.uid
+                        || old.trash != user.trash
+                        || old.recursive_delete != user.recursive_delete
+                    {
+                        changes.changed.push(name.clone());
+                    }
+                }
+            }
+        }
+        changes
+            .removed
+            .extend(old_users.into_iter().map(|(name, _)| name));
+        changes.added.sort();
+        changes.removed.sort();
+        changes.changed.sort();
+        self.config_path = other.config_path;
+        self.log_file = other.log_file;
+        self.log_path = other.log_path;
+        self.root = other.root;
+        let time = chrono::offset::Local::now();
+        writeln!(&self.log_file, "[{:?}] Users reloaded: {:?}", time, changes).unwrap();
+        changes
+    }
+
+    /// Homes of every user, sorted and without repetitions
+    pub fn homes(&self) -> Vec<PathBuf> {
+        let mut homes: Vec<PathBuf> = self
+            .users_data
+            .values()
+            .map(|user| PathBuf::from(&user.chroot))
+            .collect();
+        homes.sort();
+        homes.dedup();
+        homes
+    }
+
     pub fn user_exists(&self, user_name: &str) -> bool {
         let time = chrono::offset::Local::now();
         writeln!(
@@ -215,6 +369,31 @@ impl SystemUsers {
         }
     }
 
+    /// Stops `user_name` from logging in, returns false if there isn't such a user
+    pub fn disable_user(&mut self, user_name: &str) -> bool {
+        if !self.users_data.contains_key(user_name) {
+            return false;
+        }
+        let time = chrono::offset::Local::now();
+        writeln!(&self.log_file, "[{:?}] User '{}' disabled", time, user_name).unwrap();
+        self.disabled.insert(user_name.to_string());
+        true
+    }
+
+    /// Lets a disabled user log in again, returns false if it wasn't disabled
+    pub fn enable_user(&mut self, user_name: &str) -> bool {
+        if !self.disabled.remove(user_name) {
+            return false;
+        }
+        let time = chrono::offset::Local::now();
+        writeln!(&self.log_file, "[{:?}] User '{}' enabled", time, user_name).unwrap();
+        true
+    }
+
+    pub fn is_disabled(&self, user_name: &str) -> bool {
+        self.disabled.contains(user_name)
+    }
+
     pub fn get_user<'a>(&'a self, user_name: &str) -> Option<&'a User> {
         if self.user_exists(user_name) {
             Some(&self.users_data[user_name])
@@ -265,7 +444,7 @@ impl SystemUsers {
             }
         }
 
-        let user = User::new(user_name, passwd, uid);
+        let user = User::new_in(&self.root, user_name, passwd, uid);
         let _ = fs::create_dir(&user.get_actual_dir());
         self.users_data.insert(user_name.to_string(), user);
         self.serialize_users().unwrap();
@@ -325,7 +504,7 @@ impl SystemUsers {
 
     fn serialize_users(&self) -> Result<(), Box<dyn Error>> {
         let user_data = serde_json::to_string_pretty(&self.users_data)?;
-        fs::write(USER_PATH, &user_data)?;
+        fs::write(&self.config_path, &user_data)?;
         Ok(())
     }
 }
@@ -415,6 +594,21 @@ mod system_users_test {
         assert!(!user.are_equal_paths("./home/qwerty2//././././././//./thing3/thing4/./."));
     }
 
+    #[test]
+    fn disable_enable_user() {
+        let mut sys_users = SystemUsers::load_data(USER_PATH).unwrap();
+        assert!(!sys_users.disable_user("nobody_here"));
+        assert!(sys_users.disable_user("admin"));
+        assert!(sys_users.is_disabled("admin"));
+        assert!(!sys_users.is_disabled("user"));
+        let reloaded = SystemUsers::load_data(USER_PATH).unwrap();
+        sys_users.replace_users(reloaded);
+        assert!(sys_users.is_disabled("admin"));
+        assert!(sys_users.enable_user("admin"));
+        assert!(!sys_users.enable_user("admin"));
+        assert!(!sys_users.is_disabled("admin"));
+    }
+
     // cargo t create_delete_user -- --nocapture
     #[test]
     fn create_delete_user() {